@@ -0,0 +1,216 @@
+use crate::config::Config;
+use rust_nes_emulator::joypad::JoypadButton;
+use sdl2::controller::Button;
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    Reset,
+    SaveState,
+    LoadState,
+    Rewind,
+    FastForward,
+    SlowMotion,
+    SwitchDisk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // player, button
+    Button(usize, JoypadButton),
+    Turbo(usize, JoypadButton),
+    Hotkey(Hotkey),
+}
+
+/// Config names resolved to SDL keys and game controller buttons
+pub struct Bindings {
+    keys: HashMap<Keycode, Action>,
+    gamepads: [HashMap<Button, Action>; 2],
+}
+
+fn button_action(player: usize, name: &str) -> Action {
+    match name {
+        "up" => Action::Button(player, JoypadButton::UP),
+        "down" => Action::Button(player, JoypadButton::DOWN),
+        "left" => Action::Button(player, JoypadButton::LEFT),
+        "right" => Action::Button(player, JoypadButton::RIGHT),
+        "a" => Action::Button(player, JoypadButton::BUTTON_A),
+        "b" => Action::Button(player, JoypadButton::BUTTON_B),
+        "select" => Action::Button(player, JoypadButton::SELECT),
+        "start" => Action::Button(player, JoypadButton::START),
+        "turbo_a" => Action::Turbo(player, JoypadButton::BUTTON_A),
+        "turbo_b" => Action::Turbo(player, JoypadButton::BUTTON_B),
+        _ => unreachable!("config only accepts known buttons"),
+    }
+}
+
+fn hotkey_action(name: &str) -> Action {
+    Action::Hotkey(match name {
+        "pause" => Hotkey::Pause,
+        "reset" => Hotkey::Reset,
+        "save_state" => Hotkey::SaveState,
+        "load_state" => Hotkey::LoadState,
+        "rewind" => Hotkey::Rewind,
+        "fast_forward" => Hotkey::FastForward,
+        "slow_motion" => Hotkey::SlowMotion,
+        "switch_disk" => Hotkey::SwitchDisk,
+        _ => unreachable!("config only accepts known hotkeys"),
+    })
+}
+
+// adds a binding unless the input already triggers something else
+fn bind<K: std::hash::Hash + Eq + Copy>(
+    map: &mut HashMap<K, (Action, String)>,
+    input: K,
+    name: &str,
+    action: Action,
+    entry: String,
+) -> Result<(), String> {
+    if let Some((_, other)) = map.get(&input) {
+        if *other == entry {
+            return Err(format!("{}: '{}' is listed twice", entry, name));
+        }
+        return Err(format!(
+            "'{}' is bound to both {} and {}",
+            name, other, entry
+        ));
+    }
+    map.insert(input, (action, entry));
+    Ok(())
+}
+
+// drops the config entry names that were kept for error messages
+fn strip<K: std::hash::Hash + Eq>(map: HashMap<K, (Action, String)>) -> HashMap<K, Action> {
+    map.into_iter()
+        .map(|(input, (action, _))| (input, action))
+        .collect()
+}
+
+impl Bindings {
+    pub fn new(config: &Config) -> Result<Bindings, String> {
+        let mut keys = HashMap::new();
+        let mut gamepads = [HashMap::new(), HashMap::new()];
+
+        for (player, bindings) in config.players.iter().enumerate() {
+            for (button, names) in &bindings.keyboard {
+                let entry = format!("player{}.keyboard.{}", player + 1, button);
+                for name in names {
+                    let key = Keycode::from_name(name)
+                        .ok_or(format!("{}: unknown key '{}'", entry, name))?;
+                    bind(
+                        &mut keys,
+                        key,
+                        name,
+                        button_action(player, button),
+                        entry.clone(),
+                    )?;
+                }
+            }
+            for (button, names) in &bindings.gamepad {
+                let entry = format!("player{}.gamepad.{}", player + 1, button);
+                for name in names {
+                    let pad_button = Button::from_string(name).ok_or(format!(
+                        "{}: unknown game controller button '{}'",
+                        entry, name
+                    ))?;
+                    let action = button_action(player, button);
+                    bind(
+                        &mut gamepads[player],
+                        pad_button,
+                        name,
+                        action,
+                        entry.clone(),
+                    )?;
+                }
+            }
+        }
+        for (hotkey, names) in &config.hotkeys {
+            let entry = format!("hotkeys.{}", hotkey);
+            for name in names {
+                let key =
+                    Keycode::from_name(name).ok_or(format!("{}: unknown key '{}'", entry, name))?;
+                bind(&mut keys, key, name, hotkey_action(hotkey), entry.clone())?;
+            }
+        }
+
+        let [pad1, pad2] = gamepads;
+        Ok(Bindings {
+            keys: strip(keys),
+            gamepads: [strip(pad1), strip(pad2)],
+        })
+    }
+
+    pub fn key(&self, key: Keycode) -> Option<Action> {
+        self.keys.get(&key).copied()
+    }
+
+    /// Action of a button on the game controller assigned to `player`
+    pub fn gamepad(&self, player: usize, button: Button) -> Option<Action> {
+        self.gamepads[player].get(&button).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bindings(text: &str) -> Result<Bindings, String> {
+        Bindings::new(&Config::parse(text).unwrap())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let bindings = Bindings::new(&Config::default()).unwrap();
+        assert_eq!(
+            bindings.key(Keycode::X),
+            Some(Action::Button(0, JoypadButton::BUTTON_A))
+        );
+        assert_eq!(
+            bindings.key(Keycode::F5),
+            Some(Action::Hotkey(Hotkey::SaveState))
+        );
+        assert_eq!(
+            bindings.gamepad(1, Button::Start),
+            Some(Action::Button(1, JoypadButton::START))
+        );
+        assert_eq!(bindings.key(Keycode::Q), None);
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        assert_eq!(
+            bindings("[player1.keyboard]\na = \"Nope\"").err().unwrap(),
+            "player1.keyboard.a: unknown key 'Nope'"
+        );
+        assert_eq!(
+            bindings("[player2.gamepad]\nb = \"z\"").err().unwrap(),
+            "player2.gamepad.b: unknown game controller button 'z'"
+        );
+        assert_eq!(
+            bindings("[hotkeys]\nreset = \"F13X\"").err().unwrap(),
+            "hotkeys.reset: unknown key 'F13X'"
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        assert_eq!(
+            bindings("[player1.keyboard]\na = \"P\"").err().unwrap(),
+            "'P' is bound to both player1.keyboard.a and hotkeys.pause"
+        );
+        assert_eq!(
+            bindings("[player2.keyboard]\nup = \"X\"").err().unwrap(),
+            "'X' is bound to both player1.keyboard.a and player2.keyboard.up"
+        );
+        assert_eq!(
+            bindings("[player1.keyboard]\nb = [\"K\", \"K\"]")
+                .err()
+                .unwrap(),
+            "player1.keyboard.b: 'K' is listed twice"
+        );
+        // each player has a controller of their own
+        assert!(bindings("[player2.gamepad]\na = \"guide\"").is_ok());
+    }
+}
@@ -0,0 +1,280 @@
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+// Bindings as written in the config file: SDL key names ("Right Shift", "Return") and
+// SDL game controller button names ("a", "dpup", "start"). Every entry takes one name
+// or a list; entries that are not in the file keep their default, `[]` unbinds.
+//
+//  [player1.keyboard]
+//  a = "X"
+//  b = ["Z", "Left Ctrl"]
+//
+//  [player2.gamepad]
+//  start = "start"
+//
+//  [hotkeys]
+//  rewind = "Backspace"
+//
+// Resolving the names happens in `bindings`, this only reads the file.
+
+pub const BUTTONS: [&str; 10] = [
+    "up", "down", "left", "right", "a", "b", "select", "start", "turbo_a", "turbo_b",
+];
+pub const HOTKEYS: [&str; 8] = [
+    "pause",
+    "reset",
+    "save_state",
+    "load_state",
+    "rewind",
+    "fast_forward",
+    "slow_motion",
+    "switch_disk",
+];
+
+/// Names bound to each action
+pub type Table = BTreeMap<String, Vec<String>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Player {
+    pub keyboard: Table,
+    pub gamepad: Table,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub players: [Player; 2],
+    pub hotkeys: Table,
+}
+
+// one name or a list of names
+struct Keys(Vec<String>);
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeysVisitor;
+
+        impl<'de> Visitor<'de> for KeysVisitor {
+            type Value = Keys;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a key or button name, or a list of names")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Keys, E> {
+                Ok(Keys(vec![name.to_string()]))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Keys, A::Error> {
+                let mut names = vec![];
+                while let Some(name) = seq.next_element()? {
+                    names.push(name);
+                }
+                Ok(Keys(names))
+            }
+        }
+
+        deserializer.deserialize_any(KeysVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlayerFile {
+    keyboard: Option<HashMap<String, Keys>>,
+    gamepad: Option<HashMap<String, Keys>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    player1: Option<PlayerFile>,
+    player2: Option<PlayerFile>,
+    hotkeys: Option<HashMap<String, Keys>>,
+}
+
+fn table(entries: &[(&str, &str)]) -> Table {
+    entries
+        .iter()
+        .map(|(action, name)| (action.to_string(), vec![name.to_string()]))
+        .collect()
+}
+
+fn default_gamepad() -> Table {
+    table(&[
+        ("up", "dpup"),
+        ("down", "dpdown"),
+        ("left", "dpleft"),
+        ("right", "dpright"),
+        // by position: the bottom face button is B on a NES pad
+        ("a", "b"),
+        ("b", "a"),
+        ("select", "back"),
+        ("start", "start"),
+        ("turbo_a", "y"),
+        ("turbo_b", "x"),
+    ])
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let player1 = Player {
+            keyboard: table(&[
+                ("up", "Up"),
+                ("down", "Down"),
+                ("left", "Left"),
+                ("right", "Right"),
+                ("a", "X"),
+                ("b", "Z"),
+                ("select", "Right Shift"),
+                ("start", "Return"),
+                ("turbo_a", "S"),
+                ("turbo_b", "A"),
+            ]),
+            gamepad: default_gamepad(),
+        };
+        let player2 = Player {
+            keyboard: Table::new(),
+            gamepad: default_gamepad(),
+        };
+        Config {
+            players: [player1, player2],
+            hotkeys: table(&[
+                ("pause", "P"),
+                ("reset", "F1"),
+                ("save_state", "F5"),
+                ("load_state", "F7"),
+                ("rewind", "Backspace"),
+                ("fast_forward", "Tab"),
+                ("slow_motion", "Left Shift"),
+                ("switch_disk", "F3"),
+            ]),
+        }
+    }
+}
+
+fn merge(
+    section: &str,
+    table: &mut Table,
+    file: Option<HashMap<String, Keys>>,
+    actions: &[&str],
+) -> Result<(), String> {
+    let mut entries: Vec<(String, Keys)> = file.unwrap_or_default().into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (action, keys) in entries {
+        if !actions.contains(&action.as_str()) {
+            return Err(format!(
+                "[{}]: unknown action '{}', expected one of {}",
+                section,
+                action,
+                actions.join(", ")
+            ));
+        }
+        table.insert(action, keys.0);
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let file: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut config = Config::default();
+
+        for (i, player) in [file.player1, file.player2].into_iter().enumerate() {
+            if let Some(player) = player {
+                let bindings = &mut config.players[i];
+                let section = format!("player{}.keyboard", i + 1);
+                merge(&section, &mut bindings.keyboard, player.keyboard, &BUTTONS)?;
+                let section = format!("player{}.gamepad", i + 1);
+                merge(&section, &mut bindings.gamepad, player.gamepad, &BUTTONS)?;
+            }
+        }
+        merge("hotkeys", &mut config.hotkeys, file.hotkeys, &HOTKEYS)?;
+        Ok(config)
+    }
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config = Config::parse(
+            "
+            [player1.keyboard]
+            a = \"K\"
+            b = [\"J\", \"Left Ctrl\"]
+            turbo_a = []
+
+            [player2.gamepad]
+            start = \"guide\"
+            ",
+        )
+        .unwrap();
+        let player1 = &config.players[0];
+        assert_eq!(player1.keyboard["a"], names(&["K"]));
+        assert_eq!(player1.keyboard["b"], names(&["J", "Left Ctrl"]));
+        assert_eq!(player1.keyboard["turbo_a"], names(&[]));
+        // entries that are not in the file keep their default
+        assert_eq!(player1.keyboard["start"], names(&["Return"]));
+        assert_eq!(config.players[1].gamepad["start"], names(&["guide"]));
+        assert_eq!(config.hotkeys["pause"], names(&["P"]));
+    }
+
+    #[test]
+    fn test_unknown_actions() {
+        assert_eq!(
+            Config::parse("[hotkeys]\njump = \"J\"").err().unwrap(),
+            "[hotkeys]: unknown action 'jump', expected one of pause, reset, save_state, \
+             load_state, rewind, fast_forward, slow_motion, switch_disk"
+        );
+        assert_eq!(
+            Config::parse("[player2.keyboard]\nturbo = \"T\"")
+                .err()
+                .unwrap(),
+            "[player2.keyboard]: unknown action 'turbo', expected one of up, down, left, \
+             right, a, b, select, start, turbo_a, turbo_b"
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let err = Config::parse("[player3.keyboard]\na = \"K\"")
+            .err()
+            .unwrap();
+        assert!(
+            err.starts_with(
+                "unknown field `player3`, expected one of `player1`, `player2`, `hotkeys`"
+            ),
+            "{}",
+            err
+        );
+        let err = Config::parse("[player1.mouse]\na = \"left\"")
+            .err()
+            .unwrap();
+        assert!(
+            err.starts_with("unknown field `mouse`, expected `keyboard` or `gamepad`"),
+            "{}",
+            err
+        );
+        let err = Config::parse("[hotkeys]\npause = 5").err().unwrap();
+        assert!(
+            err.starts_with(
+                "invalid type: integer `5`, expected a key or button name, or a list of names"
+            ),
+            "{}",
+            err
+        );
+    }
+}
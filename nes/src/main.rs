@@ -0,0 +1,593 @@
+mod bindings;
+mod config;
+
+use bindings::{Action, Bindings, Hotkey};
+use config::Config;
+use rust_nes_emulator::apu::wav;
+use rust_nes_emulator::bus::Bus;
+use rust_nes_emulator::cpu::cpu::CPU;
+use rust_nes_emulator::diag::{Diagnostic, Diagnostics};
+use rust_nes_emulator::joypad::JoypadButton;
+use rust_nes_emulator::mapper::Fds;
+use rust_nes_emulator::pacing::{Pacer, SyncMode};
+use rust_nes_emulator::patch;
+use rust_nes_emulator::region::Region;
+use rust_nes_emulator::render::frame::Frame;
+use rust_nes_emulator::render::ntsc::NtscFilter;
+use rust_nes_emulator::render::palette::Palette;
+use rust_nes_emulator::rom::db::RomDb;
+use rust_nes_emulator::rom::fds::FdsImage;
+use rust_nes_emulator::rom::loader;
+use rust_nes_emulator::savestate;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: nes [options] ROM
+
+options:
+  --config FILE          key and game controller bindings
+  --sync timer|audio     pace emulation by the system clock (default) or by audio playback
+  --speed X              run at X times the normal speed, e.g. 0.5
+  --patch FILE           IPS, BPS or UPS patch to apply, by default the one next to
+                         the ROM with the same name (game.ips for game.nes)
+  --region ntsc|pal|dendy
+                         console to emulate instead of the one the ROM header asks for
+  --no-db                keep the ROM header as it is when the ROM database knows the game
+  --db FILE              add the games listed in FILE to the ROM database
+  --bios FILE            Famicom Disk System BIOS for .fds images (default disksys.rom)
+  --filter none|ntsc     video filter, ntsc simulates composite video artifacts
+  --benchmark            run as fast as possible and print the frame rate
+  --log                  print unmapped accesses, ROM writes and unknown opcodes
+
+ROM can be a .nes, .unf or .fds file or a .zip or .gz archive with one.
+
+There is no PPU yet: the screen stays black and the emulator stops with an
+error when the game first touches a PPU register, which nearly all games do
+right after reset. Programs that only use the CPU, APU and controllers run.
+
+Key and game controller bindings are read from FILE, or from nes.toml in the
+current directory when it exists. Default bindings:
+
+  arrows             d-pad
+  Z / X              B / A
+  A / S              turbo B / turbo A
+  Right Shift        select
+  Enter              start
+  P                  pause
+  F1                 reset
+  F5 / F7            save / load state
+  Backspace (hold)   rewind
+  Tab (hold)         fast-forward
+  Left Shift (hold)  slow motion
+  F3                 eject the disk / insert the next side
+  Escape             quit
+
+Game controllers are assigned to players 1 and 2 in the order they are connected.";
+
+const DEFAULT_CONFIG: &str = "nes.toml";
+const DEFAULT_BIOS: &str = "disksys.rom";
+const SCALE: u32 = 3;
+// NTSC pixels are 8/7 wider than tall
+const PIXEL_ASPECT: f32 = 8.0 / 7.0;
+const SAMPLE_RATE: i32 = 44_100;
+// audio sync keeps this much sound queued
+const AUDIO_LATENCY: Duration = Duration::from_millis(50);
+// turbo buttons alternate between pressed and released every 2 frames
+const TURBO_PERIOD: usize = 4;
+// 10 seconds of one state per frame
+const REWIND_STATES: usize = 600;
+const FAST_FORWARD_SPEED: f64 = 4.0;
+const SLOW_MOTION_SPEED: f64 = 0.5;
+
+struct Args {
+    config: Option<String>,
+    sync: SyncMode,
+    speed: f64,
+    region: Option<Region>,
+    no_db: bool,
+    db: Option<String>,
+    patch: Option<String>,
+    bios: String,
+    ntsc: bool,
+    log: bool,
+    rom: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut config = None;
+    let mut sync = SyncMode::Timer;
+    let mut speed = 1.0;
+    let mut region = None;
+    let mut no_db = false;
+    let mut db = None;
+    let mut patch = None;
+    let mut bios = DEFAULT_BIOS.to_string();
+    let mut ntsc = false;
+    let mut log = false;
+    let mut rom = None;
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--config" => config = Some(value()?),
+            "--sync" => {
+                sync = match value()?.as_str() {
+                    "timer" => SyncMode::Timer,
+                    "audio" => SyncMode::Audio,
+                    mode => return Err(format!("unknown sync mode '{}'", mode)),
+                }
+            }
+            "--speed" => {
+                let value = value()?;
+                speed = value
+                    .parse()
+                    .ok()
+                    .filter(|speed: &f64| *speed > 0.0)
+                    .ok_or(format!("malformed speed '{}'", value))?;
+            }
+            "--region" => region = Some(value()?.parse()?),
+            "--no-db" => no_db = true,
+            "--db" => db = Some(value()?),
+            "--patch" => patch = Some(value()?),
+            "--bios" => bios = value()?,
+            "--filter" => {
+                ntsc = match value()?.as_str() {
+                    "none" => false,
+                    "ntsc" => true,
+                    filter => return Err(format!("unknown video filter '{}'", filter)),
+                }
+            }
+            "--benchmark" => sync = SyncMode::Uncapped,
+            "--log" => log = true,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+    if no_db && db.is_some() {
+        return Err("--db and --no-db exclude each other".to_string());
+    }
+    Ok(Args {
+        config,
+        sync,
+        speed,
+        region,
+        no_db,
+        db,
+        patch,
+        bios,
+        ntsc,
+        log,
+        rom: rom.ok_or(USAGE)?,
+    })
+}
+
+fn load_config(args: &Args) -> Result<Config, String> {
+    match &args.config {
+        Some(path) => Config::load(path),
+        None if Path::new(DEFAULT_CONFIG).exists() => Config::load(DEFAULT_CONFIG),
+        None => Ok(Config::default()),
+    }
+}
+
+// largest rectangle with the NES aspect ratio that fits the window, centered
+fn display_rect(width: u32, height: u32) -> Rect {
+    let aspect = Frame::WIDTH as f32 * PIXEL_ASPECT / Frame::HEIGHT as f32;
+    let (w, h) = if width as f32 / height as f32 > aspect {
+        ((height as f32 * aspect) as u32, height)
+    } else {
+        (width, (width as f32 / aspect) as u32)
+    };
+    Rect::new(((width - w) / 2) as i32, ((height - h) / 2) as i32, w, h)
+}
+
+// sound waiting to be played by the device
+fn queued_audio(audio: &AudioQueue<i16>) -> Duration {
+    let samples = audio.size() as usize / std::mem::size_of::<i16>();
+    Duration::from_secs_f64(samples as f64 / SAMPLE_RATE as f64)
+}
+
+struct Emulator {
+    cpu: CPU,
+    pacer: Pacer,
+    state_path: PathBuf,
+    paused: bool,
+    rewinding: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    frames: usize,
+    // CPU cycle at which the current frame ends; NTSC frames are 29780.5 cycles on average
+    frame_end: f64,
+    // buttons held per player, directly and through turbo
+    held: [JoypadButton; 2],
+    turbo: [JoypadButton; 2],
+    rewind: VecDeque<Vec<u8>>,
+    // disk side that goes in after the next eject
+    next_side: usize,
+}
+
+impl Emulator {
+    fn run_frame(&mut self) -> Result<(), String> {
+        let turbo_on = self.frames % TURBO_PERIOD < TURBO_PERIOD / 2;
+        for player in 0..2 {
+            let mut buttons = self.held[player];
+            if turbo_on {
+                buttons |= self.turbo[player];
+            }
+            let joypad = self.cpu.bus_mut().joypad(player);
+            joypad.set_button_pressed_status(JoypadButton::all(), false);
+            joypad.set_button_pressed_status(buttons, true);
+        }
+
+        self.frame_end += self.cpu.bus().region().cycles_per_frame();
+        while (self.cpu.cycles as f64) < self.frame_end {
+            if !self.cpu.step() {
+                return Err(self
+                    .cpu
+                    .halt()
+                    .map(|halt| halt.to_string())
+                    .unwrap_or_default());
+            }
+        }
+        self.frames += 1;
+
+        if self.rewind.len() == REWIND_STATES {
+            self.rewind.pop_front();
+        }
+        self.rewind.push_back(savestate::save(&self.cpu));
+        Ok(())
+    }
+
+    fn step_back(&mut self) {
+        if let Some(state) = self.rewind.pop_back() {
+            savestate::load(&mut self.cpu, &state).unwrap();
+            self.restart_timing();
+        }
+    }
+
+    // the cycle count jumped or time stood still: frames and pacing start over from here
+    fn restart_timing(&mut self) {
+        self.frame_end = self.cpu.cycles as f64;
+        self.pacer.restart(self.cpu.cycles);
+    }
+
+    fn speed(&self, base: f64) -> f64 {
+        if self.fast_forward {
+            base * FAST_FORWARD_SPEED
+        } else if self.slow_motion {
+            base * SLOW_MOTION_SPEED
+        } else {
+            base
+        }
+    }
+
+    fn save_state(&self) {
+        match std::fs::write(&self.state_path, savestate::save(&self.cpu)) {
+            Ok(()) => println!("saved state to {}", self.state_path.display()),
+            Err(e) => eprintln!("{}: {}", self.state_path.display(), e),
+        }
+    }
+
+    fn load_state(&mut self) {
+        let result = std::fs::read(&self.state_path)
+            .map_err(|e| e.to_string())
+            .and_then(|state| savestate::load(&mut self.cpu, &state));
+        match result {
+            Ok(()) => {
+                self.restart_timing();
+                println!("loaded state from {}", self.state_path.display())
+            }
+            Err(e) => eprintln!("{}: {}", self.state_path.display(), e),
+        }
+    }
+
+    // ejects the disk, or inserts the next side when the drive is empty
+    fn switch_disk(&mut self) {
+        let Some(drive) = self.cpu.bus_mut().disk_drive() else {
+            return;
+        };
+        match drive.side() {
+            Some(side) => {
+                drive.eject();
+                self.next_side = (side + 1) % drive.sides();
+                println!("disk ejected");
+            }
+            None => {
+                drive.insert(self.next_side).unwrap();
+                let side = self.next_side;
+                println!(
+                    "disk {} side {} inserted",
+                    side / 2 + 1,
+                    ["A", "B"][side % 2]
+                );
+            }
+        }
+    }
+
+    fn handle(&mut self, action: Action, pressed: bool) {
+        match action {
+            Action::Button(player, button) => self.held[player].set(button, pressed),
+            Action::Turbo(player, button) => self.turbo[player].set(button, pressed),
+            Action::Hotkey(Hotkey::Rewind) => self.rewinding = pressed,
+            Action::Hotkey(Hotkey::FastForward) => self.fast_forward = pressed,
+            Action::Hotkey(Hotkey::SlowMotion) => self.slow_motion = pressed,
+            Action::Hotkey(_) if !pressed => {}
+            Action::Hotkey(Hotkey::Pause) => self.paused = !self.paused,
+            Action::Hotkey(Hotkey::Reset) => {
+                self.cpu.reset();
+                self.restart_timing();
+            }
+            Action::Hotkey(Hotkey::SaveState) => self.save_state(),
+            Action::Hotkey(Hotkey::LoadState) => self.load_state(),
+            Action::Hotkey(Hotkey::SwitchDisk) => self.switch_disk(),
+        }
+    }
+}
+
+fn load_cartridge(args: &Args, bytes: &Vec<u8>) -> Result<Bus, String> {
+    let mut rom = loader::parse(bytes)?;
+    let db = match &args.db {
+        _ if args.no_db => RomDb::default(),
+        Some(file) => RomDb::bundled_with(file)?,
+        None => RomDb::bundled(),
+    };
+    let game = db.correct(&mut rom);
+    if let Some(game) = game {
+        println!("{}", game.title);
+        if game.is_bad_dump() {
+            eprintln!("warning: {} is a known bad dump", args.rom);
+        }
+    }
+    if let Some(region) = args.region {
+        rom.region = region;
+    }
+    Bus::new(rom)
+}
+
+fn load_disk(args: &Args, bytes: &[u8]) -> Result<Bus, String> {
+    let image = FdsImage::parse(bytes)?;
+    let bios = std::fs::read(&args.bios).map_err(|e| format!("{}: {}", args.bios, e))?;
+    let fds = Fds::new(&image, bios).map_err(|e| format!("{}: {}", args.bios, e))?;
+    // the Famicom was only sold in NTSC regions
+    Ok(Bus::with_mapper(
+        Box::new(fds),
+        args.region.unwrap_or(Region::Ntsc),
+    ))
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let bindings = load_config(&args)
+        .and_then(|config| Bindings::new(&config))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        });
+
+    //load the game
+    let path = &args.rom;
+    let patch_path = args.patch.as_ref().map(Path::new);
+    let bytes = patch::read_patched(Path::new(path), patch_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let bus = if FdsImage::is_fds(&bytes) {
+        load_disk(&args, &bytes)
+    } else {
+        load_cartridge(&args, &bytes)
+    };
+    let mut bus = bus.unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+    if args.log {
+        bus.set_diagnostics(Some(Diagnostics::new(|d: &Diagnostic| eprintln!("{}", d))));
+    }
+    let region = bus.region();
+
+    let mut cpu = CPU::new(bus);
+    cpu.set_brk_halts(false);
+    cpu.reset();
+    cpu.bus_mut().apu().set_sample_rate(SAMPLE_RATE as u32);
+    let mut emulator = Emulator {
+        pacer: Pacer::new(region.cpu_frequency()),
+        frame_end: cpu.cycles as f64,
+        cpu,
+        state_path: Path::new(path).with_extension("state"),
+        paused: false,
+        rewinding: false,
+        fast_forward: false,
+        slow_motion: false,
+        frames: 0,
+        held: [JoypadButton::empty(); 2],
+        turbo: [JoypadButton::empty(); 2],
+        rewind: VecDeque::with_capacity(REWIND_STATES),
+        next_side: 0,
+    };
+
+    // init sdl2
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let title = format!(
+        "NES - {}",
+        Path::new(path).file_name().unwrap().to_string_lossy()
+    );
+    let window = video_subsystem
+        .window(
+            &title,
+            (Frame::WIDTH as f32 * PIXEL_ASPECT) as u32 * SCALE,
+            Frame::HEIGHT as u32 * SCALE,
+        )
+        .position_centered()
+        .resizable()
+        .build()
+        .unwrap();
+
+    // no vsync: the pacer decides when frames are shown
+    let mut canvas = window.into_canvas().build().unwrap();
+    let ntsc = args.ntsc.then(NtscFilter::default);
+    let texture_width = match &ntsc {
+        Some(filter) => filter.width,
+        None => Frame::WIDTH,
+    };
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            texture_width as u32,
+            Frame::HEIGHT as u32,
+        )
+        .unwrap();
+
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio: AudioQueue<i16> = audio_subsystem
+        .open_queue(
+            None,
+            &AudioSpecDesired {
+                freq: Some(SAMPLE_RATE),
+                channels: Some(1),
+                samples: Some(1024),
+            },
+        )
+        .unwrap();
+    audio.resume();
+
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    // opened controllers by joystick instance id, with the player they control
+    let mut controllers: HashMap<u32, (GameController, usize)> = HashMap::new();
+
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let palette = Palette::default();
+    // there is no PPU yet: the picture stays black
+    let picture = vec![0x0F; Frame::WIDTH * Frame::HEIGHT];
+    let mut frames = 0;
+    let frame_time = Duration::from_secs_f64(1.0 / region.frame_rate());
+    let mut benchmark = (Instant::now(), 0);
+    emulator.pacer.set_speed(args.speed, emulator.cpu.cycles);
+
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => std::process::exit(0),
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(action) = bindings.key(key) {
+                        emulator.handle(action, true);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(action) = bindings.key(key) {
+                        emulator.handle(action, false);
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Ok(controller) = controller_subsystem.open(which) {
+                        let player = (0..2)
+                            .find(|p| controllers.values().all(|(_, used)| used != p))
+                            .unwrap_or(1);
+                        controllers.insert(controller.instance_id(), (controller, player));
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.remove(&which);
+                }
+                Event::ControllerButtonDown { which, button, .. }
+                | Event::ControllerButtonUp { which, button, .. } => {
+                    let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                    let action = controllers
+                        .get(&which)
+                        .and_then(|(_, player)| bindings.gamepad(*player, button));
+                    if let Some(action) = action {
+                        emulator.handle(action, pressed);
+                    }
+                }
+                _ => { /* do nothing */ }
+            }
+        }
+
+        let speed = emulator.speed(args.speed);
+        if speed != emulator.pacer.speed() {
+            emulator.pacer.set_speed(speed, emulator.cpu.cycles);
+        }
+
+        if emulator.rewinding {
+            emulator.step_back();
+            std::thread::sleep(frame_time);
+        } else if emulator.paused {
+            std::thread::sleep(frame_time);
+            emulator.restart_timing();
+        } else {
+            if let Err(e) = emulator.run_frame() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            let samples = emulator.cpu.bus_mut().apu().take_samples();
+
+            // audio only plays at normal speed, otherwise the clock paces
+            let sync = match args.sync {
+                SyncMode::Audio if speed != 1.0 => SyncMode::Timer,
+                sync => sync,
+            };
+            if sync != SyncMode::Uncapped && speed == 1.0 {
+                // with timer sync the sound card clock drifts from ours, drop what piles up
+                if queued_audio(&audio) < AUDIO_LATENCY * 4 {
+                    let samples: Vec<i16> = samples.into_iter().map(wav::to_i16).collect();
+                    audio.queue_audio(&samples).unwrap();
+                }
+            } else {
+                audio.clear();
+            }
+
+            match sync {
+                SyncMode::Timer => emulator.pacer.wait(emulator.cpu.cycles),
+                SyncMode::Audio => Pacer::wait_for_audio(|| queued_audio(&audio), AUDIO_LATENCY),
+                SyncMode::Uncapped => {
+                    benchmark.1 += 1;
+                    let elapsed = benchmark.0.elapsed().as_secs_f64();
+                    if elapsed >= 1.0 {
+                        let fps = benchmark.1 as f64 / elapsed;
+                        println!("{:.1} fps ({:.0}%)", fps, fps / region.frame_rate() * 100.0);
+                        benchmark = (Instant::now(), 0);
+                    }
+                }
+            }
+        }
+
+        match &ntsc {
+            Some(filter) => {
+                let rgb = filter.filter(&picture, 0, frames);
+                texture.update(None, &rgb, filter.width * 3).unwrap();
+            }
+            None => {
+                let frame = palette.to_frame(&picture, 0);
+                texture.update(None, &frame.data, Frame::WIDTH * 3).unwrap();
+            }
+        }
+        frames += 1;
+        let (width, height) = canvas.output_size().unwrap();
+        canvas.clear();
+        canvas
+            .copy(&texture, None, Some(display_rect(width, height)))
+            .unwrap();
+        canvas.present();
+    }
+}
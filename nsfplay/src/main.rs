@@ -0,0 +1,110 @@
+use rust_nes_emulator::apu::{wav, DEFAULT_SAMPLE_RATE};
+use rust_nes_emulator::nsf::{Nsf, NsfPlayer};
+use rust_nes_emulator::region::Region;
+use std::fs::File;
+use std::io::BufWriter;
+
+const USAGE: &str =
+    "usage: nsfplay [--track N] [--seconds S] [--rate HZ] [--region ntsc|pal|dendy] FILE OUTPUT.wav
+
+Plays a track of an NSF or NSFe file and writes it to a WAV file.
+Tracks are numbered from 1, the default is the starting track of the file.
+The length defaults to the one in the NSFe file, or 120 seconds.";
+
+const DEFAULT_SECONDS: f64 = 120.0;
+
+struct Args {
+    track: Option<u8>,
+    seconds: Option<f64>,
+    rate: u32,
+    region: Option<Region>,
+    file: String,
+    output: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut track = None;
+    let mut seconds = None;
+    let mut rate = DEFAULT_SAMPLE_RATE;
+    let mut region = None;
+    let mut paths = vec![];
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--track" => {
+                let n: u8 = value()?.parse().map_err(|_| "malformed --track")?;
+                track = Some(n.checked_sub(1).ok_or("tracks are numbered from 1")?);
+            }
+            "--seconds" => seconds = Some(value()?.parse().map_err(|_| "malformed --seconds")?),
+            "--rate" => rate = value()?.parse().map_err(|_| "malformed --rate")?,
+            "--region" => region = Some(value()?.parse()?),
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => paths.push(arg),
+        }
+    }
+    match <[String; 2]>::try_from(paths) {
+        Ok([file, output]) => Ok(Args {
+            track,
+            seconds,
+            rate,
+            region,
+            file,
+            output,
+        }),
+        Err(_) => Err(USAGE.to_string()),
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let nsf = Nsf::load(&args.file)?;
+    let track = args.track.unwrap_or(nsf.starting_song);
+    println!("{}", nsf.title);
+    println!("{}", nsf.artist);
+    println!("{}", nsf.copyright);
+    match nsf.track_title(track) {
+        Some(title) => println!("track {}/{}: {}", track + 1, nsf.songs, title),
+        None => println!("track {}/{}", track + 1, nsf.songs),
+    }
+
+    // lengths from the NSFe file include the fade out
+    let time = nsf.track_times.get(track as usize).copied().flatten();
+    let fade = nsf.track_fades.get(track as usize).copied().flatten();
+    let (seconds, fade) = match (args.seconds, time) {
+        (Some(seconds), _) => (seconds, 0.0),
+        (None, Some(time)) => {
+            let fade = fade.unwrap_or(0) as f64 / 1000.0;
+            (time as f64 / 1000.0 + fade, fade)
+        }
+        (None, None) => (DEFAULT_SECONDS, 0.0),
+    };
+
+    let mut player = NsfPlayer::new(nsf, args.region);
+    player.set_sample_rate(args.rate);
+    player.start_song(track)?;
+    let mut samples = player.render(seconds)?;
+
+    let fade_len = (fade * args.rate as f64) as usize;
+    let fade_start = samples.len().saturating_sub(fade_len);
+    for (i, sample) in samples[fade_start..].iter_mut().enumerate() {
+        *sample *= 1.0 - i as f32 / fade_len as f32;
+    }
+
+    let file = File::create(&args.output).map_err(|e| format!("{}: {}", args.output, e))?;
+    wav::write(&mut BufWriter::new(file), args.rate, &samples)
+        .map_err(|e| format!("{}: {}", args.output, e))?;
+    println!("{:.1}s -> {}", seconds, args.output);
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
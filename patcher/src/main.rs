@@ -0,0 +1,61 @@
+use rust_nes_emulator::patch;
+use rust_nes_emulator::rom::loader;
+use std::path::Path;
+
+const USAGE: &str = "usage: patcher [--patch FILE] ROM OUTPUT
+
+Applies an IPS, BPS or UPS patch to ROM and writes the result to OUTPUT.
+Without --patch uses the patch next to the ROM with the same name,
+e.g. game.ips for game.nes.";
+
+struct Args {
+    patch: Option<String>,
+    rom: String,
+    output: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut patch = None;
+    let mut paths = vec![];
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--patch" => patch = Some(argv.next().ok_or("--patch needs a value")?),
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => paths.push(arg),
+        }
+    }
+    match <[String; 2]>::try_from(paths) {
+        Ok([rom, output]) => Ok(Args { patch, rom, output }),
+        Err(_) => Err(USAGE.to_string()),
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let rom_path = Path::new(&args.rom);
+    let patch_path = match &args.patch {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => patch::find_patch(rom_path)
+            .ok_or(format!("{}: no patch found next to the ROM", args.rom))?,
+    };
+    let patched = patch::read_patched(rom_path, Some(&patch_path))?;
+    // patches for other formats are fine, only warn
+    if let Err(e) = loader::parse(&patched) {
+        eprintln!("warning: {}: {}", args.output, e);
+    }
+    std::fs::write(&args.output, patched).map_err(|e| format!("{}: {}", args.output, e))?;
+    println!("{} + {} -> {}", args.rom, patch_path.display(), args.output);
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
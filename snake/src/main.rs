@@ -1,14 +1,48 @@
 use rand::Rng;
-use rust_nes_emulator::bus::Bus;
 use rust_nes_emulator::cpu::cpu::CPU;
-use rust_nes_emulator::cpu::mem::Mem;
-use rust_nes_emulator::rom::Rom;
+use rust_nes_emulator::cpu::mem::{BusHooks, Mem};
+use rust_nes_emulator::pacing::Pacer;
+use rust_nes_emulator::rom::loader;
 use sdl2::{
     event::Event,
     keyboard::Keycode,
     pixels::{Color, PixelFormatEnum},
     EventPump,
 };
+use std::path::Path;
+
+// the game has no timer of its own, the emulated clock sets its speed
+const CPU_FREQUENCY: f64 = 50_000.0;
+
+/// All the game needs of a console: 2KiB of RAM, mirrored up to $1FFF, and
+/// PRG-ROM at $8000-$FFFF. $FE is a random number, $FF the last key pressed
+/// and $0200-$05FF the 32x32 screen.
+struct Machine {
+    ram: [u8; 0x800],
+    prg_rom: Vec<u8>,
+}
+
+impl Mem for Machine {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.mem_peek(addr)
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize & 0x7FF],
+            0x8000..=0xFFFF => self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        if addr <= 0x1FFF {
+            self.ram[addr as usize & 0x7FF] = data;
+        }
+    }
+}
+
+impl BusHooks for Machine {}
 
 fn color(byte: u8) -> Color {
     match byte {
@@ -24,11 +58,11 @@ fn color(byte: u8) -> Color {
     }
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
+fn read_screen_state(cpu: &CPU<Machine>, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     let mut frame_idx = 0;
     let mut update = false;
     for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
+        let color_idx = cpu.mem_peek(i as u16);
         let (b1, b2, b3) = color(color_idx).rgb();
         if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
             frame[frame_idx] = b1;
@@ -41,7 +75,7 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+fn handle_user_input(cpu: &mut CPU<Machine>, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
@@ -98,15 +132,26 @@ fn main() {
         .unwrap();
 
     //load the game
-    let bytes: Vec<u8> = std::fs::read("snake.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let path = std::env::args().nth(1).unwrap_or("snake.nes".to_string());
+    let rom = loader::load(Path::new(&path)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
 
-    let bus = Bus::new(rom);
-    let mut cpu = CPU::new(bus);
+    if rom.prg_rom.is_empty() {
+        eprintln!("{}: no PRG-ROM", path);
+        std::process::exit(1);
+    }
+    let mut cpu = CPU::new(Machine {
+        ram: [0; 0x800],
+        prg_rom: rom.prg_rom,
+    });
     cpu.reset();
 
     let mut screen_state = [0 as u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
+    let mut pacer = Pacer::new(CPU_FREQUENCY);
+    pacer.restart(cpu.cycles);
 
     // run the game cycle
     cpu.run_with_callback(move |cpu| {
@@ -122,6 +167,6 @@ fn main() {
             canvas.present();
         }
 
-        ::std::thread::sleep(std::time::Duration::new(0, 70_000));
+        pacer.wait(cpu.cycles);
     });
 }
@@ -0,0 +1,169 @@
+use super::StateReader;
+
+/// Delta modulation channel, $4010-$4013: plays 1-bit delta encoded samples from
+/// $C000-$FFFF, the bus feeds it the bytes it asks for through `fill`
+pub struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    pub irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    pub bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new(rates: &'static [u16; 16]) -> Self {
+        Dmc {
+            rates,
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate: rates[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = self.rates[(data & 0x0F) as usize];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Address of the next sample byte when the buffer is empty
+    pub fn pending_read(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the rates are in CPU cycles
+    pub const STATE_LEN: usize = 21;
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.irq_enabled as u8, self.irq as u8, self.looping as u8]);
+        out.extend(self.rate.to_le_bytes());
+        out.extend(self.timer.to_le_bytes());
+        out.push(self.level);
+        for value in [
+            self.sample_address,
+            self.sample_length,
+            self.address,
+            self.bytes_remaining,
+        ] {
+            out.extend(value.to_le_bytes());
+        }
+        out.extend([self.buffer.is_some() as u8, self.buffer.unwrap_or(0)]);
+        out.extend([self.shift, self.bits_remaining, self.silence as u8]);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) {
+        self.irq_enabled = state.bool();
+        self.irq = state.bool();
+        self.looping = state.bool();
+        // 0 would underflow the timer and bit counter reloads
+        self.rate = state.u16().max(1);
+        self.timer = state.u16();
+        self.level = state.u8() & 0x7F;
+        self.sample_address = state.u16();
+        self.sample_length = state.u16();
+        self.address = state.u16();
+        self.bytes_remaining = state.u16();
+        let buffered = state.bool();
+        let data = state.u8();
+        self.buffer = buffered.then_some(data);
+        self.shift = state.u8();
+        self.bits_remaining = state.u8().clamp(1, 8);
+        self.silence = state.bool();
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+            self.shift >>= 1;
+        }
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
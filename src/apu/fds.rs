@@ -0,0 +1,330 @@
+use super::ExpansionAudio;
+
+// https://www.nesdev.org/wiki/FDS_audio
+pub const WAVE_RAM: u16 = 0x4040;
+pub const WAVE_RAM_END: u16 = 0x407F;
+const VOLUME_ENVELOPE: u16 = 0x4080;
+const FREQUENCY_LOW: u16 = 0x4082;
+const FREQUENCY_HIGH: u16 = 0x4083;
+const MOD_ENVELOPE: u16 = 0x4084;
+const MOD_COUNTER: u16 = 0x4085;
+const MOD_FREQUENCY_LOW: u16 = 0x4086;
+const MOD_FREQUENCY_HIGH: u16 = 0x4087;
+const MOD_TABLE: u16 = 0x4088;
+const MASTER_VOLUME: u16 = 0x4089;
+const ENVELOPE_SPEED: u16 = 0x408A;
+const VOLUME_GAIN: u16 = 0x4090;
+const MOD_GAIN: u16 = 0x4092;
+pub const REGISTERS_END: u16 = 0x4097;
+
+// master volume 2/2, 2/3, 2/4 and 2/5, over the 1152 of the output formula
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+// full volume is about as loud as both APU pulses at full volume
+const OUTPUT_SCALE: f32 = 0.004;
+
+// volume or modulation gain, ramped by the envelope unless it is off
+#[derive(Default)]
+struct GainEnvelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    off: bool,
+    timer: u32,
+}
+
+impl GainEnvelope {
+    fn write(&mut self, data: u8, master_speed: u8) {
+        self.speed = data & 0x3F;
+        self.increase = data & 0x40 != 0;
+        self.off = data & 0x80 != 0;
+        if self.off {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    // true when the gain changed
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.off || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+/// The wavetable channel of the Famicom Disk System: a 64 step wave of 6 bit
+/// samples whose pitch is bent by a modulation unit
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_write: bool,
+    wave_position: usize,
+    wave_accumulator: u16,
+    frequency: u16,
+    halt: bool,
+    envelopes_disabled: bool,
+    master_volume: usize,
+    master_speed: u8,
+    volume: GainEnvelope,
+    modulator: GainEnvelope,
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_accumulator: u16,
+    mod_frequency: u16,
+    mod_disabled: bool,
+    // signed 7 bit
+    mod_counter: i8,
+    // frequency offset from the modulation
+    pitch_offset: i32,
+    output: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            frequency: 0,
+            halt: true,
+            envelopes_disabled: false,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: GainEnvelope::default(),
+            modulator: GainEnvelope::default(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_frequency: 0,
+            mod_disabled: true,
+            mod_counter: 0,
+            pitch_offset: 0,
+            output: 0,
+        }
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // wraps around as a 7 bit signed number
+        self.mod_counter = (((value + 64) & 0x7F) - 64) as i8;
+    }
+
+    // true when the counter moved
+    fn clock_modulator(&mut self) -> bool {
+        if self.mod_disabled || self.mod_frequency == 0 {
+            return false;
+        }
+        let (accumulator, overflow) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+        self.mod_accumulator = accumulator;
+        if !overflow {
+            return false;
+        }
+        let step = self.mod_table[self.mod_position];
+        if step == 4 {
+            self.mod_counter = 0;
+        } else {
+            self.set_mod_counter(self.mod_counter as i32 + MOD_STEPS[step as usize] as i32);
+        }
+        self.mod_position = (self.mod_position + 1) & 0x3F;
+        true
+    }
+
+    // the pitch bend formula of the wiki, rounding included
+    fn update_pitch(&mut self) {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulator.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.pitch_offset = temp;
+    }
+
+    fn update_output(&mut self) {
+        let level = (self.volume.gain.min(32) as u32) * MASTER_VOLUMES[self.master_volume];
+        self.output = (self.wave[self.wave_position] as u32 * level / 1152) as u8;
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        FdsAudio::new()
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            WAVE_RAM..=WAVE_RAM_END if self.wave_write => {
+                self.wave[(addr - WAVE_RAM) as usize] = data & 0x3F
+            }
+            VOLUME_ENVELOPE => self.volume.write(data, self.master_speed),
+            FREQUENCY_LOW => self.frequency = (self.frequency & 0xF00) | data as u16,
+            FREQUENCY_HIGH => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.halt = data & 0x80 != 0;
+                self.envelopes_disabled = data & 0x40 != 0;
+                if self.halt {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_disabled {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulator.reset_timer(self.master_speed);
+                }
+            }
+            MOD_ENVELOPE => self.modulator.write(data, self.master_speed),
+            MOD_COUNTER => self.set_mod_counter(data as i32 & 0x7F),
+            MOD_FREQUENCY_LOW => self.mod_frequency = (self.mod_frequency & 0xF00) | data as u16,
+            MOD_FREQUENCY_HIGH => {
+                self.mod_frequency = (self.mod_frequency & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.mod_disabled = data & 0x80 != 0;
+                if self.mod_disabled {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the table is a ring of 32 entries written twice each
+            MOD_TABLE if self.mod_disabled => {
+                self.mod_table[self.mod_position] = data & 7;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = data & 7;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            MASTER_VOLUME => {
+                self.master_volume = (data & 3) as usize;
+                self.wave_write = data & 0x80 != 0;
+            }
+            ENVELOPE_SPEED => {
+                self.master_speed = data;
+                self.volume.reset_timer(data);
+                self.modulator.reset_timer(data);
+            }
+            WAVE_RAM..=REGISTERS_END => {}
+            _ => return false,
+        }
+        true
+    }
+
+    /// Wave RAM and the gain registers, None for the write-only registers
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            WAVE_RAM..=WAVE_RAM_END => Some(self.wave[(addr - WAVE_RAM) as usize] | 0x40),
+            VOLUME_GAIN => Some(self.volume.gain | 0x40),
+            MOD_GAIN => Some(self.modulator.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && !self.envelopes_disabled {
+            self.volume.clock(self.master_speed);
+            if self.modulator.clock(self.master_speed) {
+                self.update_pitch();
+            }
+        }
+        if self.clock_modulator() {
+            self.update_pitch();
+        }
+
+        self.update_output();
+        if self.halt {
+            return;
+        }
+        let step = self.frequency as i32 + self.pitch_offset;
+        if step > 0 && !self.wave_write {
+            let (accumulator, overflow) = self.wave_accumulator.overflowing_add(step as u16);
+            self.wave_accumulator = accumulator;
+            if overflow {
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.output as f32 * OUTPUT_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wave_playback() {
+        let mut audio = FdsAudio::new();
+        audio.write(MASTER_VOLUME, 0x80);
+        for i in 0..64 {
+            audio.write(WAVE_RAM + i, if i < 32 { 63 } else { 0 });
+        }
+        assert_eq!(audio.read(WAVE_RAM), Some(0x7F));
+        audio.write(MASTER_VOLUME, 0x00);
+        // envelope off, gain 32
+        audio.write(VOLUME_ENVELOPE, 0x80 | 32);
+        assert_eq!(audio.read(VOLUME_GAIN), Some(0x40 | 32));
+        // one wave step every 0x10000 / 0x400 = 64 cycles
+        audio.write(FREQUENCY_LOW, 0x00);
+        audio.write(FREQUENCY_HIGH, 0x04);
+
+        let mut levels = vec![];
+        for _ in 0..64 * 64 {
+            audio.clock();
+            levels.push(audio.output);
+        }
+        assert_eq!(levels[0], 63);
+        assert_eq!(levels.iter().filter(|level| **level == 63).count(), 32 * 64);
+        assert_eq!(levels.iter().filter(|level| **level == 0).count(), 32 * 64);
+
+        audio.write(FREQUENCY_HIGH, 0x84);
+        audio.clock();
+        assert_eq!(audio.wave_position, 0);
+    }
+
+    #[test]
+    fn test_modulation() {
+        let mut audio = FdsAudio::new();
+        audio.write(MOD_FREQUENCY_HIGH, 0x80);
+        for _ in 0..32 {
+            audio.write(MOD_TABLE, 1);
+        }
+        audio.write(MOD_ENVELOPE, 0x80 | 32);
+        audio.write(FREQUENCY_LOW, 0x00);
+        audio.write(FREQUENCY_HIGH, 0x01);
+        // one modulation step every 0x10000 / 0x800 = 32 cycles
+        audio.write(MOD_FREQUENCY_LOW, 0x00);
+        audio.write(MOD_FREQUENCY_HIGH, 0x08);
+        for _ in 0..32 {
+            audio.clock();
+        }
+        assert_eq!(audio.mod_counter, 1);
+        // counter 1 with gain 32 bends the pitch up by 2/64 of the frequency
+        assert_eq!(audio.pitch_offset, 0x100 * 2 / 64);
+
+        audio.write(MOD_COUNTER, 0x7F);
+        assert_eq!(audio.mod_counter, -1);
+    }
+}
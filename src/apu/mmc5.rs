@@ -0,0 +1,170 @@
+use super::pulse::Pulse;
+use super::ExpansionAudio;
+
+// https://www.nesdev.org/wiki/MMC5_audio
+const PULSE1: u16 = 0x5000;
+const PULSE2: u16 = 0x5004;
+const PCM_CONTROL: u16 = 0x5010;
+const PCM_DATA: u16 = 0x5011;
+const STATUS: u16 = 0x5015;
+// envelopes and length counters run at a fixed 240Hz
+const FRAME_PERIOD: u32 = 7457;
+// the pulses are mixed linearly at about the level of the APU pulses at low
+// volume, the PCM at about the level of the DMC
+const PULSE_SCALE: f32 = 0.00752;
+const PCM_SCALE: f32 = 0.0017;
+
+/// MMC5 sound: two APU pulses without sweep and an 8 bit PCM channel that is
+/// written to or fed by reads from $8000-$BFFF
+pub struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    odd_cycle: bool,
+    frame_timer: u32,
+    pcm: u8,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            odd_cycle: false,
+            frame_timer: FRAME_PERIOD,
+            pcm: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+        }
+    }
+
+    /// Reads of $8000-$BFFF go to the PCM channel in read mode; a 0 raises the IRQ
+    pub fn observe_read(&mut self, addr: u16, data: u8) {
+        if !self.pcm_read_mode || !(0x8000..=0xBFFF).contains(&addr) {
+            return;
+        }
+        if data == 0 {
+            self.pcm_irq = true;
+        } else {
+            self.pcm = data;
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Mmc5Audio::new()
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PULSE1..=0x5003 => self.pulse1.write(addr - PULSE1, data),
+            PULSE2..=0x5007 => self.pulse2.write(addr - PULSE2, data),
+            PCM_CONTROL => {
+                self.pcm_read_mode = data & 0x01 != 0;
+                self.pcm_irq_enabled = data & 0x80 != 0;
+            }
+            // 0 is not a sample, it only raises the IRQ in read mode
+            PCM_DATA if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            PCM_DATA => {}
+            STATUS => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PCM_CONTROL => Some((self.irq() as u8) << 7 | self.pcm_read_mode as u8),
+            STATUS => {
+                Some(self.pulse1.length.active() as u8 | (self.pulse2.length.active() as u8) << 1)
+            }
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr);
+        if addr == PCM_CONTROL {
+            self.pcm_irq = false;
+        }
+        data
+    }
+
+    fn clock(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = FRAME_PERIOD;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.envelope.clock();
+                pulse.length.clock();
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let pulses = (self.pulse1.output() + self.pulse2.output()) as f32;
+        pulses * PULSE_SCALE + self.pcm as f32 * PCM_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_length() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(STATUS, 0x01);
+        // constant volume 15, period 4: still audible unlike on the APU
+        mmc5.write(0x5000, 0x1F);
+        mmc5.write(0x5002, 0x04);
+        mmc5.write(0x5003, 0x08);
+        assert_eq!(mmc5.peek(STATUS), Some(0x01));
+        let loud = (0..16).any(|_| {
+            mmc5.clock();
+            mmc5.pulse1.output() == 15
+        });
+        assert!(loud);
+
+        // length index 1 is 254 ticks at 240Hz
+        for _ in 0..255 * FRAME_PERIOD {
+            mmc5.clock();
+        }
+        assert_eq!(mmc5.peek(STATUS), Some(0x00));
+    }
+
+    #[test]
+    fn test_pcm() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(PCM_DATA, 0x40);
+        assert_eq!(mmc5.pcm, 0x40);
+        mmc5.write(PCM_CONTROL, 0x81);
+        mmc5.observe_read(0x8000, 0x30);
+        assert_eq!(mmc5.pcm, 0x30);
+        mmc5.observe_read(0xC000, 0);
+        assert!(!mmc5.irq());
+        mmc5.observe_read(0x9000, 0);
+        assert!(mmc5.irq());
+        assert_eq!(mmc5.read(PCM_CONTROL), Some(0x81));
+        assert!(!mmc5.irq());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::region::Region;
+use std::f32::consts::PI;
+
+mod dmc;
+pub mod fds;
+pub mod mmc5;
+pub mod n163;
+mod noise;
+mod pulse;
+pub mod sunsoft5b;
+mod triangle;
+pub mod vrc6;
+pub mod vrc7;
+pub mod wav;
+
+use dmc::Dmc;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+// $4000-$4013 are the channel registers, four per channel
+const PULSE1: u16 = 0x4000;
+const PULSE2: u16 = 0x4004;
+const TRIANGLE: u16 = 0x4008;
+const NOISE: u16 = 0x400C;
+const DMC: u16 = 0x4010;
+const DMC_END: u16 = 0x4013;
+const STATUS: u16 = 0x4015;
+const FRAME_COUNTER: u16 = 0x4017;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+// the console's output has a high-pass filter that removes the DC offset
+const HIGH_PASS_HZ: f32 = 90.0;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, //
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// reads back the fields the `save_state` methods appended, in the same order
+struct StateReader<'a> {
+    raw: &'a [u8],
+}
+
+impl StateReader<'_> {
+    fn u8(&mut self) -> u8 {
+        let (byte, rest) = self.raw.split_first().expect("state length is checked");
+        self.raw = rest;
+        *byte
+    }
+
+    fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.u8(), self.u8(), self.u8(), self.u8()])
+    }
+}
+
+/// Sound chip on a cartridge. Its output is in the units of `Apu::output` and
+/// gets mixed with the console's channels, see `Apu::clock`.
+pub trait ExpansionAudio {
+    /// False when the address is not one of the chip's registers
+    fn write(&mut self, addr: u16, data: u8) -> bool;
+
+    /// Readable registers, without side effects
+    fn peek(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        self.peek(addr)
+    }
+
+    /// Runs the chip for one CPU cycle
+    fn clock(&mut self);
+
+    fn output(&self) -> f32;
+}
+
+/// Volume of pulse and noise: a constant or a decaying saw clocked by the frame counter
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // the low 6 bits of $4000/$4004/$400C
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        }
+    }
+
+    const STATE_LEN: usize = 6;
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.start as u8, self.looping as u8, self.constant as u8]);
+        out.extend([self.volume, self.divider, self.decay]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.start = state.bool();
+        self.looping = state.bool();
+        self.constant = state.bool();
+        self.volume = state.u8();
+        self.divider = state.u8();
+        self.decay = state.u8();
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+/// Silences a channel after a number of half frames unless halted
+#[derive(Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    const STATE_LEN: usize = 3;
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.enabled as u8, self.halt as u8, self.counter]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.enabled = state.bool();
+        self.halt = state.bool();
+        self.counter = state.u8();
+    }
+}
+
+/// The 2A03 audio processing unit: two pulse channels, a triangle, noise and
+/// delta modulation, sequenced by the frame counter and mixed the way the console
+/// does. `clock` runs it for one CPU cycle; samples are resampled to `sample_rate`.
+pub struct Apu {
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    odd_cycle: bool,
+    frame_cycle: usize,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    sample_rate: u32,
+    sample_phase: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    high_pass: (f32, f32),
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Self {
+        Apu {
+            region,
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(region.noise_periods()),
+            dmc: Dmc::new(region.dmc_rates()),
+            odd_cycle: false,
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_phase: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            high_pass: (0.0, 0.0),
+            samples: vec![],
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub const STATE_LEN: usize =
+        2 * Pulse::STATE_LEN + Triangle::STATE_LEN + Noise::STATE_LEN + Dmc::STATE_LEN + 8;
+
+    /// Appends the channels and the frame counter, see `savestate`. The
+    /// resampler is left out, a loaded state just carries on from its output.
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        self.pulse1.save_state(out);
+        self.pulse2.save_state(out);
+        self.triangle.save_state(out);
+        self.noise.save_state(out);
+        self.dmc.save_state(out);
+        out.push(self.odd_cycle as u8);
+        out.extend((self.frame_cycle as u32).to_le_bytes());
+        out.extend([
+            self.five_step as u8,
+            self.irq_inhibit as u8,
+            self.frame_irq as u8,
+        ]);
+    }
+
+    // the `STATE_LEN` bytes at the start of `raw`, returns the rest
+    pub fn load_state<'a>(&mut self, raw: &'a [u8]) -> &'a [u8] {
+        let (raw, rest) = raw.split_at(Apu::STATE_LEN);
+        let mut state = StateReader { raw };
+        self.pulse1.load_state(&mut state);
+        self.pulse2.load_state(&mut state);
+        self.triangle.load_state(&mut state);
+        self.noise.load_state(&mut state);
+        self.dmc.load_state(&mut state);
+        self.odd_cycle = state.bool();
+        self.frame_cycle = state.u32() as usize;
+        self.five_step = state.bool();
+        self.irq_inhibit = state.bool();
+        self.frame_irq = state.bool();
+        rest
+    }
+
+    /// Takes the samples produced so far, roughly in -1.0..1.0
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PULSE1..=0x4003 => self.pulse1.write(addr - PULSE1, data),
+            PULSE2..=0x4007 => self.pulse2.write(addr - PULSE2, data),
+            TRIANGLE..=0x400B => self.triangle.write(addr - TRIANGLE, data),
+            NOISE..=0x400F => self.noise.write(addr - NOISE, data),
+            DMC..=DMC_END => self.dmc.write(addr - DMC, data),
+            STATUS => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            FRAME_COUNTER => {
+                self.five_step = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// $4015 without the side effect of acknowledging the frame IRQ
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse1.length.active() as u8)
+            | (self.pulse2.length.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// CPU address the DMC wants to read its next sample byte from
+    pub fn dmc_pending_read(&self) -> Option<u16> {
+        self.dmc.pending_read()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    /// Runs for one CPU cycle. `expansion` is the output of the sound chip on
+    /// the cartridge, in the units of `output`.
+    pub fn clock(&mut self, expansion: f32) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.sample(expansion);
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.quarter_frame();
+        } else if cycle == steps[1] {
+            self.quarter_frame();
+            self.half_frame();
+        } else if cycle == steps[3] && !self.five_step {
+            self.quarter_frame();
+            self.half_frame();
+            if !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            self.frame_cycle = 0;
+        } else if cycle == steps[4] {
+            self.quarter_frame();
+            self.half_frame();
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    /// Mixer output in 0.0..1.0, see https://www.nesdev.org/wiki/APU_Mixer
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output() as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    // averages the output over each sample period
+    fn sample(&mut self, expansion: f32) {
+        self.sample_sum += self.output() + expansion;
+        self.sample_count += 1;
+        self.sample_phase += self.sample_rate as f64;
+        let cpu_frequency = self.region.cpu_frequency();
+        if self.sample_phase < cpu_frequency {
+            return;
+        }
+        self.sample_phase -= cpu_frequency;
+
+        let input = self.sample_sum / self.sample_count as f32;
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+
+        let rc = 1.0 / (2.0 * PI * HIGH_PASS_HZ);
+        let alpha = rc / (rc + 1.0 / self.sample_rate as f32);
+        let (last_input, last_output) = self.high_pass;
+        let output = alpha * (last_output + input - last_input);
+        self.high_pass = (input, output);
+        self.samples.push(output);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: usize) {
+        for _ in 0..cycles {
+            apu.clock(0.0);
+        }
+    }
+
+    #[test]
+    fn test_length_counter_and_status() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // disabled channels ignore length loads
+        apu.write(0x4003, 0b0000_1000);
+        assert_eq!(apu.peek_status() & 1, 0);
+
+        apu.write(STATUS, 0x0F);
+        // index 1 is 254 half frames, index 3 is 2
+        apu.write(0x4003, 0b0000_1000);
+        apu.write(0x400F, 0b0001_1000);
+        assert_eq!(apu.peek_status() & 0x0F, 0b1001);
+
+        // two half frames per 4-step sequence
+        run(&mut apu, 29830);
+        assert_eq!(apu.peek_status() & 0x0F, 0b0001);
+
+        apu.write(STATUS, 0);
+        assert_eq!(apu.peek_status() & 0x0F, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new(Region::Ntsc);
+        run(&mut apu, 29828);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.irq());
+
+        apu.write(FRAME_COUNTER, 0x40);
+        run(&mut apu, 40000);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_dmc() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // IRQ at the end of a 17 byte sample at $C040
+        apu.write(0x4010, 0x8F);
+        apu.write(0x4012, 0x01);
+        apu.write(0x4013, 0x01);
+        apu.write(STATUS, 0x10);
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+
+        let mut reads = vec![];
+        for _ in 0..10_000 {
+            if let Some(addr) = apu.dmc_pending_read() {
+                reads.push(addr);
+                apu.dmc_fill(0xFF);
+            }
+            apu.clock(0.0);
+        }
+        assert_eq!(reads.len(), 17);
+        assert_eq!((reads[0], reads[16]), (0xC040, 0xC050));
+        assert!(apu.irq());
+        assert_eq!(apu.peek_status() & 0x90, 0x80);
+        // all ones ramp the level up by 2 per bit
+        assert!(apu.dmc.output() > 100);
+    }
+
+    #[test]
+    fn test_pulse_samples() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_sample_rate(48_000);
+        // the idle triangle holds a DC level, the high-pass filter takes it out
+        run(&mut apu, 100_000);
+        assert!(apu.take_samples().last().unwrap().abs() < 0.0001);
+
+        // 50% duty, constant volume 15, ~440Hz
+        apu.write(STATUS, 0x01);
+        apu.write(0x4000, 0b1011_1111);
+        apu.write(0x4002, 0xFD);
+        apu.write(0x4003, 0x00);
+        run(&mut apu, 1_789_773 / 10);
+        let samples = apu.take_samples();
+        assert!((samples.len() as i32 - 4800).abs() <= 1);
+        let peak = samples.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+        assert!(peak > 0.05 && peak < 0.2, "{}", peak);
+    }
+}
@@ -0,0 +1,172 @@
+use super::ExpansionAudio;
+
+// https://www.nesdev.org/wiki/Namco_163_audio
+const DATA: u16 = 0x4800;
+const DATA_END: u16 = 0x4FFF;
+const ADDRESS: u16 = 0xF800;
+const ADDRESS_END: u16 = 0xFFFF;
+// channel registers are the last 8 bytes of RAM for channel 7, the 8 before for 6...
+const CHANNELS: u8 = 0x40;
+// one channel is updated every 15 CPU cycles
+const UPDATE_CYCLES: u8 = 15;
+// one step of sample times volume; the chip is loud, a channel at full volume
+// is several times as loud as an APU pulse
+const OUTPUT_SCALE: f32 = 0.004;
+
+/// Namco 163 sound: up to 8 wavetable channels that share 128 bytes of RAM
+/// with their 4 bit samples. The data port is at $4800, the address at $F800.
+pub struct N163Audio {
+    ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+    timer: u8,
+    channel: usize,
+    outputs: [i16; 8],
+}
+
+impl N163Audio {
+    pub fn new() -> Self {
+        N163Audio {
+            ram: [0; 128],
+            address: 0,
+            auto_increment: false,
+            timer: UPDATE_CYCLES,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 7) as usize + 1
+    }
+
+    fn next_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    fn update_channel(&mut self, channel: usize) {
+        let base = CHANNELS as usize + channel * 8;
+        let reg = &mut self.ram[base..base + 8];
+        let frequency = reg[0] as u32 | (reg[2] as u32) << 8 | (reg[4] as u32 & 3) << 16;
+        let mut phase = reg[1] as u32 | (reg[3] as u32) << 8 | (reg[5] as u32) << 16;
+        let length = 256 - (reg[4] & 0xFC) as u32;
+        phase = (phase + frequency) % (length << 16);
+        reg[1] = phase as u8;
+        reg[3] = (phase >> 8) as u8;
+        reg[5] = (phase >> 16) as u8;
+
+        let volume = (reg[7] & 0x0F) as i16;
+        let index = ((phase >> 16) as u8).wrapping_add(reg[6]);
+        let sample = (self.ram[index as usize / 2] >> ((index & 1) * 4)) & 0x0F;
+        self.outputs[channel] = (sample as i16 - 8) * volume;
+    }
+}
+
+impl Default for N163Audio {
+    fn default() -> Self {
+        N163Audio::new()
+    }
+}
+
+impl ExpansionAudio for N163Audio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            DATA..=DATA_END => {
+                self.ram[self.address as usize] = data;
+                self.next_address();
+            }
+            ADDRESS..=ADDRESS_END => {
+                self.address = data & 0x7F;
+                self.auto_increment = data & 0x80 != 0;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            DATA..=DATA_END => Some(self.ram[self.address as usize]),
+            _ => None,
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr);
+        if data.is_some() {
+            self.next_address();
+        }
+        data
+    }
+
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = UPDATE_CYCLES;
+        // from channel 7 down to the last enabled one
+        let first = 8 - self.enabled_channels();
+        if self.channel < first {
+            self.channel = 7;
+        }
+        self.update_channel(self.channel);
+        self.channel = if self.channel == first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    // the channels take turns on the output, so they average out
+    fn output(&self) -> f32 {
+        let count = self.enabled_channels();
+        let sum: i16 = self.outputs[8 - count..].iter().sum();
+        sum as f32 / count as f32 * OUTPUT_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn poke(n163: &mut N163Audio, addr: u8, data: &[u8]) {
+        n163.write(ADDRESS, 0x80 | addr);
+        for byte in data {
+            n163.write(DATA, *byte);
+        }
+    }
+
+    #[test]
+    fn test_ram_port() {
+        let mut n163 = N163Audio::new();
+        poke(&mut n163, 0x10, &[1, 2, 3]);
+        n163.write(ADDRESS, 0x91);
+        assert_eq!(n163.read(DATA), Some(2));
+        assert_eq!(n163.read(DATA), Some(3));
+        n163.write(ADDRESS, 0x10);
+        assert_eq!(n163.read(DATA), Some(1));
+        assert_eq!(n163.read(DATA), Some(1));
+    }
+
+    #[test]
+    fn test_channel() {
+        let mut n163 = N163Audio::new();
+        // 4 samples: 15, 0, 15, 0
+        poke(&mut n163, 0x00, &[0x0F, 0x0F]);
+        // channel 7 alone: one sample per update, length 4, wave at 0, volume 15
+        poke(&mut n163, 0x78, &[0x00, 0, 0x00, 0, 0xFC | 1, 0, 0, 0x0F]);
+        let mut levels = vec![];
+        for _ in 0..4 * UPDATE_CYCLES {
+            n163.clock();
+            levels.push(n163.outputs[7]);
+        }
+        assert_eq!(levels[UPDATE_CYCLES as usize - 1], -120);
+        assert_eq!(levels[2 * UPDATE_CYCLES as usize - 1], 105);
+        assert_eq!(levels[3 * UPDATE_CYCLES as usize - 1], -120);
+        // back at the start of the wave
+        assert_eq!(n163.output(), 105.0 * OUTPUT_SCALE);
+    }
+}
@@ -0,0 +1,86 @@
+use super::{Envelope, LengthCounter, StateReader};
+
+/// Pseudo-random noise channel, $400C-$400F
+pub struct Noise {
+    periods: &'static [u16; 16],
+    // short mode taps bit 6 instead of bit 1 and repeats after 93 steps
+    short_mode: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(periods: &'static [u16; 16]) -> Self {
+        Noise {
+            periods,
+            short_mode: false,
+            period: periods[0],
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = self.periods[(data & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle, the periods are in CPU cycles
+    pub const STATE_LEN: usize = 7 + Envelope::STATE_LEN + LengthCounter::STATE_LEN;
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.push(self.short_mode as u8);
+        out.extend(self.period.to_le_bytes());
+        out.extend(self.timer.to_le_bytes());
+        out.extend(self.shift.to_le_bytes());
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) {
+        self.short_mode = state.bool();
+        // 0 would underflow the timer reload
+        self.period = state.u16().max(1);
+        self.timer = state.u16();
+        self.shift = state.u16();
+        self.envelope.load_state(state);
+        self.length.load_state(state);
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length.active() && self.shift & 1 == 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+}
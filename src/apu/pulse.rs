@@ -0,0 +1,158 @@
+use super::{Envelope, LengthCounter, StateReader};
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Square wave channel, $4000-$4003 and $4004-$4007
+pub struct Pulse {
+    // pulse 1 negates its sweep with ones' complement, pulse 2 with two's
+    ones_complement: bool,
+    // MMC5 pulses have no sweep unit, nor its muting
+    has_sweep: bool,
+    duty: usize,
+    step: usize,
+    period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            has_sweep: true,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub fn without_sweep() -> Self {
+        Pulse {
+            has_sweep: false,
+            ..Pulse::new(false)
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = (data >> 6) as usize;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 if self.has_sweep => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 7;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 7;
+                self.sweep_reload = true;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 7) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // clocked every other CPU cycle
+    pub const STATE_LEN: usize = 12 + Envelope::STATE_LEN + LengthCounter::STATE_LEN;
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.duty as u8, self.step as u8]);
+        out.extend(self.period.to_le_bytes());
+        out.extend(self.timer.to_le_bytes());
+        out.extend([
+            self.sweep_enabled as u8,
+            self.sweep_period,
+            self.sweep_negate as u8,
+            self.sweep_shift,
+            self.sweep_divider,
+            self.sweep_reload as u8,
+        ]);
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) {
+        self.duty = state.u8() as usize & 3;
+        self.step = state.u8() as usize & 7;
+        self.period = state.u16();
+        self.timer = state.u16();
+        self.sweep_enabled = state.bool();
+        self.sweep_period = state.u8();
+        self.sweep_negate = state.bool();
+        self.sweep_shift = state.u8();
+        self.sweep_divider = state.u8();
+        self.sweep_reload = state.bool();
+        self.envelope.load_state(state);
+        self.length.load_state(state);
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.has_sweep && (self.period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length.active() && !self.muted() && DUTY[self.duty][self.step] != 0 {
+            self.envelope.volume()
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::ExpansionAudio;
+
+// https://www.nesdev.org/wiki/Sunsoft_5B_audio
+const ADDRESS: u16 = 0xC000;
+const ADDRESS_END: u16 = 0xDFFF;
+const DATA: u16 = 0xE000;
+const DATA_END: u16 = 0xFFFF;
+
+const NOISE_PERIOD: usize = 6;
+const MIXER: usize = 7;
+const VOLUME: usize = 8;
+const ENVELOPE_PERIOD: usize = 11;
+const ENVELOPE_SHAPE: usize = 13;
+// the chip runs at half the CPU clock and divides that by 8 for its timers
+const PRESCALER: u8 = 16;
+// a channel at full volume is about as loud as an APU pulse at full volume
+const OUTPUT_SCALE: f32 = 0.15;
+
+lazy_static! {
+    // 32 levels 1.5dB apart, 0 is silence
+    static ref LEVELS: [f32; 32] = {
+        let mut levels = [0.0; 32];
+        for (level, amplitude) in levels.iter_mut().enumerate().skip(1) {
+            *amplitude = 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0);
+        }
+        levels
+    };
+}
+
+#[derive(Default, Clone, Copy)]
+struct Tone {
+    timer: u16,
+    high: bool,
+}
+
+/// Sunsoft 5B sound, a YM2149 (AY-3-8910) with three square channels, noise
+/// and a shared envelope. Register number at $C000, register data at $E000.
+pub struct Sunsoft5bAudio {
+    registers: [u8; 16],
+    address: usize,
+    prescaler: u8,
+    tones: [Tone; 3],
+    noise_timer: u16,
+    // 17 bit LFSR
+    noise: u32,
+    envelope_timer: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        Sunsoft5bAudio {
+            registers: [0; 16],
+            address: 0,
+            prescaler: PRESCALER,
+            tones: [Tone::default(); 3],
+            noise_timer: 0,
+            noise: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let period = u16::from_le_bytes([
+            self.registers[channel * 2],
+            self.registers[channel * 2 + 1] & 0x0F,
+        ]);
+        period.max(1)
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    // one of the 32 steps of the envelope, then the shape decides what follows
+    fn step_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+        let shape = self.registers[ENVELOPE_SHAPE];
+        let (continues, alternate, hold) = (shape & 8 != 0, shape & 2 != 0, shape & 1 != 0);
+        self.envelope_step = if continues && !hold { 0 } else { 31 };
+        if !continues {
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+        } else {
+            self.envelope_holding = hold;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        for channel in 0..3 {
+            let period = self.tone_period(channel);
+            let tone = &mut self.tones[channel];
+            tone.timer += 1;
+            if tone.timer >= period {
+                tone.timer = 0;
+                tone.high = !tone.high;
+            }
+        }
+
+        self.noise_timer += 1;
+        if self.noise_timer >= (self.registers[NOISE_PERIOD] as u16 & 0x1F).max(1) * 2 {
+            self.noise_timer = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | feedback << 16;
+        }
+
+        let envelope_period = u16::from_le_bytes([
+            self.registers[ENVELOPE_PERIOD],
+            self.registers[ENVELOPE_PERIOD + 1],
+        ]);
+        self.envelope_timer += 1;
+        if self.envelope_timer >= envelope_period.max(1) {
+            self.envelope_timer = 0;
+            self.step_envelope();
+        }
+    }
+
+    fn channel_level(&self, channel: usize) -> f32 {
+        let mixer = self.registers[MIXER];
+        let tone = self.tones[channel].high || mixer & (1 << channel) != 0;
+        let noise = self.noise & 1 != 0 || mixer & (8 << channel) != 0;
+        if !(tone && noise) {
+            return 0.0;
+        }
+        let volume = self.registers[VOLUME + channel];
+        let level = if volume & 0x10 != 0 {
+            self.envelope_level()
+        } else if volume & 0x0F == 0 {
+            0
+        } else {
+            (volume & 0x0F) * 2 + 1
+        };
+        LEVELS[level as usize]
+    }
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Sunsoft5bAudio::new()
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            ADDRESS..=ADDRESS_END => self.address = (data & 0x0F) as usize,
+            DATA..=DATA_END => {
+                self.registers[self.address] = data;
+                if self.address == ENVELOPE_SHAPE {
+                    self.envelope_step = 0;
+                    self.envelope_timer = 0;
+                    self.envelope_attack = data & 4 != 0;
+                    self.envelope_holding = false;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn clock(&mut self) {
+        self.prescaler -= 1;
+        if self.prescaler == 0 {
+            self.prescaler = PRESCALER;
+            self.tick();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        (0..3)
+            .map(|channel| self.channel_level(channel))
+            .sum::<f32>()
+            * OUTPUT_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(chip: &mut Sunsoft5bAudio, register: u8, data: u8) {
+        chip.write(ADDRESS, register);
+        chip.write(DATA, data);
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut chip = Sunsoft5bAudio::new();
+        // channel A: period 2, full volume, no noise
+        set(&mut chip, 0, 2);
+        set(&mut chip, 7, 0b111_110);
+        set(&mut chip, 8, 0x0F);
+        let mut levels = vec![];
+        for _ in 0..4 * 2 * PRESCALER as usize {
+            chip.clock();
+            levels.push(chip.channel_level(0));
+        }
+        // toggles every 32 cycles
+        assert_eq!(levels.iter().filter(|level| **level == 1.0).count(), 64);
+        assert_eq!(levels.iter().filter(|level| **level == 0.0).count(), 64);
+        assert_eq!(chip.channel_level(1), 0.0);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut chip = Sunsoft5bAudio::new();
+        // attack, then hold at the top
+        set(&mut chip, 11, 1);
+        set(&mut chip, 13, 0b1101);
+        assert_eq!(chip.envelope_level(), 0);
+        for _ in 0..31 * PRESCALER as usize {
+            chip.clock();
+        }
+        assert_eq!(chip.envelope_level(), 31);
+        for _ in 0..8 * PRESCALER as usize {
+            chip.clock();
+        }
+        assert_eq!(chip.envelope_level(), 31);
+
+        // sawtooth down, repeating
+        set(&mut chip, 13, 0b1000);
+        for _ in 0..32 * PRESCALER as usize {
+            chip.clock();
+        }
+        assert_eq!(chip.envelope_level(), 31);
+    }
+}
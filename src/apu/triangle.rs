@@ -0,0 +1,105 @@
+use super::{LengthCounter, StateReader};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, //
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// Triangle wave channel, $4008-$400B
+pub struct Triangle {
+    step: usize,
+    period: u16,
+    timer: u16,
+    // the control flag also halts the length counter
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            step: 0,
+            period: 0,
+            timer: 0,
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 7) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle
+    pub const STATE_LEN: usize = 9 + LengthCounter::STATE_LEN;
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.push(self.step as u8);
+        out.extend(self.period.to_le_bytes());
+        out.extend(self.timer.to_le_bytes());
+        out.extend([
+            self.control as u8,
+            self.linear_reload_value,
+            self.linear_counter,
+            self.linear_reload as u8,
+        ]);
+        self.length.save_state(out);
+    }
+
+    pub(super) fn load_state(&mut self, state: &mut StateReader) {
+        self.step = state.u8() as usize & 31;
+        self.period = state.u16();
+        self.timer = state.u16();
+        self.control = state.bool();
+        self.linear_reload_value = state.u8();
+        self.linear_counter = state.u8();
+        self.linear_reload = state.bool();
+        self.length.load_state(state);
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // periods below 2 are ultrasonic, real hardware outputs a flat average
+            if self.length.active() && self.linear_counter > 0 && self.period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step]
+    }
+}
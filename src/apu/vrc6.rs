@@ -0,0 +1,248 @@
+use super::ExpansionAudio;
+
+// https://www.nesdev.org/wiki/VRC6_audio
+const PULSE1: u16 = 0x9000;
+const FREQUENCY_CONTROL: u16 = 0x9003;
+const PULSE2: u16 = 0xA000;
+const SAW: u16 = 0xB000;
+// one step of the 6 bit DAC; a pulse at full volume is about 1.5 times as loud
+// as an APU pulse at full volume
+const OUTPUT_SCALE: f32 = 0.015;
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    // ignores the duty and outputs the volume
+    constant: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 15,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.volume = data & 0x0F;
+                self.duty = (data >> 4) & 7;
+                self.constant = data & 0x80 != 0;
+            }
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.checked_sub(1).unwrap_or(15);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    // the accumulator takes the rate every other step and resets after 14
+    step: u8,
+    accumulator: u8,
+}
+
+impl Saw {
+    fn new() -> Self {
+        Saw {
+            rate: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+/// Konami VRC6 sound: two pulses with 8 duty cycles and a sawtooth.
+/// Registers at $9000-$9003, $A000-$A002 and $B000-$B002.
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Saw,
+    halt: bool,
+    // periods are shifted right by 4 or 8 bits
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Vrc6Audio {
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            saw: Saw::new(),
+            halt: false,
+            shift: 0,
+        }
+    }
+}
+
+impl Default for Vrc6Audio {
+    fn default() -> Self {
+        Vrc6Audio::new()
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PULSE1..=0x9002 => self.pulse1.write(addr - PULSE1, data),
+            FREQUENCY_CONTROL => {
+                self.halt = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 {
+                    8
+                } else if data & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            PULSE2..=0xA002 => self.pulse2.write(addr - PULSE2, data),
+            SAW..=0xB002 => self.saw.write(addr - SAW, data),
+            _ => return false,
+        }
+        true
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        level as f32 * OUTPUT_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = Vrc6Audio::new();
+        // duty 4 of 16, volume 10, period 1
+        vrc6.write(0x9000, 0x3A);
+        vrc6.write(0x9001, 1);
+        vrc6.write(0x9002, 0x80);
+        let mut high = 0;
+        for _ in 0..32 {
+            vrc6.clock();
+            if vrc6.pulse1.output() == 10 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 8);
+
+        vrc6.write(0x9000, 0x8A);
+        assert_eq!(vrc6.pulse1.output(), 10);
+        assert!(!vrc6.write(0x9010, 0));
+    }
+
+    #[test]
+    fn test_saw() {
+        let mut vrc6 = Vrc6Audio::new();
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0);
+        vrc6.write(0xB002, 0x80);
+        let levels: Vec<u8> = (0..14)
+            .map(|_| {
+                vrc6.clock();
+                vrc6.saw.output()
+            })
+            .collect();
+        // 6 additions of 42 and back to 0
+        assert_eq!(levels, [0, 5, 5, 10, 10, 15, 15, 21, 21, 26, 26, 31, 31, 0]);
+
+        vrc6.clock();
+        vrc6.clock();
+        vrc6.write(FREQUENCY_CONTROL, 0x01);
+        for _ in 0..4 {
+            vrc6.clock();
+        }
+        assert_eq!(vrc6.saw.output(), 5);
+    }
+}
@@ -0,0 +1,432 @@
+use super::ExpansionAudio;
+use std::f32::consts::PI;
+
+// https://www.nesdev.org/wiki/VRC7_audio
+const ADDRESS: u16 = 0x9010;
+const DATA: u16 = 0x9030;
+// the chip makes one sample every 36 CPU cycles
+const SAMPLE_CYCLES: u8 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / SAMPLE_CYCLES as f32;
+// a channel at full volume is about as loud as an APU pulse at full volume
+const OUTPUT_SCALE: f32 = 0.1;
+
+// instrument 0 is the custom one in registers $00-$07
+const PATCHES: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+// frequency multipliers times 2
+const MULTIPLIERS: [u8; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+// key scale level in dB at block 7 by the top 4 bits of the frequency
+const KEY_SCALE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+    42.0,
+];
+const KEY_SCALE_FACTOR: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+const MAX_ATTENUATION: f32 = 48.0;
+// modulator output at full level moves the carrier phase by 8 pi
+const MODULATION_DEPTH: f32 = 4.0;
+const TREMOLO_HZ: f32 = 3.7;
+const TREMOLO_DB: f32 = 4.8;
+const VIBRATO_HZ: f32 = 6.4;
+// about 14 cents
+const VIBRATO_DEPTH: f32 = 0.008;
+
+// one half of an instrument
+#[derive(Default, Clone, Copy)]
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    // sustained: holds at the sustain level until key off
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u8,
+    key_scale_level: u8,
+    half_sine: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn decode(patch: &[u8; 8], carrier: bool) -> Self {
+        let i = carrier as usize;
+        OperatorPatch {
+            tremolo: patch[i] & 0x80 != 0,
+            vibrato: patch[i] & 0x40 != 0,
+            sustained: patch[i] & 0x20 != 0,
+            key_scale_rate: patch[i] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[i] & 0x0F) as usize],
+            key_scale_level: patch[2 + i] >> 6,
+            half_sine: patch[3] & (0x08 << i) != 0,
+            attack: patch[4 + i] >> 4,
+            decay: patch[4 + i] & 0x0F,
+            sustain_level: patch[6 + i] >> 4,
+            release: patch[6 + i] & 0x0F,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Operator {
+    // in cycles of the wave
+    phase: f32,
+    state: EnvelopeState,
+    // dB
+    attenuation: f32,
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    // the envelope timing is an approximation: rate 1 takes about 20 seconds
+    // to decay 48dB and every 4 rates are twice as fast
+    fn clock_envelope(&mut self, patch: &OperatorPatch, rate_offset: u8, release: u8) {
+        let rate = |rate: u8| -> f32 {
+            if rate == 0 {
+                return 0.0;
+            }
+            let rate = (rate * 4 + rate_offset).min(63) as i32;
+            (4 + rate % 4) as f32 * 2f32.powi(rate / 4) / 65536.0 * 0.375
+        };
+        match self.state {
+            EnvelopeState::Attack if patch.attack == 15 => {
+                self.attenuation = 0.0;
+                self.state = EnvelopeState::Decay;
+            }
+            EnvelopeState::Attack => {
+                self.attenuation -= 4.0 * rate(patch.attack);
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                let sustain = patch.sustain_level as f32 * 3.0;
+                self.attenuation += rate(patch.decay);
+                if self.attenuation >= sustain {
+                    self.attenuation = sustain;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain if patch.sustained => {}
+            EnvelopeState::Sustain => self.attenuation += rate(patch.release),
+            EnvelopeState::Release => self.attenuation += rate(release),
+            EnvelopeState::Off => {}
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.state == EnvelopeState::Release {
+                self.state = EnvelopeState::Off;
+            }
+        }
+    }
+
+    fn output(&self, patch: &OperatorPatch, phase_offset: f32, attenuation: f32) -> f32 {
+        if self.state == EnvelopeState::Off {
+            return 0.0;
+        }
+        let mut wave = (2.0 * PI * (self.phase + phase_offset)).sin();
+        if patch.half_sine && wave < 0.0 {
+            wave = 0.0;
+        }
+        let attenuation = self.attenuation + attenuation;
+        if attenuation >= MAX_ATTENUATION * 2.0 {
+            return 0.0;
+        }
+        wave * 10f32.powf(-attenuation / 20.0)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+    frequency: u16,
+    block: u8,
+    key: bool,
+    // releases slowly on key off
+    sustain: bool,
+    instrument: usize,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    // the last two modulator outputs, for the feedback
+    feedback: [f32; 2],
+    output: f32,
+}
+
+/// Konami VRC7 sound: a YM2413 (OPLL) with 6 two operator FM channels and
+/// its own set of built in instruments. Register number at $9010, data at $9030.
+pub struct Vrc7Audio {
+    address: u8,
+    custom: [u8; 8],
+    channels: [Channel; 6],
+    timer: u8,
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Self {
+        Vrc7Audio {
+            address: 0,
+            custom: [0; 8],
+            channels: [Channel::default(); 6],
+            timer: SAMPLE_CYCLES,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+        }
+    }
+
+    fn write_register(&mut self, data: u8) {
+        let register = self.address;
+        match register {
+            0x00..=0x07 => self.custom[register as usize] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[(register & 0x0F) as usize];
+                channel.frequency = (channel.frequency & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[(register & 0x0F) as usize];
+                channel.frequency = (channel.frequency & 0xFF) | (data as u16 & 1) << 8;
+                channel.block = (data >> 1) & 7;
+                channel.sustain = data & 0x20 != 0;
+                let key = data & 0x10 != 0;
+                if key && !channel.key {
+                    channel.modulator.key_on();
+                    channel.carrier.key_on();
+                } else if !key && channel.key {
+                    channel.modulator.key_off();
+                    channel.carrier.key_off();
+                }
+                channel.key = key;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[(register & 0x0F) as usize];
+                channel.instrument = (data >> 4) as usize;
+                channel.volume = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn patch(&self, instrument: usize) -> &[u8; 8] {
+        if instrument == 0 {
+            &self.custom
+        } else {
+            &PATCHES[instrument]
+        }
+    }
+
+    fn sample(&mut self) {
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_HZ / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_HZ / SAMPLE_RATE).fract();
+        let tremolo = (1.0 - (2.0 * PI * self.tremolo_phase).cos()) / 2.0 * TREMOLO_DB;
+        let vibrato = 1.0 + (2.0 * PI * self.vibrato_phase).sin() * VIBRATO_DEPTH;
+
+        for n in 0..self.channels.len() {
+            let patch = *self.patch(self.channels[n].instrument);
+            let modulator_patch = OperatorPatch::decode(&patch, false);
+            let carrier_patch = OperatorPatch::decode(&patch, true);
+            let channel = &mut self.channels[n];
+
+            let key_code = (channel.block << 1) | (channel.frequency >> 8) as u8;
+            let key_scale = (KEY_SCALE[(channel.frequency >> 5) as usize & 0x0F]
+                - 6.0 * (7 - channel.block) as f32)
+                .max(0.0);
+            // the sustain bit shortens the release, percussive sounds use rate 7
+            let release = |patch: &OperatorPatch| {
+                if channel.sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                }
+            };
+            let modulator_release = release(&modulator_patch);
+            let carrier_release = release(&carrier_patch);
+
+            let mut operators = [
+                (&mut channel.modulator, &modulator_patch, modulator_release),
+                (&mut channel.carrier, &carrier_patch, carrier_release),
+            ];
+            for (operator, patch, release) in operators.iter_mut() {
+                let rate_offset = if patch.key_scale_rate {
+                    key_code
+                } else {
+                    key_code >> 2
+                };
+                operator.clock_envelope(patch, rate_offset, *release);
+                let mut step = (channel.frequency as f32) * 2f32.powi(channel.block as i32 - 1)
+                    / 262144.0
+                    * patch.multiplier as f32
+                    / 2.0;
+                if patch.vibrato {
+                    step *= vibrato;
+                }
+                operator.phase = (operator.phase + step).fract();
+            }
+
+            let attenuation = |patch: &OperatorPatch, level: f32| {
+                level
+                    + key_scale * KEY_SCALE_FACTOR[patch.key_scale_level as usize]
+                    + if patch.tremolo { tremolo } else { 0.0 }
+            };
+            let feedback_level = patch[3] & 7;
+            let feedback = if feedback_level == 0 {
+                0.0
+            } else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0
+                    * 2f32.powi(feedback_level as i32 - 6)
+            };
+            let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+            let modulation = channel.modulator.output(
+                &modulator_patch,
+                feedback,
+                attenuation(&modulator_patch, total_level),
+            );
+            channel.feedback = [channel.feedback[1], modulation];
+            channel.output = channel.carrier.output(
+                &carrier_patch,
+                modulation * MODULATION_DEPTH,
+                attenuation(&carrier_patch, channel.volume as f32 * 3.0),
+            );
+        }
+    }
+}
+
+impl Default for Vrc7Audio {
+    fn default() -> Self {
+        Vrc7Audio::new()
+    }
+}
+
+impl ExpansionAudio for Vrc7Audio {
+    fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            ADDRESS => self.address = data,
+            DATA => self.write_register(data),
+            _ => return false,
+        }
+        true
+    }
+
+    fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = SAMPLE_CYCLES;
+            self.sample();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.channels
+            .iter()
+            .map(|channel| channel.output)
+            .sum::<f32>()
+            * OUTPUT_SCALE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(chip: &mut Vrc7Audio, register: u8, data: u8) {
+        chip.write(ADDRESS, register);
+        chip.write(DATA, data);
+    }
+
+    // positive going zero crossings of channel 0 in a second
+    fn count_cycles(chip: &mut Vrc7Audio) -> usize {
+        let mut crossings = 0;
+        let mut last = 0.0;
+        for _ in 0..SAMPLE_RATE as usize * SAMPLE_CYCLES as usize {
+            chip.clock();
+            let output = chip.channels[0].output;
+            if last <= 0.0 && output > 0.0 {
+                crossings += 1;
+            }
+            last = output;
+        }
+        crossings
+    }
+
+    #[test]
+    fn test_sine_pitch() {
+        let mut chip = Vrc7Audio::new();
+        // custom instrument: a silent modulator and a sustained carrier at x1
+        for (register, data) in [0x00, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x00, 0x0F]
+            .iter()
+            .enumerate()
+        {
+            set(&mut chip, register as u8, *data);
+        }
+        set(&mut chip, 0x30, 0x00);
+        // A4: frequency 288 at block 4
+        set(&mut chip, 0x10, 288u16 as u8);
+        set(&mut chip, 0x20, 0x10 | 4 << 1 | 1);
+        let cycles = count_cycles(&mut chip);
+        assert!((435..=439).contains(&cycles), "{} Hz", cycles);
+        assert!(chip.output() != 0.0);
+
+        // key off with release rate 15
+        set(&mut chip, 0x20, 4 << 1 | 1);
+        for _ in 0..SAMPLE_CYCLES as usize * 1000 {
+            chip.clock();
+        }
+        assert_eq!(chip.channels[0].carrier.state, EnvelopeState::Off);
+        assert_eq!(chip.output(), 0.0);
+    }
+
+    #[test]
+    fn test_instruments() {
+        let mut chip = Vrc7Audio::new();
+        set(&mut chip, 0x32, 0x35);
+        set(&mut chip, 0x12, 0x80);
+        set(&mut chip, 0x22, 0x18);
+        let mut loudest = 0f32;
+        for _ in 0..SAMPLE_CYCLES as usize * 2000 {
+            chip.clock();
+            loudest = loudest.max(chip.output().abs());
+        }
+        assert!(loudest > 0.01);
+        assert_eq!(chip.channels[2].instrument, 3);
+        assert!(!chip.write(0x9011, 0));
+    }
+}
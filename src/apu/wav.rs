@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+
+/// 16-bit mono PCM WAV of samples in -1.0..1.0
+pub fn write<W: Write>(out: &mut W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, 1 channel
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    // bytes per second, bytes per frame, bits per sample
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&to_i16(*sample).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let mut out = vec![];
+        write(&mut out, 44_100, &[0.0, 1.0, -2.0]).unwrap();
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(&out[4..8], &42u32.to_le_bytes());
+        assert_eq!(&out[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&out[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
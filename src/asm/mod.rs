@@ -0,0 +1,912 @@
+use crate::cpu::mem::AddressingMode;
+use crate::cpu::opcodes::{OpCode, CPU_OPS_CODES};
+use std::collections::HashMap;
+
+// Programs without an `.org` directive are placed where `CPU::load` puts them
+pub const DEFAULT_ORIGIN: u16 = 0x0600;
+
+/// Assembles a source string and returns the bytes only, panicking on errors.
+/// Handy for tests: `cpu.load_and_run(asm!("LDA #$c0 \n TAX \n INX \n BRK"))`
+#[macro_export]
+macro_rules! asm {
+    ($src:expr) => {
+        $crate::asm::assemble($src).unwrap().code
+    };
+}
+
+pub struct Program {
+    pub origin: u16,
+    pub code: Vec<u8>,
+    pub labels: HashMap<String, u16>,
+}
+
+/// Assembles standard 6502 syntax:
+///
+///   label:  LDA #<table      ; immediate, low byte of a label
+///           STA $10,X        ; zero page / absolute chosen by operand value
+///           JMP (vector)     ; indirect
+///           BNE label        ; relative
+///   name = $2000 + 7         ; constants
+///           .org $8000       ; .org / .byte (.db) / .word (.dw) directives
+///
+/// Numbers are decimal, `$hex`, `%binary` or `'c'`; `*` is the current address.
+pub fn assemble(source: &str) -> Result<Program, String> {
+    assemble_at(DEFAULT_ORIGIN, source)
+}
+
+pub fn assemble_at(origin: u16, source: &str) -> Result<Program, String> {
+    let mut lines = Vec::new();
+    for (idx, text) in source.lines().enumerate() {
+        let line = parse_line(text).map_err(|e| format!("line {}: {}", idx + 1, e))?;
+        lines.push((idx + 1, line));
+    }
+
+    let mut symbols: HashMap<String, i64> = HashMap::new();
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut pending = Vec::new();
+
+    // pass 1: assign addresses to labels and pick addressing modes
+    let mut pc = origin as i64;
+    let mut emitted = false;
+    for (line_no, line) in lines.iter_mut() {
+        first_pass(
+            line,
+            &mut emitted,
+            &mut pc,
+            &mut symbols,
+            &mut labels,
+            &mut pending,
+        )
+        .map_err(|e| format!("line {}: {}", line_no, e))?;
+    }
+    resolve_constants(&lines, pending, &mut symbols)?;
+
+    // pass 2: emit bytes
+    let mut program = Program {
+        origin,
+        code: vec![],
+        labels,
+    };
+    let mut pc = origin as i64;
+    let mut started = false;
+    for (line_no, line) in lines.iter() {
+        second_pass(line, &mut pc, &mut started, &symbols, &mut program)
+            .map_err(|e| format!("line {}: {}", line_no, e))?;
+    }
+
+    Ok(program)
+}
+
+fn first_pass(
+    line: &mut Line,
+    emitted: &mut bool,
+    pc: &mut i64,
+    symbols: &mut HashMap<String, i64>,
+    labels: &mut HashMap<String, u16>,
+    pending: &mut Vec<(String, i64)>,
+) -> Result<(), String> {
+    let defined = |symbols: &HashMap<String, i64>, name: &String| {
+        symbols.contains_key(name) || pending.iter().any(|(pending, _)| pending == name)
+    };
+    if let Some(label) = &line.label {
+        if defined(symbols, label) {
+            return Err(format!("label '{}' is defined twice", label));
+        }
+        symbols.insert(label.clone(), *pc);
+        labels.insert(label.clone(), *pc as u16);
+    }
+
+    match &mut line.statement {
+        Statement::Empty => {}
+        Statement::Constant(name, expr) => {
+            if defined(symbols, name) {
+                return Err(format!("label '{}' is defined twice", name));
+            }
+            match expr.eval(symbols, *pc) {
+                Ok(value) => {
+                    symbols.insert(name.clone(), value);
+                }
+                // refers to a label further down, instructions using it are absolute
+                Err(_) => pending.push((name.clone(), *pc)),
+            }
+        }
+        Statement::Org(expr) => {
+            let value = expr.eval(symbols, *pc)?;
+            // only .orgs before any output may go below the current address
+            if value < *pc && *emitted {
+                return Err(format!(".org ${:04x} moves backwards", value));
+            }
+            *pc = value;
+        }
+        Statement::Bytes(items) => {
+            for item in items {
+                *pc += match item {
+                    Item::Expr(_) => 1,
+                    Item::Str(s) => s.len() as i64,
+                };
+                *emitted |= !matches!(item, Item::Str(s) if s.is_empty());
+            }
+        }
+        Statement::Words(items) => {
+            *pc += 2 * items.len() as i64;
+            *emitted |= !items.is_empty();
+        }
+        Statement::Instruction(ins) => {
+            let value = ins.operand.expr().and_then(|e| e.eval(symbols, *pc).ok());
+            let mode = choose_mode(&ins.mnemonic, &ins.operand, value)?;
+            let op = find_opcode(&ins.mnemonic, &mode).unwrap();
+            ins.mode = Some(mode);
+            *pc += op.len as i64;
+            *emitted = true;
+        }
+    }
+
+    if *pc > 0x10000 {
+        return Err("program does not fit in 64KiB address space".to_string());
+    }
+    Ok(())
+}
+
+// Constants that refer to labels defined after them, in as many rounds as
+// it takes for constants built on other such constants
+fn resolve_constants(
+    lines: &[(usize, Line)],
+    mut pending: Vec<(String, i64)>,
+    symbols: &mut HashMap<String, i64>,
+) -> Result<(), String> {
+    let constant = |name: &str| {
+        lines
+            .iter()
+            .find_map(|(line_no, line)| match &line.statement {
+                Statement::Constant(constant, expr) if constant == name => Some((*line_no, expr)),
+                _ => None,
+            })
+    };
+    while !pending.is_empty() {
+        let names: Vec<String> = pending.iter().map(|(name, _)| name.clone()).collect();
+        let mut error = None;
+        pending.retain(|(name, pc)| {
+            let (line_no, expr) = constant(name).unwrap();
+            match expr.eval(symbols, *pc) {
+                Ok(value) => {
+                    symbols.insert(name.clone(), value);
+                    false
+                }
+                Err(e) => {
+                    // waiting for another pending constant is not an error yet
+                    let waiting = matches!(expr.unresolved(symbols), Some(label) if names.iter().any(|n| n == label));
+                    if !waiting {
+                        error.get_or_insert(format!("line {}: {}", line_no, e));
+                    }
+                    true
+                }
+            }
+        });
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if pending.len() == names.len() {
+            let (line_no, _) = constant(&pending[0].0).unwrap();
+            return Err(format!(
+                "line {}: '{}' is defined in terms of itself",
+                line_no, pending[0].0
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn second_pass(
+    line: &Line,
+    pc: &mut i64,
+    started: &mut bool,
+    symbols: &HashMap<String, i64>,
+    program: &mut Program,
+) -> Result<(), String> {
+    let mut emit = |program: &mut Program, pc: &mut i64, byte: u8| {
+        if !*started {
+            program.origin = *pc as u16;
+            *started = true;
+        }
+        program.code.push(byte);
+        *pc += 1;
+    };
+
+    match &line.statement {
+        Statement::Empty | Statement::Constant(_, _) => {}
+        Statement::Org(expr) => {
+            let value = expr.eval(symbols, *pc)?;
+            if *started {
+                if value < program.origin as i64 {
+                    return Err(format!(
+                        ".org ${:04x} is below the start of the program at ${:04x}",
+                        value, program.origin
+                    ));
+                }
+                program
+                    .code
+                    .resize((value - program.origin as i64) as usize, 0);
+            }
+            *pc = value;
+        }
+        Statement::Bytes(items) => {
+            for item in items {
+                match item {
+                    Item::Expr(e) => {
+                        let value = byte_value(e.eval(symbols, *pc)?)?;
+                        emit(program, pc, value);
+                    }
+                    Item::Str(s) => {
+                        for b in s.bytes() {
+                            emit(program, pc, b);
+                        }
+                    }
+                }
+            }
+        }
+        Statement::Words(items) => {
+            for e in items {
+                let value = word_value(e.eval(symbols, *pc)?)?;
+                emit(program, pc, (value & 0xff) as u8);
+                emit(program, pc, (value >> 8) as u8);
+            }
+        }
+        Statement::Instruction(ins) => {
+            let mode = ins.mode.as_ref().unwrap();
+            let op = find_opcode(&ins.mnemonic, mode).unwrap();
+            let start = *pc;
+            emit(program, pc, op.code);
+
+            let value = match ins.operand.expr() {
+                Some(e) => e.eval(symbols, start)?,
+                None => 0,
+            };
+            match mode {
+                AddressingMode::Implicit | AddressingMode::Accumulator => {}
+                AddressingMode::Relative => {
+                    let offset = value - (start + 2);
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!("branch target is out of range ({})", offset));
+                    }
+                    emit(program, pc, offset as i8 as u8);
+                }
+                AddressingMode::Immediate => {
+                    let value = byte_value(value)?;
+                    emit(program, pc, value);
+                }
+                AddressingMode::ZeroPage
+                | AddressingMode::ZeroPageX
+                | AddressingMode::ZeroPageY
+                | AddressingMode::IndirectX
+                | AddressingMode::IndirectY => {
+                    if !(0..=0xff).contains(&value) {
+                        return Err(format!("${:x} is not a zero page address", value));
+                    }
+                    emit(program, pc, value as u8);
+                }
+                AddressingMode::Absolute
+                | AddressingMode::AbsoluteX
+                | AddressingMode::AbsoluteY
+                | AddressingMode::Indirect => {
+                    let value = word_value(value)?;
+                    emit(program, pc, (value & 0xff) as u8);
+                    emit(program, pc, (value >> 8) as u8);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn byte_value(value: i64) -> Result<u8, String> {
+    if !(-128..=0xff).contains(&value) {
+        return Err(format!("value {} does not fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+fn word_value(value: i64) -> Result<u16, String> {
+    if !(-0x8000..=0xffff).contains(&value) {
+        return Err(format!("value {} does not fit in a word", value));
+    }
+    Ok(value as u16)
+}
+
+fn find_opcode(mnemonic: &str, mode: &AddressingMode) -> Option<&'static OpCode> {
+    CPU_OPS_CODES
+        .iter()
+        .find(|op| op.mode == *mode && op.mnemonic.to_string() == mnemonic)
+}
+
+fn choose_mode(
+    mnemonic: &str,
+    operand: &Operand,
+    value: Option<i64>,
+) -> Result<AddressingMode, String> {
+    let has = |mode: &AddressingMode| find_opcode(mnemonic, mode).is_some();
+    let zero_page = matches!(value, Some(v) if (0..=0xff).contains(&v));
+
+    let candidates = match operand {
+        Operand::None => vec![AddressingMode::Implicit, AddressingMode::Accumulator],
+        Operand::Accumulator => vec![AddressingMode::Accumulator],
+        Operand::Immediate(_) => vec![AddressingMode::Immediate],
+        Operand::Indirect(_) => vec![AddressingMode::Indirect],
+        Operand::IndirectX(_) => vec![AddressingMode::IndirectX],
+        Operand::IndirectY(_) => vec![AddressingMode::IndirectY],
+        Operand::Direct(_) if has(&AddressingMode::Relative) => vec![AddressingMode::Relative],
+        Operand::Direct(_) if zero_page => vec![AddressingMode::ZeroPage, AddressingMode::Absolute],
+        Operand::Direct(_) => vec![AddressingMode::Absolute],
+        Operand::IndexedX(_) if zero_page => {
+            vec![AddressingMode::ZeroPageX, AddressingMode::AbsoluteX]
+        }
+        Operand::IndexedX(_) => vec![AddressingMode::AbsoluteX],
+        Operand::IndexedY(_) if zero_page => {
+            vec![AddressingMode::ZeroPageY, AddressingMode::AbsoluteY]
+        }
+        Operand::IndexedY(_) => vec![AddressingMode::AbsoluteY],
+    };
+
+    if !CPU_OPS_CODES
+        .iter()
+        .any(|op| op.mnemonic.to_string() == mnemonic)
+    {
+        return Err(format!("unknown instruction '{}'", mnemonic));
+    }
+
+    candidates
+        .into_iter()
+        .find(|mode| has(mode))
+        .ok_or_else(|| format!("{} does not support this addressing mode", mnemonic))
+}
+
+struct Line {
+    label: Option<String>,
+    statement: Statement,
+}
+
+enum Statement {
+    Empty,
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Item>),
+    Words(Vec<Expr>),
+    Instruction(Instruction),
+}
+
+enum Item {
+    Expr(Expr),
+    Str(String),
+}
+
+struct Instruction {
+    mnemonic: String,
+    operand: Operand,
+    mode: Option<AddressingMode>,
+}
+
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr),
+    IndexedX(Expr),
+    IndexedY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+impl Operand {
+    fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(e)
+            | Operand::Direct(e)
+            | Operand::IndexedX(e)
+            | Operand::IndexedY(e)
+            | Operand::Indirect(e)
+            | Operand::IndirectX(e)
+            | Operand::IndirectY(e) => Some(e),
+        }
+    }
+}
+
+enum Expr {
+    Number(i64),
+    Label(String),
+    ProgramCounter,
+    Unary(char, Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // first label the expression uses that has no value yet
+    fn unresolved<'a>(&'a self, symbols: &HashMap<String, i64>) -> Option<&'a str> {
+        match self {
+            Expr::Number(_) | Expr::ProgramCounter => None,
+            Expr::Label(name) if symbols.contains_key(name) => None,
+            Expr::Label(name) => Some(name),
+            Expr::Unary(_, e) => e.unresolved(symbols),
+            Expr::Binary(_, l, r) => l.unresolved(symbols).or_else(|| r.unresolved(symbols)),
+        }
+    }
+
+    fn eval(&self, symbols: &HashMap<String, i64>, pc: i64) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::ProgramCounter => pc,
+            Expr::Label(name) => *symbols
+                .get(name)
+                .ok_or_else(|| format!("undefined label '{}'", name))?,
+            Expr::Unary(op, e) => {
+                let v = e.eval(symbols, pc)?;
+                match op {
+                    '-' => -v,
+                    '~' => !v & 0xffff,
+                    '<' => v & 0xff,
+                    '>' => (v >> 8) & 0xff,
+                    _ => unreachable!(),
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let l = l.eval(symbols, pc)?;
+                let r = r.eval(symbols, pc)?;
+                match op {
+                    '+' => l + r,
+                    '-' => l - r,
+                    '*' => l * r,
+                    '/' if r == 0 => return Err("division by zero".to_string()),
+                    '/' => l / r,
+                    '&' => l & r,
+                    '|' => l | r,
+                    '^' => l ^ r,
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Str(String),
+    Punct(char),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => i += 1,
+            '$' | '%' => {
+                let radix = if c == '$' { 16 } else { 2 };
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i].is_digit(radix) {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let value = i64::from_str_radix(&digits, radix)
+                    .map_err(|_| format!("malformed number '{}{}'", c, digits))?;
+                tokens.push(Token::Number(value));
+            }
+            '0'..='9' => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let digits: String = chars[start..i].iter().collect();
+                let value = digits
+                    .parse()
+                    .map_err(|_| format!("malformed number '{}'", digits))?;
+                tokens.push(Token::Number(value));
+            }
+            '\'' => {
+                if i + 2 >= chars.len() || chars[i + 2] != '\'' {
+                    return Err("malformed character literal".to_string());
+                }
+                tokens.push(Token::Number(chars[i + 1] as i64));
+                i += 3;
+            }
+            '"' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err("unterminated string".to_string());
+                }
+                tokens.push(Token::Str(chars[start..i].iter().collect()));
+                i += 1;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            '#' | '(' | ')' | ',' | ':' | '=' | '+' | '-' | '*' | '/' | '&' | '|' | '^' | '<'
+            | '>' | '~' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            _ => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", c))
+        }
+    }
+
+    fn eat_register(&mut self, register: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(name)) if name.eq_ignore_ascii_case(register) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    // expr := or ; or := xor ('|' xor)* ; xor := and ('^' and)* ; and := sum ('&' sum)*
+    // sum := product (('+'|'-') product)* ; product := unary (('*'|'/') unary)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[char]; 5] = [&['|'], &['^'], &['&'], &['+', '-'], &['*', '/']];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Punct(c)) = self.peek() {
+            let c = *c;
+            if !LEVELS[level].contains(&c) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(c, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Punct(c)) if "-~<>".contains(c) => {
+                Ok(Expr::Unary(c, Box::new(self.unary()?)))
+            }
+            Some(Token::Punct('*')) => Ok(Expr::ProgramCounter),
+            Some(Token::Punct('(')) => {
+                let e = self.expr()?;
+                self.expect(')')?;
+                Ok(e)
+            }
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => Ok(Expr::Label(name)),
+            Some(token) => Err(format!("unexpected {:?} in expression", token)),
+            None => Err("expression expected".to_string()),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.at_end() {
+            return Ok(Operand::None);
+        }
+        if self.tokens.len() == self.pos + 1 && self.eat_register("A") {
+            return Ok(Operand::Accumulator);
+        }
+        if self.eat('#') {
+            return Ok(Operand::Immediate(self.expr()?));
+        }
+
+        if self.peek() == Some(&Token::Punct('(')) {
+            let start = self.pos;
+            if let Some(operand) = self.indirect_operand()? {
+                return Ok(operand);
+            }
+            // something like `(base+1)*2,X`: a parenthesised expression
+            self.pos = start;
+        }
+
+        let e = self.expr()?;
+        if self.eat(',') {
+            if self.eat_register("X") {
+                return Ok(Operand::IndexedX(e));
+            }
+            if self.eat_register("Y") {
+                return Ok(Operand::IndexedY(e));
+            }
+            return Err("expected X or Y index".to_string());
+        }
+        Ok(Operand::Direct(e))
+    }
+
+    fn indirect_operand(&mut self) -> Result<Option<Operand>, String> {
+        self.expect('(')?;
+        let e = self.expr()?;
+        if self.eat(',') {
+            if !self.eat_register("X") {
+                return Err("expected X index".to_string());
+            }
+            self.expect(')')?;
+            return Ok(Some(Operand::IndirectX(e)));
+        }
+        self.expect(')')?;
+        if self.at_end() {
+            return Ok(Some(Operand::Indirect(e)));
+        }
+        if self.eat(',') && self.eat_register("Y") && self.at_end() {
+            return Ok(Some(Operand::IndirectY(e)));
+        }
+        Ok(None)
+    }
+}
+
+fn parse_line(text: &str) -> Result<Line, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+
+    let mut label = None;
+    if let (Some(Token::Ident(name)), Some(Token::Punct(':'))) =
+        (parser.tokens.first(), parser.tokens.get(1))
+    {
+        label = Some(name.clone());
+        parser.pos = 2;
+    }
+
+    let statement = match parser.next() {
+        None => Statement::Empty,
+        Some(Token::Ident(name)) if parser.eat('=') => {
+            let e = parser.expr()?;
+            Statement::Constant(name, e)
+        }
+        Some(Token::Ident(name)) if name.starts_with('.') => {
+            match name.to_ascii_lowercase().as_str() {
+                ".org" => Statement::Org(parser.expr()?),
+                ".byte" | ".db" => {
+                    let mut items = vec![];
+                    loop {
+                        match parser.peek() {
+                            Some(Token::Str(s)) => {
+                                items.push(Item::Str(s.clone()));
+                                parser.pos += 1;
+                            }
+                            _ => items.push(Item::Expr(parser.expr()?)),
+                        }
+                        if !parser.eat(',') {
+                            break;
+                        }
+                    }
+                    Statement::Bytes(items)
+                }
+                ".word" | ".dw" => {
+                    let mut items = vec![parser.expr()?];
+                    while parser.eat(',') {
+                        items.push(parser.expr()?);
+                    }
+                    Statement::Words(items)
+                }
+                _ => return Err(format!("unknown directive '{}'", name)),
+            }
+        }
+        Some(Token::Ident(name)) => Statement::Instruction(Instruction {
+            mnemonic: name.to_ascii_uppercase(),
+            operand: parser.operand()?,
+            mode: None,
+        }),
+        Some(token) => return Err(format!("unexpected {:?}", token)),
+    };
+
+    parser.expect_end()?;
+    Ok(Line { label, statement })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_simple_program() {
+        let program = assemble(
+            "
+            LDA #$c0  ; load
+            TAX
+            INX
+            BRK
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.origin, DEFAULT_ORIGIN);
+        assert_eq!(program.code, vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let code = asm!(
+            "
+            ASL A
+            LSR
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $1234
+            LDA $1234,X
+            LDA $12,Y
+            JMP ($0200)
+            LDA ($20,X)
+            LDA ($20),Y
+            "
+        );
+        assert_eq!(
+            code,
+            vec![
+                0x0a, 0x4a, 0xa5, 0x10, 0xb5, 0x10, 0xb6, 0x10, 0xad, 0x34, 0x12, 0xbd, 0x34, 0x12,
+                0xb9, 0x12, 0x00, 0x6c, 0x00, 0x02, 0xa1, 0x20, 0xb1, 0x20,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+        let program = assemble(
+            "
+            start:  LDX #3
+            loop:   DEX
+                    BNE loop
+                    JSR sub
+                    BEQ end
+            sub:    RTS
+            end:    BRK
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x20, 0x0a, 0x06, 0xf0, 0x01, 0x60, 0x00]
+        );
+        assert_eq!(program.labels["sub"], 0x060a);
+    }
+
+    #[test]
+    fn test_forward_reference_uses_absolute() {
+        let code = asm!("LDA var\nBRK\nvar: .byte 7");
+        assert_eq!(code, vec![0xad, 0x04, 0x06, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn test_directives_and_expressions() {
+        let program = assemble(
+            "
+            ptr = $2000 + 2 * 3
+                .org $8000
+            reset:
+                LDA #<ptr
+                LDX #>ptr
+                .byte 1, 'A', \"hi\", -1
+                .org $8010
+                .word reset, * + 2
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.origin, 0x8000);
+        let mut expected = vec![0xa9, 0x06, 0xa2, 0x20, 1, 0x41, b'h', b'i', 0xff];
+        expected.resize(0x10, 0);
+        expected.extend(vec![0x00, 0x80, 0x14, 0x80]);
+        assert_eq!(program.code, expected);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble("NOP\nFOO #1").err().unwrap(),
+            "line 2: unknown instruction 'FOO'"
+        );
+        assert_eq!(
+            assemble("LDA missing").err().unwrap(),
+            "line 1: undefined label 'missing'"
+        );
+        assert_eq!(
+            assemble("STA #1").err().unwrap(),
+            "line 1: STA does not support this addressing mode"
+        );
+        assert!(assemble("BNE far\n.org $0700\nfar: BRK").is_err());
+        assert_eq!(
+            assemble("LDA #1\nLDA 99999999999999999999").err().unwrap(),
+            "line 2: malformed number '99999999999999999999'"
+        );
+        assert_eq!(
+            assemble("a = b + 1\nb = missing").err().unwrap(),
+            "line 2: undefined label 'missing'"
+        );
+        assert_eq!(
+            assemble("a = b\nb = a").err().unwrap(),
+            "line 1: 'a' is defined in terms of itself"
+        );
+    }
+
+    #[test]
+    fn test_org_backwards() {
+        assert_eq!(
+            assemble(".org $05FF\nNOP\n.org $0400").err().unwrap(),
+            "line 3: .org $0400 moves backwards"
+        );
+        assert_eq!(
+            assemble("NOP\n.org $0600").err().unwrap(),
+            "line 2: .org $0600 moves backwards"
+        );
+        // nothing is emitted before the second .org, it sets the start
+        let program = assemble(".org $0700\nstart = *\n.byte \"\"\n.org $0400\nNOP").unwrap();
+        assert_eq!((program.origin, program.code), (0x0400, vec![0xea]));
+    }
+
+    #[test]
+    fn test_constant_of_later_label() {
+        let program = assemble(
+            "
+            entry = start + 1
+            ptr = entry + 1
+                JMP ptr
+            start:
+                BRK
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.code, vec![0x4c, 0x05, 0x06, 0x00]);
+    }
+}
@@ -1,4 +1,10 @@
-use crate::cpu::mem::Mem;
+use crate::apu::Apu;
+use crate::cdl::{ChrFlags, CodeDataLogger, PrgFlags};
+use crate::cpu::mem::{BusHooks, Mem};
+use crate::diag::{Category, Diagnostics};
+use crate::joypad::Joypad;
+use crate::mapper::{self, Fds, Mapper, PpuFetch};
+use crate::region::Region;
 use crate::rom::Rom;
 
 //  _______________ $10000  _______________
@@ -33,69 +39,456 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
-const PRG_ROM: u16 = 0x8000;
-const PRG_ROM_END: u16 = 0xFFFF;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4013;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD1: u16 = 0x4016;
+// reads go to the second controller, writes to the APU frame counter
+const JOYPAD2: u16 = 0x4017;
+const CARTRIDGE: u16 = 0x4020;
+const CARTRIDGE_END: u16 = 0xFFFF;
 
 pub struct Bus {
     cpu_vram: [u8; 2048],
-    rom: Rom,
+    mapper: Box<dyn Mapper>,
+    region: Region,
+    apu: Apu,
+    cdl: Option<CodeDataLogger>,
+    joypads: [Joypad; 2],
+    // the last byte on the data bus, what reads of undriven lines see
+    open_bus: u8,
+    diagnostics: Option<Diagnostics>,
+    // the first access to a device that is not emulated, see `BusHooks::unsupported`
+    unsupported: Option<String>,
 }
 
 impl Bus {
-    pub fn new(rom: Rom) -> Self {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        let region = rom.region;
+        Ok(Bus::with_mapper(mapper::for_rom(rom)?, region))
+    }
+
+    /// Bus with a cartridge that does not come from a ROM file, e.g. an NSF
+    pub fn with_mapper(mapper: Box<dyn Mapper>, region: Region) -> Self {
         Bus {
             cpu_vram: [0; 2048],
-            rom,
+            mapper,
+            region,
+            apu: Apu::new(region),
+            cdl: None,
+            joypads: [Joypad::new(), Joypad::new()],
+            open_bus: 0,
+            diagnostics: None,
+            unsupported: None,
+        }
+    }
+
+    /// Controller of player 1 (0) or 2 (1)
+    pub fn joypad(&mut self, player: usize) -> &mut Joypad {
+        &mut self.joypads[player]
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn apu(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Reports unmapped accesses, ROM writes and unknown opcodes, None turns it off
+    pub fn set_diagnostics(&mut self, diagnostics: Option<Diagnostics>) {
+        self.diagnostics = diagnostics;
+    }
+
+    pub fn diagnostics(&self) -> Option<&Diagnostics> {
+        self.diagnostics.as_ref()
+    }
+
+    /// None unless the console is a Famicom Disk System
+    pub fn disk_drive(&mut self) -> Option<&mut Fds> {
+        self.mapper.disk_drive()
+    }
+
+    /// Starts recording how PRG/CHR bytes are used, see `BusHooks::cdl_mark`
+    pub fn enable_cdl(&mut self) {
+        let cdl = CodeDataLogger::new(self.mapper.prg_rom_len(), self.mapper.chr_rom_len());
+        self.cdl = Some(cdl);
+    }
+
+    /// Continues recording into a previously saved log of the same ROM
+    pub fn attach_cdl(&mut self, cdl: CodeDataLogger) -> Result<(), String> {
+        let (prg_size, chr_size) = (self.mapper.prg_rom_len(), self.mapper.chr_rom_len());
+        if cdl.prg_size() != prg_size || cdl.chr_size() != chr_size {
+            return Err(format!(
+                "CDL covers {} bytes of PRG-ROM and {} of CHR-ROM, the ROM has {} and {}",
+                cdl.prg_size(),
+                cdl.chr_size(),
+                prg_size,
+                chr_size
+            ));
+        }
+        self.cdl = Some(cdl);
+        Ok(())
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLogger> {
+        self.cdl.as_ref()
+    }
+
+    /// Pattern table byte for the PPU, logged as rendered or as read through PPUDATA
+    pub fn chr_read(&mut self, addr: u16, fetch: PpuFetch) -> u8 {
+        if let (Some(cdl), Some(offset)) = (&mut self.cdl, self.mapper.chr_rom_offset(addr, fetch))
+        {
+            let flags = match fetch {
+                PpuFetch::Data => ChrFlags::READ,
+                _ => ChrFlags::RENDERED,
+            };
+            cdl.mark_chr(offset, flags);
         }
+        self.mapper.chr_read(addr, fetch)
     }
 }
 
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
-        match addr {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet")
+                self.no_ppu("read", addr);
+                self.open_bus
             }
-            PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
+            // the status register is inside the CPU, the bus keeps its value
+            APU_STATUS => return self.apu.read_status() | (self.open_bus & 0x20),
+            // the controller ports drive the low 5 bits only
+            JOYPAD1 => self.joypads[0].read() | (self.open_bus & 0xE0),
+            JOYPAD2 => self.joypads[1].read() | (self.open_bus & 0xE0),
+            CARTRIDGE..=CARTRIDGE_END => match self.mapper.cpu_read(addr) {
+                Some(data) => data,
+                None => {
+                    self.report(Category::UnmappedAccess, addr, None);
+                    self.open_bus
+                }
+            },
+            // write-only registers
             _ => {
-                println!("Ignoring mem access at {}", addr);
-                0
+                self.report(Category::UnmappedAccess, addr, None);
+                self.open_bus
+            }
+        };
+        self.open_bus = data;
+        data
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
             }
+            // registers of devices that are not emulated yet
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => 0,
+            APU_STATUS => self.apu.peek_status() | (self.open_bus & 0x20),
+            JOYPAD1 => self.joypads[0].peek() | (self.open_bus & 0xE0),
+            JOYPAD2 => self.joypads[1].peek() | (self.open_bus & 0xE0),
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_peek(addr).unwrap_or(self.open_bus),
+            _ => self.open_bus,
         }
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b11111111111;
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b00100000_00000111;
-                todo!("PPU is not supported yet");
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                // boards like MMC5 snoop PPUCTRL and PPUMASK
+                self.mapper.ppu_register_write(mirror_down_addr, data);
+                self.no_ppu("write", addr);
             }
-            PRG_ROM..=PRG_ROM_END => {
-                panic!("Attempt to write to Cartridge ROM space")
+            APU_REGISTERS..=APU_REGISTERS_END | APU_STATUS | JOYPAD2 => {
+                self.apu.write(addr, data);
             }
-            _ => {
-                println!("Ignoring mem write-access at {}", addr);
+            // the strobe goes to both controller ports
+            JOYPAD1 => {
+                self.joypads[0].write(data);
+                self.joypads[1].write(data);
+            }
+            CARTRIDGE..=CARTRIDGE_END if self.mapper.cpu_write(addr, data) => {}
+            _ if self.mapper.prg_rom_offset(addr).is_some() => {
+                self.report(Category::RomWrite, addr, Some(data));
             }
+            _ => self.report(Category::UnmappedAccess, addr, Some(data)),
         }
     }
 }
 
+impl BusHooks for Bus {
+    fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            // the DMC steals the bus to fetch its samples
+            if let Some(addr) = self.apu.dmc_pending_read() {
+                let data = self.mapper.cpu_peek(addr).unwrap_or(self.open_bus);
+                self.cdl_mark(addr, PrgFlags::PCM_AUDIO);
+                self.open_bus = data;
+                self.apu.dmc_fill(data);
+            }
+            self.mapper.clock();
+            self.apu.clock(self.mapper.audio_output());
+        }
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.tick(cycles);
+        }
+    }
+
+    // the APU and the cartridge can pull it
+    fn irq(&self) -> bool {
+        self.apu.irq() || self.mapper.irq()
+    }
+
+    fn logs_code_data(&self) -> bool {
+        self.cdl.is_some()
+    }
+
+    fn cdl_mark(&mut self, addr: u16, flags: PrgFlags) {
+        if let (Some(cdl), Some(offset)) = (&mut self.cdl, self.mapper.prg_rom_offset(addr)) {
+            cdl.mark_prg(offset, addr, flags);
+        }
+    }
+
+    fn report(&mut self, category: Category, addr: u16, data: Option<u8>) {
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.report(category, addr, data);
+        }
+    }
+
+    fn unsupported(&mut self) -> Option<String> {
+        self.unsupported.take()
+    }
+}
+
 impl Bus {
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+    fn no_ppu(&mut self, access: &str, addr: u16) {
+        self.unsupported.get_or_insert_with(|| {
+            format!(
+                "{} of PPU register ${:04X}, the PPU is not emulated yet",
+                access, addr
+            )
+        });
+    }
+
+    /// Appends internal RAM, the APU and the cartridge state, see `savestate`
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.cpu_vram);
+        self.apu.save_state(out);
+        self.mapper.save_state(out);
+    }
+
+    pub fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        let fixed = self.cpu_vram.len() + Apu::STATE_LEN;
+        if raw.len() < fixed {
+            return Err(format!(
+                "bus state has {} bytes, expected at least {}",
+                raw.len(),
+                fixed
+            ));
         }
-        self.rom.prg_rom[addr as usize]
+        let (ram, raw) = raw.split_at(self.cpu_vram.len());
+        let (apu, mapper) = raw.split_at(Apu::STATE_LEN);
+        self.mapper.load_state(mapper)?;
+        self.apu.load_state(apu);
+        self.cpu_vram.copy_from_slice(ram);
+        Ok(())
+    }
+
+    // 16KiB PRG-ROM bank that is mapped at the given CPU address
+    pub fn prg_bank(&self, addr: u16) -> Option<usize> {
+        self.mapper
+            .prg_rom_offset(addr)
+            .map(|offset| offset / 0x4000)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::cpu::{Halt, CPU};
+    use crate::diag::Diagnostic;
+    use crate::joypad::JoypadButton;
+    use crate::rom::test::{test_rom, test_rom_with_prg};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_ppu_registers_unsupported() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.mem_write(0x2001, 0x1e);
+        bus.mem_read(0x2002);
+        assert_eq!(
+            bus.unsupported(),
+            Some("write of PPU register $2001, the PPU is not emulated yet".to_string())
+        );
+        assert_eq!(bus.unsupported(), None);
+
+        // the CPU stops after the instruction
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  LDA #$80
+                    STA $2000
+                    LDX #1
+                    .org $fffc
+                    .word reset, 0
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(Bus::new(test_rom_with_prg(program.code)).unwrap());
+        cpu.reset();
+        cpu.run();
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(
+            cpu.halt(),
+            Some(&Halt::Unsupported(
+                "write of PPU register $2000, the PPU is not emulated yet".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_peek_matches_read() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.mem_write(0x0010, 0x55);
+        assert_eq!(bus.mem_peek(0x0810), 0x55);
+        assert_eq!(bus.mem_peek(0x0810), bus.mem_read(0x0810));
+        assert_eq!(bus.mem_peek(0xc000), bus.mem_read(0xc000));
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.mem_write(0x6004, 0x41);
+        assert_eq!(bus.mem_read(0x6004), 0x41);
+        assert_eq!(bus.mem_peek(0x6004), 0x41);
+    }
+
+    #[test]
+    fn test_joypad_ports() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.joypad(1)
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 0);
+        assert_eq!(bus.mem_peek(0x4017), 1);
+        assert_eq!(bus.mem_read(0x4017), 1);
+        assert_eq!(bus.mem_read(0x4017), 0);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.mem_write(0x0000, 0xA5);
+        bus.mem_read(0x0000);
+        assert_eq!(bus.mem_peek(0x5000), 0xA5);
+        assert_eq!(bus.mem_read(0x5000), 0xA5);
+        // write-only registers show the last write
+        bus.mem_write(0x4000, 0x3C);
+        assert_eq!(bus.mem_read(0x4000), 0x3C);
+        assert_eq!(bus.mem_read(0x4014), 0x3C);
+
+        // the top 3 bits of the controller ports are not driven
+        bus.mem_write(0x0001, 0x40);
+        bus.mem_read(0x0001);
+        bus.joypad(0)
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 0x01);
+        bus.mem_read(0x0001);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+
+        // nor is bit 5 of the APU status, whose read does not reach the bus
+        bus.mem_read(0x0000);
+        assert_eq!(bus.mem_read(0x4015), 0x20);
+        assert_eq!(bus.mem_read(0x5000), 0xA5);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let sink = seen.clone();
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.mem_read(0x5000);
+        bus.set_diagnostics(Some(Diagnostics::new(move |d: &Diagnostic| {
+            sink.borrow_mut().push(d.clone());
+        })));
+        bus.mem_read(0x0000);
+        bus.mem_write(0x6000, 1);
+        bus.mem_read(0x5000);
+        bus.mem_write(0x4018, 2);
+        bus.mem_write(0xC000, 3);
+        let diagnostic = |category, addr, data| Diagnostic {
+            category,
+            addr,
+            data,
+        };
+        assert_eq!(
+            *seen.borrow(),
+            [
+                diagnostic(Category::UnmappedAccess, 0x5000, None),
+                diagnostic(Category::UnmappedAccess, 0x4018, Some(2)),
+                diagnostic(Category::RomWrite, 0xC000, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attach_cdl() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        let (prg_size, chr_size) = (bus.mapper.prg_rom_len(), bus.mapper.chr_rom_len());
+        assert!(bus
+            .attach_cdl(CodeDataLogger::new(prg_size, chr_size))
+            .is_ok());
+        assert_eq!(
+            bus.attach_cdl(CodeDataLogger::new(0x100, chr_size)).err(),
+            Some(format!(
+                "CDL covers 256 bytes of PRG-ROM and {} of CHR-ROM, the ROM has {} and {}",
+                chr_size, prg_size, chr_size
+            ))
+        );
+    }
+
+    #[test]
+    fn test_cdl_chr_and_samples() {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        bus.enable_cdl();
+        bus.chr_read(0x0010, PpuFetch::Background);
+        bus.chr_read(0x1020, PpuFetch::Data);
+
+        // a one byte DMC sample at $C000
+        bus.mem_write(0x4012, 0);
+        bus.mem_write(0x4013, 0);
+        bus.mem_write(0x4015, 0x10);
+        bus.tick(10);
+
+        let cdl = bus.cdl().unwrap();
+        assert_eq!(cdl.chr_flags(0x0010), ChrFlags::RENDERED);
+        assert_eq!(cdl.chr_flags(0x1020), ChrFlags::READ);
+        assert_eq!(cdl.chr_flags(0x0011), ChrFlags::empty());
+        let offset = bus.mapper.prg_rom_offset(0xC000).unwrap();
+        assert!(cdl.prg_flags(offset).contains(PrgFlags::PCM_AUDIO));
+        assert!(!cdl.prg_flags(offset + 1).contains(PrgFlags::PCM_AUDIO));
+    }
+
+    #[test]
+    fn test_peek_io_registers() {
+        let bus = Bus::new(test_rom()).unwrap();
+        assert_eq!(bus.mem_peek(0x2002), 0);
     }
 }
@@ -0,0 +1,150 @@
+use super::{CodeDataLogger, PrgFlags};
+use crate::cpu::mem::AddressingMode;
+use crate::cpu::opcodes::{OpCode, OPSCODES_MAP};
+use std::io::{self, Write};
+
+// bytes per `.byte` line
+const BYTES_PER_LINE: usize = 8;
+
+#[derive(PartialEq, Clone, Copy)]
+enum Kind {
+    Data,
+    Unused,
+}
+
+/// Disassembles PRG-ROM, decoding only the bytes the log saw executed as code.
+/// Bytes read as data and bytes never touched are listed as `.byte` lines.
+/// Every line starts with the PRG-ROM offset and the CPU address the byte was
+/// accessed through, or where it would be without banking when it never was.
+pub fn write_listing<W: Write>(out: &mut W, prg: &[u8], cdl: &CodeDataLogger) -> io::Result<()> {
+    let flags = |offset: usize| match cdl.prg.get(offset) {
+        Some(flags) => PrgFlags::from_bits_truncate(*flags),
+        None => PrgFlags::empty(),
+    };
+    let kind = |offset: usize| {
+        if flags(offset).intersects(PrgFlags::DATA | PrgFlags::PCM_AUDIO) {
+            Kind::Data
+        } else {
+            Kind::Unused
+        }
+    };
+
+    let mut offset = 0;
+    while offset < prg.len() {
+        let addr = address(offset, flags(offset));
+        if flags(offset).contains(PrgFlags::CODE) {
+            if let Some(op) = OPSCODES_MAP.get(&prg[offset]) {
+                let len = op.len as usize;
+                if offset + len <= prg.len() {
+                    let bytes = &prg[offset..offset + len];
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    writeln!(
+                        out,
+                        "{:05X}  {:04X}  {:8}  {}",
+                        offset,
+                        addr,
+                        hex.join(" "),
+                        instruction(op, addr, bytes)
+                    )?;
+                    offset += len;
+                    continue;
+                }
+            }
+        }
+
+        // a run of data or unused bytes, up to the next code byte
+        let run_kind = kind(offset);
+        let start = offset;
+        while offset < prg.len()
+            && offset - start < BYTES_PER_LINE
+            && (offset == start
+                || (!flags(offset).contains(PrgFlags::CODE) && kind(offset) == run_kind))
+        {
+            offset += 1;
+        }
+        let bytes: Vec<String> = prg[start..offset]
+            .iter()
+            .map(|b| format!("${:02X}", b))
+            .collect();
+        let comment = match run_kind {
+            Kind::Data => "data",
+            Kind::Unused => "unused",
+        };
+        writeln!(
+            out,
+            "{:05X}  {:04X}  .byte {:<31} ; {}",
+            start,
+            addr,
+            bytes.join(", "),
+            comment
+        )?;
+    }
+    Ok(())
+}
+
+fn address(offset: usize, flags: PrgFlags) -> u16 {
+    if flags.is_empty() {
+        return 0x8000 | (offset & 0x7fff) as u16;
+    }
+    let window = ((flags & PrgFlags::WINDOW).bits() >> 2) as u16;
+    0x8000 + window * 0x2000 + (offset & 0x1fff) as u16
+}
+
+fn instruction(op: &OpCode, addr: u16, bytes: &[u8]) -> String {
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let operand = match op.mode {
+        AddressingMode::Implicit => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", byte),
+        AddressingMode::ZeroPage => format!("${:02X}", byte),
+        AddressingMode::ZeroPageX => format!("${:02X},X", byte),
+        AddressingMode::ZeroPageY => format!("${:02X},Y", byte),
+        AddressingMode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+        AddressingMode::Absolute => format!("${:04X}", word),
+        AddressingMode::AbsoluteX => format!("${:04X},X", word),
+        AddressingMode::AbsoluteY => format!("${:04X},Y", word),
+        AddressingMode::Indirect => format!("(${:04X})", word),
+        AddressingMode::IndirectX => format!("(${:02X},X)", byte),
+        AddressingMode::IndirectY => format!("(${:02X}),Y", byte),
+    };
+    format!("{} {}", op.mnemonic, operand)
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        // LDA $8007,X / BNE -5 / BRK, then two data bytes and an unused one
+        let prg = [0xbd, 0x07, 0x80, 0xd0, 0xfb, 0x00, 0xff, 0x12, 0x34, 0xea];
+        let mut cdl = CodeDataLogger::new(prg.len(), 0);
+        for offset in 0..6 {
+            cdl.mark_prg(offset, 0xc000 + offset as u16, PrgFlags::CODE);
+        }
+        cdl.mark_prg(7, 0xc007, PrgFlags::DATA);
+        cdl.mark_prg(8, 0xc008, PrgFlags::DATA);
+
+        let mut out = vec![];
+        write_listing(&mut out, &prg, &cdl).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = listing.lines().map(|line| line.trim_end()).collect();
+        assert_eq!(
+            lines,
+            [
+                "00000  C000  BD 07 80  LDA $8007,X",
+                "00003  C003  D0 FB     BNE $C000",
+                "00005  C005  00        BRK",
+                "00006  8006  .byte $FF                             ; unused",
+                "00007  C007  .byte $12, $34                        ; data",
+                "00009  8009  .byte $EA                             ; unused",
+            ]
+        );
+    }
+}
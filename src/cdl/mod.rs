@@ -0,0 +1,142 @@
+pub mod listing;
+
+use std::fs;
+use std::io;
+
+bitflags! {
+    /// # PRG byte flags of the FCEUX .cdl format
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  _ P d c A A D C
+    ///    | | | | | | +--- Executed as code
+    ///    | | | | | +----- Read as data
+    ///    | | | +-+------- 8KiB window it was accessed through ($8000/$A000/$C000/$E000)
+    ///    | | +----------- Executed as the target of an indirect jump
+    ///    | +------------- Read as data through (zp,X) or (zp),Y
+    ///    +--------------- Played as PCM sample by the DMC
+    ///
+    pub struct PrgFlags: u8 {
+        const CODE          = 0b00000001;
+        const DATA          = 0b00000010;
+        const WINDOW        = 0b00001100;
+        const INDIRECT_CODE = 0b00010000;
+        const INDIRECT_DATA = 0b00100000;
+        const PCM_AUDIO     = 0b01000000;
+    }
+}
+
+bitflags! {
+    /// # CHR byte flags of the FCEUX .cdl format
+    pub struct ChrFlags: u8 {
+        const RENDERED = 0b00000001;
+        const READ     = 0b00000010;
+    }
+}
+
+/// Code/data logger: records how every PRG and CHR byte was used.
+/// The file is the PRG flags followed by the CHR flags, one byte per ROM byte.
+pub struct CodeDataLogger {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLogger {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        CodeDataLogger {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    pub fn from_bytes(raw: &[u8], prg_size: usize, chr_size: usize) -> Result<Self, String> {
+        if raw.len() != prg_size + chr_size {
+            return Err(format!(
+                "CDL size {} does not match ROM size {}",
+                raw.len(),
+                prg_size + chr_size
+            ));
+        }
+        Ok(CodeDataLogger {
+            prg: raw[..prg_size].to_vec(),
+            chr: raw[prg_size..].to_vec(),
+        })
+    }
+
+    /// `offset` is the PRG-ROM offset and `addr` the CPU address it was accessed through
+    pub fn mark_prg(&mut self, offset: usize, addr: u16, flags: PrgFlags) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            let window = ((addr.wrapping_sub(0x8000) >> 13) as u8 & 0b11) << 2;
+            *byte |= flags.bits() | window;
+        }
+    }
+
+    pub fn mark_chr(&mut self, offset: usize, flags: ChrFlags) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags.bits();
+        }
+    }
+
+    pub fn prg_size(&self) -> usize {
+        self.prg.len()
+    }
+
+    pub fn chr_size(&self) -> usize {
+        self.chr.len()
+    }
+
+    pub fn prg_flags(&self, offset: usize) -> PrgFlags {
+        PrgFlags::from_bits_truncate(self.prg[offset])
+    }
+
+    pub fn chr_flags(&self, offset: usize) -> ChrFlags {
+        ChrFlags::from_bits_truncate(self.chr[offset])
+    }
+
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.prg_flags(offset).contains(PrgFlags::CODE)
+    }
+
+    pub fn is_data(&self, offset: usize) -> bool {
+        self.prg_flags(offset).contains(PrgFlags::DATA)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(self.prg.len() + self.chr.len());
+        raw.extend(&self.prg);
+        raw.extend(&self.chr);
+        raw
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mark_and_serialize() {
+        let mut cdl = CodeDataLogger::new(4, 2);
+        cdl.mark_prg(0, 0x8000, PrgFlags::CODE);
+        cdl.mark_prg(1, 0xe001, PrgFlags::DATA | PrgFlags::INDIRECT_DATA);
+        cdl.mark_prg(1, 0xe001, PrgFlags::DATA);
+        cdl.mark_chr(1, ChrFlags::RENDERED);
+        cdl.mark_prg(100, 0x8000, PrgFlags::CODE);
+
+        assert!(cdl.is_code(0));
+        assert!(!cdl.is_data(0));
+        assert!(cdl.is_data(1));
+        assert_eq!(cdl.to_bytes(), vec![0x01, 0x2e, 0x00, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let cdl = CodeDataLogger::from_bytes(&[0x01, 0x02, 0x01], 2, 1).unwrap();
+        assert!(cdl.is_code(0));
+        assert!(cdl.is_data(1));
+        assert_eq!(cdl.chr_flags(0), ChrFlags::RENDERED);
+        assert!(CodeDataLogger::from_bytes(&[0; 2], 2, 1).is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use crate::bus::Bus;
-use crate::cpu::mem::{AddressingMode, Mem};
+use crate::cdl::PrgFlags;
+use crate::cpu::mem::{AddressingMode, BusHooks, Mem};
 use crate::cpu::opcodes;
 use crate::cpu::opcodes::Instruction;
+use crate::diag::Category;
 use std::collections::HashMap;
+use std::fmt;
 
 bitflags! {
     /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
@@ -31,27 +34,58 @@ bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
+const IRQ_VECTOR: u16 = 0xfffe;
+
+/// Why `CPU::step` returned false
+#[derive(Debug, Clone, PartialEq)]
+pub enum Halt {
+    /// BRK at the address, while `set_brk_halts` is on
+    Brk(u16),
+    /// The opcode at the address locks up a 6502 until reset
+    Jam(u8, u16),
+    /// The program used hardware that is not emulated, see `BusHooks::unsupported`
+    Unsupported(String),
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Halt::Brk(addr) => write!(f, "CPU stopped on BRK at ${:04X}", addr),
+            Halt::Jam(code, addr) => write!(f, "CPU jammed by ${:02X} at ${:04X}", code, addr),
+            Halt::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
 
-pub struct CPU {
+/// 6502 core, `M` is everything on the other side of its address and data bus:
+/// the NES `Bus`, a `FlatMemory` or custom hardware
+pub struct CPU<M: Mem + BusHooks = Bus> {
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: CpuFlags,
-    bus: Bus,
+    pub cycles: usize,
+    bus: M,
+    brk_halts: bool,
+    halt: Option<Halt>,
 }
 
-impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
+impl<M: Mem + BusHooks> Mem for CPU<M> {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.bus.mem_peek(addr)
+    }
+
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.bus.mem_write(addr, data);
     }
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         self.bus.mem_read_u16(pos)
     }
 
@@ -60,8 +94,8 @@ impl Mem for CPU {
     }
 }
 
-impl CPU {
-    pub fn new(bus: Bus) -> Self {
+impl<M: Mem + BusHooks> CPU<M> {
+    pub fn new(bus: M) -> Self {
         CPU {
             program_counter: 0,
             stack_pointer: STACK_RESET,
@@ -69,7 +103,10 @@ impl CPU {
             register_x: 0,
             register_y: 0,
             status: CpuFlags::from_bits_truncate(0b100100),
+            cycles: 0,
             bus,
+            brk_halts: true,
+            halt: None,
         }
     }
 
@@ -92,108 +129,266 @@ impl CPU {
         self.register_y = 0;
         self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b100100);
+        // the reset sequence takes 7 cycles
+        self.cycles = 7;
+        self.halt = None;
 
         self.program_counter = self.mem_read_u16(0xfffc);
     }
 
+    /// Why the last `step` stopped the CPU
+    pub fn halt(&self) -> Option<&Halt> {
+        self.halt.as_ref()
+    }
+
+    pub fn bus(&self) -> &M {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut M {
+        &mut self.bus
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
 
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<M>),
     {
+        loop {
+            callback(self);
+            if !self.step() {
+                return;
+            }
+        }
+    }
+
+    /// Vector of the interrupt the next `step` takes before its instruction
+    pub fn pending_interrupt(&self) -> Option<u16> {
+        if self.bus.irq() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            Some(IRQ_VECTOR)
+        } else {
+            None
+        }
+    }
+
+    /// By default BRK stops `run`, which suits snippets and tests. Programs made
+    /// for the console need it to take the IRQ/BRK vector like on hardware.
+    pub fn set_brk_halts(&mut self, halts: bool) {
+        self.brk_halts = halts;
+    }
+
+    /// Executes a single instruction. Returns false when BRK is hit (see
+    /// `set_brk_halts`), when an opcode jams the CPU or when the instruction
+    /// used hardware the bus does not emulate; `halt` tells which.
+    pub fn step(&mut self) -> bool {
+        if let Some(vector) = self.pending_interrupt() {
+            self.interrupt(vector);
+        }
+
         let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPSCODES_MAP;
 
-        loop {
-            let code = self.mem_read(self.program_counter);
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
-
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
-
-            match opcode.mnemonic {
-                Instruction::LDA => self.lda(&opcode.mode),
-                Instruction::TAX => self.tax(),
-                Instruction::INX => self.inx(),
-                Instruction::BRK => return,
-                Instruction::CLD => self.status.remove(CpuFlags::DECIMAL_MODE),
-                Instruction::CLI => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
-                Instruction::CLV => self.status.remove(CpuFlags::OVERFLOW),
-                Instruction::CLC => self.clear_carry_flag(),
-                Instruction::SEC => self.set_carry_flag(),
-                Instruction::SEI => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
-                Instruction::SED => self.status.insert(CpuFlags::DECIMAL_MODE),
-                Instruction::PHA => self.stack_push(self.register_a),
-                Instruction::PLA => self.pla(),
-                Instruction::PHP => self.php(),
-                Instruction::PLP => self.plp(),
-                Instruction::ADC => self.adc(&opcode.mode),
-                Instruction::SBC => self.sbc(&opcode.mode),
-                Instruction::AND => self.and(&opcode.mode),
-                Instruction::EOR => self.eor(&opcode.mode),
-                Instruction::ORA => self.ora(&opcode.mode),
-                Instruction::LSR if opcode.mode == AddressingMode::Accumulator => {
-                    self.lsr_accumulator()
-                }
-                Instruction::LSR => self.lsr(&opcode.mode),
-                Instruction::ASL if opcode.mode == AddressingMode::Accumulator => {
-                    self.asl_accumulator()
+        let code = self.mem_read(self.program_counter);
+        let opcode = match opcodes.get(&code) {
+            Some(opcode) => opcode,
+            None => {
+                self.bus
+                    .report(Category::UnknownOpcode, self.program_counter, Some(code));
+                if is_jam(code) {
+                    self.halt = Some(Halt::Jam(code, self.program_counter));
+                    return false;
                 }
-                Instruction::ASL => self.asl(&opcode.mode),
-                Instruction::ROL if opcode.mode == AddressingMode::Accumulator => {
-                    self.rol_accumulator()
+                // undocumented NOPs skip their operand, other unknown opcodes one byte
+                let (len, mut cycles) = undocumented_nop(code);
+                if matches!(code, 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc) {
+                    let base = self.mem_peek_u16(self.program_counter.wrapping_add(1));
+                    if base & 0xff00 != base.wrapping_add(self.register_x as u16) & 0xff00 {
+                        cycles += 1;
+                    }
                 }
-                Instruction::ROL => self.rol(&opcode.mode),
-                Instruction::ROR if opcode.mode == AddressingMode::Accumulator => {
-                    self.ror_accumulator()
-                }
-                Instruction::ROR => self.ror(&opcode.mode),
-                Instruction::INC => self.inc(&opcode.mode),
-                Instruction::INY => self.iny(),
-                Instruction::DEC => self.dec(&opcode.mode),
-                Instruction::DEX => self.dex(),
-                Instruction::DEY => self.dey(),
-                Instruction::CMP => self.compare(&opcode.mode, self.register_a),
-                Instruction::CPY => self.compare(&opcode.mode, self.register_y),
-                Instruction::CPX => self.compare(&opcode.mode, self.register_x),
-                Instruction::JMP if opcode.mode == AddressingMode::Absolute => self.jmp_absolute(),
-                Instruction::JMP if opcode.mode == AddressingMode::Indirect => self.jmp_indirect(),
-                Instruction::JSR => self.jsr(),
-                Instruction::RTS => self.rts(),
-                Instruction::RTI => self.rti(),
-                Instruction::BNE => self.branch(!self.status.contains(CpuFlags::ZERO)),
-                Instruction::BVS => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
-                Instruction::BVC => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
-                Instruction::BPL => self.branch(!self.status.contains(CpuFlags::NEGATIV)),
-                Instruction::BMI => self.branch(self.status.contains(CpuFlags::NEGATIV)),
-                Instruction::BEQ => self.branch(self.status.contains(CpuFlags::ZERO)),
-                Instruction::BCS => self.branch(self.status.contains(CpuFlags::CARRY)),
-                Instruction::BCC => self.branch(!self.status.contains(CpuFlags::CARRY)),
-                Instruction::BIT => self.bit(&opcode.mode),
-                Instruction::STA => self.sta(&opcode.mode),
-                Instruction::STX => self.stx(&opcode.mode),
-                Instruction::STY => self.sty(&opcode.mode),
-                Instruction::LDX => self.ldx(&opcode.mode),
-                Instruction::LDY => self.ldy(&opcode.mode),
-                Instruction::NOP => { /* do nothing */ }
-                Instruction::TAY => self.tay(),
-                Instruction::TSX => self.tsx(),
-                Instruction::TXA => self.txa(),
-                Instruction::TXS => self.txs(),
-                Instruction::TYA => self.tya(),
-                _ => todo!("{}", &format!("OpCode {:x} is not implemented", code)),
+                self.program_counter = self.program_counter.wrapping_add(len);
+                self.cycles += cycles;
+                self.bus.tick(cycles);
+                return true;
             }
+        };
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+        let logging_code_data = self.bus.logs_code_data();
+        if logging_code_data {
+            self.log_code_data(opcode);
+        }
+
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
+
+        let cycles = self.cycles;
+        self.cycles += opcode.cycles as usize;
+        if self.page_crossed(opcode) {
+            self.cycles += 1;
+        }
+
+        match opcode.mnemonic {
+            Instruction::LDA => self.lda(&opcode.mode),
+            Instruction::TAX => self.tax(),
+            Instruction::INX => self.inx(),
+            Instruction::BRK if self.brk_halts => {
+                self.halt = Some(Halt::Brk(self.program_counter - 1));
+                return false;
             }
+            Instruction::BRK => self.brk(),
+            Instruction::CLD => self.status.remove(CpuFlags::DECIMAL_MODE),
+            Instruction::CLI => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+            Instruction::CLV => self.status.remove(CpuFlags::OVERFLOW),
+            Instruction::CLC => self.clear_carry_flag(),
+            Instruction::SEC => self.set_carry_flag(),
+            Instruction::SEI => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            Instruction::SED => self.status.insert(CpuFlags::DECIMAL_MODE),
+            Instruction::PHA => self.stack_push(self.register_a),
+            Instruction::PLA => self.pla(),
+            Instruction::PHP => self.php(),
+            Instruction::PLP => self.plp(),
+            Instruction::ADC => self.adc(&opcode.mode),
+            Instruction::SBC => self.sbc(&opcode.mode),
+            Instruction::AND => self.and(&opcode.mode),
+            Instruction::EOR => self.eor(&opcode.mode),
+            Instruction::ORA => self.ora(&opcode.mode),
+            Instruction::LSR if opcode.mode == AddressingMode::Accumulator => {
+                self.lsr_accumulator()
+            }
+            Instruction::LSR => self.lsr(&opcode.mode),
+            Instruction::ASL if opcode.mode == AddressingMode::Accumulator => {
+                self.asl_accumulator()
+            }
+            Instruction::ASL => self.asl(&opcode.mode),
+            Instruction::ROL if opcode.mode == AddressingMode::Accumulator => {
+                self.rol_accumulator()
+            }
+            Instruction::ROL => self.rol(&opcode.mode),
+            Instruction::ROR if opcode.mode == AddressingMode::Accumulator => {
+                self.ror_accumulator()
+            }
+            Instruction::ROR => self.ror(&opcode.mode),
+            Instruction::INC => self.inc(&opcode.mode),
+            Instruction::INY => self.iny(),
+            Instruction::DEC => self.dec(&opcode.mode),
+            Instruction::DEX => self.dex(),
+            Instruction::DEY => self.dey(),
+            Instruction::CMP => self.compare(&opcode.mode, self.register_a),
+            Instruction::CPY => self.compare(&opcode.mode, self.register_y),
+            Instruction::CPX => self.compare(&opcode.mode, self.register_x),
+            Instruction::JMP if opcode.mode == AddressingMode::Absolute => self.jmp_absolute(),
+            Instruction::JMP if opcode.mode == AddressingMode::Indirect => self.jmp_indirect(),
+            Instruction::JSR => self.jsr(),
+            Instruction::RTS => self.rts(),
+            Instruction::RTI => self.rti(),
+            Instruction::BNE => self.branch(!self.status.contains(CpuFlags::ZERO)),
+            Instruction::BVS => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
+            Instruction::BVC => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
+            Instruction::BPL => self.branch(!self.status.contains(CpuFlags::NEGATIV)),
+            Instruction::BMI => self.branch(self.status.contains(CpuFlags::NEGATIV)),
+            Instruction::BEQ => self.branch(self.status.contains(CpuFlags::ZERO)),
+            Instruction::BCS => self.branch(self.status.contains(CpuFlags::CARRY)),
+            Instruction::BCC => self.branch(!self.status.contains(CpuFlags::CARRY)),
+            Instruction::BIT => self.bit(&opcode.mode),
+            Instruction::STA => self.sta(&opcode.mode),
+            Instruction::STX => self.stx(&opcode.mode),
+            Instruction::STY => self.sty(&opcode.mode),
+            Instruction::LDX => self.ldx(&opcode.mode),
+            Instruction::LDY => self.ldy(&opcode.mode),
+            Instruction::NOP => { /* do nothing */ }
+            Instruction::TAY => self.tay(),
+            Instruction::TSX => self.tsx(),
+            Instruction::TXA => self.txa(),
+            Instruction::TXS => self.txs(),
+            Instruction::TYA => self.tya(),
+            _ => todo!("{}", &format!("OpCode {:x} is not implemented", code)),
+        }
 
-            callback(self);
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+
+        if logging_code_data && opcode.mode == AddressingMode::Indirect {
+            self.bus
+                .cdl_mark(self.program_counter, PrgFlags::INDIRECT_CODE);
+        }
+
+        self.bus.tick(self.cycles - cycles);
+        if let Some(reason) = self.bus.unsupported() {
+            self.halt = Some(Halt::Unsupported(reason));
+            return false;
+        }
+        true
+    }
+
+    // Marks the instruction at the program counter as code and its operand as data
+    fn log_code_data(&mut self, opcode: &opcodes::OpCode) {
+        let pc = self.program_counter;
+        for i in 0..opcode.len as u16 {
+            self.bus.cdl_mark(pc.wrapping_add(i), PrgFlags::CODE);
+        }
+
+        match opcode.mnemonic {
+            Instruction::STA | Instruction::STX | Instruction::STY | Instruction::JSR => return,
+            Instruction::JMP if opcode.mode == AddressingMode::Absolute => return,
+            _ => {}
         }
+
+        let flags = match opcode.mode {
+            AddressingMode::IndirectX | AddressingMode::IndirectY => {
+                PrgFlags::DATA | PrgFlags::INDIRECT_DATA
+            }
+            _ => PrgFlags::DATA,
+        };
+        match opcode.mode {
+            AddressingMode::Implicit
+            | AddressingMode::Accumulator
+            | AddressingMode::Immediate
+            | AddressingMode::Relative => {}
+            AddressingMode::Indirect => {
+                let ptr = self.mem_peek_u16(pc.wrapping_add(1));
+                // the high byte does not cross the page, see jmp_indirect
+                let ptr_hi = (ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF);
+                self.bus.cdl_mark(ptr, flags);
+                self.bus.cdl_mark(ptr_hi, flags);
+            }
+            _ => {
+                let addr = self.peek_absolute_address(&opcode.mode, pc.wrapping_add(1));
+                self.bus.cdl_mark(addr, flags);
+            }
+        }
+    }
+
+    // Indexed reads take an extra cycle when the index carries into the high byte
+    fn page_crossed(&self, opcode: &opcodes::OpCode) -> bool {
+        match opcode.mnemonic {
+            Instruction::LDA
+            | Instruction::LDX
+            | Instruction::LDY
+            | Instruction::ADC
+            | Instruction::SBC
+            | Instruction::AND
+            | Instruction::EOR
+            | Instruction::ORA
+            | Instruction::CMP => {}
+            _ => return false,
+        }
+        let base = match opcode.mode {
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                self.mem_peek_u16(self.program_counter)
+            }
+            AddressingMode::IndirectY => {
+                let ptr = self.mem_peek(self.program_counter);
+                let lo = self.mem_peek(ptr as u16);
+                let hi = self.mem_peek(ptr.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
+            }
+            _ => return false,
+        };
+        let addr = self.peek_absolute_address(&opcode.mode, self.program_counter);
+        base & 0xFF00 != addr & 0xFF00
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
@@ -373,6 +568,30 @@ impl CPU {
         self.stack_push(lo);
     }
 
+    // pushes PC and P, then jumps through the vector; takes 7 cycles
+    fn interrupt(&mut self, vector: u16) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status;
+        flags.remove(CpuFlags::BREAK);
+        flags.insert(CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+
+        self.cycles += 7;
+        self.bus.tick(7);
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
+    // like an IRQ with B set in the pushed flags, the byte after the opcode is skipped
+    fn brk(&mut self) {
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        let mut flags = self.status;
+        flags.insert(CpuFlags::BREAK | CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.program_counter = self.mem_read_u16(IRQ_VECTOR);
+    }
+
     fn stack_pop_u16(&mut self) -> u16 {
         let lo = self.stack_pop() as u16;
         let hi = self.stack_pop() as u16;
@@ -617,77 +836,120 @@ impl CPU {
                 .wrapping_add(1)
                 .wrapping_add(jump as u16);
 
+            // +1 if branch succeeds +2 if to a new page
+            self.cycles += 1;
+            if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
+                self.cycles += 1;
+            }
+
             self.program_counter = jump_addr;
         }
     }
 
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
             _ => self.get_absolute_address(mode, self.program_counter),
         }
     }
 
-    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
-        match mode {
-            AddressingMode::ZeroPage => self.mem_read(addr) as u16,
+    pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> u16 {
+        let (x, y) = (self.register_x, self.register_y);
+        resolve_address(mode, addr, x, y, |pos| self.mem_read(pos))
+    }
 
-            AddressingMode::Absolute => self.mem_read_u16(addr),
+    /// Same as `get_absolute_address` but without side effects on memory-mapped I/O
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+        let (x, y) = (self.register_x, self.register_y);
+        resolve_address(mode, addr, x, y, |pos| self.mem_peek(pos))
+    }
+}
 
-            AddressingMode::ZeroPageX => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
-            }
-            AddressingMode::ZeroPageY => {
-                let pos = self.mem_read(addr);
-                let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
-            }
+// $02, $12, ... $72, $92, $B2, $D2 and $F2 halt a 6502 until reset
+fn is_jam(code: u8) -> bool {
+    code & 0x0f == 0x02 && !matches!(code >> 4, 0x8 | 0xa | 0xc | 0xe)
+}
 
-            AddressingMode::AbsoluteX => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_x as u16);
-                addr
-            }
-            AddressingMode::AbsoluteY => {
-                let base = self.mem_read_u16(addr);
-                let addr = base.wrapping_add(self.register_y as u16);
-                addr
-            }
-            AddressingMode::IndirectX => {
-                let base = self.mem_read(addr);
+// length and cycles of the NOPs without a documented opcode, abs,X ones
+// take a cycle more when the address crosses a page
+fn undocumented_nop(code: u8) -> (u16, usize) {
+    match code {
+        0x04 | 0x44 | 0x64 => (2, 3),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 => (2, 4),
+        0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => (2, 2),
+        0x0c | 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => (3, 4),
+        _ => (1, 2),
+    }
+}
 
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
-            }
-            AddressingMode::IndirectY => {
-                let base = self.mem_read(addr);
+fn resolve_address<F>(mode: &AddressingMode, addr: u16, x: u8, y: u8, mut read: F) -> u16
+where
+    F: FnMut(u16) -> u8,
+{
+    match mode {
+        AddressingMode::ZeroPage => read(addr) as u16,
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
-            }
-            _ => panic!("mode {:?} is not supported", mode),
+        AddressingMode::Absolute => {
+            let lo = read(addr);
+            let hi = read(addr.wrapping_add(1));
+            (hi as u16) << 8 | (lo as u16)
+        }
+
+        AddressingMode::ZeroPageX => {
+            let pos = read(addr);
+            pos.wrapping_add(x) as u16
+        }
+        AddressingMode::ZeroPageY => {
+            let pos = read(addr);
+            pos.wrapping_add(y) as u16
+        }
+
+        AddressingMode::AbsoluteX => {
+            let base = resolve_address(&AddressingMode::Absolute, addr, x, y, read);
+            base.wrapping_add(x as u16)
+        }
+        AddressingMode::AbsoluteY => {
+            let base = resolve_address(&AddressingMode::Absolute, addr, x, y, read);
+            base.wrapping_add(y as u16)
+        }
+        AddressingMode::IndirectX => {
+            let base = read(addr);
+
+            let ptr: u8 = base.wrapping_add(x);
+            let lo = read(ptr as u16);
+            let hi = read(ptr.wrapping_add(1) as u16);
+            (hi as u16) << 8 | (lo as u16)
+        }
+        AddressingMode::IndirectY => {
+            let base = read(addr);
+
+            let lo = read(base as u16);
+            let hi = read(base.wrapping_add(1) as u16);
+            let deref_base = (hi as u16) << 8 | (lo as u16);
+            deref_base.wrapping_add(y as u16)
         }
+        _ => panic!("mode {:?} is not supported", mode),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cpu::mem::FlatMemory;
     use crate::rom::test;
     use pretty_assertions::{assert_eq, assert_ne};
 
+    // CPU right after reset with `program` at $8000
+    fn cpu(program: &[u8]) -> CPU<FlatMemory> {
+        let mut cpu = CPU::new(FlatMemory::with_program(0x8000, program));
+        cpu.reset();
+        cpu
+    }
+
     #[test]
     fn test_lda_immediate_load_data() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        let mut cpu = cpu(&[0xa9, 0x05, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_a, 0x05);
         assert_ne!(cpu.status.contains(CpuFlags::ZERO), true);
         assert_ne!(cpu.status.contains(CpuFlags::NEGATIV), true);
@@ -695,26 +957,23 @@ mod test {
 
     #[test]
     fn test_lda_from_memory() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
+        let mut cpu = cpu(&[0xa5, 0x10, 0x00]);
         cpu.mem_write(0x10, 0x55);
-        cpu.load_and_run(vec![0xa5, 0x10, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_a, 0x55);
     }
 
     #[test]
     fn test_lda_zero_flag() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
+        let mut cpu = cpu(&[0xa9, 0x00, 0x00]);
+        cpu.run();
         assert!(cpu.status.contains(CpuFlags::ZERO));
     }
 
     #[test]
     fn test_ldx_immediate_load_data() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa2, 0x05, 0x00]);
+        let mut cpu = cpu(&[0xa2, 0x05, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_x, 0x05);
         assert_ne!(cpu.status.contains(CpuFlags::ZERO), true);
         assert_ne!(cpu.status.contains(CpuFlags::NEGATIV), true);
@@ -722,26 +981,23 @@ mod test {
 
     #[test]
     fn test_ldx_from_memory() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
+        let mut cpu = cpu(&[0xa6, 0x10, 0x00]);
         cpu.mem_write(0x10, 0x55);
-        cpu.load_and_run(vec![0xa6, 0x10, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_x, 0x55);
     }
 
     #[test]
     fn test_ldx_zero_flag() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa2, 0x00, 0x00]);
+        let mut cpu = cpu(&[0xa2, 0x00, 0x00]);
+        cpu.run();
         assert!(cpu.status.contains(CpuFlags::ZERO));
     }
 
     #[test]
     fn test_ldy_immediate_load_data() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa0, 0x05, 0x00]);
+        let mut cpu = cpu(&[0xa0, 0x05, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_y, 0x05);
         assert_ne!(cpu.status.contains(CpuFlags::ZERO), true);
         assert_ne!(cpu.status.contains(CpuFlags::NEGATIV), true);
@@ -749,27 +1005,22 @@ mod test {
 
     #[test]
     fn test_ldy_from_memory() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
+        let mut cpu = cpu(&[0xa4, 0x10, 0x00]);
         cpu.mem_write(0x10, 0x55);
-        cpu.load_and_run(vec![0xa4, 0x10, 0x00]);
+        cpu.run();
         assert_eq!(cpu.register_y, 0x55);
     }
 
     #[test]
     fn test_ldy_zero_flag() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa0, 0x00, 0x00]);
+        let mut cpu = cpu(&[0xa0, 0x00, 0x00]);
+        cpu.run();
         assert!(cpu.status.contains(CpuFlags::ZERO));
     }
 
     #[test]
     fn test_inx_overflow() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load(vec![0xe8, 0xe8, 0x00]);
-        cpu.reset();
+        let mut cpu = cpu(&[0xe8, 0xe8, 0x00]);
         cpu.register_x = 0xff;
 
         cpu.run();
@@ -779,10 +1030,7 @@ mod test {
 
     #[test]
     fn test_iny_overflow() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load(vec![0xc8, 0xc8, 0x00]);
-        cpu.reset();
+        let mut cpu = cpu(&[0xc8, 0xc8, 0x00]);
         cpu.register_y = 0xff;
 
         cpu.run();
@@ -792,19 +1040,129 @@ mod test {
 
     #[test]
     fn test_lda_tax_inx_ops_working_together() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        let mut cpu = cpu(&[0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        cpu.run();
 
         assert_eq!(cpu.register_x, 0xc1)
     }
 
     #[test]
     fn test_lda_tay_iny_ops_working_together() {
-        let bus = Bus::new(test::test_rom());
-        let mut cpu = CPU::new(bus);
-        cpu.load_and_run(vec![0xa9, 0xc0, 0xa8, 0xc8, 0x00]);
+        let mut cpu = cpu(&[0xa9, 0xc0, 0xa8, 0xc8, 0x00]);
+        cpu.run();
 
         assert_eq!(cpu.register_y, 0xc1)
     }
+
+    #[test]
+    fn test_load_at_0600() {
+        let mut cpu = CPU::new(FlatMemory::new());
+        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        // $1A is one of the undocumented NOPs
+        let mut cpu = cpu(&[0x1a, 0xa9, 0x05, 0x00]);
+        cpu.run();
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 7);
+        assert_eq!(cpu.halt(), Some(&Halt::Brk(0x8003)));
+
+        // the ones with an operand skip it: zp, zp,X, imm, abs and abs,X with and without a page cross
+        let mut nops = self::cpu(&[
+            0x04, 0x10, 0x14, 0x10, 0x80, 0xff, 0x0c, 0x00, 0x02, 0xa2, 0xff, 0x1c, 0x01, 0x02,
+            0x1c, 0x00, 0x02, 0x00,
+        ]);
+        nops.run();
+        assert_eq!(nops.halt(), Some(&Halt::Brk(0x8011)));
+        assert_eq!(nops.cycles, 7 + 3 + 4 + 2 + 4 + 2 + 5 + 4 + 7);
+
+        // $02 jams a real 6502
+        let mut jammed = self::cpu(&[0xa9, 0x05, 0x02, 0xa9, 0x06, 0x00]);
+        jammed.run();
+        assert_eq!(jammed.register_a, 0x05);
+        assert_eq!(jammed.program_counter, 0x8002);
+        assert_eq!(
+            jammed.halt().map(|halt| halt.to_string()),
+            Some("CPU jammed by $02 at $8002".to_string())
+        );
+    }
+
+    #[test]
+    fn test_brk_interrupt() {
+        let mut mem = FlatMemory::with_program(0x8000, &[0x58, 0x00, 0xff, 0xa9, 0x05, 0x02]);
+        mem.write_program(0x9000, &[0xa2, 0x07, 0x40]);
+        mem.mem_write_u16(IRQ_VECTOR, 0x9000);
+        let mut cpu = CPU::new(mem);
+        cpu.set_brk_halts(false);
+        cpu.reset();
+        cpu.run();
+
+        // RTI comes back past the padding byte, the pushed flags have B set
+        assert_eq!((cpu.register_a, cpu.register_x), (0x05, 0x07));
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x8003);
+        assert_eq!(cpu.mem_read(0x01fb) & 0b0011_0000, 0b0011_0000);
+    }
+
+    #[test]
+    fn test_code_data_logging() {
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  LDX #0
+                    LDA table,X
+                    JMP (vector)
+            target: BRK
+            table:  .byte 7
+            vector: .word target
+                    .org $fffc
+                    .word reset, 0
+            ",
+        )
+        .unwrap();
+        let mut bus = Bus::new(test::test_rom_with_prg(program.code)).unwrap();
+        bus.enable_cdl();
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.run();
+
+        let cdl = cpu.bus().cdl().unwrap();
+        assert!((0..9).all(|offset| cdl.is_code(offset)));
+        assert_eq!(cdl.prg_flags(8), PrgFlags::CODE | PrgFlags::INDIRECT_CODE);
+        assert_eq!(cdl.prg_flags(9), PrgFlags::DATA);
+        assert_eq!(cdl.prg_flags(10), PrgFlags::DATA);
+        assert_eq!(cdl.prg_flags(11), PrgFlags::DATA);
+        assert!(!cdl.is_code(12));
+    }
+
+    #[test]
+    fn test_irq() {
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  LDA #0
+                    STA $4017
+                    CLI
+            loop:   JMP loop
+            irq:    BRK
+                    .org $fffc
+                    .word reset, irq
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(Bus::new(test::test_rom_with_prg(program.code)).unwrap());
+        cpu.reset();
+        cpu.run();
+
+        // the APU frame IRQ comes at the end of the 4 step sequence
+        assert!(cpu.cycles > 29828);
+        assert_eq!(cpu.program_counter, program.labels["irq"] + 1);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.mem_read(0x01fb) & 0b0011_0100, 0b0010_0000);
+        assert_eq!(cpu.mem_read_u16(0x01fc), program.labels["loop"]);
+    }
 }
@@ -1,3 +1,6 @@
+use crate::cdl::PrgFlags;
+use crate::diag::Category;
+
 #[derive(Debug, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
@@ -17,20 +20,120 @@ pub enum AddressingMode {
 }
 
 pub trait Mem {
-    fn mem_read(&self, pos: u16) -> u8;
+    fn mem_read(&mut self, pos: u16) -> u8;
+
+    /// Reads without side effects (no register latches, buffers or flags are touched).
+    /// Meant for tracers, debuggers and memory viewers.
+    fn mem_peek(&self, pos: u16) -> u8;
 
     fn mem_write(&mut self, pos: u16, data: u8);
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
+    fn mem_peek_u16(&self, pos: u16) -> u16 {
+        let lo = self.mem_peek(pos) as u16;
+        let hi = self.mem_peek(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
+    }
+}
+
+/// The rest of the hardware around the CPU: the clock, the IRQ line and the
+/// NES debugging aids. Every hook does nothing by default, plain memory needs none.
+pub trait BusHooks {
+    /// Runs the devices clocked by the CPU for the cycles its last instruction took
+    fn tick(&mut self, _cycles: usize) {}
+
+    /// The IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Whether the CPU should tell how it uses every byte through `cdl_mark`
+    fn logs_code_data(&self) -> bool {
+        false
+    }
+
+    fn cdl_mark(&mut self, _addr: u16, _flags: PrgFlags) {}
+
+    /// See `Diagnostics`
+    fn report(&mut self, _category: Category, _addr: u16, _data: Option<u8>) {}
+
+    /// Takes the error of an access to hardware that is not emulated, the CPU
+    /// stops after the instruction that made it
+    fn unsupported(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// 64KiB of RAM and nothing else, to run 6502 code without a console around it
+pub struct FlatMemory {
+    data: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory {
+            data: vec![0; 0x10000],
+        }
+    }
+
+    /// Memory with `program` at `addr` and the reset vector pointing to it
+    pub fn with_program(addr: u16, program: &[u8]) -> Self {
+        let mut mem = FlatMemory::new();
+        mem.write_program(addr, program);
+        mem.mem_write_u16(0xfffc, addr);
+        mem
+    }
+
+    pub fn write_program(&mut self, addr: u16, program: &[u8]) {
+        let start = addr as usize;
+        self.data[start..start + program.len()].copy_from_slice(program);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        FlatMemory::new()
+    }
+}
+
+impl Mem for FlatMemory {
+    fn mem_read(&mut self, pos: u16) -> u8 {
+        self.data[pos as usize]
+    }
+
+    fn mem_peek(&self, pos: u16) -> u8 {
+        self.data[pos as usize]
+    }
+
+    fn mem_write(&mut self, pos: u16, data: u8) {
+        self.data[pos as usize] = data;
+    }
+}
+
+impl BusHooks for FlatMemory {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_u16_wraps_around() {
+        let mut mem = FlatMemory::new();
+        mem.mem_write_u16(0xffff, 0x1234);
+        assert_eq!((mem.mem_peek(0xffff), mem.mem_peek(0x0000)), (0x34, 0x12));
+        assert_eq!(mem.mem_read_u16(0xffff), 0x1234);
+        assert_eq!(mem.mem_peek_u16(0xffff), 0x1234);
     }
 }
@@ -1,16 +1,41 @@
 pub mod cpu;
 pub mod mem;
 pub mod opcodes;
+pub mod tracer;
 
 use crate::cpu::cpu::CPU;
-use crate::cpu::mem::{AddressingMode, Mem};
+use crate::cpu::mem::{AddressingMode, BusHooks, Mem};
 use std::collections::HashMap;
 
-pub fn trace(cpu: &CPU) -> String {
+pub fn trace<M: Mem + BusHooks>(cpu: &CPU<M>) -> String {
+    let (hex_dump, asm) = disassemble(cpu);
+
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02x}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let asm_str = format!("{:04x}  {:8}  {}", cpu.program_counter, hex_str, asm)
+        .trim()
+        .to_string();
+
+    format!(
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
+        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
+    )
+    .to_ascii_uppercase()
+}
+
+/// Decodes the instruction at the program counter into its raw bytes and
+/// its assembly text with the effective address and value annotated.
+pub fn disassemble<M: Mem + BusHooks>(cpu: &CPU<M>) -> (Vec<u8>, String) {
     let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPSCODES_MAP;
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+    let code = cpu.mem_peek(cpu.program_counter);
+    let ops = match opscodes.get(&code) {
+        Some(ops) => ops,
+        None => return (vec![code], String::from("???")),
+    };
 
     let begin = cpu.program_counter;
     let mut hex_dump = vec![];
@@ -23,8 +48,8 @@ pub fn trace(cpu: &CPU) -> String {
         | AddressingMode::Relative
         | AddressingMode::Indirect => (0, 0),
         _ => {
-            let addr = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            let addr = cpu.peek_absolute_address(&ops.mode, begin + 1);
+            (addr, cpu.mem_peek(addr))
         }
     };
 
@@ -34,7 +59,7 @@ pub fn trace(cpu: &CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
+            let address: u8 = cpu.mem_peek(begin + 1);
             // let value = cpu.mem_read(address));
             hex_dump.push(address);
 
@@ -80,12 +105,12 @@ pub fn trace(cpu: &CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.mem_peek(begin + 1);
+            let address_hi = cpu.mem_peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = cpu.mem_peek_u16(begin + 1);
 
             match ops.mode {
                 AddressingMode::Implicit
@@ -95,11 +120,11 @@ pub fn trace(cpu: &CPU) -> String {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.mem_peek(address);
+                            let hi = cpu.mem_peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.mem_peek_u16(address)
                         };
 
                         // let jmp_addr = cpu.mem_read_u16(address);
@@ -126,31 +151,18 @@ pub fn trace(cpu: &CPU) -> String {
         _ => String::from(""),
     };
 
-    let hex_str = hex_dump
-        .iter()
-        .map(|z| format!("{:02x}", z))
-        .collect::<Vec<String>>()
-        .join(" ");
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-        .trim()
-        .to_string();
-
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
-    )
-    .to_ascii_uppercase()
+    let asm = format!("{} {}", ops.mnemonic, tmp).trim().to_string();
+    (hex_dump, asm)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::bus::Bus;
-    use crate::rom::test::test_rom;
+    use crate::cpu::mem::FlatMemory;
 
     #[test]
     fn test_format_trace() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = FlatMemory::new();
         bus.mem_write(100, 0xa2);
         bus.mem_write(101, 0x01);
         bus.mem_write(102, 0xca);
@@ -182,7 +194,7 @@ mod test {
 
     #[test]
     fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
+        let mut bus = FlatMemory::new();
         // ORA ($33), Y
         bus.mem_write(100, 0x11);
         bus.mem_write(101, 0x33);
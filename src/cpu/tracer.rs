@@ -0,0 +1,397 @@
+use crate::cpu::cpu::{CpuFlags, CPU};
+use crate::cpu::{disassemble, trace};
+use crate::region::Region;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+const DOTS_PER_SCANLINE: usize = 341;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    // same as nestest.log
+    Nestest,
+    // same columns as Mesen's trace logger
+    Mesen,
+    // one JSON object per line
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nestest" => Ok(TraceFormat::Nestest),
+            "mesen" => Ok(TraceFormat::Mesen),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("unknown trace format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Trigger {
+    Pc(u16),
+    Frame(usize),
+}
+
+impl Trigger {
+    fn fired(&self, cpu: &CPU) -> bool {
+        match *self {
+            Trigger::Pc(addr) => cpu.program_counter == addr,
+            Trigger::Frame(frame) => ppu_position(cpu.cycles, cpu.bus().region()).0 == frame,
+        }
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    /// `pc:C000` or `frame:10`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("malformed trigger '{}', expected pc:ADDR or frame:N", s);
+        let (kind, value) = s.split_once(':').ok_or_else(err)?;
+        match kind {
+            "pc" => Ok(Trigger::Pc(parse_hex(value).map_err(|_| err())?)),
+            "frame" => Ok(Trigger::Frame(value.parse().map_err(|_| err())?)),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Hex address with or without a leading `$`, e.g. `C000` or `$C000`
+pub fn parse_hex(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s.strip_prefix('$').unwrap_or(s), 16)
+        .map_err(|_| format!("malformed address '{}'", s))
+}
+
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    // only instructions inside one of the ranges are traced (all if empty)
+    pub ranges: Vec<RangeInclusive<u16>>,
+    // only instructions inside one of the PRG banks are traced (all if empty)
+    pub banks: Vec<usize>,
+    // tracing is enabled when `start` fires and disabled when `stop` fires
+    pub start: Option<Trigger>,
+    pub stop: Option<Trigger>,
+    pub line_limit: Option<usize>,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            format: TraceFormat::Nestest,
+            ranges: vec![],
+            banks: vec![],
+            start: None,
+            stop: None,
+            line_limit: None,
+        }
+    }
+}
+
+pub struct Tracer<W: Write> {
+    options: TraceOptions,
+    out: W,
+    active: bool,
+    lines: usize,
+}
+
+impl Tracer<BufWriter<File>> {
+    pub fn to_file(options: TraceOptions, path: &str) -> io::Result<Self> {
+        Ok(Tracer::new(options, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(options: TraceOptions, out: W) -> Self {
+        let active = options.start.is_none();
+        Tracer {
+            options,
+            out,
+            active,
+            lines: 0,
+        }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.out
+    }
+
+    /// Writes out what a buffered writer still holds, call it after the last `trace`
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// True once the line limit is reached
+    pub fn is_done(&self) -> bool {
+        matches!(self.options.line_limit, Some(limit) if self.lines >= limit)
+    }
+
+    /// Traces the instruction at the program counter, call it before every step
+    pub fn trace(&mut self, cpu: &CPU) -> io::Result<()> {
+        if self.is_done() {
+            return Ok(());
+        }
+
+        if !self.active && self.options.start.is_some_and(|t| t.fired(cpu)) {
+            self.active = true;
+        } else if self.active && self.options.stop.is_some_and(|t| t.fired(cpu)) {
+            self.active = false;
+        }
+
+        if !self.active || !self.is_selected(cpu) {
+            return Ok(());
+        }
+
+        let line = format_line(self.options.format, cpu);
+        writeln!(self.out, "{}", line)?;
+        self.lines += 1;
+
+        if self.is_done() {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    fn is_selected(&self, cpu: &CPU) -> bool {
+        let pc = cpu.program_counter;
+        let in_range =
+            self.options.ranges.is_empty() || self.options.ranges.iter().any(|r| r.contains(&pc));
+        let in_bank = self.options.banks.is_empty()
+            || cpu
+                .bus()
+                .prg_bank(pc)
+                .is_some_and(|bank| self.options.banks.contains(&bank));
+        in_range && in_bank
+    }
+}
+
+/// Frame, scanline and dot the PPU is at after the given number of CPU cycles
+pub fn ppu_position(cycles: usize, region: Region) -> (usize, usize, usize) {
+    let dots = cycles * region.cpu_divider() as usize / region.ppu_divider() as usize;
+    let scanlines = dots / DOTS_PER_SCANLINE;
+    let scanlines_per_frame = region.scanlines_per_frame();
+    (
+        scanlines / scanlines_per_frame,
+        scanlines % scanlines_per_frame,
+        dots % DOTS_PER_SCANLINE,
+    )
+}
+
+pub fn format_line(format: TraceFormat, cpu: &CPU) -> String {
+    let (frame, scanline, dot) = ppu_position(cpu.cycles, cpu.bus().region());
+    match format {
+        TraceFormat::Nestest => format!(
+            "{} PPU:{:3},{:3} CYC:{}",
+            trace(cpu),
+            scanline,
+            dot,
+            cpu.cycles
+        ),
+        TraceFormat::Mesen => {
+            let (bytes, asm) = disassemble(cpu);
+            let bytes = bytes
+                .iter()
+                .map(|b| format!("${:02X}", b))
+                .collect::<Vec<String>>()
+                .join(" ");
+            format!(
+                "{:04X}  {:12} {:30} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Fr:{} Cycle:{}",
+                cpu.program_counter,
+                bytes,
+                asm.to_ascii_uppercase(),
+                cpu.register_a,
+                cpu.register_x,
+                cpu.register_y,
+                cpu.stack_pointer,
+                flags_string(cpu.status),
+                scanline,
+                dot,
+                frame,
+                cpu.cycles
+            )
+        }
+        TraceFormat::Json => {
+            let (bytes, asm) = disassemble(cpu);
+            let bytes = bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<String>>()
+                .join(" ");
+            format!(
+                "{{\"frame\":{},\"cycle\":{},\"pc\":\"{:04X}\",\"bytes\":\"{}\",\"asm\":\"{}\",\"a\":\"{:02X}\",\"x\":\"{:02X}\",\"y\":\"{:02X}\",\"p\":\"{:02X}\",\"sp\":\"{:02X}\"}}",
+                frame,
+                cpu.cycles,
+                cpu.program_counter,
+                bytes,
+                json_escape(&asm.to_ascii_uppercase()),
+                cpu.register_a,
+                cpu.register_x,
+                cpu.register_y,
+                cpu.status.bits(),
+                cpu.stack_pointer
+            )
+        }
+    }
+}
+
+// NV-BDIZC, upper case when set
+fn flags_string(status: CpuFlags) -> String {
+    "NVUBDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if status.bits() & (0x80 >> i) != 0 {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::mem::Mem;
+    use crate::rom::test::test_rom;
+
+    fn run_traced(program: &str, options: TraceOptions) -> Vec<String> {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        let program = crate::asm::assemble(program).unwrap();
+        for (i, byte) in program.code.iter().enumerate() {
+            bus.mem_write(program.origin + i as u16, *byte);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = program.origin;
+        let mut tracer = Tracer::new(options, vec![]);
+        loop {
+            tracer.trace(&cpu).unwrap();
+            if tracer.is_done() || !cpu.step() {
+                break;
+            }
+        }
+        String::from_utf8(tracer.writer().clone())
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    const LOOP: &str = "
+              LDX #2
+        loop: DEX
+              BNE loop
+              BRK
+    ";
+
+    #[test]
+    fn test_nestest_format() {
+        let lines = run_traced(LOOP, TraceOptions::default());
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "0600  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0"
+        );
+        // taken branch costs an extra cycle
+        assert_eq!(
+            lines[3],
+            "0602  CA        DEX                             A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+    }
+
+    #[test]
+    fn test_mesen_and_json_formats() {
+        let options = TraceOptions {
+            format: TraceFormat::Mesen,
+            line_limit: Some(1),
+            ..TraceOptions::default()
+        };
+        assert_eq!(
+            run_traced(LOOP, options),
+            vec!["0600  $A2 $02      LDX #$02                       A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:0   Fr:0 Cycle:0"]
+        );
+
+        let options = TraceOptions {
+            format: TraceFormat::Json,
+            line_limit: Some(1),
+            ..TraceOptions::default()
+        };
+        assert_eq!(
+            run_traced(LOOP, options),
+            vec!["{\"frame\":0,\"cycle\":0,\"pc\":\"0600\",\"bytes\":\"A2 02\",\"asm\":\"LDX #$02\",\"a\":\"00\",\"x\":\"00\",\"y\":\"00\",\"p\":\"24\",\"sp\":\"FD\"}"]
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        let mut tracer = Tracer::new(TraceOptions::default(), BufWriter::new(vec![]));
+        tracer.trace(&cpu).unwrap();
+        assert!(tracer.writer().get_ref().is_empty());
+        tracer.flush().unwrap();
+        assert_eq!(
+            tracer
+                .writer()
+                .get_ref()
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_range_filter_and_triggers() {
+        let options = TraceOptions {
+            ranges: vec![0x0602..=0x0602],
+            ..TraceOptions::default()
+        };
+        let lines = run_traced(LOOP, options);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|l| l.starts_with("0602")));
+
+        let options = TraceOptions {
+            start: Some(Trigger::Pc(0x0603)),
+            stop: Some(Trigger::Pc(0x0602)),
+            ..TraceOptions::default()
+        };
+        let lines = run_traced(LOOP, options);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("0603"));
+        assert!(lines[1].starts_with("0603"));
+        assert!(lines[2].starts_with("0605"));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!("pc:C000".parse(), Ok(Trigger::Pc(0xc000)));
+        assert_eq!("pc:$c000".parse(), Ok(Trigger::Pc(0xc000)));
+        assert_eq!("frame:3".parse(), Ok(Trigger::Frame(3)));
+        assert!("pc".parse::<Trigger>().is_err());
+        assert_eq!("Mesen".parse(), Ok(TraceFormat::Mesen));
+    }
+
+    #[test]
+    fn test_ppu_position() {
+        assert_eq!(ppu_position(114, Region::Ntsc), (0, 1, 1));
+        assert_eq!(ppu_position(29781, Region::Ntsc), (1, 0, 1));
+        // 3.2 dots per cycle and 312 scanlines
+        assert_eq!(ppu_position(107, Region::Pal), (0, 1, 1));
+        assert_eq!(ppu_position(33248, Region::Pal), (1, 0, 1));
+        assert_eq!(ppu_position(29781, Region::Dendy), (0, 262, 1));
+    }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+
+// a second of NTSC CPU time
+const DEFAULT_PERIOD: usize = 1_789_773;
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Read or write of an address no device answers
+    UnmappedAccess,
+    /// Write to cartridge ROM that is not a mapper register
+    RomWrite,
+    /// Opcode the CPU does not know, it is skipped as a NOP unless it jams the CPU
+    UnknownOpcode,
+}
+
+const CATEGORIES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub category: Category,
+    pub addr: u16,
+    /// The byte written or the opcode, None for reads
+    pub data: Option<u8>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.category, self.data) {
+            (Category::UnmappedAccess, None) => write!(f, "unmapped read at ${:04X}", self.addr),
+            (Category::UnmappedAccess, Some(data)) => {
+                write!(f, "unmapped write of ${:02X} at ${:04X}", data, self.addr)
+            }
+            (Category::RomWrite, data) => write!(
+                f,
+                "write of ${:02X} to ROM at ${:04X}",
+                data.unwrap_or(0),
+                self.addr
+            ),
+            (Category::UnknownOpcode, data) => write!(
+                f,
+                "unknown opcode ${:02X} at ${:04X}",
+                data.unwrap_or(0),
+                self.addr
+            ),
+        }
+    }
+}
+
+/// Forwards diagnostics to a sink, e.g. a closure that prints them or hands
+/// them to the `log` crate. Programs that hit a bad address tend to do it in a
+/// loop, so only `limit` of each category get through per `period` CPU cycles.
+pub struct Diagnostics {
+    sink: Box<dyn FnMut(&Diagnostic)>,
+    limit: usize,
+    period: usize,
+    cycles_left: usize,
+    reported: [usize; CATEGORIES],
+    suppressed: [usize; CATEGORIES],
+}
+
+impl Diagnostics {
+    pub fn new<F>(sink: F) -> Self
+    where
+        F: FnMut(&Diagnostic) + 'static,
+    {
+        Diagnostics {
+            sink: Box::new(sink),
+            limit: DEFAULT_LIMIT,
+            period: DEFAULT_PERIOD,
+            cycles_left: DEFAULT_PERIOD,
+            reported: [0; CATEGORIES],
+            suppressed: [0; CATEGORIES],
+        }
+    }
+
+    /// Lets `limit` diagnostics of each category through every `period` CPU cycles
+    pub fn with_limit(mut self, limit: usize, period: usize) -> Self {
+        self.limit = limit;
+        self.period = period;
+        self.cycles_left = period;
+        self
+    }
+
+    pub fn report(&mut self, category: Category, addr: u16, data: Option<u8>) {
+        let index = category as usize;
+        if self.reported[index] >= self.limit {
+            self.suppressed[index] += 1;
+            return;
+        }
+        self.reported[index] += 1;
+        (self.sink)(&Diagnostic {
+            category,
+            addr,
+            data,
+        });
+    }
+
+    /// Diagnostics of a category that were dropped by the rate limit so far
+    pub fn suppressed(&self, category: Category) -> usize {
+        self.suppressed[category as usize]
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if cycles < self.cycles_left {
+            self.cycles_left -= cycles;
+        } else {
+            self.cycles_left = self.period;
+            self.reported = [0; CATEGORIES];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_rate_limit() {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let sink = seen.clone();
+        let mut diagnostics = Diagnostics::new(move |d: &Diagnostic| {
+            sink.borrow_mut().push(d.to_string());
+        })
+        .with_limit(2, 100);
+
+        for addr in 0..3 {
+            diagnostics.report(Category::UnmappedAccess, 0x5000 + addr, None);
+        }
+        diagnostics.report(Category::RomWrite, 0x8000, Some(0x12));
+        assert_eq!(
+            *seen.borrow(),
+            [
+                "unmapped read at $5000",
+                "unmapped read at $5001",
+                "write of $12 to ROM at $8000"
+            ]
+        );
+        assert_eq!(diagnostics.suppressed(Category::UnmappedAccess), 1);
+
+        diagnostics.tick(99);
+        diagnostics.report(Category::UnmappedAccess, 0x4018, Some(0xFF));
+        assert_eq!(seen.borrow().len(), 3);
+        diagnostics.tick(1);
+        diagnostics.report(Category::UnmappedAccess, 0x4018, Some(0xFF));
+        assert_eq!(
+            seen.borrow().last().unwrap(),
+            "unmapped write of $FF at $4018"
+        );
+    }
+}
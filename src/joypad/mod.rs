@@ -0,0 +1,102 @@
+bitflags! {
+    /// # Standard controller buttons, in the order they are shifted out
+    pub struct JoypadButton: u8 {
+        const RIGHT    = 0b10000000;
+        const LEFT     = 0b01000000;
+        const DOWN     = 0b00100000;
+        const UP       = 0b00010000;
+        const START    = 0b00001000;
+        const SELECT   = 0b00000100;
+        const BUTTON_B = 0b00000010;
+        const BUTTON_A = 0b00000001;
+    }
+}
+
+/// Standard controller: a shift register that is reloaded while strobe is high
+/// and shifts one button out per read of $4016/$4017
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    button_status: JoypadButton,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::empty(),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let response = self.peek();
+        if !self.strobe && self.button_index <= 7 {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    /// The bit the next read returns, without shifting
+    pub fn peek(&self) -> u8 {
+        // an official controller returns 1s after all buttons are read
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits() >> self.button_index) & 1
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_mode() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        for _ in 0..10 {
+            assert_eq!(joypad.read(), 1);
+        }
+    }
+
+    #[test]
+    fn test_shift_out_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        joypad.set_button_pressed_status(JoypadButton::SELECT, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        joypad.write(1);
+        joypad.write(0);
+
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, vec![0, 1, 1, 0, 0, 0, 1, 1, 1, 1]);
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+    }
+}
@@ -1,6 +1,21 @@
+pub mod apu;
+pub mod asm;
 pub mod bus;
+pub mod cdl;
 pub mod cpu;
+pub mod diag;
+pub mod hash;
+pub mod joypad;
+pub mod mapper;
+pub mod nsf;
+pub mod pacing;
+pub mod patch;
+pub mod profiler;
+pub mod region;
+pub mod render;
 pub mod rom;
+pub mod savestate;
+pub mod testrunner;
 
 #[macro_use]
 extern crate bitflags;
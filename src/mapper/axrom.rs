@@ -0,0 +1,121 @@
+use super::{bank_offset, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 7: 32KiB PRG banks, CHR-RAM and one-screen mirroring, both selected
+/// by writes to $8000-$FFFF
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_bank: u8,
+    upper_screen: bool,
+}
+
+impl Axrom {
+    pub fn new(rom: Rom) -> Self {
+        Axrom {
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            prg_bank: 0,
+            upper_screen: false,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        Some(self.prg_rom[self.prg_rom_offset(addr)?])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => {
+                self.prg_bank = data & 0x07;
+                self.upper_screen = data & 0x10 != 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => Some(bank_offset(
+                self.prg_rom.len(),
+                self.prg_bank as usize,
+                0x8000,
+                addr,
+            )),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.upper_screen {
+            Mirroring::SINGLE_SCREEN_UPPER
+        } else {
+            Mirroring::SINGLE_SCREEN_LOWER
+        }
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.chr.read(0, 0x2000, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        self.chr.rom_offset(0, 0x2000, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, 0x2000, addr, data);
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.chr.save_state(out);
+        out.extend([self.prg_bank, self.upper_screen as u8]);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.chr.state_len() + 2)?;
+        let raw = self.chr.load_state(raw);
+        self.prg_bank = raw[0];
+        self.upper_screen = raw[1] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut axrom = Axrom::new(banked_rom(7, 256, 0));
+        assert_eq!(axrom.cpu_peek(0x8000), Some(0));
+        assert!(axrom.cpu_write(0xFFFF, 0x13));
+        assert_eq!(axrom.cpu_peek(0x8000), Some(12));
+        assert_eq!(axrom.cpu_peek(0xE000), Some(15));
+        assert_eq!(axrom.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+        assert_eq!(axrom.cpu_peek(0x6000), None);
+
+        axrom.chr_write(0x1234, 0x56);
+        assert_eq!(axrom.chr_read(0x1234, PpuFetch::Data), 0x56);
+        let mut state = vec![];
+        axrom.save_state(&mut state);
+        let mut other = Axrom::new(banked_rom(7, 256, 0));
+        other.load_state(&state).unwrap();
+        assert_eq!(other.chr_read(0x1234, PpuFetch::Data), 0x56);
+        assert_eq!(other.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+    }
+}
@@ -0,0 +1,150 @@
+use super::{bank_offset, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+// NINA-001 registers, in the PRG-RAM
+const PRG_BANK: u16 = 0x7FFD;
+const CHR_BANK0: u16 = 0x7FFE;
+const CHR_BANK1: u16 = 0x7FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 34, two boards: BNROM with CHR-RAM and 32KiB PRG banks selected by
+/// writes to $8000-$FFFF, and NINA-001 with CHR-ROM, 8KiB of PRG-RAM and its
+/// PRG and two 4KiB CHR bank registers at $7FFD-$7FFF
+pub struct Bnrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    nina: bool,
+    prg_ram: [u8; 0x2000],
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+}
+
+impl Bnrom {
+    pub fn new(rom: Rom) -> Self {
+        Bnrom {
+            nina: rom.chr_rom.len() > 0x2000,
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            mirroring: rom.screen_mirroring,
+            prg_ram: [0; 0x2000],
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+}
+
+impl Mapper for Bnrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.nina => Some(self.prg_ram[(addr - PRG_RAM) as usize]),
+            _ => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.nina => {
+                match addr {
+                    PRG_BANK => self.prg_bank = data & 0x01,
+                    CHR_BANK0 => self.chr_banks[0] = data & 0x0F,
+                    CHR_BANK1 => self.chr_banks[1] = data & 0x0F,
+                    _ => {}
+                }
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+            }
+            PRG_ROM..=PRG_ROM_END if !self.nina => self.prg_bank = data,
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => Some(bank_offset(
+                self.prg_rom.len(),
+                self.prg_bank as usize,
+                0x8000,
+                addr,
+            )),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        let bank = self.chr_banks[(addr as usize >> 12) & 1];
+        self.chr.read(bank as usize, 0x1000, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        let bank = self.chr_banks[(addr as usize >> 12) & 1];
+        self.chr.rom_offset(bank as usize, 0x1000, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize >> 12) & 1];
+        self.chr.write(bank as usize, 0x1000, addr, data);
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        self.chr.save_state(out);
+        out.extend([self.prg_bank, self.chr_banks[0], self.chr_banks[1]]);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.prg_ram.len() + self.chr.state_len() + 3)?;
+        let (ram, raw) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        let raw = self.chr.load_state(raw);
+        self.prg_bank = raw[0];
+        self.chr_banks = [raw[1], raw[2]];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_bnrom() {
+        let mut bnrom = Bnrom::new(banked_rom(34, 128, 0));
+        assert!(bnrom.cpu_write(0x8000, 3));
+        assert_eq!(bnrom.cpu_peek(0x8000), Some(12));
+        assert_eq!(bnrom.cpu_peek(0x6000), None);
+        assert!(!bnrom.cpu_write(0x7FFD, 0));
+        bnrom.chr_write(0x1000, 9);
+        assert_eq!(bnrom.chr_read(0x1000, PpuFetch::Background), 9);
+    }
+
+    #[test]
+    fn test_nina() {
+        let mut nina = Bnrom::new(banked_rom(34, 64, 64));
+        assert!(nina.cpu_write(0x7FFD, 1));
+        assert!(nina.cpu_write(0x7FFE, 2));
+        assert!(nina.cpu_write(0x7FFF, 5));
+        assert_eq!(nina.cpu_peek(0x8000), Some(4));
+        assert_eq!(nina.chr_read(0x0400, PpuFetch::Background), 9);
+        assert_eq!(nina.chr_read(0x1000, PpuFetch::Sprite), 20);
+        assert_eq!(nina.cpu_peek(0x7FFE), Some(2));
+        // ROM writes are not registers on this board
+        assert!(!nina.cpu_write(0x8000, 0));
+    }
+}
@@ -0,0 +1,111 @@
+use super::{bank_offset, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 11: a 32KiB PRG bank in bits 0-1 and an 8KiB CHR bank in bits 4-7
+/// of writes to $8000-$FFFF
+pub struct ColorDreams {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl ColorDreams {
+    pub fn new(rom: Rom) -> Self {
+        ColorDreams {
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        Some(self.prg_rom[self.prg_rom_offset(addr)?])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => {
+                self.prg_bank = data & 0x03;
+                self.chr_bank = data >> 4;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => Some(bank_offset(
+                self.prg_rom.len(),
+                self.prg_bank as usize,
+                0x8000,
+                addr,
+            )),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.chr.read(self.chr_bank as usize, 0x2000, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        self.chr.rom_offset(self.chr_bank as usize, 0x2000, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank as usize, 0x2000, addr, data);
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.chr.save_state(out);
+        out.extend([self.prg_bank, self.chr_bank]);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.chr.state_len() + 2)?;
+        let raw = self.chr.load_state(raw);
+        self.prg_bank = raw[0];
+        self.chr_bank = raw[1];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut mapper = ColorDreams::new(banked_rom(11, 128, 128));
+        assert!(mapper.cpu_write(0xC000, 0x52));
+        assert_eq!(mapper.cpu_peek(0x8000), Some(8));
+        assert_eq!(mapper.chr_read(0x0400, PpuFetch::Background), 41);
+        // all 4 bits of the CHR bank
+        mapper.cpu_write(0x8000, 0xF0);
+        assert_eq!(mapper.chr_read(0x0000, PpuFetch::Background), 120);
+        assert_eq!(mapper.cpu_peek(0xE000), Some(3));
+    }
+}
@@ -0,0 +1,537 @@
+use super::{check_state_size, Mapper};
+use crate::apu::fds::{self as audio, FdsAudio};
+use crate::apu::ExpansionAudio;
+use crate::rom::fds::{crc16, FdsImage};
+use crate::rom::Mirroring;
+
+const IRQ_RELOAD_LOW: u16 = 0x4020;
+const IRQ_RELOAD_HIGH: u16 = 0x4021;
+const IRQ_CONTROL: u16 = 0x4022;
+const MASTER_IO: u16 = 0x4023;
+const WRITE_DATA: u16 = 0x4024;
+const CONTROL: u16 = 0x4025;
+const EXTERNAL_WRITE: u16 = 0x4026;
+const DISK_STATUS: u16 = 0x4030;
+const READ_DATA: u16 = 0x4031;
+const DRIVE_STATUS: u16 = 0x4032;
+const EXTERNAL_READ: u16 = 0x4033;
+const RAM: u16 = 0x6000;
+const RAM_END: u16 = 0xDFFF;
+const BIOS: u16 = 0xE000;
+const BIOS_END: u16 = 0xFFFF;
+pub const BIOS_SIZE: usize = 0x2000;
+
+// the drive moves a byte about every 150 CPU cycles and takes a while to
+// get the head back to the start of the disk
+const BYTE_CYCLES: u32 = 150;
+const REWIND_CYCLES: u32 = 50000;
+
+/// The Famicom Disk System RAM adapter: 32KiB of PRG-RAM, the BIOS, the disk
+/// drive interface with its timer IRQ and the wavetable sound channel
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    // sides with gaps and CRCs, see `FdsImage::raw_side`
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    audio: FdsAudio,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+    disk_io: bool,
+    sound_io: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    mirroring: Mirroring,
+    crc_control: bool,
+    disk_ready: bool,
+    transfer_irq_enabled: bool,
+    // a byte was read or is needed for writing
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    crc: u16,
+    previous_crc_control: bool,
+}
+
+impl Fds {
+    /// `bios` is the 8KiB disksys.rom, the disk starts in the drive with side A up
+    pub fn new(image: &FdsImage, bios: Vec<u8>) -> Result<Fds, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "FDS BIOS has {} bytes, expected {}",
+                bios.len(),
+                BIOS_SIZE
+            ));
+        }
+        Ok(Fds {
+            bios,
+            ram: vec![0; (RAM_END - RAM) as usize + 1],
+            sides: (0..image.sides.len()).map(|n| image.raw_side(n)).collect(),
+            side: Some(0),
+            audio: FdsAudio::new(),
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            disk_io: false,
+            sound_io: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            mirroring: Mirroring::VERTICAL,
+            crc_control: false,
+            disk_ready: false,
+            transfer_irq_enabled: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            crc: 0,
+            previous_crc_control: false,
+        })
+    }
+
+    pub fn sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    /// Side in the drive, None when it is empty
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    /// Games ask for the next side and wait for the disk to be ejected
+    /// before it is inserted again
+    pub fn insert(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.sides.len() {
+            return Err(format!(
+                "side {} is out of range, the disk has {}",
+                side + 1,
+                self.sides.len()
+            ));
+        }
+        self.side = Some(side);
+        Ok(())
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            self.irq_enabled = self.irq_repeat;
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    // moves the head over the next byte when it is time to
+    fn clock_drive(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let disk = &mut self.sides[side];
+        if self.read_mode {
+            let data = disk.get(self.position).copied().unwrap_or(0);
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark of a block is not handed to the CPU
+                self.gap_ended = true;
+            } else if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                if self.disk_ready {
+                    data = self.write_data;
+                }
+                self.crc = if self.disk_ready {
+                    crc16(self.crc, data)
+                } else {
+                    0
+                };
+            } else {
+                if !self.previous_crc_control {
+                    self.crc = crc16(crc16(self.crc, 0), 0);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            if let Some(byte) = disk.get_mut(self.position) {
+                *byte = data;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= disk.len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            DISK_STATUS => Some(self.timer_irq as u8 | (self.transfer_complete as u8) << 1),
+            READ_DATA => Some(self.read_data),
+            DRIVE_STATUS => {
+                let empty = self.side.is_none();
+                Some(empty as u8 | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2)
+            }
+            // the battery is fine
+            EXTERNAL_READ => Some(0x80),
+            audio::WAVE_RAM..=audio::REGISTERS_END => self.audio.peek(addr),
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            DISK_STATUS..=audio::REGISTERS_END => self.peek_register(addr),
+            RAM..=RAM_END => Some(self.ram[(addr - RAM) as usize]),
+            BIOS..=BIOS_END => Some(self.bios[(addr - BIOS) as usize]),
+            _ => None,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.cpu_peek(addr);
+        match addr {
+            DISK_STATUS => {
+                self.timer_irq = false;
+                self.transfer_complete = false;
+            }
+            READ_DATA => self.transfer_complete = false,
+            _ => {}
+        }
+        data
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            IRQ_RELOAD_LOW => self.irq_reload = (self.irq_reload & 0xFF00) | data as u16,
+            IRQ_RELOAD_HIGH => self.irq_reload = (self.irq_reload & 0x00FF) | (data as u16) << 8,
+            IRQ_CONTROL => {
+                self.irq_repeat = data & 0x01 != 0;
+                self.irq_enabled = data & 0x02 != 0 && self.disk_io;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            MASTER_IO => {
+                self.disk_io = data & 0x01 != 0;
+                self.sound_io = data & 0x02 != 0;
+                if !self.disk_io {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.transfer_complete = false;
+                }
+            }
+            WRITE_DATA if self.disk_io => {
+                self.write_data = data;
+                self.transfer_complete = false;
+            }
+            CONTROL if self.disk_io => {
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.mirroring = if data & 0x08 != 0 {
+                    Mirroring::HORIZONTAL
+                } else {
+                    Mirroring::VERTICAL
+                };
+                self.crc_control = data & 0x10 != 0;
+                self.disk_ready = data & 0x40 != 0;
+                self.transfer_irq_enabled = data & 0x80 != 0;
+            }
+            // the expansion port is not connected
+            WRITE_DATA | CONTROL | EXTERNAL_WRITE => {}
+            audio::WAVE_RAM..=audio::REGISTERS_END => {
+                if self.sound_io {
+                    self.audio.write(addr, data);
+                }
+            }
+            RAM..=RAM_END => self.ram[(addr - RAM) as usize] = data,
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        0
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || (self.transfer_irq_enabled && self.transfer_complete)
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut Fds> {
+        Some(self)
+    }
+
+    // the disk contents are saved too, games write to them
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.ram);
+        for side in &self.sides {
+            out.extend(side);
+        }
+        out.push(self.side.map_or(0xFF, |side| side as u8));
+        out.extend(self.irq_reload.to_le_bytes());
+        out.extend(self.irq_counter.to_le_bytes());
+        out.extend((self.position as u32).to_le_bytes());
+        out.extend(self.delay.to_le_bytes());
+        out.extend(self.crc.to_le_bytes());
+        out.extend([self.read_data, self.write_data]);
+        out.extend(
+            [
+                self.irq_repeat,
+                self.irq_enabled,
+                self.timer_irq,
+                self.disk_io,
+                self.sound_io,
+                self.motor_on,
+                self.reset_transfer,
+                self.read_mode,
+                self.mirroring == Mirroring::HORIZONTAL,
+                self.crc_control,
+                self.disk_ready,
+                self.transfer_irq_enabled,
+                self.transfer_complete,
+                self.end_of_head,
+                self.scanning,
+                self.gap_ended,
+                self.previous_crc_control,
+            ]
+            .map(u8::from),
+        );
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        let disk_size: usize = self.sides.iter().map(|side| side.len()).sum();
+        check_state_size(
+            raw,
+            self.ram.len() + disk_size + 1 + 2 + 2 + 4 + 4 + 2 + 2 + 17,
+        )?;
+        let (ram, mut raw) = raw.split_at(self.ram.len());
+        self.ram.copy_from_slice(ram);
+        for side in self.sides.iter_mut() {
+            let (data, rest) = raw.split_at(side.len());
+            side.copy_from_slice(data);
+            raw = rest;
+        }
+        let u16_at = |at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+        let u32_at =
+            |at: usize| u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+        self.side = Some(raw[0] as usize).filter(|side| *side < self.sides.len());
+        self.irq_reload = u16_at(1);
+        self.irq_counter = u16_at(3);
+        self.position = u32_at(5) as usize;
+        self.delay = u32_at(9);
+        self.crc = u16_at(13);
+        self.read_data = raw[15];
+        self.write_data = raw[16];
+        let flag = |n: usize| raw[17 + n] != 0;
+        self.irq_repeat = flag(0);
+        self.irq_enabled = flag(1);
+        self.timer_irq = flag(2);
+        self.disk_io = flag(3);
+        self.sound_io = flag(4);
+        self.motor_on = flag(5);
+        self.reset_transfer = flag(6);
+        self.read_mode = flag(7);
+        self.mirroring = if flag(8) {
+            Mirroring::HORIZONTAL
+        } else {
+            Mirroring::VERTICAL
+        };
+        self.crc_control = flag(9);
+        self.disk_ready = flag(10);
+        self.transfer_irq_enabled = flag(11);
+        self.transfer_complete = flag(12);
+        self.end_of_head = flag(13);
+        self.scanning = flag(14);
+        self.gap_ended = flag(15);
+        self.previous_crc_control = flag(16);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::fds::test::test_image;
+
+    fn test_fds() -> Fds {
+        let image = FdsImage::parse(&test_image(b"data")).unwrap();
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[BIOS_SIZE - 4] = 0x24;
+        let mut fds = Fds::new(&image, bios).unwrap();
+        fds.cpu_write(MASTER_IO, 0x03);
+        fds
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut fds = test_fds();
+        assert_eq!(fds.cpu_peek(0xFFFC), Some(0x24));
+        assert!(!fds.cpu_write(0xE000, 1));
+        assert!(fds.cpu_write(0xDFFF, 0x42));
+        assert_eq!(fds.cpu_read(0xDFFF), Some(0x42));
+        assert_eq!(fds.cpu_peek(IRQ_RELOAD_LOW), None);
+        assert!(Fds::new(&FdsImage::parse(&test_image(b"")).unwrap(), vec![]).is_err());
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = test_fds();
+        fds.cpu_write(IRQ_RELOAD_LOW, 10);
+        fds.cpu_write(IRQ_RELOAD_HIGH, 0);
+        fds.cpu_write(IRQ_CONTROL, 0x03);
+        for _ in 0..10 {
+            fds.clock();
+        }
+        assert!(!fds.irq());
+        fds.clock();
+        assert!(fds.irq());
+        assert_eq!(fds.cpu_read(DISK_STATUS).unwrap() & 1, 1);
+        assert!(!fds.irq());
+        // repeats
+        for _ in 0..11 {
+            fds.clock();
+        }
+        assert!(fds.irq());
+        fds.cpu_write(MASTER_IO, 0x00);
+        assert!(!fds.irq());
+    }
+
+    // the bytes of the disk the CPU gets from the start of the first block
+    fn read_bytes(fds: &mut Fds, count: usize) -> Vec<u8> {
+        // motor on, read mode, transfer IRQ
+        fds.cpu_write(CONTROL, 0x85);
+        let mut bytes = vec![];
+        let mut cycles = 0;
+        while bytes.len() < count {
+            if !fds.scanning && cycles > 2 * REWIND_CYCLES {
+                panic!("the drive does not spin");
+            }
+            // ready once the head is in the gap before the block
+            if fds.scanning && fds.cpu_peek(DRIVE_STATUS).unwrap() & 2 == 0 {
+                fds.cpu_write(CONTROL, 0xC5);
+            }
+            fds.clock();
+            cycles += 1;
+            if fds.irq() {
+                bytes.push(fds.cpu_read(READ_DATA).unwrap());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_read_disk() {
+        let mut fds = test_fds();
+        assert_eq!(fds.cpu_peek(DRIVE_STATUS), Some(0x02));
+        assert_eq!(&read_bytes(&mut fds, 15), b"\x01*NINTENDO-HVC*");
+    }
+
+    #[test]
+    fn test_disk_sides() {
+        let mut fds = test_fds();
+        assert_eq!((fds.sides(), fds.side()), (1, Some(0)));
+        fds.eject();
+        assert_eq!(fds.side(), None);
+        assert_eq!(fds.cpu_peek(DRIVE_STATUS), Some(0x07));
+        assert!(fds.insert(1).is_err());
+        fds.insert(0).unwrap();
+        assert_eq!(fds.cpu_peek(DRIVE_STATUS), Some(0x02));
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut fds = test_fds();
+        fds.cpu_write(0x6000, 0x11);
+        fds.cpu_write(IRQ_RELOAD_LOW, 0x34);
+        let mut state = vec![];
+        fds.save_state(&mut state);
+
+        let mut other = test_fds();
+        other.eject();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.cpu_peek(0x6000), Some(0x11));
+        assert_eq!(other.irq_reload, 0x34);
+        assert_eq!(other.side(), Some(0));
+        assert!(other.load_state(&state[1..]).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use super::{bank_offset, check_state_size, mirroring_from_state, Chr, Mapper, PpuFetch};
+use crate::apu::sunsoft5b::Sunsoft5bAudio;
+use crate::apu::ExpansionAudio;
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const COMMAND: u16 = 0x8000;
+const COMMAND_END: u16 = 0x9FFF;
+const PARAMETER: u16 = 0xA000;
+const PARAMETER_END: u16 = 0xBFFF;
+
+// commands 0-7 select the CHR banks
+const PRG_BANK_6000: u8 = 0x8;
+const PRG_BANK_8000: u8 = 0x9;
+const PRG_BANK_C000: u8 = 0xB;
+const MIRRORING: u8 = 0xC;
+const IRQ_CONTROL: u8 = 0xD;
+const IRQ_COUNTER_LOW: u8 = 0xE;
+const IRQ_COUNTER_HIGH: u8 = 0xF;
+
+/// Sunsoft FME-7 and 5B, mapper 69: a command register at $8000 and its
+/// parameter at $A000 select eight 1KiB CHR banks, four 8KiB PRG banks with
+/// RAM or ROM at $6000, the mirroring and a 16 bit IRQ counter clocked by the
+/// CPU. The 5B adds a sound chip at $C000 and $E000.
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: [u8; 0x2000],
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000 and $C000
+    prg_banks: [u8; 4],
+    mirroring: Mirroring,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    pub fn new(rom: Rom) -> Self {
+        Fme7 {
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            prg_ram: [0; 0x2000],
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: rom.screen_mirroring,
+            irq_enabled: false,
+            counter_enabled: false,
+            counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    // bit 6 maps RAM instead of ROM at $6000, bit 7 enables the RAM
+    fn ram_at_6000(&self) -> bool {
+        self.prg_banks[0] & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.prg_banks[0] & 0xC0 == 0xC0
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0..=7 => self.chr_banks[self.command as usize] = data,
+            PRG_BANK_6000 => self.prg_banks[0] = data,
+            PRG_BANK_8000..=PRG_BANK_C000 => {
+                self.prg_banks[(self.command - PRG_BANK_8000) as usize + 1] = data & 0x3F
+            }
+            MIRRORING => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::VERTICAL,
+                    1 => Mirroring::HORIZONTAL,
+                    2 => Mirroring::SINGLE_SCREEN_LOWER,
+                    _ => Mirroring::SINGLE_SCREEN_UPPER,
+                }
+            }
+            IRQ_CONTROL => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            IRQ_COUNTER_LOW => self.counter = (self.counter & 0xFF00) | data as u16,
+            IRQ_COUNTER_HIGH => self.counter = (self.counter & 0x00FF) | (data as u16) << 8,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.ram_at_6000() => self
+                .ram_enabled()
+                .then(|| self.prg_ram[(addr - PRG_RAM) as usize]),
+            _ => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.ram_enabled() => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data
+            }
+            PRG_RAM..=PRG_RAM_END => {}
+            COMMAND..=COMMAND_END => self.command = data & 0x0F,
+            PARAMETER..=PARAMETER_END => self.write_parameter(data),
+            _ => return self.audio.write(addr, data),
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let bank = match addr {
+            PRG_RAM..=PRG_RAM_END if !self.ram_at_6000() => self.prg_banks[0] & 0x3F,
+            PRG_ROM..=0xDFFF => self.prg_banks[(addr - PRG_RAM) as usize / 0x2000],
+            0xE000..=PRG_ROM_END => (len / 0x2000 - 1) as u8,
+            _ => return None,
+        };
+        Some(bank_offset(len, bank as usize, 0x2000, addr))
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.read(bank as usize, 0x400, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.rom_offset(bank as usize, 0x400, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.write(bank as usize, 0x400, addr, data);
+    }
+
+    // the counter decrements every cycle and fires when it wraps to $FFFF
+    fn clock(&mut self) {
+        self.audio.clock();
+        if !self.counter_enabled {
+            return;
+        }
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        self.chr.save_state(out);
+        out.push(self.command);
+        out.extend(self.chr_banks);
+        out.extend(self.prg_banks);
+        out.push(self.mirroring as u8);
+        out.extend(self.counter.to_le_bytes());
+        out.extend([self.irq_enabled, self.counter_enabled, self.irq_pending].map(u8::from));
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(
+            raw,
+            self.prg_ram.len() + self.chr.state_len() + 1 + 8 + 4 + 1 + 2 + 3,
+        )?;
+        let (ram, raw) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        let raw = self.chr.load_state(raw);
+        self.command = raw[0];
+        self.chr_banks.copy_from_slice(&raw[1..9]);
+        self.prg_banks.copy_from_slice(&raw[9..13]);
+        self.mirroring = mirroring_from_state(raw[13]);
+        self.counter = u16::from_le_bytes([raw[14], raw[15]]);
+        self.irq_enabled = raw[16] != 0;
+        self.counter_enabled = raw[17] != 0;
+        self.irq_pending = raw[18] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    fn command(fme7: &mut Fme7, command: u8, parameter: u8) {
+        fme7.cpu_write(0x8000, command);
+        fme7.cpu_write(0xA000, parameter);
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut fme7 = Fme7::new(banked_rom(69, 256, 256));
+        command(&mut fme7, 0x9, 3);
+        command(&mut fme7, 0xA, 4);
+        command(&mut fme7, 0xB, 5);
+        command(&mut fme7, 0x5, 0x21);
+        command(&mut fme7, 0xC, 2);
+        assert_eq!(fme7.cpu_peek(0x8000), Some(3));
+        assert_eq!(fme7.cpu_peek(0xA000), Some(4));
+        assert_eq!(fme7.cpu_peek(0xC000), Some(5));
+        assert_eq!(fme7.cpu_peek(0xE000), Some(31));
+        assert_eq!(fme7.chr_read(0x1400, PpuFetch::Sprite), 0x21);
+        assert_eq!(fme7.mirroring(), Mirroring::SINGLE_SCREEN_LOWER);
+
+        // ROM, disabled RAM and RAM at $6000
+        command(&mut fme7, 0x8, 7);
+        assert_eq!(fme7.cpu_peek(0x6000), Some(7));
+        command(&mut fme7, 0x8, 0x40);
+        fme7.cpu_write(0x6000, 0x11);
+        assert_eq!(fme7.cpu_peek(0x6000), None);
+        command(&mut fme7, 0x8, 0xC0);
+        fme7.cpu_write(0x6000, 0x11);
+        assert_eq!(fme7.cpu_peek(0x6000), Some(0x11));
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut fme7 = Fme7::new(banked_rom(69, 128, 128));
+        command(&mut fme7, 0xE, 0x02);
+        command(&mut fme7, 0xF, 0x00);
+        command(&mut fme7, 0xD, 0x81);
+        // 2, 1, 0, then $FFFF
+        for _ in 0..2 {
+            fme7.clock();
+        }
+        assert!(!fme7.irq());
+        fme7.clock();
+        assert!(fme7.irq());
+        command(&mut fme7, 0xD, 0x00);
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut fme7 = Fme7::new(banked_rom(69, 128, 128));
+        // channel A: tone only at full volume
+        for (register, data) in [(0, 0x10), (7, 0x3E), (8, 0x0F)] {
+            fme7.cpu_write(0xC000, register);
+            fme7.cpu_write(0xE000, data);
+        }
+        let mut levels = vec![];
+        for _ in 0..2000 {
+            fme7.clock();
+            levels.push(fme7.audio_output());
+        }
+        assert!(levels.iter().any(|level| *level > 0.1));
+        assert!(levels.contains(&0.0));
+    }
+}
@@ -0,0 +1,114 @@
+use super::{bank_offset, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 66: a 32KiB PRG bank in bits 4-5 and an 8KiB CHR bank in bits 0-1
+/// of writes to $8000-$FFFF
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Gxrom {
+    pub fn new(rom: Rom) -> Self {
+        Gxrom {
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        Some(self.prg_rom[self.prg_rom_offset(addr)?])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => {
+                self.prg_bank = (data >> 4) & 0x03;
+                self.chr_bank = data & 0x03;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => Some(bank_offset(
+                self.prg_rom.len(),
+                self.prg_bank as usize,
+                0x8000,
+                addr,
+            )),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.chr.read(self.chr_bank as usize, 0x2000, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        self.chr.rom_offset(self.chr_bank as usize, 0x2000, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank as usize, 0x2000, addr, data);
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.chr.save_state(out);
+        out.extend([self.prg_bank, self.chr_bank]);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.chr.state_len() + 2)?;
+        let raw = self.chr.load_state(raw);
+        self.prg_bank = raw[0];
+        self.chr_bank = raw[1];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut gxrom = Gxrom::new(banked_rom(66, 128, 32));
+        assert!(gxrom.cpu_write(0x8000, 0x32));
+        assert_eq!(gxrom.cpu_peek(0x8000), Some(12));
+        assert_eq!(gxrom.cpu_peek(0xFFFF), Some(15));
+        assert_eq!(gxrom.chr_read(0x0000, PpuFetch::Background), 16);
+        assert_eq!(gxrom.chr_read(0x1C00, PpuFetch::Sprite), 23);
+        // CHR-ROM is not writable
+        gxrom.chr_write(0x0000, 0xAA);
+        assert_eq!(gxrom.chr_read(0x0000, PpuFetch::Data), 16);
+        assert_eq!(gxrom.prg_rom_offset(0x8001), Some(0x18001));
+        assert_eq!(gxrom.chr_rom_offset(0x1C01, PpuFetch::Sprite), Some(0x5C01));
+    }
+}
@@ -0,0 +1,220 @@
+use super::{bank_offset, check_prg_size, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const PRG_BANK: u16 = 0xA000;
+// $B000-$E000: the $FD and $FE banks of each pattern table
+const CHR_BANKS: u16 = 0xB000;
+const CHR_BANKS_END: u16 = 0xEFFF;
+const MIRRORING: u16 = 0xF000;
+
+const LATCH_FD: u8 = 0;
+const LATCH_FE: u8 = 1;
+
+/// Mappers 9 (MMC2, Punch-Out!!) and 10 (MMC4). Each pattern table has two 4KiB
+/// CHR banks and a latch that picks one: the PPU fetching tile $FD or $FE flips
+/// it. MMC2 has one switchable 8KiB PRG bank, MMC4 one of 16KiB and PRG-RAM.
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mmc4: bool,
+    prg_ram: [u8; 0x2000],
+    prg_bank: u8,
+    // $FD and $FE banks for $0000 and $1000
+    chr_banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+    horizontal: bool,
+}
+
+impl Mmc2 {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        // a switchable bank and the fixed ones
+        check_prg_size(&rom, 0x8000)?;
+        Ok(Mmc2 {
+            mmc4: rom.mapper == 10,
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            prg_ram: [0; 0x2000],
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [LATCH_FD, LATCH_FE],
+            horizontal: false,
+        })
+    }
+
+    // MMC2 reacts to the first row of the tile only in the first pattern table
+    fn update_latch(&mut self, addr: u16) {
+        let table = (addr >> 12) as usize & 1;
+        let latch = match addr & 0x0FF8 {
+            0x0FD8 => LATCH_FD,
+            0x0FE8 => LATCH_FE,
+            _ => return,
+        };
+        if table == 0 && !self.mmc4 && addr & 0x07 != 0 {
+            return;
+        }
+        self.latches[table] = latch;
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.mmc4 => Some(self.prg_ram[(addr - PRG_RAM) as usize]),
+            _ => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.mmc4 => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            PRG_BANK..=0xAFFF => self.prg_bank = data & 0x0F,
+            CHR_BANKS..=CHR_BANKS_END => {
+                let register = (addr - CHR_BANKS) as usize / 0x1000;
+                self.chr_banks[register / 2][register % 2] = data & 0x1F;
+            }
+            MIRRORING..=PRG_ROM_END => self.horizontal = data & 0x01 != 0,
+            PRG_ROM..=PRG_ROM_END => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let last_8k = len / 0x2000 - 1;
+        match addr {
+            PRG_ROM..=0x9FFF if !self.mmc4 => {
+                Some(bank_offset(len, self.prg_bank as usize, 0x2000, addr))
+            }
+            // the last three 8KiB banks are fixed
+            PRG_ROM..=PRG_ROM_END if !self.mmc4 => {
+                let bank = last_8k - 2 + (addr - 0xA000) as usize / 0x2000;
+                Some(bank_offset(len, bank, 0x2000, addr))
+            }
+            PRG_ROM..=0xBFFF => Some(bank_offset(len, self.prg_bank as usize, 0x4000, addr)),
+            0xC000..=PRG_ROM_END => Some(bank_offset(len, len / 0x4000 - 1, 0x4000, addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal {
+            Mirroring::HORIZONTAL
+        } else {
+            Mirroring::VERTICAL
+        }
+    }
+
+    // the latch flips after the fetch, the tile itself comes from the old bank
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        let table = (addr >> 12) as usize & 1;
+        let bank = self.chr_banks[table][self.latches[table] as usize];
+        let data = self.chr.read(bank as usize, 0x1000, addr);
+        self.update_latch(addr);
+        data
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        let table = (addr >> 12) as usize & 1;
+        let bank = self.chr_banks[table][self.latches[table] as usize];
+        self.chr.rom_offset(bank as usize, 0x1000, addr)
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        out.extend([
+            self.prg_bank,
+            self.chr_banks[0][0],
+            self.chr_banks[0][1],
+            self.chr_banks[1][0],
+            self.chr_banks[1][1],
+            self.latches[0],
+            self.latches[1],
+            self.horizontal as u8,
+        ]);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.prg_ram.len() + 8)?;
+        let (ram, raw) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        self.prg_bank = raw[0];
+        self.chr_banks = [[raw[1], raw[2]], [raw[3], raw[4]]];
+        self.latches = [raw[5] & 1, raw[6] & 1];
+        self.horizontal = raw[7] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_mmc2_prg() {
+        let mut mmc2 = Mmc2::new(banked_rom(9, 128, 128)).unwrap();
+        mmc2.cpu_write(0xA000, 5);
+        assert_eq!(mmc2.cpu_peek(0x8000), Some(5));
+        assert_eq!(mmc2.cpu_peek(0xA000), Some(13));
+        assert_eq!(mmc2.cpu_peek(0xE000), Some(15));
+        assert_eq!(mmc2.cpu_peek(0x6000), None);
+        mmc2.cpu_write(0xF000, 1);
+        assert_eq!(mmc2.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_mmc2_latches() {
+        let mut mmc2 = Mmc2::new(banked_rom(9, 128, 128)).unwrap();
+        mmc2.cpu_write(0xB000, 1);
+        mmc2.cpu_write(0xC000, 2);
+        mmc2.cpu_write(0xD000, 3);
+        mmc2.cpu_write(0xE000, 4);
+        // $0000 starts on the $FD bank, $1000 on the $FE one
+        assert_eq!(mmc2.chr_read(0x0000, PpuFetch::Background), 4);
+        assert_eq!(mmc2.chr_read(0x1000, PpuFetch::Background), 16);
+
+        // tile $FE: the fetch itself still sees the old bank
+        assert_eq!(mmc2.chr_read(0x0FE8, PpuFetch::Background), 7);
+        assert_eq!(mmc2.chr_read(0x0000, PpuFetch::Background), 8);
+        // only the first row of the tile in the first table
+        mmc2.chr_read(0x0FD9, PpuFetch::Background);
+        assert_eq!(mmc2.chr_read(0x0000, PpuFetch::Background), 8);
+        mmc2.chr_read(0x1FDF, PpuFetch::Sprite);
+        assert_eq!(mmc2.chr_read(0x1000, PpuFetch::Sprite), 12);
+    }
+
+    #[test]
+    fn test_mmc4() {
+        let mut mmc4 = Mmc2::new(banked_rom(10, 128, 128)).unwrap();
+        mmc4.cpu_write(0xA000, 2);
+        assert_eq!(mmc4.cpu_peek(0x8000), Some(4));
+        assert_eq!(mmc4.cpu_peek(0xA000), Some(5));
+        assert_eq!(mmc4.cpu_peek(0xC000), Some(14));
+        mmc4.cpu_write(0x6000, 0x77);
+        assert_eq!(mmc4.cpu_peek(0x6000), Some(0x77));
+
+        mmc4.cpu_write(0xC000, 2);
+        mmc4.chr_read(0x0FED, PpuFetch::Background);
+        assert_eq!(mmc4.chr_read(0x0000, PpuFetch::Background), 8);
+
+        let mut state = vec![];
+        mmc4.save_state(&mut state);
+        let mut other = Mmc2::new(banked_rom(10, 128, 128)).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.chr_read(0x0000, PpuFetch::Background), 8);
+        assert_eq!(other.cpu_peek(0x8000), Some(4));
+    }
+}
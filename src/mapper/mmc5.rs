@@ -0,0 +1,788 @@
+use super::{check_state_size, Mapper, Nametable, PpuFetch};
+use crate::apu::mmc5::Mmc5Audio;
+use crate::apu::ExpansionAudio;
+use crate::rom::{Mirroring, Rom};
+
+// https://www.nesdev.org/wiki/MMC5
+const AUDIO: u16 = 0x5000;
+const AUDIO_END: u16 = 0x5015;
+const PRG_MODE: u16 = 0x5100;
+const CHR_MODE: u16 = 0x5101;
+const PRG_RAM_PROTECT1: u16 = 0x5102;
+const PRG_RAM_PROTECT2: u16 = 0x5103;
+const EXRAM_MODE: u16 = 0x5104;
+const NAMETABLES: u16 = 0x5105;
+const FILL_TILE: u16 = 0x5106;
+const FILL_ATTRIBUTE: u16 = 0x5107;
+// $5113 for the RAM at $6000, $5114-$5117 for $8000-$FFFF
+const PRG_BANKS: u16 = 0x5113;
+const PRG_BANKS_END: u16 = 0x5117;
+// $5120-$5127 for sprites, $5128-$512B for the background
+const CHR_BANKS: u16 = 0x5120;
+const CHR_BANKS_END: u16 = 0x512B;
+const CHR_UPPER: u16 = 0x5130;
+const SPLIT_CONTROL: u16 = 0x5200;
+const SPLIT_SCROLL: u16 = 0x5201;
+const SPLIT_PAGE: u16 = 0x5202;
+const IRQ_SCANLINE: u16 = 0x5203;
+const IRQ_STATUS: u16 = 0x5204;
+const MULTIPLICAND: u16 = 0x5205;
+const MULTIPLIER: u16 = 0x5206;
+const EXRAM: u16 = 0x5C00;
+const EXRAM_END: u16 = 0x5FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+const PRG_RAM_SIZE: usize = 0x10000;
+const ATTRIBUTES: usize = 0x3C0;
+// the PPU stops fetching in vblank and when rendering is off
+const IDLE_CPU_CYCLES: u8 = 3;
+
+// ExRAM modes of $5104
+const EXRAM_NAMETABLE: u8 = 0;
+const EXRAM_ATTRIBUTES: u8 = 1;
+const EXRAM_READ_ONLY: u8 = 3;
+
+/// Mapper 5: PRG banks of 8 to 32KiB with RAM in any of them, CHR banks of 1 to
+/// 8KiB in separate sets for sprites and background, 1KiB of ExRAM for extended
+/// attributes or a third nametable, a fill mode nametable, a vertical split, a
+/// scanline IRQ, a multiplier and a sound chip. It counts scanlines by watching
+/// the PPU's fetches.
+pub struct Mmc5 {
+    rom: Rom,
+    prg_ram: Vec<u8>,
+    exram: [u8; 0x400],
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    // two bits per nametable: VRAM page 0 or 1, ExRAM or fill mode
+    nametables: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_banks: [u8; 5],
+    // 10 bits with the upper bits of $5130 at the time of the write
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    // the background set was written last, used for PPUDATA in 8x16 mode
+    background_set_last: bool,
+    split_control: u8,
+    split_scroll: u8,
+    split_page: u8,
+    irq_scanline: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    sprites_8x16: bool,
+    // scanline detection: three identical nametable reads in a row start one
+    in_frame: bool,
+    scanline: u8,
+    last_ppu_addr: u16,
+    repeats: u8,
+    idle_cycles: u8,
+    // background tile being fetched, 0 and 1 are prefetched on the line before
+    tile: u8,
+    sprite_fetches: bool,
+    split_y: u8,
+    in_split: bool,
+    // ExRAM byte of the tile, in extended attribute mode
+    tile_attribute: u8,
+    audio: Mmc5Audio,
+}
+
+impl Mmc5 {
+    pub fn new(rom: Rom) -> Self {
+        Mmc5 {
+            rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            exram: [0; 0x400],
+            prg_mode: 3,
+            chr_mode: 3,
+            prg_ram_protect: [0; 2],
+            exram_mode: EXRAM_NAMETABLE,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            background_set_last: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_page: 0,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprites_8x16: false,
+            in_frame: false,
+            scanline: 0,
+            last_ppu_addr: 0,
+            repeats: 0,
+            idle_cycles: 0,
+            tile: 0,
+            sprite_fetches: false,
+            split_y: 0,
+            in_split: false,
+            tile_attribute: 0,
+            audio: Mmc5Audio::new(),
+        }
+    }
+
+    // (is ROM, 8KiB bank) mapped at $8000-$FFFF
+    fn prg_bank(&self, addr: u16) -> (bool, usize) {
+        let slot = (addr - PRG_ROM) as usize / 0x2000;
+        let bank =
+            |register: usize, mask: u8| (register, (self.prg_banks[register] & mask) as usize);
+        let (register, bank) = match (self.prg_mode, slot) {
+            (0, _) => bank(4, 0x7C),
+            (1, 0 | 1) | (2, 0 | 1) => bank(2, 0x7E),
+            (1, _) => bank(4, 0x7E),
+            _ => bank(slot + 1, 0x7F),
+        };
+        // the bank registers of 16 and 32KiB slots ignore the low bits
+        let bank = match (self.prg_mode, register) {
+            (0, _) => bank | slot,
+            (1, _) | (2, 2) => bank | (slot & 1),
+            _ => bank,
+        };
+        (register == 4 || self.prg_banks[register] & 0x80 != 0, bank)
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [2, 1]
+    }
+
+    fn chr_offset(&self, addr: u16, background_set: bool) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        let (size, index) = match self.chr_mode {
+            0 => (0x2000, 7),
+            1 => (0x1000, 3 + 4 * (addr / 0x1000)),
+            2 => (0x800, 1 + 2 * (addr / 0x800)),
+            _ => (0x400, addr / 0x400),
+        };
+        // the background set covers 4KiB and repeats in both pattern tables
+        let bank = if background_set {
+            self.chr_banks[8 + (index & 3)]
+        } else {
+            self.chr_banks[index]
+        };
+        bank as usize * size + addr % size
+    }
+
+    // CHR offset of a PPU fetch, before wrapping it to the CHR-ROM size
+    fn fetch_offset(&self, addr: u16, fetch: PpuFetch) -> usize {
+        if fetch == PpuFetch::Background && self.in_split {
+            let fine_y = (self.split_y & 7) as usize;
+            return self.split_page as usize * 0x1000 + (addr as usize & 0x0FF8 | fine_y);
+        }
+        if fetch == PpuFetch::Background && self.exram_mode == EXRAM_ATTRIBUTES {
+            let bank = (self.chr_upper as usize) << 6 | (self.tile_attribute & 0x3F) as usize;
+            return bank * 0x1000 + (addr as usize & 0x0FFF);
+        }
+        let background_set = self.sprites_8x16
+            && match fetch {
+                PpuFetch::Background => true,
+                PpuFetch::Sprite => false,
+                PpuFetch::Data => self.background_set_last,
+            };
+        self.chr_offset(addr, background_set)
+    }
+
+    fn chr_byte(&self, offset: usize) -> u8 {
+        match self.rom.chr_rom.len() {
+            0 => 0,
+            len => self.rom.chr_rom[offset % len],
+        }
+    }
+
+    // every PPU read counts for the scanline detection
+    fn observe_ppu_read(&mut self, addr: u16) {
+        self.idle_cycles = 0;
+        if addr == self.last_ppu_addr && (0x2000..0x3000).contains(&addr) {
+            self.repeats += 1;
+            if self.repeats == 2 {
+                self.start_scanline();
+            }
+        } else {
+            self.repeats = 0;
+        }
+        self.last_ppu_addr = addr;
+    }
+
+    fn start_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_scanline {
+                self.irq_pending = true;
+            }
+            self.split_y = if self.split_y >= 239 {
+                0
+            } else {
+                self.split_y + 1
+            };
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+            self.split_y = self.split_scroll;
+        }
+        // the third read is the nametable fetch of the third tile
+        self.tile = 2;
+    }
+
+    fn end_frame(&mut self) {
+        self.in_frame = false;
+        self.repeats = 0;
+    }
+
+    fn tile_in_split(&self) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > EXRAM_ATTRIBUTES {
+            return false;
+        }
+        let threshold = self.split_control & 0x1F;
+        if self.split_control & 0x40 != 0 {
+            self.tile >= threshold
+        } else {
+            self.tile < threshold
+        }
+    }
+
+    // the byte of the split screen, it comes from ExRAM
+    fn split_nametable(&self, attribute: bool) -> u8 {
+        let column = (self.tile & 0x1F) as usize;
+        let row = self.split_y as usize / 8;
+        if !attribute {
+            return self.exram[row * 32 + column];
+        }
+        let byte = self.exram[ATTRIBUTES + row / 4 * 8 + column / 4];
+        let shift = (row & 2) * 2 + (column & 2);
+        // the same palette for all four quadrants, whatever the PPU's scroll
+        ((byte >> shift) & 3) * 0x55
+    }
+
+    fn vram_page(&self, addr: u16) -> u8 {
+        let nametable = (addr >> 10) & 3;
+        (self.nametables >> (nametable * 2)) & 3
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.audio.peek(addr) {
+            return Some(data);
+        }
+        match addr {
+            IRQ_STATUS => Some((self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6),
+            MULTIPLICAND => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            MULTIPLIER => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            EXRAM..=EXRAM_END if self.exram_mode > EXRAM_ATTRIBUTES => {
+                Some(self.exram[(addr - EXRAM) as usize])
+            }
+            PRG_RAM..=PRG_RAM_END => {
+                let bank = (self.prg_banks[0] & 7) as usize;
+                Some(self.prg_ram[bank * 0x2000 + (addr - PRG_RAM) as usize])
+            }
+            PRG_ROM..=PRG_ROM_END => match self.prg_bank(addr) {
+                (true, _) => Some(self.rom.prg_rom[self.prg_rom_offset(addr)?]),
+                (false, bank) => Some(self.prg_ram[(bank & 7) * 0x2000 + addr as usize % 0x2000]),
+            },
+            _ => None,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.audio.read(addr) {
+            return Some(data);
+        }
+        let data = self.cpu_peek(addr);
+        match (addr, data) {
+            (IRQ_STATUS, _) => self.irq_pending = false,
+            (PRG_ROM..=0xBFFF, Some(data)) => self.audio.observe_read(addr, data),
+            _ => {}
+        }
+        data
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            AUDIO..=AUDIO_END => return self.audio.write(addr, data),
+            PRG_MODE => self.prg_mode = data & 3,
+            CHR_MODE => self.chr_mode = data & 3,
+            PRG_RAM_PROTECT1 => self.prg_ram_protect[0] = data & 3,
+            PRG_RAM_PROTECT2 => self.prg_ram_protect[1] = data & 3,
+            EXRAM_MODE => self.exram_mode = data & 3,
+            NAMETABLES => self.nametables = data,
+            FILL_TILE => self.fill_tile = data,
+            FILL_ATTRIBUTE => self.fill_attribute = data & 3,
+            PRG_BANKS..=PRG_BANKS_END => self.prg_banks[(addr - PRG_BANKS) as usize] = data,
+            CHR_BANKS..=CHR_BANKS_END => {
+                self.chr_banks[(addr - CHR_BANKS) as usize] =
+                    data as u16 | (self.chr_upper as u16) << 8;
+                self.background_set_last = addr >= CHR_BANKS + 8;
+            }
+            CHR_UPPER => self.chr_upper = data & 3,
+            SPLIT_CONTROL => self.split_control = data,
+            SPLIT_SCROLL => self.split_scroll = data,
+            SPLIT_PAGE => self.split_page = data,
+            IRQ_SCANLINE => self.irq_scanline = data,
+            IRQ_STATUS => self.irq_enabled = data & 0x80 != 0,
+            MULTIPLICAND => self.multiplicand = data,
+            MULTIPLIER => self.multiplier = data,
+            // as a nametable ExRAM can only be written while the PPU renders
+            EXRAM..=EXRAM_END => match self.exram_mode {
+                EXRAM_READ_ONLY => {}
+                EXRAM_NAMETABLE | EXRAM_ATTRIBUTES if !self.in_frame => {
+                    self.exram[(addr - EXRAM) as usize] = 0
+                }
+                _ => self.exram[(addr - EXRAM) as usize] = data,
+            },
+            PRG_RAM..=PRG_RAM_END => {
+                if self.prg_ram_writable() {
+                    let bank = (self.prg_banks[0] & 7) as usize;
+                    self.prg_ram[bank * 0x2000 + (addr - PRG_RAM) as usize] = data;
+                }
+            }
+            PRG_ROM..=PRG_ROM_END => {
+                if let (false, bank) = self.prg_bank(addr) {
+                    if self.prg_ram_writable() {
+                        self.prg_ram[(bank & 7) * 0x2000 + addr as usize % 0x2000] = data;
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => match self.prg_bank(addr) {
+                (true, bank) => {
+                    Some((bank * 0x2000 + addr as usize % 0x2000) % self.rom.prg_rom.len())
+                }
+                (false, _) => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.rom.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.rom.chr_rom.len()
+    }
+
+    // only what the nametable register can express; the PPU hooks have the rest
+    fn mirroring(&self) -> Mirroring {
+        match self.nametables {
+            0x44 => Mirroring::VERTICAL,
+            0x50 => Mirroring::HORIZONTAL,
+            0x55 => Mirroring::SINGLE_SCREEN_UPPER,
+            _ => Mirroring::SINGLE_SCREEN_LOWER,
+        }
+    }
+
+    fn chr_read(&mut self, addr: u16, fetch: PpuFetch) -> u8 {
+        self.observe_ppu_read(addr);
+        self.chr_byte(self.fetch_offset(addr, fetch))
+    }
+
+    fn chr_rom_offset(&self, addr: u16, fetch: PpuFetch) -> Option<usize> {
+        match self.rom.chr_rom.len() {
+            0 => None,
+            len => Some(self.fetch_offset(addr, fetch) % len),
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16, fetch: PpuFetch) -> Nametable {
+        let attribute = addr as usize & 0x3FF >= ATTRIBUTES;
+        match fetch {
+            PpuFetch::Background if !attribute => {
+                if self.sprite_fetches {
+                    self.sprite_fetches = false;
+                    self.tile = 0;
+                } else {
+                    self.tile = self.tile.wrapping_add(1);
+                }
+            }
+            PpuFetch::Sprite => self.sprite_fetches = true,
+            _ => {}
+        }
+        self.observe_ppu_read(addr);
+
+        if fetch == PpuFetch::Background {
+            if !attribute {
+                self.in_split = self.tile_in_split();
+            }
+            if self.in_split {
+                return Nametable::Cartridge(self.split_nametable(attribute));
+            }
+            if self.exram_mode == EXRAM_ATTRIBUTES {
+                if attribute {
+                    return Nametable::Cartridge((self.tile_attribute >> 6) * 0x55);
+                }
+                self.tile_attribute = self.exram[addr as usize & 0x3FF];
+            }
+        }
+        match self.vram_page(addr) {
+            page @ (0 | 1) => Nametable::Vram((page as u16) << 10 | addr & 0x3FF),
+            2 if self.exram_mode <= EXRAM_ATTRIBUTES => {
+                Nametable::Cartridge(self.exram[addr as usize & 0x3FF])
+            }
+            2 => Nametable::Cartridge(0),
+            _ if attribute => Nametable::Cartridge(self.fill_attribute * 0x55),
+            _ => Nametable::Cartridge(self.fill_tile),
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> Option<u16> {
+        match self.vram_page(addr) {
+            page @ (0 | 1) => Some((page as u16) << 10 | addr & 0x3FF),
+            2 => {
+                if self.exram_mode <= EXRAM_ATTRIBUTES {
+                    self.exram[addr as usize & 0x3FF] = data;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr & 0x2007 {
+            0x2000 => self.sprites_8x16 = data & 0x20 != 0,
+            0x2001 if data & 0x18 == 0 => self.end_frame(),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.audio.clock();
+        if self.idle_cycles < IDLE_CPU_CYCLES {
+            self.idle_cycles += 1;
+            if self.idle_cycles == IDLE_CPU_CYCLES {
+                self.end_frame();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.irq_enabled && self.irq_pending) || self.audio.irq()
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        out.extend(&self.exram);
+        out.extend(&self.prg_banks);
+        for bank in self.chr_banks {
+            out.extend(bank.to_le_bytes());
+        }
+        out.extend([
+            self.prg_mode,
+            self.chr_mode,
+            self.prg_ram_protect[0],
+            self.prg_ram_protect[1],
+            self.exram_mode,
+            self.nametables,
+            self.fill_tile,
+            self.fill_attribute,
+            self.chr_upper,
+            self.split_control,
+            self.split_scroll,
+            self.split_page,
+            self.irq_scanline,
+            self.multiplicand,
+            self.multiplier,
+            self.scanline,
+            self.split_y,
+        ]);
+        out.extend(
+            [
+                self.background_set_last,
+                self.irq_enabled,
+                self.irq_pending,
+                self.sprites_8x16,
+                self.in_frame,
+            ]
+            .map(u8::from),
+        );
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, PRG_RAM_SIZE + 0x400 + 5 + 24 + 17 + 5)?;
+        let (ram, raw) = raw.split_at(PRG_RAM_SIZE);
+        let (exram, raw) = raw.split_at(0x400);
+        let (prg_banks, raw) = raw.split_at(5);
+        let (chr_banks, raw) = raw.split_at(24);
+        self.prg_ram.copy_from_slice(ram);
+        self.exram.copy_from_slice(exram);
+        self.prg_banks.copy_from_slice(prg_banks);
+        for (bank, raw) in self.chr_banks.iter_mut().zip(chr_banks.chunks(2)) {
+            *bank = u16::from_le_bytes([raw[0], raw[1]]);
+        }
+        self.prg_mode = raw[0];
+        self.chr_mode = raw[1];
+        self.prg_ram_protect = [raw[2], raw[3]];
+        self.exram_mode = raw[4];
+        self.nametables = raw[5];
+        self.fill_tile = raw[6];
+        self.fill_attribute = raw[7];
+        self.chr_upper = raw[8];
+        self.split_control = raw[9];
+        self.split_scroll = raw[10];
+        self.split_page = raw[11];
+        self.irq_scanline = raw[12];
+        self.multiplicand = raw[13];
+        self.multiplier = raw[14];
+        self.scanline = raw[15];
+        self.split_y = raw[16];
+        let flag = |n: usize| raw[17 + n] != 0;
+        self.background_set_last = flag(0);
+        self.irq_enabled = flag(1);
+        self.irq_pending = flag(2);
+        self.sprites_8x16 = flag(3);
+        self.in_frame = flag(4);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    fn test_mmc5() -> Mmc5 {
+        // 256KiB PRG, 256KiB CHR
+        Mmc5::new(banked_rom(5, 256, 256))
+    }
+
+    // the fetches of one scanline: 32 tiles, 8 sprites, 2 prefetched tiles and
+    // the two extra nametable reads, see https://www.nesdev.org/wiki/PPU_rendering
+    fn render_line(mmc5: &mut Mmc5, nametable: u16) -> Vec<(Nametable, u8)> {
+        let mut tiles = vec![];
+        let mut fetch_tile = |mmc5: &mut Mmc5, addr: u16| {
+            let tile = mmc5.nametable_read(addr, PpuFetch::Background);
+            mmc5.nametable_read(0x23C0, PpuFetch::Background);
+            let pattern = mmc5.chr_read(0x0000, PpuFetch::Background);
+            mmc5.chr_read(0x0008, PpuFetch::Background);
+            tiles.push((tile, pattern));
+        };
+        for column in 2..34 {
+            fetch_tile(mmc5, nametable + column);
+        }
+        for _ in 0..8 {
+            mmc5.nametable_read(nametable, PpuFetch::Sprite);
+            mmc5.nametable_read(nametable, PpuFetch::Sprite);
+            mmc5.chr_read(0x1000, PpuFetch::Sprite);
+            mmc5.chr_read(0x1008, PpuFetch::Sprite);
+        }
+        for column in 0..2 {
+            fetch_tile(mmc5, nametable + column);
+        }
+        mmc5.nametable_read(nametable + 2, PpuFetch::Background);
+        mmc5.nametable_read(nametable + 2, PpuFetch::Background);
+        tiles
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc5 = test_mmc5();
+        // mode 3 on power up, $5117 is the last bank
+        assert_eq!(mmc5.cpu_peek(0xE000), Some(31));
+        mmc5.cpu_write(0x5114, 0x80 | 5);
+        mmc5.cpu_write(0x5115, 0x80 | 6);
+        assert_eq!(mmc5.cpu_peek(0x8000), Some(5));
+        assert_eq!(mmc5.cpu_peek(0xA000), Some(6));
+
+        mmc5.cpu_write(0x5100, 1);
+        assert_eq!(mmc5.cpu_peek(0x8000), Some(6));
+        assert_eq!(mmc5.cpu_peek(0xA000), Some(7));
+        assert_eq!(mmc5.cpu_peek(0xC000), Some(30));
+
+        mmc5.cpu_write(0x5100, 0);
+        mmc5.cpu_write(0x5117, 0x09);
+        assert_eq!(mmc5.cpu_peek(0x8000), Some(8));
+        assert_eq!(mmc5.cpu_peek(0xE000), Some(11));
+        assert_eq!(mmc5.prg_rom_offset(0xE001), Some(11 * 0x2000 + 1));
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc5.cpu_peek(0x6000), Some(0));
+        mmc5.cpu_write(0x5102, 2);
+        mmc5.cpu_write(0x5103, 1);
+        mmc5.cpu_write(0x5113, 3);
+        mmc5.cpu_write(0x6000, 0x42);
+        // RAM bank 3 in $C000-$DFFF
+        mmc5.cpu_write(0x5100, 2);
+        mmc5.cpu_write(0x5116, 3);
+        assert_eq!(mmc5.cpu_peek(0xC000), Some(0x42));
+        assert_eq!(mmc5.prg_rom_offset(0xC000), None);
+        mmc5.cpu_write(0xC001, 0x43);
+        assert_eq!(mmc5.cpu_peek(0x6001), Some(0x43));
+    }
+
+    #[test]
+    fn test_chr_banking() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x5130, 1);
+        mmc5.cpu_write(0x5123, 2);
+        mmc5.cpu_write(0x5130, 0);
+        mmc5.cpu_write(0x512B, 5);
+        // 1KiB banks
+        assert_eq!(mmc5.chr_read(0x0C00, PpuFetch::Sprite), 0x02);
+        mmc5.cpu_write(0x5101, 1);
+        // the 4KiB bank 0x102 is past the end of the 256KiB and wraps
+        assert_eq!(mmc5.chr_read(0x0000, PpuFetch::Sprite), 8);
+        // the background set only counts with 8x16 sprites
+        assert_eq!(mmc5.chr_read(0x0000, PpuFetch::Background), 8);
+        mmc5.ppu_register_write(0x2000, 0x20);
+        assert_eq!(mmc5.chr_read(0x1400, PpuFetch::Background), 21);
+        assert_eq!(mmc5.chr_read(0x0000, PpuFetch::Data), 20);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mmc5 = test_mmc5();
+        // page 0, page 1, ExRAM and fill mode
+        mmc5.cpu_write(0x5105, 0b11_10_01_00);
+        mmc5.cpu_write(0x5106, 0x33);
+        mmc5.cpu_write(0x5107, 2);
+        assert_eq!(mmc5.nametable_write(0x2405, 1), Some(0x405));
+        assert_eq!(mmc5.nametable_write(0x2805, 7), None);
+        assert_eq!(
+            mmc5.nametable_read(0x2805, PpuFetch::Data),
+            Nametable::Cartridge(7)
+        );
+        assert_eq!(
+            mmc5.nametable_read(0x2C05, PpuFetch::Data),
+            Nametable::Cartridge(0x33)
+        );
+        assert_eq!(
+            mmc5.nametable_read(0x2FC0, PpuFetch::Data),
+            Nametable::Cartridge(0xAA)
+        );
+        mmc5.cpu_write(0x5105, 0x44);
+        assert_eq!(mmc5.mirroring(), Mirroring::VERTICAL);
+
+        // ExRAM as CPU RAM
+        mmc5.cpu_write(0x5104, 2);
+        mmc5.cpu_write(0x5C10, 0x99);
+        assert_eq!(mmc5.cpu_peek(0x5C10), Some(0x99));
+        mmc5.cpu_write(0x5104, 3);
+        mmc5.cpu_write(0x5C10, 0x11);
+        assert_eq!(mmc5.cpu_peek(0x5C10), Some(0x99));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x5203, 3);
+        mmc5.cpu_write(0x5204, 0x80);
+        // the pre-render line
+        render_line(&mut mmc5, 0x2000);
+        assert_eq!(mmc5.cpu_peek(0x5204), Some(0x00));
+        for line in 0..3 {
+            render_line(&mut mmc5, 0x2000);
+            assert_eq!(mmc5.scanline, line);
+            assert!(!mmc5.irq());
+        }
+        render_line(&mut mmc5, 0x2000);
+        assert!(mmc5.irq());
+        assert_eq!(mmc5.cpu_read(0x5204), Some(0xC0));
+        assert!(!mmc5.irq());
+
+        // vblank: the PPU stops reading
+        for _ in 0..IDLE_CPU_CYCLES {
+            mmc5.clock();
+        }
+        assert_eq!(mmc5.cpu_peek(0x5204), Some(0x00));
+    }
+
+    #[test]
+    fn test_extended_attributes() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x5104, 2);
+        // tile 5: 4KiB CHR bank 3, palette 2
+        mmc5.cpu_write(0x5C05, 0x80 | 3);
+        mmc5.cpu_write(0x5104, 1);
+        render_line(&mut mmc5, 0x2000);
+        let tiles = render_line(&mut mmc5, 0x2000);
+        assert_eq!(tiles[3].1, 12);
+        assert_eq!(tiles[4].1, 0);
+        assert_eq!(
+            mmc5.nametable_read(0x2005, PpuFetch::Background),
+            Nametable::Vram(5)
+        );
+        assert_eq!(
+            mmc5.nametable_read(0x23C1, PpuFetch::Background),
+            Nametable::Cartridge(0xAA)
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x5104, 2);
+        // row 1 of the split nametable
+        for column in 0..32 {
+            mmc5.cpu_write(0x5C20 + column, 0x70 + column as u8);
+        }
+        mmc5.cpu_write(0x5104, 0);
+        // tiles 0-3 on the left come from the split, 8 lines down, CHR page 2
+        mmc5.cpu_write(0x5200, 0x80 | 4);
+        mmc5.cpu_write(0x5201, 8);
+        mmc5.cpu_write(0x5202, 2);
+        render_line(&mut mmc5, 0x2000);
+        let tiles = render_line(&mut mmc5, 0x2000);
+        // tiles 2 and 3 of the line are the first fetched
+        assert_eq!(tiles[0], (Nametable::Cartridge(0x72), 8));
+        assert_eq!(tiles[1], (Nametable::Cartridge(0x73), 8));
+        assert_eq!(tiles[2], (Nametable::Vram(4), 0));
+        // the prefetched tiles of the next line
+        assert_eq!(tiles[32].0, Nametable::Cartridge(0x70));
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mmc5 = test_mmc5();
+        assert_eq!(mmc5.cpu_peek(0x5205), Some(0x01));
+        assert_eq!(mmc5.cpu_peek(0x5206), Some(0xFE));
+        mmc5.cpu_write(0x5205, 200);
+        mmc5.cpu_write(0x5206, 3);
+        assert_eq!(mmc5.cpu_read(0x5205), Some(600u16 as u8));
+        assert_eq!(mmc5.cpu_read(0x5206), Some(2));
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mmc5 = test_mmc5();
+        mmc5.cpu_write(0x5117, 0x83);
+        mmc5.cpu_write(0x5127, 9);
+        mmc5.cpu_write(0x5102, 2);
+        mmc5.cpu_write(0x5103, 1);
+        mmc5.cpu_write(0x7000, 0x5A);
+        let mut state = vec![];
+        mmc5.save_state(&mut state);
+
+        let mut other = test_mmc5();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.cpu_peek(0xE000), Some(3));
+        assert_eq!(other.cpu_peek(0x7000), Some(0x5A));
+        assert_eq!(other.chr_read(0x1C00, PpuFetch::Sprite), 9);
+        assert!(other.load_state(&state[1..]).is_err());
+    }
+}
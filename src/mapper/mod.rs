@@ -0,0 +1,305 @@
+use crate::rom::{Mirroring, Rom};
+
+mod axrom;
+mod bnrom;
+mod color_dreams;
+mod fds;
+mod fme7;
+mod gxrom;
+mod mmc2;
+mod mmc5;
+mod namco118;
+mod nrom;
+mod vrc4;
+mod vrc6;
+
+pub use axrom::Axrom;
+pub use bnrom::Bnrom;
+pub use color_dreams::ColorDreams;
+pub use fds::Fds;
+pub use fme7::Fme7;
+pub use gxrom::Gxrom;
+pub use mmc2::Mmc2;
+pub use mmc5::Mmc5;
+pub use namco118::Namco118;
+pub use nrom::Nrom;
+pub use vrc4::Vrc4;
+pub use vrc6::Vrc6;
+
+/// What the PPU is fetching, some boards bank background and sprite tiles apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuFetch {
+    Background,
+    Sprite,
+    // PPUDATA accesses of the CPU
+    Data,
+}
+
+/// Where a nametable byte comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nametable {
+    // offset into the console's VRAM, or the cartridge's for four screen boards
+    Vram(u16),
+    Cartridge(u8),
+}
+
+/// Offset into VRAM of a $2000-$2FFF PPU address with fixed mirroring.
+/// Four screen boards have 2KiB more RAM, at offsets $800-$FFF.
+pub fn vram_offset(mirroring: Mirroring, addr: u16) -> u16 {
+    let addr = addr & 0x0FFF;
+    match mirroring {
+        Mirroring::VERTICAL => addr & 0x07FF,
+        Mirroring::HORIZONTAL => (addr >> 1) & 0x0400 | addr & 0x03FF,
+        Mirroring::SINGLE_SCREEN_LOWER => addr & 0x03FF,
+        Mirroring::SINGLE_SCREEN_UPPER => 0x0400 | addr & 0x03FF,
+        Mirroring::FOUR_SCREEN => addr,
+    }
+}
+
+/// The cartridge side of the CPU bus, $4020-$FFFF: PRG-ROM banking, PRG-RAM
+/// and whatever registers the board has.
+pub trait Mapper {
+    /// None when nothing on the cartridge drives the bus at the address
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+
+    /// Same as `cpu_peek` for boards where reads have no side effects
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    /// False when nothing on the cartridge takes the write
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool;
+
+    /// Offset into PRG-ROM of the byte mapped at the CPU address
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize>;
+
+    fn prg_rom_len(&self) -> usize;
+
+    fn chr_rom_len(&self) -> usize {
+        0
+    }
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Pattern table byte at $0000-$1FFF of the PPU bus
+    fn chr_read(&mut self, _addr: u16, _fetch: PpuFetch) -> u8 {
+        0
+    }
+
+    /// CHR-ROM offset `chr_read` fetches from, for the code/data logger
+    fn chr_rom_offset(&self, _addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        None
+    }
+
+    /// Writes to CHR-RAM, ignored by boards with CHR-ROM
+    fn chr_write(&mut self, _addr: u16, _data: u8) {}
+
+    /// Nametable byte at $2000-$2FFF of the PPU bus. Boards like MMC5 watch these
+    /// fetches to count scanlines.
+    fn nametable_read(&mut self, addr: u16, _fetch: PpuFetch) -> Nametable {
+        Nametable::Vram(vram_offset(self.mirroring(), addr))
+    }
+
+    /// None when the cartridge takes the write, else the VRAM offset to store it at
+    fn nametable_write(&mut self, addr: u16, _data: u8) -> Option<u16> {
+        Some(vram_offset(self.mirroring(), addr))
+    }
+
+    /// Writes to $2000-$3FFF of the CPU bus, for boards that snoop PPUCTRL and PPUMASK
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    /// Runs the board for one CPU cycle: IRQ counters, sound chips, disk drives
+    fn clock(&mut self) {}
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Output of the sound chip on the board, in the units of `Apu::output`
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    /// The disk drive of the Famicom Disk System, for switching sides
+    fn disk_drive(&mut self) -> Option<&mut Fds> {
+        None
+    }
+
+    /// Appends the PRG-RAM and the registers, see `savestate`
+    fn save_state(&self, out: &mut Vec<u8>);
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String>;
+}
+
+/// Mapper for the board of the ROM, an error for boards that are not emulated
+pub fn for_rom(rom: Rom) -> Result<Box<dyn Mapper>, String> {
+    Ok(match rom.mapper {
+        0 => Box::new(Nrom::new(rom)),
+        5 => Box::new(Mmc5::new(rom)),
+        7 => Box::new(Axrom::new(rom)),
+        9 | 10 => Box::new(Mmc2::new(rom)?),
+        11 => Box::new(ColorDreams::new(rom)),
+        21 | 22 | 23 | 25 => Box::new(Vrc4::new(rom)?),
+        24 | 26 => Box::new(Vrc6::new(rom)),
+        34 => Box::new(Bnrom::new(rom)),
+        66 => Box::new(Gxrom::new(rom)),
+        69 => Box::new(Fme7::new(rom)),
+        206 => Box::new(Namco118::new(rom)?),
+        mapper => return Err(format!("Mapper {} is not supported", mapper)),
+    })
+}
+
+// offset into a ROM of the byte at `addr` in a bank of `size` bytes; bank
+// numbers past the end wrap around like the unconnected address lines do
+pub(crate) fn bank_offset(len: usize, bank: usize, size: usize, addr: u16) -> usize {
+    (bank * size + addr as usize % size) % len.max(1)
+}
+
+/// CHR-ROM, or 8KiB of CHR-RAM on boards without it
+pub(crate) struct Chr {
+    data: Vec<u8>,
+    ram: bool,
+}
+
+impl Chr {
+    pub fn new(chr_rom: Vec<u8>) -> Self {
+        if chr_rom.is_empty() {
+            Chr {
+                data: vec![0; 0x2000],
+                ram: true,
+            }
+        } else {
+            Chr {
+                data: chr_rom,
+                ram: false,
+            }
+        }
+    }
+
+    pub fn read(&self, bank: usize, size: usize, addr: u16) -> u8 {
+        self.data[bank_offset(self.data.len(), bank, size, addr)]
+    }
+
+    // CHR-RAM has no place in the code/data log
+    pub fn rom_offset(&self, bank: usize, size: usize, addr: u16) -> Option<usize> {
+        (!self.ram).then(|| bank_offset(self.data.len(), bank, size, addr))
+    }
+
+    pub fn write(&mut self, bank: usize, size: usize, addr: u16, data: u8) {
+        if self.ram {
+            let offset = bank_offset(self.data.len(), bank, size, addr);
+            self.data[offset] = data;
+        }
+    }
+
+    pub fn rom_len(&self) -> usize {
+        if self.ram {
+            0
+        } else {
+            self.data.len()
+        }
+    }
+
+    /// CHR-RAM goes into save states, CHR-ROM does not
+    pub fn state_len(&self) -> usize {
+        if self.ram {
+            self.data.len()
+        } else {
+            0
+        }
+    }
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        if self.ram {
+            out.extend(&self.data);
+        }
+    }
+
+    // the `state_len` bytes at the start of `raw`, returns the rest
+    pub fn load_state<'a>(&mut self, raw: &'a [u8]) -> &'a [u8] {
+        let (data, rest) = raw.split_at(self.state_len());
+        if self.ram {
+            self.data.copy_from_slice(data);
+        }
+        rest
+    }
+}
+
+// save states keep `Mirroring` as its `as u8` value
+pub(crate) fn mirroring_from_state(value: u8) -> Mirroring {
+    [
+        Mirroring::VERTICAL,
+        Mirroring::HORIZONTAL,
+        Mirroring::FOUR_SCREEN,
+        Mirroring::SINGLE_SCREEN_LOWER,
+        Mirroring::SINGLE_SCREEN_UPPER,
+    ]
+    .into_iter()
+    .find(|mirroring| *mirroring as u8 == value)
+    .unwrap_or(Mirroring::VERTICAL)
+}
+
+// for boards whose fixed banks are counted from the end of PRG-ROM
+pub(crate) fn check_prg_size(rom: &Rom, min: usize) -> Result<(), String> {
+    if rom.prg_rom.len() < min {
+        return Err(format!(
+            "PRG-ROM has {} bytes, mapper {} needs at least {}",
+            rom.prg_rom.len(),
+            rom.mapper,
+            min
+        ));
+    }
+    Ok(())
+}
+
+// the length check shared by `load_state` implementations
+pub(crate) fn check_state_size(raw: &[u8], expected: usize) -> Result<(), String> {
+    if raw.len() != expected {
+        return Err(format!(
+            "mapper state has {} bytes, expected {}",
+            raw.len(),
+            expected
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::for_rom;
+    use crate::region::Region;
+    use crate::rom::{Mirroring, Rom};
+
+    // every byte holds the number of its 8KiB PRG bank or 1KiB CHR bank
+    pub fn banked_rom(mapper: u8, prg_kib: usize, chr_kib: usize) -> Rom {
+        Rom {
+            prg_rom: (0..prg_kib * 1024).map(|i| (i / 0x2000) as u8).collect(),
+            chr_rom: (0..chr_kib * 1024).map(|i| (i / 0x400) as u8).collect(),
+            mapper,
+            screen_mirroring: Mirroring::VERTICAL,
+            battery: false,
+            region: Region::Ntsc,
+            nes2: false,
+        }
+    }
+
+    #[test]
+    fn test_for_rom() {
+        assert!(for_rom(banked_rom(0, 32, 8)).is_ok());
+        assert_eq!(
+            for_rom(banked_rom(1, 128, 128)).err(),
+            Some("Mapper 1 is not supported".to_string())
+        );
+        for (mapper, prg_kib, min) in [(9, 16, 0x8000), (23, 8, 0x4000), (206, 8, 0x4000)] {
+            assert_eq!(
+                for_rom(banked_rom(mapper, prg_kib, 8)).err(),
+                Some(format!(
+                    "PRG-ROM has {} bytes, mapper {} needs at least {}",
+                    prg_kib * 1024,
+                    mapper,
+                    min
+                ))
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use super::{bank_offset, check_prg_size, check_state_size, Chr, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const BANK_SELECT: u16 = 0x8000;
+const BANK_SELECT_END: u16 = 0x9FFF;
+
+/// Namco 118 and DxROM, mapper 206: the MMC3 banking without its extras. Even
+/// addresses in $8000-$9FFF select one of the eight bank registers, odd ones
+/// write it: two 2KiB and four 1KiB CHR banks, two 8KiB PRG banks.
+pub struct Namco118 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring,
+    selected: u8,
+    banks: [u8; 8],
+}
+
+impl Namco118 {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        // the two fixed banks
+        check_prg_size(&rom, 0x4000)?;
+        Ok(Namco118 {
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            mirroring: rom.screen_mirroring,
+            selected: 0,
+            banks: [0; 8],
+        })
+    }
+
+    // in 1KiB units
+    fn chr_bank(&self, addr: u16) -> usize {
+        let slot = (addr as usize >> 10) & 7;
+        match slot {
+            0..=3 => (self.banks[slot / 2] & 0x3E) as usize + slot % 2,
+            _ => self.banks[slot - 2] as usize,
+        }
+    }
+}
+
+impl Mapper for Namco118 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        Some(self.prg_rom[self.prg_rom_offset(addr)?])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            BANK_SELECT..=BANK_SELECT_END if addr & 1 == 0 => self.selected = data & 0x07,
+            BANK_SELECT..=BANK_SELECT_END => {
+                let mask = if self.selected >= 6 { 0x0F } else { 0x3F };
+                self.banks[self.selected as usize] = data & mask;
+            }
+            // no registers at $A000-$FFFF
+            0xA000..=PRG_ROM_END => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let last = len / 0x2000 - 1;
+        let bank = match addr {
+            PRG_ROM..=0x9FFF => self.banks[6] as usize,
+            0xA000..=0xBFFF => self.banks[7] as usize,
+            0xC000..=0xDFFF => last - 1,
+            0xE000..=PRG_ROM_END => last,
+            _ => return None,
+        };
+        Some(bank_offset(len, bank, 0x2000, addr))
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.chr.read(self.chr_bank(addr), 0x400, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        self.chr.rom_offset(self.chr_bank(addr), 0x400, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank(addr), 0x400, addr, data);
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.chr.save_state(out);
+        out.push(self.selected);
+        out.extend(self.banks);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.chr.state_len() + 1 + 8)?;
+        let raw = self.chr.load_state(raw);
+        self.selected = raw[0];
+        self.banks.copy_from_slice(&raw[1..9]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut namco = Namco118::new(banked_rom(206, 128, 64)).unwrap();
+        for (register, bank) in [(0, 5), (1, 8), (2, 20), (5, 33), (6, 3), (7, 0x14)] {
+            namco.cpu_write(0x8000, register);
+            namco.cpu_write(0x8001, bank);
+        }
+        // 2KiB banks ignore the low bit
+        assert_eq!(namco.chr_read(0x0000, PpuFetch::Background), 4);
+        assert_eq!(namco.chr_read(0x0400, PpuFetch::Background), 5);
+        assert_eq!(namco.chr_read(0x0800, PpuFetch::Background), 8);
+        assert_eq!(namco.chr_read(0x1000, PpuFetch::Sprite), 20);
+        assert_eq!(namco.chr_read(0x1C00, PpuFetch::Sprite), 33);
+        assert_eq!(namco.cpu_peek(0x8000), Some(3));
+        // 4 bits of PRG bank
+        assert_eq!(namco.cpu_peek(0xA000), Some(4));
+        assert_eq!(namco.cpu_peek(0xC000), Some(14));
+        assert_eq!(namco.cpu_peek(0xE000), Some(15));
+        // $A000-$FFFF has no registers
+        namco.cpu_write(0xA000, 0);
+        namco.cpu_write(0xA001, 0);
+        assert_eq!(namco.cpu_peek(0x8000), Some(3));
+    }
+}
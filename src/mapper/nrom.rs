@@ -0,0 +1,96 @@
+use super::{bank_offset, check_state_size, Mapper, PpuFetch};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 0: 16KiB or 32KiB of PRG-ROM without banking and 8KiB of PRG-RAM
+pub struct Nrom {
+    rom: Rom,
+    prg_ram: [u8; 0x2000],
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        Nrom {
+            rom,
+            prg_ram: [0; 0x2000],
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => Some(self.prg_ram[(addr - PRG_RAM) as usize]),
+            PRG_ROM..=PRG_ROM_END => Some(self.rom.prg_rom[self.prg_rom_offset(addr)?]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            // smaller PRG-ROMs are mirrored
+            PRG_ROM..=PRG_ROM_END => Some(bank_offset(self.rom.prg_rom.len(), 0, 0x8000, addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.rom.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.rom.chr_rom.len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.screen_mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.rom.chr_rom.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        ((addr as usize) < self.rom.chr_rom.len()).then_some(addr as usize)
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(raw, self.prg_ram.len())?;
+        self.prg_ram.copy_from_slice(raw);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_mirroring() {
+        for prg_kib in [8, 16, 32] {
+            let nrom = Nrom::new(banked_rom(0, prg_kib, 8));
+            assert_eq!(nrom.cpu_peek(0x8000), Some(0));
+            assert_eq!(nrom.cpu_peek(0xFFFC), Some(prg_kib as u8 / 8 - 1));
+            assert_eq!(nrom.prg_rom_offset(0xFFFC), Some(0x7FFC % (prg_kib * 1024)));
+        }
+    }
+}
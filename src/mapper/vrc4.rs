@@ -0,0 +1,361 @@
+use super::{
+    bank_offset, check_prg_size, check_state_size, mirroring_from_state, Chr, Mapper, PpuFetch,
+};
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+// registers after the address lines are sorted out
+const PRG_BANK0: u16 = 0x8000;
+const MIRRORING: u16 = 0x9000;
+const PRG_MODE: u16 = 0x9002;
+const PRG_BANK1: u16 = 0xA000;
+// $B000-$E003: low and high nibbles of the eight 1KiB banks
+const CHR_BANKS: u16 = 0xB000;
+const CHR_BANKS_END: u16 = 0xE003;
+const IRQ_LATCH_LOW: u16 = 0xF000;
+const IRQ_LATCH_HIGH: u16 = 0xF001;
+const IRQ_CONTROL: u16 = 0xF002;
+const IRQ_ACKNOWLEDGE: u16 = 0xF003;
+
+/// The IRQ counter of the VRC4, VRC6 and VRC7: counts up from the latch to $FF,
+/// in CPU cycles or in scanlines of 341/3 CPU cycles
+#[derive(Default)]
+pub(super) struct VrcIrq {
+    pub latch: u8,
+    counter: u8,
+    enabled: bool,
+    enabled_after_ack: bool,
+    cycle_mode: bool,
+    prescaler: i16,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn write_control(&mut self, data: u8) {
+        self.enabled_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+        self.pending = false;
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_ack;
+    }
+
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += 341;
+        }
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend([self.latch, self.counter]);
+        out.extend(self.prescaler.to_le_bytes());
+        out.extend(
+            [
+                self.enabled,
+                self.enabled_after_ack,
+                self.cycle_mode,
+                self.pending,
+            ]
+            .map(u8::from),
+        );
+    }
+
+    pub const STATE_LEN: usize = 8;
+
+    pub fn load_state(&mut self, raw: &[u8]) {
+        self.latch = raw[0];
+        self.counter = raw[1];
+        self.prescaler = i16::from_le_bytes([raw[2], raw[3]]);
+        self.enabled = raw[4] != 0;
+        self.enabled_after_ack = raw[5] != 0;
+        self.cycle_mode = raw[6] != 0;
+        self.pending = raw[7] != 0;
+    }
+}
+
+/// Konami VRC2 and VRC4, mappers 21, 22, 23 and 25: two switchable 8KiB PRG
+/// banks, eight 1KiB CHR banks and the VRC IRQ. The boards wire different CPU
+/// address lines to the register select inputs; each mapper number covers the
+/// variants of both wirings.
+pub struct Vrc4 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    mapper: u8,
+    prg_ram: [u8; 0x2000],
+    prg_banks: [u8; 2],
+    // $8000 and $C000 swapped
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        // the two fixed banks
+        check_prg_size(&rom, 0x4000)?;
+        Ok(Vrc4 {
+            mapper: rom.mapper,
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            prg_ram: [0; 0x2000],
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: rom.screen_mirroring,
+            irq: VrcIrq::default(),
+        })
+    }
+
+    // $x000-$x003 out of the board's address lines
+    fn register(&self, addr: u16) -> u16 {
+        let line = |n: u16| (addr >> n) & 1;
+        let (a0, a1) = match self.mapper {
+            21 => (line(1) | line(6), line(2) | line(7)),
+            22 => (line(1), line(0)),
+            23 => (line(0) | line(2), line(1) | line(3)),
+            _ => (line(1) | line(3), line(0) | line(2)),
+        };
+        (addr & 0xF000) | a1 << 1 | a0
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7] as usize;
+        // VRC2a ignores the low bit of the bank number
+        if self.mapper == 22 {
+            bank >> 1
+        } else {
+            bank
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END => Some(self.prg_ram[(addr - PRG_RAM) as usize]),
+            _ => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        if let PRG_RAM..=PRG_RAM_END = addr {
+            self.prg_ram[(addr - PRG_RAM) as usize] = data;
+            return true;
+        }
+        if addr < PRG_ROM {
+            return false;
+        }
+        match self.register(addr) {
+            PRG_BANK0..=0x8003 => self.prg_banks[0] = data & 0x1F,
+            MIRRORING..=0x9001 => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::VERTICAL,
+                    1 => Mirroring::HORIZONTAL,
+                    2 => Mirroring::SINGLE_SCREEN_LOWER,
+                    _ => Mirroring::SINGLE_SCREEN_UPPER,
+                }
+            }
+            PRG_MODE..=0x9003 => self.prg_swap = data & 0x02 != 0,
+            PRG_BANK1..=0xA003 => self.prg_banks[1] = data & 0x1F,
+            register @ CHR_BANKS..=CHR_BANKS_END => {
+                let index =
+                    ((register - CHR_BANKS) >> 12) as usize * 2 + (register as usize & 3) / 2;
+                let bank = &mut self.chr_banks[index];
+                *bank = if register & 1 == 0 {
+                    (*bank & 0x1F0) | (data as u16 & 0x0F)
+                } else {
+                    (*bank & 0x0F) | (data as u16 & 0x1F) << 4
+                };
+            }
+            IRQ_LATCH_LOW => self.irq.latch = (self.irq.latch & 0xF0) | (data & 0x0F),
+            IRQ_LATCH_HIGH => self.irq.latch = (self.irq.latch & 0x0F) | (data << 4),
+            IRQ_CONTROL => self.irq.write_control(data),
+            IRQ_ACKNOWLEDGE => self.irq.acknowledge(),
+            _ => {}
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        let second_last = len / 0x2000 - 2;
+        let bank = match addr {
+            PRG_ROM..=0x9FFF if self.prg_swap => second_last,
+            PRG_ROM..=0x9FFF => self.prg_banks[0] as usize,
+            0xA000..=0xBFFF => self.prg_banks[1] as usize,
+            0xC000..=0xDFFF if self.prg_swap => self.prg_banks[0] as usize,
+            0xC000..=0xDFFF => second_last,
+            0xE000..=PRG_ROM_END => second_last + 1,
+            _ => return None,
+        };
+        Some(bank_offset(len, bank, 0x2000, addr))
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        self.chr.read(self.chr_bank(addr), 0x400, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        self.chr.rom_offset(self.chr_bank(addr), 0x400, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank(addr), 0x400, addr, data);
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        self.chr.save_state(out);
+        out.extend(self.prg_banks);
+        for bank in self.chr_banks {
+            out.extend(bank.to_le_bytes());
+        }
+        out.push(self.prg_swap as u8);
+        out.push(self.mirroring as u8);
+        self.irq.save_state(out);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(
+            raw,
+            self.prg_ram.len() + self.chr.state_len() + 2 + 16 + 2 + VrcIrq::STATE_LEN,
+        )?;
+        let (ram, raw) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        let raw = self.chr.load_state(raw);
+        self.prg_banks = [raw[0], raw[1]];
+        for (n, bank) in self.chr_banks.iter_mut().enumerate() {
+            *bank = u16::from_le_bytes([raw[2 + n * 2], raw[3 + n * 2]]);
+        }
+        self.prg_swap = raw[18] != 0;
+        self.mirroring = mirroring_from_state(raw[19]);
+        self.irq.load_state(&raw[20..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_prg_banks() {
+        let mut vrc4 = Vrc4::new(banked_rom(21, 256, 256)).unwrap();
+        vrc4.cpu_write(0x8000, 3);
+        vrc4.cpu_write(0xA000, 4);
+        assert_eq!(vrc4.cpu_peek(0x8000), Some(3));
+        assert_eq!(vrc4.cpu_peek(0xA000), Some(4));
+        assert_eq!(vrc4.cpu_peek(0xC000), Some(30));
+        assert_eq!(vrc4.cpu_peek(0xE000), Some(31));
+        // VRC4a selects $9002 with A2
+        vrc4.cpu_write(0x9004, 0x02);
+        assert_eq!(vrc4.cpu_peek(0x8000), Some(30));
+        assert_eq!(vrc4.cpu_peek(0xC000), Some(3));
+        // and $9001 with A6, the VRC4c wiring
+        vrc4.cpu_write(0x9040, 0x03);
+        assert_eq!(vrc4.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+        vrc4.cpu_write(0x6000, 0x12);
+        assert_eq!(vrc4.cpu_peek(0x6000), Some(0x12));
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        // the addresses that select $x002 and $x001 on each wiring
+        for (mapper, a1, a0) in [
+            (21, 0xB004, 0xB002),
+            (21, 0xB080, 0xB040),
+            (23, 0xB002, 0xB001),
+            (23, 0xB008, 0xB004),
+            (25, 0xB001, 0xB002),
+            (25, 0xB004, 0xB008),
+        ] {
+            let mut vrc4 = Vrc4::new(banked_rom(mapper, 128, 512)).unwrap();
+            // bank 1 at $0400: $B002 and $B003
+            vrc4.cpu_write(a1, 0x05);
+            vrc4.cpu_write(a1 | a0, 0x11);
+            assert_eq!(vrc4.chr_banks[1], 0x115, "mapper {}", mapper);
+            assert_eq!(vrc4.chr_read(0x0400, PpuFetch::Background), 0x15);
+        }
+
+        let mut vrc2a = Vrc4::new(banked_rom(22, 128, 128)).unwrap();
+        vrc2a.cpu_write(0xC000, 0x07);
+        assert_eq!(vrc2a.chr_read(0x0800, PpuFetch::Background), 3);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut vrc4 = Vrc4::new(banked_rom(23, 128, 128)).unwrap();
+        vrc4.cpu_write(0xF000, 0x0C);
+        vrc4.cpu_write(0xF001, 0x0F);
+        // cycle mode: $FC, $FD, $FE, $FF, then the IRQ
+        vrc4.cpu_write(0xF002, 0x07);
+        for _ in 0..3 {
+            vrc4.clock();
+        }
+        assert!(!vrc4.irq());
+        vrc4.clock();
+        assert!(vrc4.irq());
+        vrc4.cpu_write(0xF003, 0);
+        assert!(!vrc4.irq());
+
+        // scanline mode: 341 / 3 CPU cycles per count
+        vrc4.cpu_write(0xF001, 0x0F);
+        vrc4.cpu_write(0xF000, 0x0E);
+        vrc4.cpu_write(0xF002, 0x02);
+        let mut cycles = 0;
+        while !vrc4.irq() {
+            vrc4.clock();
+            cycles += 1;
+        }
+        assert_eq!(cycles, 228);
+    }
+}
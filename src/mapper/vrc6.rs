@@ -0,0 +1,242 @@
+use super::vrc4::VrcIrq;
+use super::{bank_offset, check_state_size, mirroring_from_state, Chr, Mapper, PpuFetch};
+use crate::apu::vrc6::Vrc6Audio;
+use crate::apu::ExpansionAudio;
+use crate::rom::{Mirroring, Rom};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+// registers after the address lines are sorted out
+const PRG_BANK_16K: u16 = 0x8000;
+const BANKING_CONTROL: u16 = 0xB003;
+const PRG_BANK_8K: u16 = 0xC000;
+// $D000-$E003: eight 1KiB banks
+const CHR_BANKS: u16 = 0xD000;
+const CHR_BANKS_END: u16 = 0xE003;
+const IRQ_LATCH: u16 = 0xF000;
+const IRQ_CONTROL: u16 = 0xF001;
+const IRQ_ACKNOWLEDGE: u16 = 0xF002;
+
+/// Konami VRC6, mappers 24 and 26 (A0 and A1 swapped): a 16KiB and an 8KiB PRG
+/// bank, eight 1KiB CHR banks, the VRC IRQ and three sound channels. Only the
+/// usual PPU banking mode of $B003 is emulated.
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    swapped_lines: bool,
+    prg_ram: [u8; 0x2000],
+    prg_ram_enabled: bool,
+    prg_banks: [u8; 2],
+    chr_banks: [u8; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(rom: Rom) -> Self {
+        Vrc6 {
+            swapped_lines: rom.mapper == 26,
+            prg_rom: rom.prg_rom,
+            chr: Chr::new(rom.chr_rom),
+            prg_ram: [0; 0x2000],
+            prg_ram_enabled: false,
+            prg_banks: [0; 2],
+            chr_banks: [0; 8],
+            mirroring: rom.screen_mirroring,
+            irq: VrcIrq::default(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn register(&self, addr: u16) -> u16 {
+        if self.swapped_lines {
+            (addr & 0xF000) | (addr & 1) << 1 | (addr >> 1) & 1
+        } else {
+            addr & 0xF003
+        }
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled => {
+                Some(self.prg_ram[(addr - PRG_RAM) as usize])
+            }
+            _ => Some(self.prg_rom[self.prg_rom_offset(addr)?]),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+                return true;
+            }
+            PRG_ROM..=PRG_ROM_END => {}
+            _ => return false,
+        }
+        let register = self.register(addr);
+        if self.audio.write(register, data) {
+            return true;
+        }
+        match register {
+            PRG_BANK_16K..=0x8003 => self.prg_banks[0] = data & 0x0F,
+            BANKING_CONTROL => {
+                self.prg_ram_enabled = data & 0x80 != 0;
+                self.mirroring = match (data >> 2) & 0x03 {
+                    0 => Mirroring::VERTICAL,
+                    1 => Mirroring::HORIZONTAL,
+                    2 => Mirroring::SINGLE_SCREEN_LOWER,
+                    _ => Mirroring::SINGLE_SCREEN_UPPER,
+                };
+            }
+            PRG_BANK_8K..=0xC003 => self.prg_banks[1] = data & 0x1F,
+            CHR_BANKS..=CHR_BANKS_END => {
+                let index = ((register - CHR_BANKS) >> 12) as usize * 4 + (register as usize & 3);
+                self.chr_banks[index] = data;
+            }
+            IRQ_LATCH => self.irq.latch = data,
+            IRQ_CONTROL => self.irq.write_control(data),
+            IRQ_ACKNOWLEDGE => self.irq.acknowledge(),
+            _ => {}
+        }
+        true
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let len = self.prg_rom.len();
+        match addr {
+            PRG_ROM..=0xBFFF => Some(bank_offset(len, self.prg_banks[0] as usize, 0x4000, addr)),
+            0xC000..=0xDFFF => Some(bank_offset(len, self.prg_banks[1] as usize, 0x2000, addr)),
+            0xE000..=PRG_ROM_END => Some(bank_offset(len, len / 0x2000 - 1, 0x2000, addr)),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.prg_rom.len()
+    }
+
+    fn chr_rom_len(&self) -> usize {
+        self.chr.rom_len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn chr_read(&mut self, addr: u16, _fetch: PpuFetch) -> u8 {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.read(bank as usize, 0x400, addr)
+    }
+
+    fn chr_rom_offset(&self, addr: u16, _fetch: PpuFetch) -> Option<usize> {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.rom_offset(bank as usize, 0x400, addr)
+    }
+
+    fn chr_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        self.chr.write(bank as usize, 0x400, addr, data);
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        self.chr.save_state(out);
+        out.extend(self.prg_banks);
+        out.extend(self.chr_banks);
+        out.push(self.prg_ram_enabled as u8);
+        out.push(self.mirroring as u8);
+        self.irq.save_state(out);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        check_state_size(
+            raw,
+            self.prg_ram.len() + self.chr.state_len() + 2 + 8 + 2 + VrcIrq::STATE_LEN,
+        )?;
+        let (ram, raw) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        let raw = self.chr.load_state(raw);
+        self.prg_banks = [raw[0], raw[1]];
+        self.chr_banks.copy_from_slice(&raw[2..10]);
+        self.prg_ram_enabled = raw[10] != 0;
+        self.mirroring = mirroring_from_state(raw[11]);
+        self.irq.load_state(&raw[12..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::banked_rom;
+
+    #[test]
+    fn test_banks() {
+        let mut vrc6 = Vrc6::new(banked_rom(24, 256, 256));
+        vrc6.cpu_write(0x8000, 2);
+        vrc6.cpu_write(0xC000, 9);
+        assert_eq!(vrc6.cpu_peek(0x8000), Some(4));
+        assert_eq!(vrc6.cpu_peek(0xA000), Some(5));
+        assert_eq!(vrc6.cpu_peek(0xC000), Some(9));
+        assert_eq!(vrc6.cpu_peek(0xE000), Some(31));
+        vrc6.cpu_write(0xE002, 0x42);
+        assert_eq!(vrc6.chr_read(0x1800, PpuFetch::Background), 0x42);
+
+        assert_eq!(vrc6.cpu_peek(0x6000), None);
+        vrc6.cpu_write(0xB003, 0x80 | 0x04);
+        assert_eq!(vrc6.mirroring(), Mirroring::HORIZONTAL);
+        vrc6.cpu_write(0x6000, 0x31);
+        assert_eq!(vrc6.cpu_peek(0x6000), Some(0x31));
+    }
+
+    #[test]
+    fn test_swapped_lines() {
+        let mut vrc6 = Vrc6::new(banked_rom(26, 256, 256));
+        // $D001 on mapper 24
+        vrc6.cpu_write(0xD002, 7);
+        assert_eq!(vrc6.chr_read(0x0400, PpuFetch::Background), 7);
+        // $B003
+        vrc6.cpu_write(0xB003, 0x0C);
+        assert_eq!(vrc6.mirroring(), Mirroring::SINGLE_SCREEN_UPPER);
+        // $F001 and $F000
+        vrc6.cpu_write(0xF000, 0xFF);
+        vrc6.cpu_write(0xF002, 0x06);
+        vrc6.clock();
+        assert!(vrc6.irq());
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut vrc6 = Vrc6::new(banked_rom(24, 128, 128));
+        // the saw at full rate
+        vrc6.cpu_write(0xB000, 0x3F);
+        vrc6.cpu_write(0xB001, 0x00);
+        vrc6.cpu_write(0xB002, 0x80);
+        let mut loudest = 0f32;
+        for _ in 0..100 {
+            vrc6.clock();
+            loudest = loudest.max(vrc6.audio_output());
+        }
+        assert!(loudest > 0.0);
+        assert_eq!(vrc6.prg_banks, [0, 0]);
+    }
+}
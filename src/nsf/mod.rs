@@ -0,0 +1,299 @@
+use crate::region::Region;
+
+mod player;
+
+pub use player::NsfPlayer;
+
+const NSF_TAG: &[u8] = b"NESM\x1a";
+const NSFE_TAG: &[u8] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// bits of `Nsf::expansion`
+pub const VRC6: u8 = 0x01;
+pub const VRC7: u8 = 0x02;
+pub const FDS: u8 = 0x04;
+pub const MMC5: u8 = 0x08;
+pub const N163: u8 = 0x10;
+pub const SUNSOFT_5B: u8 = 0x20;
+
+/// A music rip: the game's sound driver and data, and the addresses of the routines
+/// that start a song (INIT) and advance it by one tick (PLAY)
+#[derive(Debug, Default)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub songs: u8,
+    // 0-based
+    pub starting_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    // microseconds between PLAY calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // 4KiB banks at $8000-$FFFF, all zero when the rip does not bankswitch
+    pub bank_init: [u8; 8],
+    pub region: Region,
+    // sound chips of the cartridge, see `VRC6` and the other bits
+    pub expansion: u8,
+    pub data: Vec<u8>,
+    // NSFe track titles and lengths in milliseconds
+    pub track_titles: Vec<String>,
+    pub track_times: Vec<Option<u32>>,
+    pub track_fades: Vec<Option<u32>>,
+}
+
+impl Nsf {
+    pub fn load(path: &str) -> Result<Nsf, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Nsf::parse(&raw).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// `.nsf` or `.nsfe` file contents
+    pub fn parse(raw: &[u8]) -> Result<Nsf, String> {
+        let nsf = if raw.starts_with(NSF_TAG) {
+            parse_nsf(raw)?
+        } else if raw.starts_with(NSFE_TAG) {
+            parse_nsfe(raw)?
+        } else {
+            return Err("File is not in NSF or NSFe format".to_string());
+        };
+        // the player only maps the rip at $8000-$FFFF
+        if nsf.expansion & FDS != 0 {
+            return Err("FDS rips are not supported, they need RAM at $6000-$DFFF".to_string());
+        }
+        if nsf.load_address < 0x8000 {
+            return Err(format!(
+                "load address ${:04X} is below $8000",
+                nsf.load_address
+            ));
+        }
+        Ok(nsf)
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|bank| *bank != 0)
+    }
+
+    /// CPU cycles between PLAY calls
+    pub fn play_period(&self, region: Region) -> f64 {
+        let speed = match region {
+            Region::Ntsc => self.ntsc_speed,
+            Region::Pal | Region::Dendy => self.pal_speed,
+        };
+        if speed == 0 {
+            region.cycles_per_frame()
+        } else {
+            speed as f64 * region.cpu_frequency() / 1_000_000.0
+        }
+    }
+
+    pub fn track_title(&self, song: u8) -> Option<&str> {
+        let title = self.track_titles.get(song as usize)?;
+        Some(title.as_str()).filter(|title| !title.is_empty())
+    }
+}
+
+fn u16_at(raw: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([raw[at], raw[at + 1]])
+}
+
+fn string(raw: &[u8]) -> String {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).to_string()
+}
+
+// bit 0 of the PAL/NTSC byte asks for PAL, bit 1 says both work
+fn region(flags: u8) -> Region {
+    if flags & 0b11 == 1 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+fn parse_nsf(raw: &[u8]) -> Result<Nsf, String> {
+    if raw.len() < NSF_HEADER_SIZE {
+        return Err("NSF file is truncated".to_string());
+    }
+    let mut bank_init = [0; 8];
+    bank_init.copy_from_slice(&raw[0x70..0x78]);
+    Ok(Nsf {
+        title: string(&raw[0x0E..0x2E]),
+        artist: string(&raw[0x2E..0x4E]),
+        copyright: string(&raw[0x4E..0x6E]),
+        songs: raw[0x06],
+        starting_song: raw[0x07].saturating_sub(1),
+        load_address: u16_at(raw, 0x08),
+        init_address: u16_at(raw, 0x0A),
+        play_address: u16_at(raw, 0x0C),
+        ntsc_speed: u16_at(raw, 0x6E),
+        pal_speed: u16_at(raw, 0x78),
+        bank_init,
+        region: region(raw[0x7A]),
+        expansion: raw[0x7B],
+        data: raw[NSF_HEADER_SIZE..].to_vec(),
+        ..Default::default()
+    })
+}
+
+// chunks of a 32 bit length, a 4 byte id and the data. Chunks with an uppercase
+// first letter must be understood, others can be skipped.
+fn parse_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+    let mut nsf = Nsf::default();
+    let mut info = false;
+    let mut pos = NSFE_TAG.len();
+    loop {
+        let header = raw.get(pos..pos + 8).ok_or("NSFe file is truncated")?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let id = &header[4..8];
+        let data = raw
+            .get(pos + 8..pos + 8 + len)
+            .ok_or("NSFe file is truncated")?;
+        pos += 8 + len;
+
+        match id {
+            b"INFO" => {
+                if data.len() < 8 {
+                    return Err("NSFe INFO chunk is too short".to_string());
+                }
+                nsf.load_address = u16_at(data, 0);
+                nsf.init_address = u16_at(data, 2);
+                nsf.play_address = u16_at(data, 4);
+                nsf.region = region(data[6]);
+                nsf.expansion = data[7];
+                nsf.songs = data.get(8).copied().unwrap_or(1);
+                nsf.starting_song = data.get(9).copied().unwrap_or(0);
+                info = true;
+            }
+            b"DATA" => nsf.data = data.to_vec(),
+            b"BANK" => {
+                let len = data.len().min(8);
+                nsf.bank_init[..len].copy_from_slice(&data[..len]);
+            }
+            b"RATE" if data.len() >= 2 => {
+                nsf.ntsc_speed = u16_at(data, 0);
+                nsf.pal_speed = if data.len() >= 4 { u16_at(data, 2) } else { 0 };
+            }
+            b"auth" => {
+                let mut fields = data.split(|b| *b == 0).map(string);
+                nsf.title = fields.next().unwrap_or_default();
+                nsf.artist = fields.next().unwrap_or_default();
+                nsf.copyright = fields.next().unwrap_or_default();
+            }
+            b"tlbl" => {
+                let labels = data.strip_suffix(&[0]).unwrap_or(data);
+                nsf.track_titles = labels.split(|b| *b == 0).map(string).collect();
+            }
+            b"time" | b"fade" => {
+                let times = data
+                    .chunks_exact(4)
+                    .map(|t| i32::from_le_bytes([t[0], t[1], t[2], t[3]]))
+                    .map(|t| u32::try_from(t).ok())
+                    .collect();
+                if id == b"time" {
+                    nsf.track_times = times;
+                } else {
+                    nsf.track_fades = times;
+                }
+            }
+            b"NEND" => break,
+            _ if id[0].is_ascii_uppercase() => {
+                return Err(format!(
+                    "unsupported NSFe chunk {}",
+                    String::from_utf8_lossy(id)
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    if !info {
+        return Err("NSFe file has no INFO chunk".to_string());
+    }
+    if nsf.data.is_empty() {
+        return Err("NSFe file has no DATA chunk".to_string());
+    }
+    Ok(nsf)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// NSF with the program assembled at $8000, INIT at $8000 and PLAY at $8003
+    pub fn test_nsf(program: &str) -> Vec<u8> {
+        let program = crate::asm::assemble_at(0x8000, program).unwrap();
+        let mut raw = NSF_TAG.to_vec();
+        raw.extend([1, 3, 2]);
+        raw.extend(0x8000u16.to_le_bytes());
+        raw.extend(0x8000u16.to_le_bytes());
+        raw.extend(0x8003u16.to_le_bytes());
+        for text in ["Song", "Composer", "2024"] {
+            let mut field = text.as_bytes().to_vec();
+            field.resize(32, 0);
+            raw.extend(field);
+        }
+        raw.extend(16639u16.to_le_bytes());
+        raw.extend([0; 8]);
+        raw.extend(19997u16.to_le_bytes());
+        raw.extend([0, 0, 0, 0, 0, 0]);
+        raw.extend(program.code);
+        raw
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let nsf = Nsf::parse(&test_nsf("RTS\nRTS")).unwrap();
+        assert_eq!(nsf.title, "Song");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!((nsf.songs, nsf.starting_song), (3, 1));
+        assert_eq!(nsf.play_address, 0x8003);
+        assert_eq!(nsf.data, vec![0x60, 0x60]);
+        assert!(!nsf.is_bankswitched());
+        assert!((nsf.play_period(Region::Ntsc) - 29780.0).abs() < 1.0);
+
+        let mut raw = test_nsf("RTS\nRTS");
+        raw[0x7B] = FDS;
+        assert_eq!(
+            Nsf::parse(&raw).err(),
+            Some("FDS rips are not supported, they need RAM at $6000-$DFFF".to_string())
+        );
+        let mut raw = test_nsf("RTS\nRTS");
+        raw[0x09] = 0x60;
+        assert_eq!(
+            Nsf::parse(&raw).err(),
+            Some("load address $6000 is below $8000".to_string())
+        );
+    }
+
+    #[test]
+    fn test_nsfe() {
+        let mut raw = NSFE_TAG.to_vec();
+        let mut chunk = |id: &[u8], data: &[u8]| {
+            raw.extend((data.len() as u32).to_le_bytes());
+            raw.extend(id);
+            raw.extend(data);
+        };
+        chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 1, 0, 2, 1]);
+        chunk(b"DATA", &[0x60]);
+        chunk(b"auth", b"Game\0Composer\0\0Ripper\0");
+        chunk(b"tlbl", b"Intro\0Boss\0");
+        chunk(b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        chunk(b"NEND", b"");
+        let nsf = Nsf::parse(&raw).unwrap();
+        assert_eq!(nsf.region, Region::Pal);
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.track_title(1), Some("Boss"));
+        assert_eq!(nsf.track_times, vec![Some(10_000), None]);
+
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(0u32.to_le_bytes());
+        raw.extend(b"VRC7");
+        assert_eq!(
+            Nsf::parse(&raw).err(),
+            Some("unsupported NSFe chunk VRC7".to_string())
+        );
+    }
+}
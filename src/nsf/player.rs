@@ -0,0 +1,375 @@
+use super::{Nsf, MMC5, N163, SUNSOFT_5B, VRC6, VRC7};
+use crate::apu::mmc5::Mmc5Audio;
+use crate::apu::n163::N163Audio;
+use crate::apu::sunsoft5b::Sunsoft5bAudio;
+use crate::apu::vrc6::Vrc6Audio;
+use crate::apu::vrc7::Vrc7Audio;
+use crate::apu::ExpansionAudio;
+use crate::bus::Bus;
+use crate::cpu::cpu::CPU;
+use crate::cpu::mem::{BusHooks, Mem};
+use crate::mapper::{self, Mapper};
+use crate::region::Region;
+use crate::rom::Mirroring;
+
+const BANK_REGISTERS: u16 = 0x5FF8;
+const BANK_REGISTERS_END: u16 = 0x5FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const BANK_SIZE: usize = 0x1000;
+
+// INIT and PLAY are called with this return address on the stack; nothing is
+// mapped there, so the player knows the routine returned once the PC gets to it
+const RETURN_ADDR: u16 = 0x4100;
+// routines that take longer than a second are considered stuck
+const MAX_CALL_SECONDS: f64 = 1.0;
+
+/// The cartridge of an NSF: the rip data in 4KiB banks switched by $5FF8-$5FFF,
+/// or laid out from the load address, 8KiB of RAM at $6000-$7FFF and the sound
+/// chips of the expansion bits
+struct NsfMapper {
+    data: Vec<u8>,
+    load_address: u16,
+    bankswitched: bool,
+    banks: [u8; 8],
+    prg_ram: [u8; 0x2000],
+    chips: Vec<Box<dyn ExpansionAudio>>,
+}
+
+impl NsfMapper {
+    fn new(nsf: &Nsf) -> Self {
+        let bankswitched = nsf.is_bankswitched();
+        let mut data = Vec::new();
+        if bankswitched {
+            // the load address is where the data starts in the first bank
+            data.resize(nsf.load_address as usize & (BANK_SIZE - 1), 0);
+        }
+        data.extend(&nsf.data);
+        NsfMapper {
+            data,
+            load_address: nsf.load_address,
+            bankswitched,
+            banks: nsf.bank_init,
+            prg_ram: [0; 0x2000],
+            chips: expansion_chips(nsf.expansion),
+        }
+    }
+}
+
+// `Nsf::parse` turns down FDS rips, they would need RAM up to $DFFF
+fn expansion_chips(expansion: u8) -> Vec<Box<dyn ExpansionAudio>> {
+    let mut chips: Vec<Box<dyn ExpansionAudio>> = Vec::new();
+    if expansion & VRC6 != 0 {
+        chips.push(Box::new(Vrc6Audio::new()));
+    }
+    if expansion & VRC7 != 0 {
+        chips.push(Box::new(Vrc7Audio::new()));
+    }
+    if expansion & MMC5 != 0 {
+        chips.push(Box::new(Mmc5Audio::new()));
+    }
+    if expansion & N163 != 0 {
+        chips.push(Box::new(N163Audio::new()));
+    }
+    if expansion & SUNSOFT_5B != 0 {
+        chips.push(Box::new(Sunsoft5bAudio::new()));
+    }
+    chips
+}
+
+impl Mapper for NsfMapper {
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.chips.iter().find_map(|chip| chip.peek(addr)) {
+            return Some(data);
+        }
+        match addr {
+            PRG_RAM..=PRG_RAM_END => Some(self.prg_ram[(addr - PRG_RAM) as usize]),
+            // unused parts of the address space read as 0
+            PRG_ROM..=PRG_ROM_END => Some(
+                self.prg_rom_offset(addr)
+                    .and_then(|offset| self.data.get(offset).copied())
+                    .unwrap_or(0),
+            ),
+            _ => None,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.chips.iter_mut().find_map(|chip| chip.read(addr)) {
+            return Some(data);
+        }
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        // the chips' registers overlap the ROM, e.g. $9000 of the VRC6
+        let mut handled = false;
+        for chip in self.chips.iter_mut() {
+            handled |= chip.write(addr, data);
+        }
+        if handled {
+            return true;
+        }
+        match addr {
+            BANK_REGISTERS..=BANK_REGISTERS_END => {
+                self.banks[(addr - BANK_REGISTERS) as usize] = data;
+                true
+            }
+            PRG_RAM..=PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END if self.bankswitched => {
+                let bank = self.banks[(addr - PRG_ROM) as usize / BANK_SIZE] as usize;
+                Some(bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1)))
+            }
+            PRG_ROM..=PRG_ROM_END => addr.checked_sub(self.load_address).map(usize::from),
+            _ => None,
+        }
+    }
+
+    fn prg_rom_len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::HORIZONTAL
+    }
+
+    fn clock(&mut self) {
+        for chip in self.chips.iter_mut() {
+            chip.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.chips.iter().map(|chip| chip.output()).sum()
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend(&self.prg_ram);
+        out.extend(&self.banks);
+    }
+
+    fn load_state(&mut self, raw: &[u8]) -> Result<(), String> {
+        mapper::check_state_size(raw, self.prg_ram.len() + self.banks.len())?;
+        let (ram, banks) = raw.split_at(self.prg_ram.len());
+        self.prg_ram.copy_from_slice(ram);
+        self.banks.copy_from_slice(banks);
+        Ok(())
+    }
+}
+
+/// Runs the sound driver of an NSF the way hardware players do: INIT once per
+/// song, then PLAY at the rate from the header, and collects the APU output
+pub struct NsfPlayer {
+    cpu: CPU,
+    nsf: Nsf,
+    region: Region,
+    // CPU cycle of the next PLAY call
+    next_play: f64,
+}
+
+impl NsfPlayer {
+    /// Plays in the region of the rip unless `region` is given
+    pub fn new(nsf: Nsf, region: Option<Region>) -> Self {
+        let region = region.unwrap_or(nsf.region);
+        let bus = Bus::with_mapper(Box::new(NsfMapper::new(&nsf)), region);
+        NsfPlayer {
+            cpu: CPU::new(bus),
+            nsf,
+            region,
+            next_play: 0.0,
+        }
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus_mut().apu().set_sample_rate(sample_rate);
+    }
+
+    /// Resets the machine and runs INIT for the 0-based song number
+    pub fn start_song(&mut self, song: u8) -> Result<(), String> {
+        if song >= self.nsf.songs {
+            return Err(format!(
+                "song {} is out of range, the NSF has {}",
+                song + 1,
+                self.nsf.songs
+            ));
+        }
+        for addr in (0x0000..0x0800).chain(PRG_RAM..=PRG_RAM_END) {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in 0x4000..=0x4013 {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(0x4015, 0);
+        self.cpu.mem_write(0x4015, 0x0F);
+        self.cpu.mem_write(0x4017, 0x40);
+        for (i, bank) in self.nsf.bank_init.into_iter().enumerate() {
+            self.cpu.mem_write(BANK_REGISTERS + i as u16, bank);
+        }
+        self.cpu.bus_mut().apu().take_samples();
+
+        self.cpu.register_a = song;
+        self.cpu.register_x = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal | Region::Dendy => 1,
+        };
+        self.cpu.register_y = 0;
+        self.call(self.nsf.init_address)?;
+        self.next_play = self.cpu.cycles as f64;
+        Ok(())
+    }
+
+    /// Plays for the given time and returns the samples
+    pub fn render(&mut self, seconds: f64) -> Result<Vec<f32>, String> {
+        let end = self.cpu.cycles as f64 + seconds * self.region.cpu_frequency();
+        let period = self.nsf.play_period(self.region);
+        let mut samples = self.cpu.bus_mut().apu().take_samples();
+        while (self.cpu.cycles as f64) < end {
+            if self.cpu.cycles as f64 >= self.next_play {
+                self.next_play += period;
+                self.call(self.nsf.play_address)?;
+            }
+            // the driver waits for the next PLAY call with the CPU idle
+            let idle = (self.next_play.min(end) - self.cpu.cycles as f64).ceil();
+            if idle > 0.0 {
+                self.cpu.cycles += idle as usize;
+                self.cpu.bus_mut().tick(idle as usize);
+            }
+            samples.extend(self.cpu.bus_mut().apu().take_samples());
+        }
+        Ok(samples)
+    }
+
+    // JSR from the return address, then runs until the matching RTS
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        let ret = RETURN_ADDR - 1;
+        let sp = self.cpu.stack_pointer;
+        self.cpu.mem_write(0x0100 + sp as u16, (ret >> 8) as u8);
+        self.cpu
+            .mem_write(0x0100 + sp.wrapping_sub(1) as u16, ret as u8);
+        self.cpu.stack_pointer = sp.wrapping_sub(2);
+        self.cpu.program_counter = addr;
+
+        let limit = self.cpu.cycles + (MAX_CALL_SECONDS * self.region.cpu_frequency()) as usize;
+        while self.cpu.program_counter != RETURN_ADDR {
+            if !self.cpu.step() {
+                return Err(format!("BRK in the routine at {:04X}", addr));
+            }
+            if self.cpu.cycles > limit {
+                return Err(format!("the routine at {:04X} does not return", addr));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::test::test_nsf;
+
+    // INIT keeps the song number, PLAY counts its calls and plays a pulse note
+    const PROGRAM: &str = "
+        JMP init
+        JMP play
+    init:
+        STA $10
+        RTS
+    play:
+        INC $11
+        LDA #$BF
+        STA $4000
+        LDA #$FD
+        STA $4002
+        LDA #$08
+        STA $4003
+        RTS
+    ";
+
+    #[test]
+    fn test_play() {
+        let nsf = Nsf::parse(&test_nsf(PROGRAM)).unwrap();
+        let mut player = NsfPlayer::new(nsf, None);
+        player.start_song(2).unwrap();
+        assert_eq!(player.cpu.mem_peek(0x10), 2);
+
+        let samples = player.render(0.1).unwrap();
+        // PLAY runs at 60Hz, starting right after INIT: at 0ms, 16.6ms, ... 99.8ms
+        assert_eq!(player.cpu.mem_peek(0x11), 7);
+        assert!((samples.len() as i32 - 4410).abs() <= 1);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.05));
+
+        assert!(player.start_song(3).is_err());
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        // a VRC6 square at 50% duty and full volume
+        let program = "
+            JMP init
+            RTS
+        init:
+            LDA #$7F
+            STA $9000
+            LDA #$FF
+            STA $9001
+            LDA #$80
+            STA $9002
+            RTS
+        ";
+        let mut raw = test_nsf(program);
+        raw[0x7B] = VRC6;
+        let mut player = NsfPlayer::new(Nsf::parse(&raw).unwrap(), None);
+        player.start_song(0).unwrap();
+        let samples = player.render(0.05).unwrap();
+        assert!(samples.iter().any(|sample| *sample > 0.05));
+        assert!(samples.iter().any(|sample| *sample < -0.05));
+    }
+
+    #[test]
+    fn test_stuck_routine() {
+        let nsf = Nsf::parse(&test_nsf("JMP init\nRTS\ninit:\nJMP init")).unwrap();
+        let mut player = NsfPlayer::new(nsf, None);
+        assert_eq!(
+            player.start_song(0).err(),
+            Some("the routine at 8000 does not return".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut nsf = Nsf {
+            load_address: 0x8100,
+            bank_init: [0, 1, 2, 3, 4, 5, 6, 7],
+            data: vec![0; 0x2000],
+            ..Default::default()
+        };
+        nsf.data[0x0F00] = 0x11;
+        nsf.data[0x1F00] = 0x22;
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!(mapper.cpu_peek(0x8100), Some(0));
+        assert_eq!(mapper.cpu_peek(0x9000), Some(0x11));
+        assert_eq!(mapper.cpu_peek(0xA000), Some(0x22));
+        assert!(mapper.cpu_write(0x5FF8, 2));
+        assert_eq!(mapper.cpu_peek(0x8000), Some(0x22));
+        assert_eq!(mapper.cpu_peek(0xF000), Some(0));
+    }
+}
@@ -0,0 +1,150 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// when emulation falls further behind than this (slow host, debugger, window drag)
+// the schedule restarts from now instead of running flat out to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+// sleeping is only worth it for longer waits, shorter ones are caught by the next call
+const MIN_SLEEP: Duration = Duration::from_micros(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    /// Sleep until the wall time that matches the emulated cycle count
+    Timer,
+    /// Let the audio device's consumption of samples set the pace
+    Audio,
+    /// Run as fast as possible, for benchmarks
+    Uncapped,
+}
+
+/// Keeps emulation in step with wall time: `cycles` emulated CPU cycles at
+/// `cpu_frequency * speed` cycles per second. Frame rates follow from how many
+/// cycles a frame takes, 29780.5 on NTSC gives 60.0988 Hz.
+pub struct Pacer {
+    cpu_frequency: f64,
+    speed: f64,
+    // wall time and cycle count the schedule is measured from
+    origin: Instant,
+    origin_cycles: usize,
+}
+
+impl Pacer {
+    pub fn new(cpu_frequency: f64) -> Self {
+        Pacer {
+            cpu_frequency,
+            speed: 1.0,
+            origin: Instant::now(),
+            origin_cycles: 0,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Changes the emulation speed multiplier: 2.0 runs twice as fast, 0.5 in slow motion
+    pub fn set_speed(&mut self, speed: f64, cycles: usize) {
+        self.speed = speed;
+        self.restart(cycles);
+    }
+
+    /// Starts the schedule over from now. Needed whenever the cycle count jumps
+    /// (reset, loading a state) or emulation was paused.
+    pub fn restart(&mut self, cycles: usize) {
+        self.origin = Instant::now();
+        self.origin_cycles = cycles;
+    }
+
+    /// Wall time at which emulation should have reached `cycles`
+    pub fn deadline(&self, cycles: usize) -> Instant {
+        let elapsed = cycles.saturating_sub(self.origin_cycles) as f64;
+        self.origin + Duration::from_secs_f64(elapsed / (self.cpu_frequency * self.speed))
+    }
+
+    /// Blocks until the wall time for `cycles` has come
+    pub fn wait(&mut self, cycles: usize) {
+        if cycles < self.origin_cycles {
+            self.restart(cycles);
+            return;
+        }
+        let deadline = self.deadline(cycles);
+        let now = Instant::now();
+        if deadline > now {
+            let ahead = deadline - now;
+            if ahead >= MIN_SLEEP {
+                thread::sleep(ahead);
+            }
+        } else if now - deadline > MAX_LAG {
+            self.restart(cycles);
+        }
+    }
+
+    /// Audio sync: blocks while more than `latency` worth of samples is waiting
+    /// to be played. `queued` reports what the audio device has not played yet.
+    pub fn wait_for_audio<F: FnMut() -> Duration>(mut queued: F, latency: Duration) {
+        loop {
+            let waiting = queued();
+            if waiting <= latency {
+                return;
+            }
+            thread::sleep((waiting - latency).min(Duration::from_millis(1)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::Region;
+
+    #[test]
+    fn test_deadline() {
+        let frequency = Region::Ntsc.cpu_frequency().round();
+        let mut pacer = Pacer::new(frequency);
+        pacer.restart(1000);
+        let second = pacer.deadline(1000 + frequency as usize) - pacer.origin;
+        assert_eq!(second.as_millis(), 1000);
+
+        // NTSC frames alternate between 29780 and 29781 cycles
+        let two_frames = pacer.deadline(1000 + 29780 + 29781) - pacer.deadline(1000);
+        assert!((2.0 / two_frames.as_secs_f64() - 60.0988).abs() < 0.0001);
+
+        pacer.set_speed(4.0, 1000);
+        let second = pacer.deadline(1000 + frequency as usize) - pacer.origin;
+        assert_eq!(second.as_millis(), 250);
+        assert_eq!(pacer.deadline(10), pacer.origin);
+    }
+
+    #[test]
+    fn test_wait() {
+        // 1000 cycles per second
+        let mut pacer = Pacer::new(1000.0);
+        let start = Instant::now();
+        pacer.restart(0);
+        pacer.wait(20);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // the cycle count went back, e.g. a state was loaded
+        pacer.restart(100);
+        pacer.wait(10);
+        assert_eq!(pacer.origin_cycles, 10);
+
+        // far behind schedule: start over instead of catching up
+        pacer.origin -= Duration::from_secs(1);
+        pacer.wait(30);
+        assert_eq!(pacer.origin_cycles, 30);
+    }
+
+    #[test]
+    fn test_wait_for_audio() {
+        let mut queued = Duration::from_millis(5);
+        Pacer::wait_for_audio(
+            || {
+                queued = queued.saturating_sub(Duration::from_millis(1));
+                queued
+            },
+            Duration::from_millis(2),
+        );
+        assert_eq!(queued, Duration::from_millis(2));
+    }
+}
@@ -0,0 +1,187 @@
+use super::{read_checksums, read_number, read_size};
+use crate::hash::crc32;
+
+// "BPS1", source size, target size, metadata, then actions that build the target
+// from the source, from the patch or from already written target bytes
+pub const MAGIC: &[u8] = b"BPS1";
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_crc, target_crc) = read_checksums(patch)?;
+    if crc32(rom) != source_crc {
+        return Err("the patch is for a different ROM".to_string());
+    }
+    // without the checksums, so reads can't run into them
+    let body = &patch[..patch.len() - 12];
+    let truncated = || "patch is truncated".to_string();
+
+    let mut pos = MAGIC.len();
+    let source_size = read_size(body, &mut pos)?;
+    let target_size = read_size(body, &mut pos)?;
+    let metadata_size = read_number(body, &mut pos)?;
+    pos = pos
+        .checked_add(metadata_size)
+        .filter(|pos| *pos <= body.len())
+        .ok_or_else(truncated)?;
+    if source_size != rom.len() {
+        return Err("the patch is for a different ROM".to_string());
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while pos < body.len() {
+        let action = read_number(body, &mut pos)?;
+        let length = (action >> 2) + 1;
+        if out.len() + length > target_size {
+            return Err("patch writes past the end of the ROM".to_string());
+        }
+        match action & 3 {
+            SOURCE_READ => {
+                let at = out.len();
+                out.extend(rom.get(at..at + length).ok_or_else(truncated)?);
+            }
+            TARGET_READ => {
+                out.extend(body.get(pos..pos + length).ok_or_else(truncated)?);
+                pos += length;
+            }
+            SOURCE_COPY | TARGET_COPY => {
+                let relative = read_number(body, &mut pos)?;
+                let offset = if action & 3 == SOURCE_COPY {
+                    &mut source_offset
+                } else {
+                    &mut target_offset
+                };
+                // bit 0 is the sign
+                *offset = if relative & 1 != 0 {
+                    offset.checked_sub(relative >> 1)
+                } else {
+                    offset.checked_add(relative >> 1)
+                }
+                .ok_or_else(truncated)?;
+
+                if action & 3 == SOURCE_COPY {
+                    let until = offset.checked_add(length).ok_or_else(truncated)?;
+                    out.extend(rom.get(*offset..until).ok_or_else(truncated)?);
+                    *offset += length;
+                } else {
+                    // may overlap the bytes being written, copy one at a time
+                    for _ in 0..length {
+                        let byte = *out.get(*offset).ok_or_else(truncated)?;
+                        out.push(byte);
+                        *offset += 1;
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if out.len() != target_size {
+        return Err("patch is truncated".to_string());
+    }
+    if crc32(&out) != target_crc {
+        return Err("patched ROM checksum mismatch".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{write_checksums, write_number};
+    use super::*;
+
+    fn patch(source: &[u8], target: &[u8], actions: &[(usize, usize, &[u8])]) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        write_number(&mut patch, 3);
+        patch.extend(b"xyz");
+        for (action, length, data) in actions {
+            write_number(&mut patch, (length - 1) << 2 | action);
+            patch.extend(*data);
+        }
+        write_checksums(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn test_actions() {
+        let source = b"ABCDEFGH";
+        let target = b"ABxyEFGHGHGHGH";
+        // overlaps the bytes it writes
+        let mut copy_back = vec![];
+        write_number(&mut copy_back, 6 << 1);
+        let mut source_copy = vec![];
+        write_number(&mut source_copy, 4 << 1);
+        let patch = patch(
+            source,
+            target,
+            &[
+                (SOURCE_READ, 2, b""),
+                (TARGET_READ, 2, b"xy"),
+                (SOURCE_COPY, 4, &source_copy),
+                (TARGET_COPY, 6, &copy_back),
+            ],
+        );
+        assert_eq!(apply(source, &patch), Ok(target.to_vec()));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let patch = patch(
+            b"AB",
+            b"AC",
+            &[(SOURCE_READ, 1, b""), (TARGET_READ, 1, b"C")],
+        );
+        assert_eq!(
+            apply(b"AX", &patch).err(),
+            Some("the patch is for a different ROM".to_string())
+        );
+
+        let mut corrupted = patch.clone();
+        corrupted[patch.len() - 13] = b'D';
+        assert_eq!(
+            apply(b"AB", &corrupted).err(),
+            Some("patch checksum mismatch, the patch is corrupted".to_string())
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let with_header = |sizes: [usize; 3], actions: &[u8]| {
+            let mut patch = MAGIC.to_vec();
+            for size in sizes {
+                write_number(&mut patch, size);
+            }
+            patch.extend(actions);
+            write_checksums(&mut patch, b"AB", b"AB");
+            patch
+        };
+        assert_eq!(
+            apply(b"AB", &with_header([2, 2, 100], &[])).err(),
+            Some("patch is truncated".to_string())
+        );
+        assert_eq!(
+            apply(b"AB", &with_header([2, 1 << 40, 0], &[])).err(),
+            Some("ROM size 1099511627776 in the patch is too large".to_string())
+        );
+        // a target read of 2 bytes with one left before the checksums
+        assert_eq!(
+            apply(b"AB", &with_header([2, 2, 0], &[0x85, b'A'])).err(),
+            Some("patch is truncated".to_string())
+        );
+        // a source copy from far past the end of the source
+        let mut actions = vec![];
+        write_number(&mut actions, SOURCE_COPY);
+        write_number(&mut actions, usize::MAX & !1);
+        assert_eq!(
+            apply(b"AB", &with_header([2, 2, 0], &actions)).err(),
+            Some("patch is truncated".to_string())
+        );
+    }
+}
@@ -0,0 +1,66 @@
+// "PATCH", then records of a 24 bit offset and a 16 bit size followed by the data,
+// or by a 16 bit count and a byte to repeat when the size is 0, then "EOF" and
+// optionally the 24 bit size to truncate the result to
+pub const MAGIC: &[u8] = b"PATCH";
+const EOF: [u8; 3] = *b"EOF";
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut pos = MAGIC.len();
+    let mut read = |len: usize| -> Result<&[u8], String> {
+        let bytes = patch.get(pos..pos + len).ok_or("patch is truncated")?;
+        pos += len;
+        Ok(bytes)
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+
+    loop {
+        let offset = read(3)?;
+        if offset == EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(read(2)?);
+        let (size, data) = if size == 0 {
+            let count = be(read(2)?);
+            (count, vec![read(1)?[0]; count])
+        } else {
+            (size, read(size)?.to_vec())
+        };
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+
+    if let Ok(size) = read(3) {
+        out.truncate(be(size));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAA\xBB\x00\x00\x06\x00\x00\x00\x03\xCCEOF";
+        assert_eq!(
+            apply(&[0; 4], patch),
+            Ok(vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC])
+        );
+    }
+
+    #[test]
+    fn test_truncate_and_errors() {
+        assert_eq!(
+            apply(&[1, 2, 3, 4], b"PATCHEOF\x00\x00\x02"),
+            Ok(vec![1, 2])
+        );
+        assert_eq!(
+            apply(&[1, 2, 3, 4], b"PATCH\x00\x00\x01\x00\x05\xAA").err(),
+            Some("patch is truncated".to_string())
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::rom::loader;
+use std::path::{Path, PathBuf};
+
+mod bps;
+mod ips;
+mod ups;
+
+// the order patches are looked for next to a ROM
+const EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+// far more than any NES ROM, a larger size in a patch means it is corrupted
+const MAX_ROM_SIZE: usize = 64 << 20;
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+    Ups,
+}
+
+impl PatchFormat {
+    /// Tells the format by the magic at the start of the patch
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(ips::MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(bps::MAGIC) {
+            Some(PatchFormat::Bps)
+        } else if patch.starts_with(ups::MAGIC) {
+            Some(PatchFormat::Ups)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies an IPS, BPS or UPS patch to the raw bytes of a ROM file.
+/// BPS and UPS patches are rejected when the source, result or patch checksums don't match.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => ips::apply(rom, patch),
+        Some(PatchFormat::Bps) => bps::apply(rom, patch),
+        Some(PatchFormat::Ups) => ups::apply(rom, patch),
+        None => Err("unknown patch format".to_string()),
+    }
+}
+
+/// Patch with the same name as the ROM, e.g. `game.ips` for `game.nes`
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+/// Reads the ROM file (or archive, see `rom::loader`) and applies the given patch,
+/// or the one found next to it
+pub fn read_patched(rom_path: &Path, patch_path: Option<&Path>) -> Result<Vec<u8>, String> {
+    let rom = loader::read(rom_path, None)?;
+    let patch_path = match patch_path {
+        Some(path) => path.to_path_buf(),
+        None => match find_patch(rom_path) {
+            Some(path) => path,
+            None => return Ok(rom),
+        },
+    };
+    let patch =
+        std::fs::read(&patch_path).map_err(|e| format!("{}: {}", patch_path.display(), e))?;
+    apply(&rom, &patch).map_err(|e| format!("{}: {}", patch_path.display(), e))
+}
+
+// variable length number of BPS and UPS: 7 bits per byte, the last one has bit 7 set
+fn read_number(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let mut number = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *patch.get(*pos).ok_or("patch is truncated")?;
+        *pos += 1;
+        number = (byte as usize & 0x7f)
+            .checked_mul(shift)
+            .and_then(|n| n.checked_add(number))
+            .ok_or("number is too large")?;
+        if byte & 0x80 != 0 {
+            return Ok(number);
+        }
+        shift = shift.checked_shl(7).ok_or("number is too large")?;
+        number = number.checked_add(shift).ok_or("number is too large")?;
+    }
+}
+
+// a source or target size of a BPS or UPS header
+fn read_size(patch: &[u8], pos: &mut usize) -> Result<usize, String> {
+    let size = read_number(patch, pos)?;
+    if size > MAX_ROM_SIZE {
+        return Err(format!("ROM size {} in the patch is too large", size));
+    }
+    Ok(size)
+}
+
+// BPS and UPS end with the CRC-32s of the source, the result and the patch itself
+fn read_checksums(patch: &[u8]) -> Result<(u32, u32), String> {
+    if patch.len() < 12 {
+        return Err("patch is truncated".to_string());
+    }
+    let crc = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    let end = patch.len() - 12;
+    if crate::hash::crc32(&patch[..end + 8]) != crc(end + 8) {
+        return Err("patch checksum mismatch, the patch is corrupted".to_string());
+    }
+    Ok((crc(end), crc(end + 4)))
+}
+
+#[cfg(test)]
+fn write_number(out: &mut Vec<u8>, mut number: usize) {
+    loop {
+        let byte = (number & 0x7f) as u8;
+        number >>= 7;
+        if number == 0 {
+            out.push(byte | 0x80);
+            return;
+        }
+        out.push(byte);
+        number -= 1;
+    }
+}
+
+#[cfg(test)]
+fn write_checksums(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+    use crate::hash::crc32;
+    patch.extend(crc32(source).to_le_bytes());
+    patch.extend(crc32(target).to_le_bytes());
+    patch.extend(crc32(patch).to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        for number in [0, 1, 127, 128, 255, 16511, 16512, 1 << 20, 0xFFFF_FFFF] {
+            let mut out = vec![];
+            write_number(&mut out, number);
+            let mut pos = 0;
+            assert_eq!(read_number(&out, &mut pos), Ok(number));
+            assert_eq!(pos, out.len());
+        }
+        assert_eq!(
+            read_number(&[0x00], &mut 0),
+            Err("patch is truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(PatchFormat::detect(b"PATCHEOF"), Some(PatchFormat::Ips));
+        assert_eq!(PatchFormat::detect(b"BPS1"), Some(PatchFormat::Bps));
+        assert_eq!(PatchFormat::detect(b"UPS1"), Some(PatchFormat::Ups));
+        assert_eq!(
+            apply(&[], b"NES\x1a").err(),
+            Some("unknown patch format".to_string())
+        );
+    }
+
+    #[test]
+    fn test_read_patched() {
+        let dir = std::env::temp_dir().join(format!("nes_patch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.nes");
+        std::fs::write(&rom, [1, 2, 3]).unwrap();
+        assert_eq!(read_patched(&rom, None), Ok(vec![1, 2, 3]));
+
+        std::fs::write(dir.join("game.ips"), b"PATCH\x00\x00\x01\x00\x01\x09EOF").unwrap();
+        assert_eq!(find_patch(&rom), Some(dir.join("game.ips")));
+        assert_eq!(read_patched(&rom, None), Ok(vec![1, 9, 3]));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
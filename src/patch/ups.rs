@@ -0,0 +1,131 @@
+use super::{read_checksums, read_number, read_size};
+use crate::hash::crc32;
+
+// "UPS1", input size, output size, then runs of a distance to skip followed by
+// bytes to XOR with the input, each run ends with a 0
+pub const MAGIC: &[u8] = b"UPS1";
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (input_crc, output_crc) = read_checksums(patch)?;
+    if crc32(rom) != input_crc {
+        return Err("the patch is for a different ROM".to_string());
+    }
+    // without the checksums, so reads can't run into them
+    let body = &patch[..patch.len() - 12];
+
+    let mut pos = MAGIC.len();
+    let input_size = read_size(body, &mut pos)?;
+    let output_size = read_size(body, &mut pos)?;
+    if input_size != rom.len() {
+        return Err("the patch is for a different ROM".to_string());
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(output_size, 0);
+    let mut offset = 0usize;
+    while pos < body.len() {
+        offset = offset
+            .checked_add(read_number(body, &mut pos)?)
+            .ok_or("patch writes past the end of the ROM")?;
+        loop {
+            let byte = *body.get(pos).ok_or("patch is truncated")?;
+            pos += 1;
+            if offset < out.len() {
+                out[offset] ^= byte;
+            } else if byte != 0 {
+                return Err("patch writes past the end of the ROM".to_string());
+            }
+            offset += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32(&out) != output_crc {
+        return Err("patched ROM checksum mismatch".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{write_checksums, write_number};
+    use super::*;
+
+    fn patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        let mut offset = 0;
+        let mut i = 0;
+        while i < target.len() {
+            let old = |i: usize| source.get(i).copied().unwrap_or(0);
+            if old(i) == target[i] {
+                i += 1;
+                continue;
+            }
+            write_number(&mut patch, i - offset);
+            while i < target.len() && old(i) != target[i] {
+                patch.push(old(i) ^ target[i]);
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            offset = i;
+        }
+        write_checksums(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn test_apply() {
+        let source = b"Hello, World";
+        let target = b"Hallo, Welt!!";
+        assert_eq!(apply(source, &patch(source, target)), Ok(target.to_vec()));
+    }
+
+    #[test]
+    fn test_wrong_rom() {
+        let patch = patch(b"abc", b"abd");
+        assert_eq!(
+            apply(b"abd", &patch).err(),
+            Some("the patch is for a different ROM".to_string())
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let rom = b"abc";
+        let with_body = |body: &[u8]| {
+            let mut patch = MAGIC.to_vec();
+            patch.extend(body);
+            write_checksums(&mut patch, rom, rom);
+            patch
+        };
+        // a run that is cut off by the checksums
+        let mut body = vec![];
+        write_number(&mut body, 3);
+        write_number(&mut body, 3);
+        body.extend([0x80, 0x01]);
+        assert_eq!(
+            apply(rom, &with_body(&body)).err(),
+            Some("patch is truncated".to_string())
+        );
+        // an offset that is cut off by the checksums
+        body.truncate(2);
+        body.push(0x01);
+        assert_eq!(
+            apply(rom, &with_body(&body)).err(),
+            Some("patch is truncated".to_string())
+        );
+
+        let mut body = vec![];
+        write_number(&mut body, 3);
+        write_number(&mut body, 1 << 40);
+        assert_eq!(
+            apply(rom, &with_body(&body)).err(),
+            Some("ROM size 1099511627776 in the patch is too large".to_string())
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use crate::cpu::cpu::CPU;
+use crate::cpu::mem::Mem;
+use crate::cpu::opcodes::{self, Instruction};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortBy {
+    SelfCycles,
+    InclusiveCycles,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RoutineStats {
+    pub name: String,
+    pub entry: u16,
+    pub calls: usize,
+    // cycles spent in the routine itself
+    pub self_cycles: usize,
+    // cycles spent in the routine and everything it called
+    pub inclusive_cycles: usize,
+}
+
+struct Frame {
+    name: String,
+    // `;` separated names of the whole call stack, as used by flamegraph tools
+    path: String,
+    started_at: usize,
+}
+
+/// Attributes executed cycles to instruction addresses and to subroutines.
+/// Subroutines are tracked through JSR/RTS, interrupt handlers through
+/// interrupts taken by the CPU or BRK, and RTI.
+pub struct Profiler {
+    pc_cycles: HashMap<u16, usize>,
+    routines: HashMap<String, RoutineStats>,
+    folded: HashMap<String, usize>,
+    stack: Vec<Frame>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            pc_cycles: HashMap::new(),
+            routines: HashMap::new(),
+            folded: HashMap::new(),
+            stack: vec![],
+        }
+    }
+
+    /// Executes one instruction through `CPU::step` and records its cycles
+    pub fn step(&mut self, cpu: &mut CPU) -> bool {
+        let mut pc = cpu.program_counter;
+        let cycles = cpu.cycles;
+
+        if self.stack.is_empty() {
+            self.enter(format!("entry_{:04X}", pc), pc, cycles);
+        }
+        // the step runs the interrupt sequence and the first instruction of the handler
+        if let Some(vector) = cpu.pending_interrupt() {
+            pc = cpu.mem_peek_u16(vector);
+            self.enter(format!("irq_{:04X}", pc), pc, cycles);
+        }
+        let code = cpu.mem_peek(pc);
+
+        let running = cpu.step();
+
+        let spent = cpu.cycles - cycles;
+        *self.pc_cycles.entry(pc).or_insert(0) += spent;
+        let top = self.stack.last().unwrap();
+        self.routines.get_mut(&top.name).unwrap().self_cycles += spent;
+        match self.folded.get_mut(&top.path) {
+            Some(total) => *total += spent,
+            None => {
+                self.folded.insert(top.path.clone(), spent);
+            }
+        }
+
+        let mnemonic = opcodes::OPSCODES_MAP.get(&code).map(|op| &op.mnemonic);
+        match mnemonic {
+            Some(Instruction::JSR) => {
+                let entry = cpu.program_counter;
+                self.enter(format!("sub_{:04X}", entry), entry, cpu.cycles);
+            }
+            Some(Instruction::RTS) | Some(Instruction::RTI) => self.leave(cpu.cycles),
+            // unless it halted the CPU, BRK went through the IRQ vector
+            Some(Instruction::BRK) if running => {
+                let entry = cpu.program_counter;
+                self.enter(format!("irq_{:04X}", entry), entry, cpu.cycles);
+            }
+            _ => {}
+        }
+
+        running
+    }
+
+    fn enter(&mut self, name: String, entry: u16, cycles: usize) {
+        let path = match self.stack.last() {
+            Some(parent) => format!("{};{}", parent.path, name),
+            None => name.clone(),
+        };
+        self.routines
+            .entry(name.clone())
+            .or_insert(RoutineStats {
+                name: name.clone(),
+                entry,
+                calls: 0,
+                self_cycles: 0,
+                inclusive_cycles: 0,
+            })
+            .calls += 1;
+        self.stack.push(Frame {
+            name,
+            path,
+            started_at: cycles,
+        });
+    }
+
+    fn leave(&mut self, cycles: usize) {
+        // returning from the routine that was running when profiling started
+        if self.stack.len() == 1 {
+            return;
+        }
+        let frame = self.stack.pop().unwrap();
+        self.routines.get_mut(&frame.name).unwrap().inclusive_cycles += cycles - frame.started_at;
+    }
+
+    /// Cycles spent per instruction address, hottest first
+    pub fn hot_spots(&self) -> Vec<(u16, usize)> {
+        let mut spots: Vec<(u16, usize)> = self.pc_cycles.iter().map(|(k, v)| (*k, *v)).collect();
+        spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// Per-routine statistics; routines that are still running count up to `cycles`
+    pub fn routines(&self, cycles: usize, sort: SortBy) -> Vec<RoutineStats> {
+        let mut routines = self.routines.clone();
+        for frame in &self.stack {
+            routines.get_mut(&frame.name).unwrap().inclusive_cycles += cycles - frame.started_at;
+        }
+
+        let mut routines: Vec<RoutineStats> = routines.into_values().collect();
+        routines.sort_by(|a, b| {
+            let (a_key, b_key) = match sort {
+                SortBy::SelfCycles => (a.self_cycles, b.self_cycles),
+                SortBy::InclusiveCycles => (a.inclusive_cycles, b.inclusive_cycles),
+            };
+            b_key.cmp(&a_key).then(a.entry.cmp(&b.entry))
+        });
+        routines
+    }
+
+    pub fn write_report<W: Write>(
+        &self,
+        out: &mut W,
+        cycles: usize,
+        sort: SortBy,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "{:<16} {:>8} {:>12} {:>12}",
+            "routine", "calls", "self", "inclusive"
+        )?;
+        for routine in self.routines(cycles, sort) {
+            writeln!(
+                out,
+                "{:<16} {:>8} {:>12} {:>12}",
+                routine.name, routine.calls, routine.self_cycles, routine.inclusive_cycles
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "{:<16} {:>8}", "address", "cycles")?;
+        for (pc, cycles) in self.hot_spots() {
+            writeln!(out, "{:<16} {:>8}", format!("${:04X}", pc), cycles)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `caller;callee cycles` lines, the input format of flamegraph.pl and inferno
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &usize)> = self.folded.iter().collect();
+        stacks.sort();
+        for (path, cycles) in stacks {
+            writeln!(out, "{} {}", path, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::rom::test::{test_rom, test_rom_with_prg};
+
+    fn profile(source: &str) -> (Profiler, CPU) {
+        let mut bus = Bus::new(test_rom()).unwrap();
+        let program = crate::asm::assemble(source).unwrap();
+        for (i, byte) in program.code.iter().enumerate() {
+            bus.mem_write(program.origin + i as u16, *byte);
+        }
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = program.origin;
+        let mut profiler = Profiler::new();
+        while profiler.step(&mut cpu) {}
+        (profiler, cpu)
+    }
+
+    const PROGRAM: &str = "
+              JSR outer     ; 6
+              JSR inner     ; 6
+              BRK           ; 7
+        outer:
+              LDX #2        ; 2
+        loop: JSR inner     ; 6 * 2
+              DEX           ; 2 * 2
+              BNE loop      ; 3 + 2
+              RTS           ; 6
+        inner:
+              NOP           ; 2 * 3
+              RTS           ; 6 * 3
+    ";
+
+    #[test]
+    fn test_routine_cycles() {
+        let (profiler, cpu) = profile(PROGRAM);
+        let routines = profiler.routines(cpu.cycles, SortBy::SelfCycles);
+        let names: Vec<&str> = routines.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["sub_0607", "sub_0610", "entry_0600"]);
+
+        assert_eq!(routines[0].calls, 1);
+        assert_eq!(routines[0].self_cycles, 2 + 12 + 4 + 5 + 6);
+        assert_eq!(routines[0].inclusive_cycles, 29 + 16);
+
+        assert_eq!(routines[1].calls, 3);
+        assert_eq!(routines[1].self_cycles, 24);
+        assert_eq!(routines[1].inclusive_cycles, 24);
+
+        assert_eq!(routines[2].self_cycles, 6 + 6 + 7);
+        assert_eq!(routines[2].inclusive_cycles, cpu.cycles);
+
+        let routines = profiler.routines(cpu.cycles, SortBy::InclusiveCycles);
+        assert_eq!(routines[0].name, "entry_0600");
+        assert_eq!(profiler.hot_spots()[0], (0x0611, 18));
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (profiler, _) = profile(PROGRAM);
+        let mut out = vec![];
+        profiler.write_folded(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "entry_0600 19\n\
+             entry_0600;sub_0607 29\n\
+             entry_0600;sub_0607;sub_0610 16\n\
+             entry_0600;sub_0610 8\n"
+        );
+    }
+
+    #[test]
+    fn test_irq_handler() {
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  LDX #3
+                    LDA #0
+                    STA $4017
+                    CLI
+            loop:   CPX #0
+                    BNE loop
+                    BRK
+            irq:    JSR ack
+                    DEX
+                    RTI
+            ack:    LDA $4015
+                    RTS
+                    .org $fffc
+                    .word reset, irq
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(Bus::new(test_rom_with_prg(program.code)).unwrap());
+        cpu.reset();
+        let mut profiler = Profiler::new();
+        while profiler.step(&mut cpu) {}
+
+        // the APU frame IRQ was taken three times and every RTI left its handler
+        let routines = profiler.routines(cpu.cycles, SortBy::InclusiveCycles);
+        let calls: Vec<(&str, usize)> = routines
+            .iter()
+            .map(|r| (r.name.as_str(), r.calls))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![("entry_8000", 1), ("irq_800D", 3), ("sub_8012", 3)]
+        );
+        assert_eq!(routines[0].inclusive_cycles, cpu.cycles - 7);
+        assert_eq!(
+            profiler.routines(cpu.cycles + 100, SortBy::InclusiveCycles)[1],
+            routines[1]
+        );
+        // 7 cycles for the interrupt and 6 for the JSR
+        assert_eq!(routines[1].self_cycles, 3 * (7 + 6 + 2 + 6));
+
+        let mut out = vec![];
+        profiler.write_folded(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let paths: Vec<&str> = out
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "entry_8000",
+                "entry_8000;irq_800D",
+                "entry_8000;irq_800D;sub_8012"
+            ]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::str::FromStr;
+
+const NTSC_MASTER_CLOCK: f64 = 21_477_272.0;
+const PAL_MASTER_CLOCK: f64 = 26_601_712.0;
+
+// APU frame counter steps in CPU cycles; the 4-step sequence ends at the 4th, the 5-step at the 5th
+const NTSC_FRAME_COUNTER: [usize; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_COUNTER: [usize; 5] = [8313, 16627, 24939, 33253, 41565];
+
+// timer periods in CPU cycles
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// TV system a console was built for. It decides the clock speeds, the number of
+/// scanlines and the APU tables. Dendy is the Russian famiclone: PAL clock and
+/// scanline count with NTSC-like CPU/PPU ratio and APU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn master_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_MASTER_CLOCK,
+            Region::Pal | Region::Dendy => PAL_MASTER_CLOCK,
+        }
+    }
+
+    /// Master clock ticks per CPU cycle
+    pub fn cpu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /// Master clock ticks per PPU dot
+    pub fn ppu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_frequency(self) -> f64 {
+        self.master_clock() / self.cpu_divider() as f64
+    }
+
+    /// PPU dots per CPU cycle: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn dots_per_cpu_cycle(self) -> f64 {
+        self.cpu_divider() as f64 / self.ppu_divider() as f64
+    }
+
+    pub fn scanlines_per_frame(self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanlines between the end of the picture and the start of vblank
+    pub fn post_render_scanlines(self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 1,
+            Region::Dendy => 51,
+        }
+    }
+
+    pub fn vblank_scanlines(self) -> usize {
+        match self {
+            Region::Ntsc | Region::Dendy => 20,
+            Region::Pal => 70,
+        }
+    }
+
+    /// Average CPU cycles per frame. NTSC skips a dot every other frame.
+    pub fn cycles_per_frame(self) -> f64 {
+        let mut dots = (341 * self.scanlines_per_frame()) as f64;
+        if self == Region::Ntsc {
+            dots -= 0.5;
+        }
+        dots / self.dots_per_cpu_cycle()
+    }
+
+    /// 60.0988 Hz on NTSC, 50.007 Hz on PAL and Dendy
+    pub fn frame_rate(self) -> f64 {
+        self.cpu_frequency() / self.cycles_per_frame()
+    }
+
+    pub fn frame_counter_steps(self) -> &'static [usize; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_FRAME_COUNTER,
+            Region::Pal => &PAL_FRAME_COUNTER,
+        }
+    }
+
+    pub fn noise_periods(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    pub fn dmc_rates(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+            Region::Pal => &PAL_DMC_RATES,
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("unknown region '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timing() {
+        assert_eq!(Region::Ntsc.cpu_frequency().round(), 1_789_773.0);
+        assert_eq!(Region::Pal.cpu_frequency().round(), 1_662_607.0);
+        assert_eq!(Region::Dendy.cpu_frequency().round(), 1_773_447.0);
+
+        assert_eq!(Region::Ntsc.cycles_per_frame(), 29780.5);
+        assert_eq!(Region::Pal.cycles_per_frame(), 33247.5);
+        assert_eq!(Region::Dendy.cycles_per_frame(), 35464.0);
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.0001);
+        assert!((Region::Pal.frame_rate() - 50.007).abs() < 0.0001);
+        assert!((Region::Dendy.frame_rate() - 50.007).abs() < 0.0001);
+
+        assert_eq!(Region::Dendy.noise_periods()[15], 4068);
+        assert_eq!(Region::Pal.dmc_rates()[0], 398);
+        assert_eq!(
+            Region::Pal.scanlines_per_frame(),
+            240 + Region::Pal.post_render_scanlines() + Region::Pal.vblank_scanlines() + 1
+        );
+        assert_eq!(
+            Region::Dendy.scanlines_per_frame(),
+            240 + Region::Dendy.post_render_scanlines() + Region::Dendy.vblank_scanlines() + 1
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("PAL".parse::<Region>(), Ok(Region::Pal));
+        assert_eq!("dendy".parse::<Region>(), Ok(Region::Dendy));
+        assert!("secam".parse::<Region>().is_err());
+        assert_eq!(Region::Ntsc.to_string(), "NTSC");
+    }
+}
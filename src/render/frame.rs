@@ -0,0 +1,71 @@
+use crate::hash;
+use crate::render::png;
+use std::io;
+
+/// 256x240 RGB picture as produced by the PPU
+pub struct Frame {
+    pub data: Vec<u8>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
+
+    pub fn new() -> Self {
+        Frame {
+            data: vec![0; Frame::WIDTH * Frame::HEIGHT * 3],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        if x < Frame::WIDTH && y < Frame::HEIGHT {
+            let base = y * 3 * Frame::WIDTH + x * 3;
+            self.data[base] = rgb.0;
+            self.data[base + 1] = rgb.1;
+            self.data[base + 2] = rgb.2;
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * Frame::WIDTH + x * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    /// Stable fingerprint of the picture for golden-image tests
+    pub fn hash(&self) -> u32 {
+        hash::crc32(&self.data)
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode_rgb(Frame::WIDTH, Frame::HEIGHT, &self.data)
+    }
+
+    pub fn save_png(&self, path: &str) -> io::Result<()> {
+        std::fs::write(path, self.to_png())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pixels_and_hash() {
+        let mut frame = Frame::new();
+        let blank = frame.hash();
+        frame.set_pixel(255, 239, (1, 2, 3));
+        frame.set_pixel(256, 239, (1, 2, 3));
+        // off the right edge, not the start of the next line
+        frame.set_pixel(256, 0, (1, 2, 3));
+        assert_eq!(frame.pixel(0, 1), (0, 0, 0));
+        assert_eq!(frame.pixel(255, 239), (1, 2, 3));
+        assert_ne!(frame.hash(), blank);
+        assert_eq!(Frame::new().hash(), blank);
+    }
+}
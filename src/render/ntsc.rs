@@ -0,0 +1,201 @@
+use std::f32::consts::PI;
+
+// Composite signal generation and decoding after the "NTSC video" article on the nesdev wiki.
+// The PPU outputs each pixel as 8 samples of a square wave at 12 samples per color subcarrier
+// cycle: the color index selects the phase of the wave, the brightness its low/high voltages.
+
+// voltages of the low and high halves of the wave for brightness 0-3
+const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// voltage scale while an emphasis bit is in phase
+const ATTENUATION: f32 = 0.746;
+
+const SAMPLES_PER_PIXEL: usize = 8;
+const SUBCARRIER_PERIOD: usize = 12;
+// a scanline is 341 dots, so the phase moves 341 * 8 % 12 = 4 samples every line
+const PHASE_PER_SCANLINE: usize = 4;
+
+const PPUMASK_GREYSCALE: u8 = 0b0000_0001;
+
+pub struct NtscFilter {
+    pub width: usize,
+    // phase offset of the decoder, in subcarrier twelfths
+    pub hue: f32,
+    pub saturation: f32,
+    cos: [f32; SUBCARRIER_PERIOD],
+    sin: [f32; SUBCARRIER_PERIOD],
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        NtscFilter::new(NtscFilter::WIDTH)
+    }
+}
+
+impl NtscFilter {
+    /// Output width that keeps the 8:7 pixel aspect of NTSC for 256 pixels
+    pub const WIDTH: usize = 602;
+    pub const HEIGHT: usize = 240;
+
+    pub fn new(width: usize) -> Self {
+        let mut filter = NtscFilter {
+            width,
+            hue: 3.9,
+            saturation: 1.0,
+            cos: [0.0; SUBCARRIER_PERIOD],
+            sin: [0.0; SUBCARRIER_PERIOD],
+        };
+        filter.set_hue(3.9);
+        filter
+    }
+
+    pub fn set_hue(&mut self, hue: f32) {
+        self.hue = hue;
+        for phase in 0..SUBCARRIER_PERIOD {
+            let angle = PI * (phase as f32 + hue) / 6.0;
+            self.cos[phase] = angle.cos();
+            self.sin[phase] = angle.sin();
+        }
+    }
+
+    /// Voltage of one sample of a 9-bit pixel: emphasis bits 8-6, color index 5-0
+    fn signal(pixel: u16, phase: usize) -> f32 {
+        let color = (pixel & 0x0F) as usize;
+        let mut level = ((pixel >> 4) & 3) as usize;
+        let emphasis = pixel >> 6;
+
+        // $xE/$xF output the black level
+        if color > 13 {
+            level = 1;
+        }
+        let mut low = LEVELS[level];
+        let mut high = LEVELS[4 + level];
+        // $x0 is grey, $xD and above have no color
+        if color == 0 {
+            low = high;
+        }
+        if color > 12 {
+            high = low;
+        }
+
+        let in_phase = |color: usize| (color + phase) % SUBCARRIER_PERIOD < 6;
+        let mut signal = if in_phase(color) { high } else { low };
+        if (emphasis & 1 != 0 && in_phase(0))
+            || (emphasis & 2 != 0 && in_phase(4))
+            || (emphasis & 4 != 0 && in_phase(8))
+        {
+            signal *= ATTENUATION;
+        }
+        signal
+    }
+
+    /// Converts a 256x240 picture of palette indices shown with the given PPUMASK
+    /// into `width`x240 RGB. Consecutive frames start at different subcarrier phases,
+    /// which is where the flickering dot crawl comes from; pass the frame number to get it.
+    pub fn filter(&self, indices: &[u8], mask: u8, frame: usize) -> Vec<u8> {
+        let emphasis = ((mask & 0b1110_0000) as u16) << 1;
+        let pixels: Vec<u16> = indices
+            .iter()
+            .map(|index| {
+                let index = if mask & PPUMASK_GREYSCALE != 0 {
+                    index & 0x30
+                } else {
+                    index & 0x3F
+                };
+                index as u16 | emphasis
+            })
+            .collect();
+        self.filter_pixels(&pixels, frame)
+    }
+
+    /// Same as `filter` for pixels that carry their own emphasis bits (bits 8-6),
+    /// for pictures where PPUMASK changed mid-frame
+    pub fn filter_pixels(&self, pixels: &[u16], frame: usize) -> Vec<u8> {
+        let line_samples = 256 * SAMPLES_PER_PIXEL;
+        let mut out = Vec::with_capacity(self.width * NtscFilter::HEIGHT * 3);
+        let mut signal = vec![0.0; line_samples];
+
+        // the odd frame dot skip makes the start phase alternate between two values
+        let frame_phase = (frame % 2) * PHASE_PER_SCANLINE;
+        for (y, line) in pixels.chunks(256).take(NtscFilter::HEIGHT).enumerate() {
+            let line_phase = frame_phase + y * PHASE_PER_SCANLINE;
+            for (s, sample) in signal.iter_mut().enumerate() {
+                let pixel = line.get(s / SAMPLES_PER_PIXEL).copied().unwrap_or(0x0F);
+                let phase = (line_phase + s) % SUBCARRIER_PERIOD;
+                *sample = (NtscFilter::signal(pixel, phase) - BLACK) / (WHITE - BLACK);
+            }
+
+            for x in 0..self.width {
+                // average one subcarrier cycle around the output pixel
+                let center = x * line_samples / self.width + SAMPLES_PER_PIXEL / 2;
+                let begin = center
+                    .saturating_sub(SUBCARRIER_PERIOD / 2)
+                    .min(line_samples - SUBCARRIER_PERIOD);
+                let (mut luma, mut i, mut q) = (0.0, 0.0, 0.0);
+                for (s, level) in signal
+                    .iter()
+                    .enumerate()
+                    .skip(begin)
+                    .take(SUBCARRIER_PERIOD)
+                {
+                    let phase = (line_phase + s) % SUBCARRIER_PERIOD;
+                    luma += level;
+                    i += level * self.cos[phase];
+                    q += level * self.sin[phase];
+                }
+                let period = SUBCARRIER_PERIOD as f32;
+                let (luma, i, q) = (
+                    luma / period,
+                    i / period * self.saturation,
+                    q / period * self.saturation,
+                );
+
+                // YIQ to RGB as in FCC NTSC
+                let r = luma + 0.946882 * i + 0.623557 * q;
+                let g = luma - 0.274788 * i - 0.635691 * q;
+                let b = luma - 1.108545 * i + 1.709007 * q;
+                out.push(to_byte(r));
+                out.push(to_byte(g));
+                out.push(to_byte(b));
+            }
+        }
+        out
+    }
+}
+
+fn to_byte(level: f32) -> u8 {
+    (level * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn color(filter: &NtscFilter, index: u8, mask: u8) -> (u8, u8, u8) {
+        let rgb = filter.filter(&[index; 256 * 240], mask, 0);
+        let at = (100 * filter.width + 300) * 3;
+        (rgb[at], rgb[at + 1], rgb[at + 2])
+    }
+
+    #[test]
+    fn test_solid_colors() {
+        let filter = NtscFilter::default();
+        assert_eq!(filter.filter(&[0; 256 * 240], 0, 0).len(), 602 * 240 * 3);
+        assert_eq!(color(&filter, 0x20, 0), (255, 255, 255));
+        assert_eq!(color(&filter, 0x0F, 0), (0, 0, 0));
+
+        let (r, g, b) = color(&filter, 0x16, 0);
+        assert!(r > g && r > b, "{:?}", (r, g, b));
+        let (r, g, b) = color(&filter, 0x12, 0);
+        assert!(b > r && b > g, "{:?}", (r, g, b));
+        let (r, g, b) = color(&filter, 0x1A, 0);
+        assert!(g > r && g > b, "{:?}", (r, g, b));
+
+        // greyscale drops the color, emphasis darkens white
+        let (r, g, b) = color(&filter, 0x16, PPUMASK_GREYSCALE);
+        assert!(r == g && g == b);
+        let (r, _, _) = color(&filter, 0x20, 0b1110_0000);
+        assert!(r < 255);
+    }
+}
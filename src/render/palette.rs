@@ -0,0 +1,139 @@
+use crate::render::frame::Frame;
+
+// PPUMASK bits that affect colors
+const GREYSCALE: u8 = 0b0000_0001;
+const EMPHASIS: u8 = 0b1110_0000;
+// level of the other channels while emphasis is on
+const ATTENUATION: f32 = 0.816;
+
+#[rustfmt::skip]
+pub static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
+    (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
+    (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
+    (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
+    (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
+    (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
+    (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
+    (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
+    (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
+    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+/// Maps the PPU's 6-bit color indices to RGB.
+/// Either 64 colors, with emphasis approximated by dimming the other channels,
+/// or 512 colors, one set of 64 for every combination of the 3 emphasis bits.
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colors: SYSTEM_PALETTE.to_vec(),
+        }
+    }
+}
+
+impl Palette {
+    /// Parses a `.pal` file: 64 or 512 RGB triplets
+    pub fn from_bytes(raw: &[u8]) -> Result<Palette, String> {
+        if raw.len() != 64 * 3 && raw.len() != 512 * 3 {
+            return Err(format!(
+                "palette has {} bytes, expected {} or {}",
+                raw.len(),
+                64 * 3,
+                512 * 3
+            ));
+        }
+        Ok(Palette {
+            colors: raw.chunks(3).map(|c| (c[0], c[1], c[2])).collect(),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Palette::from_bytes(&raw).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn has_emphasis(&self) -> bool {
+        self.colors.len() == 512
+    }
+
+    /// Color of a palette index as displayed with the given PPUMASK
+    pub fn rgb(&self, index: u8, mask: u8) -> (u8, u8, u8) {
+        let mut index = index & 0x3F;
+        if mask & GREYSCALE != 0 {
+            index &= 0x30;
+        }
+        let emphasis = (mask & EMPHASIS) >> 5;
+
+        if self.has_emphasis() {
+            return self.colors[(emphasis as usize) << 6 | index as usize];
+        }
+
+        let (r, g, b) = self.colors[index as usize];
+        if emphasis == 0 {
+            return (r, g, b);
+        }
+        let mut rgb = [r, g, b];
+        for (channel, level) in rgb.iter_mut().enumerate() {
+            // emphasis bits are red, green, blue from bit 5 up (NTSC),
+            // each one dims the other two channels
+            if emphasis & !(1 << channel) != 0 {
+                *level = (*level as f32 * ATTENUATION) as u8;
+            }
+        }
+        (rgb[0], rgb[1], rgb[2])
+    }
+
+    /// Converts a 256x240 picture of palette indices into RGB
+    pub fn to_frame(&self, indices: &[u8], mask: u8) -> Frame {
+        let mut frame = Frame::new();
+        for (i, index) in indices
+            .iter()
+            .enumerate()
+            .take(Frame::WIDTH * Frame::HEIGHT)
+        {
+            frame.set_pixel(i % Frame::WIDTH, i / Frame::WIDTH, self.rgb(*index, mask));
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(palette.rgb(0x70, 0), (0xFF, 0xFF, 0xFF));
+        // greyscale keeps only the brightness column
+        assert_eq!(palette.rgb(0x16, GREYSCALE), palette.rgb(0x10, 0));
+        // red emphasis dims green and blue
+        assert_eq!(palette.rgb(0x30, 0b0010_0000), (0xFF, 0xD0, 0xD0));
+        // all three darken everything
+        assert_eq!(palette.rgb(0x30, 0b1110_0000), (0xD0, 0xD0, 0xD0));
+
+        let frame = palette.to_frame(&[0x21; 256 * 240], 0);
+        assert_eq!(frame.pixel(100, 100), SYSTEM_PALETTE[0x21]);
+    }
+
+    #[test]
+    fn test_pal_files() {
+        assert!(Palette::from_bytes(&[0; 100]).is_err());
+
+        let mut raw = vec![0; 512 * 3];
+        // color $01 with green emphasis
+        raw[(2 << 6 | 0x01) * 3] = 42;
+        let palette = Palette::from_bytes(&raw).unwrap();
+        assert!(palette.has_emphasis());
+        assert_eq!(palette.rgb(0x01, 0b0100_0000), (42, 0, 0));
+        assert_eq!(palette.rgb(0x01, 0), (0, 0, 0));
+    }
+}
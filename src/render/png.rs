@@ -0,0 +1,92 @@
+use crate::hash;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const COLOR_TYPE_RGB: u8 = 2;
+// largest payload of a stored deflate block
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encodes 8-bit RGB pixels as a PNG file.
+/// The image data is not compressed: it is wrapped in stored deflate blocks,
+/// which every decoder accepts and which keeps the encoder trivial.
+pub fn encode_rgb(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(
+        rgb.len(),
+        width * height * 3,
+        "pixel data does not match size"
+    );
+
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = vec![];
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // bit depth, color type, compression, filter, interlace
+    header.extend([8, COLOR_TYPE_RGB, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // every scanline starts with its filter type, 0 = none
+    let mut scanlines = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks(width * 3) {
+        scanlines.push(0);
+        scanlines.extend(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let crc = hash::crc32_update(hash::crc32(kind), data);
+    png.extend(crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate, 32K window, no preset dictionary; 0x78 0x01 passes the header check
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend([0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(block);
+    }
+    out.extend(hash::adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_small_image() {
+        let png = encode_rgb(2, 1, &[255, 0, 0, 0, 0, 255]);
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(
+            png[8..33],
+            [
+                0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0, 0x7B,
+                0x40, 0xE8, 0xDD
+            ]
+        );
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+
+    #[test]
+    fn test_zlib_stored_blocks() {
+        let data = vec![7u8; MAX_STORED_BLOCK + 10];
+        let zlib = zlib_stored(&data);
+        // header + two block headers + data + adler32
+        assert_eq!(zlib.len(), 2 + 5 + 5 + data.len() + 4);
+        assert_eq!(zlib[2], 0);
+        assert_eq!(zlib[2 + 5 + MAX_STORED_BLOCK], 1);
+    }
+}
@@ -0,0 +1,268 @@
+use super::{Mirroring, Rom};
+use crate::region::Region;
+use std::collections::HashMap;
+
+// one line per game, see the header of the file for the columns
+const BUNDLED: &str = include_str!("db.txt");
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DumpStatus {
+    Good,
+    // known to be corrupted, overdumped or hacked
+    Bad,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct GameInfo {
+    pub title: String,
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+    pub status: DumpStatus,
+    crc32: u32,
+    sha1: Option<[u8; 20]>,
+}
+
+impl GameInfo {
+    pub fn is_bad_dump(&self) -> bool {
+        self.status == DumpStatus::Bad
+    }
+}
+
+/// Known dumps by the hashes of their PRG-ROM and CHR-ROM. Many iNES 1.0 headers
+/// have wrong mapper, mirroring or battery bits, the database has the right ones.
+#[derive(Default)]
+pub struct RomDb {
+    games: Vec<GameInfo>,
+    by_crc32: HashMap<u32, Vec<usize>>,
+}
+
+impl RomDb {
+    /// The database compiled into the emulator. It is a stub that only knows the
+    /// bundled Snake ROM, real games need a database added with `extend`.
+    pub fn bundled() -> RomDb {
+        RomDb::parse(BUNDLED).expect("bundled ROM database")
+    }
+
+    /// The bundled database with the entries of a file in the same format added
+    pub fn bundled_with(path: &str) -> Result<RomDb, String> {
+        let mut db = RomDb::bundled();
+        db.extend(RomDb::load(path)?);
+        Ok(db)
+    }
+
+    pub fn load(path: &str) -> Result<RomDb, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        RomDb::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Lines of `crc32 sha1 mapper mirroring battery region status title`, `#` starts a comment
+    pub fn parse(text: &str) -> Result<RomDb, String> {
+        let mut db = RomDb::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let game = parse_line(line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+            db.add(game);
+        }
+        Ok(db)
+    }
+
+    /// Adds the entries of `other`, they win over existing ones with the same hashes
+    pub fn extend(&mut self, other: RomDb) {
+        for game in other.games {
+            self.add(game);
+        }
+    }
+
+    fn add(&mut self, game: GameInfo) {
+        self.by_crc32
+            .entry(game.crc32)
+            .or_default()
+            .insert(0, self.games.len());
+        self.games.push(game);
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    /// Finds the game by CRC-32; entries with a SHA-1 must match it too
+    pub fn lookup(&self, rom: &Rom) -> Option<&GameInfo> {
+        let candidates = self.by_crc32.get(&rom.crc32())?;
+        let sha1 = rom.sha1();
+        candidates
+            .iter()
+            .map(|i| &self.games[*i])
+            .find(|game| game.sha1.is_none_or(|hash| hash == sha1))
+    }
+
+    /// Replaces the header fields of a known ROM with the database ones,
+    /// unless the header is NES 2.0
+    pub fn correct(&self, rom: &mut Rom) -> Option<&GameInfo> {
+        let game = self.lookup(rom)?;
+        if rom.nes2 {
+            return Some(game);
+        }
+        rom.mapper = game.mapper;
+        rom.screen_mirroring = game.mirroring;
+        rom.battery = game.battery;
+        rom.region = game.region;
+        Some(game)
+    }
+}
+
+fn parse_line(line: &str) -> Result<GameInfo, String> {
+    let mut fields = line.split_whitespace();
+    let mut field = |name: &str| fields.next().ok_or(format!("missing {}", name));
+
+    let crc32 = field("crc32")?;
+    let crc32 =
+        u32::from_str_radix(crc32, 16).map_err(|_| format!("malformed crc32 '{}'", crc32))?;
+    let sha1 = match field("sha1")? {
+        "-" => None,
+        sha1 => Some(parse_sha1(sha1).ok_or(format!("malformed sha1 '{}'", sha1))?),
+    };
+    let mapper = field("mapper")?;
+    let mapper = mapper
+        .parse()
+        .map_err(|_| format!("malformed mapper '{}'", mapper))?;
+    let mirroring = match field("mirroring")? {
+        "h" => Mirroring::HORIZONTAL,
+        "v" => Mirroring::VERTICAL,
+        "4" => Mirroring::FOUR_SCREEN,
+        mirroring => return Err(format!("unknown mirroring '{}'", mirroring)),
+    };
+    let battery = match field("battery")? {
+        "b" => true,
+        "-" => false,
+        battery => return Err(format!("malformed battery '{}'", battery)),
+    };
+    let region = field("region")?.parse()?;
+    let status = match field("status")? {
+        "good" => DumpStatus::Good,
+        "bad" => DumpStatus::Bad,
+        status => return Err(format!("unknown status '{}'", status)),
+    };
+    let title = fields.collect::<Vec<&str>>().join(" ");
+    if title.is_empty() {
+        return Err("missing title".to_string());
+    }
+
+    Ok(GameInfo {
+        title,
+        mapper,
+        mirroring,
+        battery,
+        region,
+        status,
+        crc32,
+        sha1,
+    })
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0u8; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(sha1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash;
+    use crate::rom::test::test_rom_with_prg;
+
+    #[test]
+    fn test_correct_header() {
+        let mut rom = test_rom_with_prg(vec![0xEA; 0x8000]);
+        let line = format!(
+            "{:08x} {} 4 h b pal good  Some  Game",
+            rom.crc32(),
+            hash::to_hex(&rom.sha1())
+        );
+        let db = RomDb::parse(&format!("# comment\n\n{}\n", line)).unwrap();
+
+        let game = db.correct(&mut rom).unwrap();
+        assert_eq!(game.title, "Some Game");
+        assert!(!game.is_bad_dump());
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.screen_mirroring, Mirroring::HORIZONTAL);
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::Pal);
+
+        let mut rom = test_rom_with_prg(vec![0xEA; 0x8000]);
+        rom.nes2 = true;
+        assert_eq!(db.correct(&mut rom).unwrap().title, "Some Game");
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let rom = test_rom_with_prg(vec![0xEA; 0x8000]);
+        let crc32 = rom.crc32();
+        let db = RomDb::parse(&format!(
+            "{:08x} - 0 v - ntsc bad Hacked\n{:08x} {} 1 v - ntsc good Collision",
+            crc32,
+            crc32,
+            "0".repeat(40)
+        ))
+        .unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.lookup(&rom).unwrap().is_bad_dump());
+
+        let other = test_rom_with_prg(vec![0; 0x8000]);
+        assert_eq!(db.lookup(&other), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            RomDb::parse("# header\n12345678 - 0 x - ntsc good Game").err(),
+            Some("line 2: unknown mirroring 'x'".to_string())
+        );
+        assert_eq!(
+            RomDb::parse("12345678 abc 0 v - ntsc good Game").err(),
+            Some("line 1: malformed sha1 'abc'".to_string())
+        );
+        assert_eq!(
+            RomDb::parse("12345678 - 0 v - ntsc good").err(),
+            Some("line 1: missing title".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bundled() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/snake.nes");
+        let mut rom = Rom::new(&std::fs::read(path).unwrap()).unwrap();
+        let game = RomDb::bundled().correct(&mut rom).unwrap().clone();
+        assert_eq!(game.title, "Snake");
+        assert_eq!(rom.mapper, 0);
+
+        let extra = std::env::temp_dir().join(format!("nes_db_{}.txt", std::process::id()));
+        let line = format!(
+            "{:08x} {} 0 h - pal good Snake (E)",
+            rom.crc32(),
+            hash::to_hex(&rom.sha1())
+        );
+        std::fs::write(&extra, line).unwrap();
+        let db = RomDb::bundled_with(extra.to_str().unwrap());
+        std::fs::remove_file(&extra).unwrap();
+        let db = db.unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.lookup(&rom).unwrap().title, "Snake (E)");
+    }
+}
@@ -0,0 +1,162 @@
+// optional 16 byte fwNES header: "FDS\x1a", the number of sides and padding
+pub const FDS_TAG: &[u8] = b"FDS\x1a";
+const HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+// every side starts with the disk info block
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+
+const DISK_INFO_BLOCK: u8 = 1;
+const FILE_AMOUNT_BLOCK: u8 = 2;
+const FILE_HEADER_BLOCK: u8 = 3;
+const FILE_DATA_BLOCK: u8 = 4;
+// the drive sees about 28300 bits of gap before the first block and 976 after each
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+
+/// Famicom Disk System disk: the blocks of each side without gaps and CRCs
+#[derive(Debug, Clone)]
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    /// `.fds` file contents, with or without the header
+    pub fn parse(raw: &[u8]) -> Result<FdsImage, String> {
+        let data = if raw.starts_with(FDS_TAG) {
+            raw.get(HEADER_SIZE..).ok_or("FDS file is truncated")?
+        } else {
+            raw
+        };
+        if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+            return Err(format!(
+                "FDS image has {} bytes, expected a multiple of {}",
+                data.len(),
+                SIDE_SIZE
+            ));
+        }
+        let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(|side| side.to_vec()).collect();
+        if let Some(n) = sides.iter().position(|side| !side.starts_with(DISK_INFO)) {
+            return Err(format!(
+                "side {} of the FDS image has no disk info block",
+                n + 1
+            ));
+        }
+        Ok(FdsImage { sides })
+    }
+
+    pub fn is_fds(raw: &[u8]) -> bool {
+        raw.starts_with(FDS_TAG) || raw.starts_with(DISK_INFO)
+    }
+
+    /// The side the way the drive reads it: blocks between gaps, each with
+    /// a start mark and a CRC
+    pub fn raw_side(&self, side: usize) -> Vec<u8> {
+        let data = &self.sides[side];
+        let mut raw = vec![0; LEADING_GAP];
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = match data[pos] {
+                DISK_INFO_BLOCK => 56,
+                FILE_AMOUNT_BLOCK => 2,
+                FILE_HEADER_BLOCK => 16,
+                // the size is in the file header right before
+                FILE_DATA_BLOCK if pos >= 3 => {
+                    1 + u16::from_le_bytes([data[pos - 3], data[pos - 2]]) as usize
+                }
+                // the rest of the side is unused
+                _ => break,
+            };
+            let block = &data[pos..(pos + len).min(data.len())];
+            let mut crc = 0;
+            for byte in [BLOCK_START].iter().chain(block) {
+                crc = crc16(crc, *byte);
+            }
+            crc = crc16(crc16(crc, 0), 0);
+
+            raw.push(BLOCK_START);
+            raw.extend(block);
+            raw.extend(crc.to_le_bytes());
+            raw.extend([0; BLOCK_GAP]);
+            pos += len;
+        }
+        raw.resize(raw.len().max(SIDE_SIZE + LEADING_GAP), 0);
+        raw
+    }
+}
+
+/// Shifts a byte into the CRC of the disk drive. A block followed by its
+/// CRC leaves 0, the CRC itself is what two more zero bytes leave.
+pub fn crc16(crc: u16, data: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if data & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// One side with the disk info block and a file of `data`
+    pub fn test_image(data: &[u8]) -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(56, 0);
+        side.extend([FILE_AMOUNT_BLOCK, 1, FILE_HEADER_BLOCK, 0, 0]);
+        side.extend(b"FILE0000");
+        side.extend(0x6000u16.to_le_bytes());
+        side.extend((data.len() as u16).to_le_bytes());
+        side.push(0);
+        side.push(FILE_DATA_BLOCK);
+        side.extend(data);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut raw = FDS_TAG.to_vec();
+        raw.extend([2]);
+        raw.resize(HEADER_SIZE, 0);
+        raw.extend(test_image(b"one"));
+        raw.extend(test_image(b"two"));
+        assert!(FdsImage::is_fds(&raw));
+        let image = FdsImage::parse(&raw).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.sides[1], test_image(b"two"));
+
+        raw.truncate(raw.len() - 1);
+        assert!(FdsImage::parse(&raw).is_err());
+        assert_eq!(
+            FdsImage::parse(&[0; SIDE_SIZE]).err(),
+            Some("side 1 of the FDS image has no disk info block".to_string())
+        );
+    }
+
+    #[test]
+    fn test_raw_side() {
+        let image = FdsImage::parse(&test_image(b"data")).unwrap();
+        let raw = image.raw_side(0);
+        assert!(raw[..LEADING_GAP].iter().all(|b| *b == 0));
+        assert_eq!(raw[LEADING_GAP], BLOCK_START);
+        assert_eq!(&raw[LEADING_GAP + 1..LEADING_GAP + 16], DISK_INFO);
+
+        // start mark, block and CRC check out to 0
+        let block = &raw[LEADING_GAP..LEADING_GAP + 1 + 56 + 2];
+        assert_eq!(block.iter().fold(0, |crc, b| crc16(crc, *b)), 0);
+
+        let file = LEADING_GAP
+            + (1 + 56 + 2 + BLOCK_GAP)
+            + (1 + 2 + 2 + BLOCK_GAP)
+            + (1 + 16 + 2 + BLOCK_GAP);
+        assert_eq!(&raw[file..file + 6], b"\x80\x04data");
+    }
+}
@@ -0,0 +1,270 @@
+use super::db::RomDb;
+use super::fds::FdsImage;
+use super::unif::{self, UNIF_TAG};
+use super::Rom;
+use crate::hash::crc32;
+use miniz_oxide::inflate::decompress_to_vec;
+use std::path::Path;
+
+const NES_TAG: &[u8] = b"NES\x1a";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_LOCAL_HEADER: u32 = 0x04034b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP_END_OF_DIRECTORY: u32 = 0x06054b50;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Loads a `.nes` or `.unf` file, or the first of them in a `.zip` or `.gz` archive.
+/// Header fields of games in the bundled `RomDb` are replaced with the database ones.
+/// `.fds` disk images are not cartridges, see `FdsImage::parse`.
+pub fn load(path: &Path) -> Result<Rom, String> {
+    load_entry(path, None)
+}
+
+/// Same as `load`, with the name of the zip entry to use instead of the first ROM
+pub fn load_entry(path: &Path, entry: Option<&str>) -> Result<Rom, String> {
+    load_with_db(path, entry, &RomDb::bundled())
+}
+
+/// Same as `load_entry`, with the header checked against the given database
+pub fn load_with_db(path: &Path, entry: Option<&str>, db: &RomDb) -> Result<Rom, String> {
+    let mut rom = load_as_is(path, entry)?;
+    db.correct(&mut rom);
+    Ok(rom)
+}
+
+/// Same as `load_entry`, but keeps the header as the file has it
+pub fn load_as_is(path: &Path, entry: Option<&str>) -> Result<Rom, String> {
+    let bytes = read(path, entry)?;
+    parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// iNES or UNIF file contents, the header is not checked against the `RomDb`
+pub fn parse(raw: &Vec<u8>) -> Result<Rom, String> {
+    if raw.starts_with(UNIF_TAG) {
+        unif::parse(raw)
+    } else {
+        Rom::new(raw)
+    }
+}
+
+/// Raw ROM file bytes, taken out of the archive when the file is one
+pub fn read(path: &Path, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    extract(&raw, entry).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Tells archives by their magic, anything else is returned as is
+pub fn extract(raw: &[u8], entry: Option<&str>) -> Result<Vec<u8>, String> {
+    if raw.starts_with(GZIP_MAGIC) {
+        gunzip(raw)
+    } else if raw.len() >= 4 && u32_at(raw, 0) == ZIP_LOCAL_HEADER {
+        unzip(raw, entry)
+    } else {
+        Ok(raw.to_vec())
+    }
+}
+
+fn u16_at(raw: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([raw[at], raw[at + 1]])
+}
+
+fn u32_at(raw: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]])
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    decompress_to_vec(data).map_err(|e| format!("corrupted archive: {:?}", e.status))
+}
+
+// RFC 1952: 10 byte header, optional fields, deflate data, CRC-32 and size
+fn gunzip(raw: &[u8]) -> Result<Vec<u8>, String> {
+    const FHCRC: u8 = 0b0010;
+    const FEXTRA: u8 = 0b0100;
+    const FNAME: u8 = 0b1000;
+    const FCOMMENT: u8 = 0b1_0000;
+
+    let truncated = || "gzip file is truncated".to_string();
+    if raw.len() < 18 {
+        return Err(truncated());
+    }
+    if raw[2] != 8 {
+        return Err("unsupported gzip compression method".to_string());
+    }
+    let flags = raw[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        pos += 2 + u16_at(raw, pos) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let len = raw
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0));
+            pos += len.ok_or_else(truncated)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let trailer = raw.len() - 8;
+    let data = inflate(raw.get(pos..trailer).ok_or_else(truncated)?)?;
+    if crc32(&data) != u32_at(raw, trailer) {
+        return Err("gzip checksum mismatch".to_string());
+    }
+    Ok(data)
+}
+
+struct ZipEntry<'a> {
+    name: String,
+    method: u16,
+    crc: u32,
+    data: &'a [u8],
+}
+
+fn zip_entries(raw: &[u8]) -> Result<Vec<ZipEntry<'_>>, String> {
+    let corrupted = || "corrupted zip file".to_string();
+    // the end of central directory record is 22 bytes plus a comment of up to 64KiB
+    let end = (0..raw.len().saturating_sub(21))
+        .rev()
+        .take(0x10000)
+        .find(|at| u32_at(raw, *at) == ZIP_END_OF_DIRECTORY)
+        .ok_or_else(corrupted)?;
+    let count = u16_at(raw, end + 10) as usize;
+    let mut pos = u32_at(raw, end + 16) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if pos + 46 > raw.len() || u32_at(raw, pos) != ZIP_CENTRAL_HEADER {
+            return Err(corrupted());
+        }
+        let method = u16_at(raw, pos + 10);
+        let crc = u32_at(raw, pos + 16);
+        let size = u32_at(raw, pos + 20) as usize;
+        let name_len = u16_at(raw, pos + 28) as usize;
+        let extra_len = u16_at(raw, pos + 30) as usize;
+        let comment_len = u16_at(raw, pos + 32) as usize;
+        let local = u32_at(raw, pos + 42) as usize;
+        let name = raw
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or_else(corrupted)?;
+
+        if local + 30 > raw.len() || u32_at(raw, local) != ZIP_LOCAL_HEADER {
+            return Err(corrupted());
+        }
+        // the local header has its own name and extra field lengths
+        let start =
+            local + 30 + u16_at(raw, local + 26) as usize + u16_at(raw, local + 28) as usize;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).to_string(),
+            method,
+            crc,
+            data: raw.get(start..start + size).ok_or_else(corrupted)?,
+        });
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn unzip_entry(entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    let data = match entry.method {
+        STORED => entry.data.to_vec(),
+        DEFLATED => inflate(entry.data)?,
+        method => {
+            return Err(format!(
+                "{}: unsupported zip compression method {}",
+                entry.name, method
+            ))
+        }
+    };
+    if crc32(&data) != entry.crc {
+        return Err(format!("{}: zip checksum mismatch", entry.name));
+    }
+    Ok(data)
+}
+
+fn unzip(raw: &[u8], name: Option<&str>) -> Result<Vec<u8>, String> {
+    let entries = zip_entries(raw)?;
+    if let Some(name) = name {
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or(format!("no {} in the archive", name))?;
+        return unzip_entry(entry);
+    }
+
+    for entry in entries.iter().filter(|entry| !entry.name.ends_with('/')) {
+        let data = unzip_entry(entry)?;
+        if data.starts_with(NES_TAG) || data.starts_with(UNIF_TAG) || FdsImage::is_fds(&data) {
+            return Ok(data);
+        }
+    }
+    Err("no iNES, UNIF or FDS file in the archive".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::Mirroring;
+
+    fn data(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/data")
+            .join(name)
+    }
+
+    #[test]
+    fn test_archives() {
+        let plain = load(&data("../../snake.nes")).unwrap();
+        for archive in ["snake.zip", "snake.nes.gz"] {
+            let rom = load(&data(archive)).unwrap();
+            assert_eq!(rom.prg_rom, plain.prg_rom);
+            assert_eq!(rom.chr_rom, plain.chr_rom);
+        }
+    }
+
+    #[test]
+    fn test_header_correction() {
+        // snake.nes with the horizontal mirroring bit instead of vertical
+        let mut raw = std::fs::read(data("../../snake.nes")).unwrap();
+        raw[6] &= !1;
+        let path = std::env::temp_dir().join(format!("nes_loader_{}.nes", std::process::id()));
+        std::fs::write(&path, &raw).unwrap();
+        let corrected = load(&path).unwrap();
+        let as_is = load_as_is(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(corrected.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(as_is.screen_mirroring, Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_named_entry() {
+        assert_eq!(
+            read(&data("snake.zip"), Some("readme.txt")).unwrap(),
+            b"Snake for the NES\n"
+        );
+        let err = load_entry(&data("snake.zip"), Some("readme.txt")).err();
+        assert!(err.unwrap().ends_with("File is not in iNES file format"));
+        assert!(read(&data("snake.zip"), Some("game.nes"))
+            .err()
+            .unwrap()
+            .ends_with("no game.nes in the archive"));
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut raw = std::fs::read(data("snake.nes.gz")).unwrap();
+        let at = raw.len() - 8;
+        raw[at] ^= 1;
+        assert_eq!(
+            extract(&raw, None).err(),
+            Some("gzip checksum mismatch".to_string())
+        );
+        assert_eq!(
+            extract(&[0x50, 0x4b, 0x03, 0x04], None).err(),
+            Some("corrupted zip file".to_string())
+        );
+    }
+}
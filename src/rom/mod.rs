@@ -1,12 +1,23 @@
+use crate::hash;
+use crate::region::Region;
+
+pub mod db;
+pub mod fds;
+pub mod loader;
+pub mod unif;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
+    // both nametables show the first or the second 1KiB of VRAM
+    SINGLE_SCREEN_LOWER,
+    SINGLE_SCREEN_UPPER,
 }
 
 pub struct Rom {
@@ -14,20 +25,27 @@ pub struct Rom {
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    // PRG-RAM is battery backed and should be saved
+    pub battery: bool,
+    pub region: Region,
+    // NES 2.0 headers are exact, the `RomDb` leaves them alone
+    pub nes2: bool,
 }
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 16 || &raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
 
         let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
-        }
+        let nes2 = match ines_ver {
+            0 => false,
+            2 => true,
+            _ => return Err("Unknown iNES header version".to_string()),
+        };
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
@@ -37,21 +55,85 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let truncated = || "ROM file is truncated".to_string();
+        let (prg_rom_size, chr_rom_size, region) = if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+            let region = match raw[12] & 0b11 {
+                1 => Region::Pal,
+                3 => Region::Dendy,
+                // 2 is "multiple regions", those run fine as NTSC
+                _ => Region::Ntsc,
+            };
+            (
+                // sizes too large to count can't be in the file either
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE).ok_or_else(truncated)?,
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE).ok_or_else(truncated)?,
+                region,
+            )
+        } else {
+            let region = if raw[9] & 1 != 0 {
+                Region::Pal
+            } else {
+                Region::Ntsc
+            };
+            (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                raw[5] as usize * CHR_ROM_PAGE_SIZE,
+                region,
+            )
+        };
+
+        if mapper > 0xFF {
+            return Err(format!("Mapper {} is not supported", mapper));
+        }
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let prg_rom_start: usize = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start
+            .checked_add(prg_rom_size)
+            .ok_or_else(truncated)?;
+        let chr_rom_end = chr_rom_start
+            .checked_add(chr_rom_size)
+            .ok_or_else(truncated)?;
+        if raw.len() < chr_rom_end {
+            return Err(truncated());
+        }
 
         Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
+            mapper: mapper as u8,
             screen_mirroring: screen_mirroring,
+            battery: raw[6] & 0b10 != 0,
+            region,
+            nes2,
         })
     }
+
+    /// CRC-32 of PRG-ROM followed by CHR-ROM, the key of ROM databases
+    pub fn crc32(&self) -> u32 {
+        hash::crc32_update(hash::crc32(&self.prg_rom), &self.chr_rom)
+    }
+
+    /// SHA-1 of PRG-ROM followed by CHR-ROM
+    pub fn sha1(&self) -> [u8; 20] {
+        let mut data = self.prg_rom.clone();
+        data.extend(&self.chr_rom);
+        hash::sha1(&data)
+    }
+}
+
+// NES 2.0 sizes: the header's LSB byte with a MSB nibble, or 2^E * (MM*2+1)
+// when the nibble is $F and the LSB byte holds EEEEEEMM; None when it overflows
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Option<usize> {
+    if msb == 0xF {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.checked_pow(exponent)?.checked_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize).checked_mul(page_size)
+    }
 }
 
 pub mod test {
@@ -83,10 +165,11 @@ pub mod test {
         result
     }
 
+    // NROM-256 with CHR-ROM, vertical mirroring
     pub fn test_rom() -> Rom {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
             pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
@@ -96,6 +179,19 @@ pub mod test {
         Rom::new(&test_rom).unwrap()
     }
 
+    // NROM cartridge running the given PRG-ROM, e.g. assembled with `.org $8000`
+    pub fn test_rom_with_prg(prg_rom: Vec<u8>) -> Rom {
+        Rom {
+            prg_rom,
+            chr_rom: vec![],
+            mapper: 0,
+            screen_mirroring: Mirroring::VERTICAL,
+            battery: false,
+            region: Region::Ntsc,
+            nes2: false,
+        }
+    }
+
     #[test]
     fn test() {
         let test_rom = create_rom(TestRom {
@@ -112,6 +208,7 @@ pub mod test {
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
+        assert!(!rom.nes2);
         assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
     }
 
@@ -150,19 +247,74 @@ pub mod test {
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2_header() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 0x01, 00, 00, 00,
             ],
             trainer: None,
-            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format is not supported"),
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom, vec!(1; PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.region, Region::Pal);
+        assert!(rom.nes2);
+
+        assert_eq!(
+            nes2_rom_size(0x02, 0x1, PRG_ROM_PAGE_SIZE),
+            Some(0x102 * PRG_ROM_PAGE_SIZE)
+        );
+        // 2^3 * 3
+        assert_eq!(nes2_rom_size(0b1101, 0xF, PRG_ROM_PAGE_SIZE), Some(24));
+        assert_eq!(nes2_rom_size(0xFF, 0xF, PRG_ROM_PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn test_nes2_huge_sizes() {
+        let mut raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0xFF, 0x01, 0x00, 0x8, 00, 0x0F, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        // 2^63 * 7 does not fit, 2^63 does but the sum with the start does not
+        for (prg, chr) in [(0xFF, 0x01), (0xFC, 0xFC)] {
+            raw[4] = prg;
+            raw[5] = chr;
+            raw[9] = if chr == 0xFC { 0xFF } else { 0x0F };
+            assert_eq!(
+                Rom::new(&raw).err(),
+                Some("ROM file is truncated".to_string())
+            );
         }
     }
+
+    #[test]
+    fn test_ines_region_and_errors() {
+        let mut raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 00, 00, 0x01, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        assert_eq!(Rom::new(&raw).unwrap().region, Region::Pal);
+
+        raw[7] = 0b0100;
+        assert_eq!(
+            Rom::new(&raw).err(),
+            Some("Unknown iNES header version".to_string())
+        );
+        raw[7] = 0;
+        raw.truncate(100);
+        assert_eq!(
+            Rom::new(&raw).err(),
+            Some("ROM file is truncated".to_string())
+        );
+    }
 }
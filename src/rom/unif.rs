@@ -0,0 +1,230 @@
+use super::{Mirroring, Rom};
+use crate::region::Region;
+
+// "UNIF", revision and padding up to 32 bytes, then chunks of a 4 byte id,
+// a 32 bit length and the data
+pub const UNIF_TAG: &[u8] = b"UNIF";
+const HEADER_SIZE: usize = 32;
+
+// PRG chunks hold whole 8KiB banks, CHR chunks whole 1KiB banks
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+// UNIF names boards instead of numbering mappers
+const BOARDS: [(&str, u8); 38] = [
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("RROM", 0),
+    ("SAROM", 1),
+    ("SBROM", 1),
+    ("SCROM", 1),
+    ("SEROM", 1),
+    ("SGROM", 1),
+    ("SKROM", 1),
+    ("SLROM", 1),
+    ("SL1ROM", 1),
+    ("SNROM", 1),
+    ("SOROM", 1),
+    ("SUROM", 1),
+    ("SXROM", 1),
+    ("UNROM", 2),
+    ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4),
+    ("TEROM", 4),
+    ("TFROM", 4),
+    ("TGROM", 4),
+    ("TKROM", 4),
+    ("TLROM", 4),
+    ("TR1ROM", 4),
+    ("TSROM", 4),
+    ("TVROM", 4),
+    ("EKROM", 5),
+    ("ELROM", 5),
+    ("ETROM", 5),
+    ("EWROM", 5),
+    ("AMROM", 7),
+    ("ANROM", 7),
+    ("AOROM", 7),
+    ("PNROM", 9),
+    ("CPROM", 13),
+    ("GNROM", 66),
+];
+
+/// Mapper number of a UNIF board name like `NES-SNROM`
+pub fn board_mapper(board: &str) -> Option<u8> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, mapper)| *mapper)
+}
+
+/// Builds a Rom out of the MAPR, PRGn, CHRn, MIRR, BATR and TVCI chunks of a `.unf` file
+pub fn parse(raw: &[u8]) -> Result<Rom, String> {
+    if !raw.starts_with(UNIF_TAG) {
+        return Err("File is not in UNIF file format".to_string());
+    }
+    if raw.len() < HEADER_SIZE {
+        return Err("UNIF file is truncated".to_string());
+    }
+
+    let mut board = None;
+    // PRG0-PRGF and CHR0-CHRF are concatenated in the order of their numbers
+    let mut prg_chunks: [&[u8]; 16] = Default::default();
+    let mut chr_chunks: [&[u8]; 16] = Default::default();
+    let mut screen_mirroring = Mirroring::HORIZONTAL;
+    let mut battery = false;
+    let mut region = Region::Ntsc;
+
+    let mut pos = HEADER_SIZE;
+    while pos < raw.len() {
+        let header = raw.get(pos..pos + 8).ok_or("UNIF file is truncated")?;
+        let id = &header[0..4];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = raw
+            .get(pos + 8..pos + 8 + len)
+            .ok_or("UNIF file is truncated")?;
+        pos += 8 + len;
+
+        let first = |what: &str| data.first().copied().ok_or(format!("empty {} chunk", what));
+        match id {
+            b"MAPR" => {
+                let name = data.split(|b| *b == 0).next().unwrap_or_default();
+                board = Some(String::from_utf8_lossy(name).to_string());
+            }
+            [b'P', b'R', b'G', n] | [b'C', b'H', b'R', n] => {
+                let index = (*n as char)
+                    .to_digit(16)
+                    .ok_or(format!("unknown chunk {}", String::from_utf8_lossy(id)))?;
+                let (chunks, bank_size) = if id[0] == b'P' {
+                    (&mut prg_chunks, PRG_BANK_SIZE)
+                } else {
+                    (&mut chr_chunks, CHR_BANK_SIZE)
+                };
+                if data.len() % bank_size != 0 {
+                    return Err(format!(
+                        "{} chunk has {} bytes, not a multiple of {}",
+                        String::from_utf8_lossy(id),
+                        data.len(),
+                        bank_size
+                    ));
+                }
+                chunks[index as usize] = data;
+            }
+            b"MIRR" => {
+                screen_mirroring = match first("MIRR")? {
+                    0 => Mirroring::HORIZONTAL,
+                    1 => Mirroring::VERTICAL,
+                    2 => Mirroring::SINGLE_SCREEN_LOWER,
+                    3 => Mirroring::SINGLE_SCREEN_UPPER,
+                    4 => Mirroring::FOUR_SCREEN,
+                    // controlled by the mapper, it sets it on reset
+                    5 => Mirroring::HORIZONTAL,
+                    mode => return Err(format!("unknown mirroring {} in MIRR chunk", mode)),
+                }
+            }
+            b"BATR" => battery = true,
+            b"TVCI" => {
+                region = match first("TVCI")? {
+                    1 => Region::Pal,
+                    _ => Region::Ntsc,
+                }
+            }
+            // name, dumper, checksums and other metadata
+            _ => {}
+        }
+    }
+
+    let board = board.ok_or("UNIF file has no MAPR chunk")?;
+    let mapper = board_mapper(&board).ok_or(format!("Unknown UNIF board '{}'", board))?;
+    let prg_rom = prg_chunks.concat();
+    if prg_rom.is_empty() {
+        return Err("UNIF file has no PRG chunk".to_string());
+    }
+
+    Ok(Rom {
+        prg_rom,
+        chr_rom: chr_chunks.concat(),
+        mapper,
+        screen_mirroring,
+        battery,
+        region,
+        nes2: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unif(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut raw = UNIF_TAG.to_vec();
+        raw.extend(7u32.to_le_bytes());
+        raw.resize(HEADER_SIZE, 0);
+        for (id, data) in chunks {
+            raw.extend(*id);
+            raw.extend((data.len() as u32).to_le_bytes());
+            raw.extend(*data);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = unif(&[
+            (b"MAPR", b"NES-SNROM\0"),
+            (b"NAME", b"Game\0"),
+            (b"PRG1", &[2; 0x4000]),
+            (b"PRG0", &[1; 0x4000]),
+            (b"CHR0", &[3; 0x2000]),
+            (b"MIRR", &[1]),
+            (b"BATR", &[0]),
+            (b"TVCI", &[1]),
+        ]);
+        let rom = parse(&raw).unwrap();
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!((rom.prg_rom[0], rom.prg_rom[0x4000]), (1, 2));
+        assert_eq!(rom.chr_rom, vec![3; 0x2000]);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::Pal);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(board_mapper("UNL-TLROM"), Some(4));
+        assert_eq!(board_mapper("NROM-256"), Some(0));
+
+        let raw = unif(&[(b"MAPR", b"NES-XYZ\0"), (b"PRG0", &[0; 0x2000])]);
+        assert_eq!(
+            parse(&raw).err(),
+            Some("Unknown UNIF board 'NES-XYZ'".to_string())
+        );
+        let raw = unif(&[(b"MAPR", b"NES-NROM\0"), (b"PRG0", &[0; 16])]);
+        assert_eq!(
+            parse(&raw).err(),
+            Some("PRG0 chunk has 16 bytes, not a multiple of 8192".to_string())
+        );
+        let raw = unif(&[
+            (b"MAPR", b"NES-NROM\0"),
+            (b"PRG0", &[0; 0x2000]),
+            (b"CHR1", &[0; 0x300]),
+        ]);
+        assert_eq!(
+            parse(&raw).err(),
+            Some("CHR1 chunk has 768 bytes, not a multiple of 1024".to_string())
+        );
+        let mut raw = unif(&[(b"MAPR", b"NES-NROM\0"), (b"PRG0", &[0; 0x2000])]);
+        raw.truncate(raw.len() - 1);
+        assert_eq!(
+            parse(&raw).err(),
+            Some("UNIF file is truncated".to_string())
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::cpu::cpu::{CpuFlags, CPU};
+
+const MAGIC: [u8; 4] = *b"NESS";
+// 2 appended the mapper registers, 3 added the APU
+const VERSION: u8 = 3;
+// magic, version, A, X, Y, P, S, PC, cycles
+const HEADER_SIZE: usize = 4 + 1 + 5 + 2 + 8;
+
+/// Snapshot of the CPU registers, the memory on the bus, the APU and the cartridge.
+/// The cartridge ROM is not part of it: a state only loads into the same game.
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut state = Vec::with_capacity(HEADER_SIZE + 0x4000);
+    state.extend(MAGIC);
+    state.push(VERSION);
+    state.extend([
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status.bits(),
+        cpu.stack_pointer,
+    ]);
+    state.extend(cpu.program_counter.to_le_bytes());
+    state.extend((cpu.cycles as u64).to_le_bytes());
+    cpu.bus().save_state(&mut state);
+    state
+}
+
+pub fn load(cpu: &mut CPU, state: &[u8]) -> Result<(), String> {
+    if state.len() < HEADER_SIZE || state[0..4] != MAGIC {
+        return Err("not a save state".to_string());
+    }
+    if state[4] != VERSION {
+        return Err(format!("unsupported save state version {}", state[4]));
+    }
+    cpu.bus_mut().load_state(&state[HEADER_SIZE..])?;
+
+    cpu.register_a = state[5];
+    cpu.register_x = state[6];
+    cpu.register_y = state[7];
+    cpu.status = CpuFlags::from_bits_truncate(state[8]);
+    cpu.stack_pointer = state[9];
+    cpu.program_counter = u16::from_le_bytes([state[10], state[11]]);
+    let mut cycles = [0; 8];
+    cycles.copy_from_slice(&state[12..20]);
+    cpu.cycles = u64::from_le_bytes(cycles) as usize;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::mem::{BusHooks, Mem};
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_save_and_load() {
+        let mut cpu = CPU::new(Bus::new(test_rom()).unwrap());
+        cpu.register_a = 0x42;
+        cpu.program_counter = 0x8123;
+        cpu.cycles = 1_000_000;
+        cpu.mem_write(0x0010, 0x99);
+        cpu.mem_write(0x6000, 0x77);
+        // a playing pulse channel and a pending frame IRQ
+        cpu.mem_write(0x4015, 0x01);
+        cpu.mem_write(0x4003, 0x08);
+        cpu.bus_mut().tick(30_000);
+        assert_eq!(cpu.mem_peek(0x4015), 0x41);
+        let state = save(&cpu);
+
+        let mut other = CPU::new(Bus::new(test_rom()).unwrap());
+        load(&mut other, &state).unwrap();
+        assert_eq!(other.register_a, 0x42);
+        assert_eq!(other.program_counter, 0x8123);
+        assert_eq!(other.cycles, 1_000_000);
+        assert_eq!(other.mem_peek(0x0010), 0x99);
+        assert_eq!(other.mem_peek(0x6000), 0x77);
+        assert_eq!(other.mem_peek(0x4015), 0x41);
+        assert!(other.bus().irq());
+        assert_eq!(save(&other), state);
+
+        assert!(load(&mut other, &state[..10]).is_err());
+        assert!(load(&mut other, &state[..state.len() - 1]).is_err());
+
+        let mut old = state.clone();
+        old[4] = 2;
+        assert_eq!(
+            load(&mut other, &old).err(),
+            Some("unsupported save state version 2".to_string())
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use crate::bus::Bus;
+use crate::cpu::cpu::CPU;
+use crate::cpu::mem::Mem;
+use crate::rom::loader;
+use crate::rom::Rom;
+use std::path::{Path, PathBuf};
+
+// Test ROMs by blargg and others report through PRG-RAM:
+//  $6000       status: $80 running, $81 reset requested, $00-$7F result code (0 = passed)
+//  $6001-$6003 signature DE B0 61, tells the status is valid
+//  $6004-      zero-terminated text output
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+const TEXT_END: u16 = 0x7FFF;
+const SIGNATURE_BYTES: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+pub const CPU_FREQUENCY: usize = 1_789_773;
+// the ROM asks to wait at least 100ms before pressing reset
+const RESET_DELAY: usize = CPU_FREQUENCY / 10;
+pub const NO_PPU: &str = "screenshots need a PPU, which is not emulated yet";
+
+#[derive(Debug, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    Failed(u8),
+    Timeout,
+    // the CPU stopped, see `Halt`: a jam opcode or hardware that is not emulated
+    Crashed(String),
+}
+
+#[derive(Debug)]
+pub struct TestReport {
+    pub outcome: TestOutcome,
+    // text the ROM printed at $6004
+    pub message: String,
+    pub cycles: usize,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+    }
+}
+
+pub fn run_test_rom_file(path: &str, max_cycles: usize) -> Result<TestReport, String> {
+    let rom = loader::load(Path::new(path))?;
+    run_test_rom(rom, max_cycles).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// `.nes` files in the directory and its subdirectories, sorted by path
+pub fn find_test_roms(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut roms = vec![];
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_dir() {
+            roms.extend(find_test_roms(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("nes"))
+        {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    Ok(roms)
+}
+
+/// Runs the ROM until it reports a result through the $6000 protocol
+/// or until `max_cycles` CPU cycles have passed.
+pub fn run_test_rom(rom: Rom, max_cycles: usize) -> Result<TestReport, String> {
+    let mut cpu = CPU::new(Bus::new(rom)?);
+    cpu.set_brk_halts(false);
+    cpu.reset();
+
+    let mut reset_at = None;
+    let outcome = loop {
+        if !cpu.step() {
+            let halt = cpu.halt().map(|halt| halt.to_string());
+            break TestOutcome::Crashed(halt.unwrap_or_default());
+        }
+
+        if has_signature(&cpu) {
+            match cpu.mem_peek(STATUS) {
+                STATUS_RUNNING => {}
+                STATUS_RESET => match reset_at {
+                    None => reset_at = Some(cpu.cycles + RESET_DELAY),
+                    Some(at) if cpu.cycles >= at => {
+                        reset_at = None;
+                        let cycles = cpu.cycles;
+                        cpu.reset();
+                        cpu.cycles = cycles;
+                    }
+                    Some(_) => {}
+                },
+                0 => break TestOutcome::Passed,
+                code if code < STATUS_RUNNING => break TestOutcome::Failed(code),
+                _ => {}
+            }
+        }
+
+        if cpu.cycles >= max_cycles {
+            break TestOutcome::Timeout;
+        }
+    };
+
+    Ok(TestReport {
+        outcome,
+        message: read_message(&cpu),
+        cycles: cpu.cycles,
+    })
+}
+
+/// Runs the ROM headlessly for `frames` frames and returns the last picture as palette
+/// indices, ready for `Palette::to_frame` or the NTSC filter.
+/// There is no PPU yet to draw the picture: once the ROM is known to fit a mapper
+/// this fails with `NO_PPU` rather than hand out a blank picture that any ROM would match.
+pub fn run_frames(rom: Rom, _frames: usize) -> Result<Vec<u8>, String> {
+    Bus::new(rom)?;
+    Err(NO_PPU.to_string())
+}
+
+fn has_signature(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.mem_peek(SIGNATURE + i) == SIGNATURE_BYTES[i as usize])
+}
+
+fn read_message(cpu: &CPU) -> String {
+    if !has_signature(cpu) {
+        return String::new();
+    }
+    (TEXT..=TEXT_END)
+        .map(|addr| cpu.mem_peek(addr))
+        .take_while(|b| *b != 0)
+        .map(|b| b as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom_with_prg;
+
+    fn protocol_rom(result: u8) -> Rom {
+        let program = crate::asm::assemble(&format!(
+            "
+                    .org $8000
+            reset:  LDA #$80
+                    STA $6000
+                    LDA #$de
+                    STA $6001
+                    LDA #$b0
+                    STA $6002
+                    LDA #$61
+                    STA $6003
+                    LDX #0
+            copy:   LDA text,X
+                    STA $6004,X
+                    INX
+                    CPX #5
+                    BNE copy
+                    LDA #{}
+                    STA $6000
+            hang:   JMP hang
+            text:   .byte \"done\", 0
+                    .org $fffc
+                    .word reset, 0
+            ",
+            result
+        ))
+        .unwrap();
+        test_rom_with_prg(program.code)
+    }
+
+    #[test]
+    fn test_passing_rom() {
+        let report = run_test_rom(protocol_rom(0), CPU_FREQUENCY).unwrap();
+        assert_eq!(report.outcome, TestOutcome::Passed);
+        assert_eq!(report.message, "done");
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn test_failing_rom() {
+        let report = run_test_rom(protocol_rom(3), CPU_FREQUENCY).unwrap();
+        assert_eq!(report.outcome, TestOutcome::Failed(3));
+    }
+
+    #[test]
+    fn test_timeout_and_crash() {
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  JMP reset
+            stop:   .byte $02
+                    .org $fffc
+                    .word reset, 0
+            ",
+        )
+        .unwrap();
+        let report = run_test_rom(test_rom_with_prg(program.code.clone()), 1000).unwrap();
+        assert_eq!(report.outcome, TestOutcome::Timeout);
+
+        let mut prg = program.code.clone();
+        prg[0x7ffc] = 0x03;
+        let report = run_test_rom(test_rom_with_prg(prg), 1000).unwrap();
+        assert_eq!(
+            report.outcome,
+            TestOutcome::Crashed("CPU jammed by $02 at $8003".to_string())
+        );
+
+        let mut prg = program.code;
+        prg[0..3].copy_from_slice(&[0x8D, 0x00, 0x20]);
+        let report = run_test_rom(test_rom_with_prg(prg), 1000).unwrap();
+        assert_eq!(
+            report.outcome,
+            TestOutcome::Crashed(
+                "write of PPU register $2000, the PPU is not emulated yet".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_brk_handler() {
+        let program = crate::asm::assemble(
+            "
+                    .org $8000
+            reset:  LDA #$80
+                    STA $6000
+                    LDA #$de
+                    STA $6001
+                    LDA #$b0
+                    STA $6002
+                    LDA #$61
+                    STA $6003
+                    BRK
+                    .byte 0
+            hang:   JMP hang
+            irq:    LDA #0
+                    STA $6000
+                    RTI
+                    .org $fffc
+                    .word reset, irq
+            ",
+        )
+        .unwrap();
+        let report = run_test_rom(test_rom_with_prg(program.code), CPU_FREQUENCY).unwrap();
+        assert_eq!(report.outcome, TestOutcome::Passed);
+    }
+
+    #[test]
+    fn test_run_frames() {
+        assert_eq!(run_frames(protocol_rom(0), 2), Err(NO_PPU.to_string()));
+        let mut rom = protocol_rom(0);
+        rom.mapper = 1;
+        assert_eq!(
+            run_frames(rom, 2),
+            Err("Mapper 1 is not supported".to_string())
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use rust_nes_emulator::hash;
+use rust_nes_emulator::render::ntsc::NtscFilter;
+use rust_nes_emulator::render::palette::Palette;
+use rust_nes_emulator::render::png;
+use rust_nes_emulator::rom::loader;
+use rust_nes_emulator::testrunner::{self, TestOutcome, CPU_FREQUENCY};
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: testrunner [--timeout SECONDS] ROM|DIR...
+       testrunner --screenshot FILE [--frames N] [--palette FILE] [--ntsc] ROM
+
+Runs test ROMs that report their result at $6000 and prints PASS/FAIL per ROM.
+Directories are searched for .nes files recursively.
+With --screenshot runs the ROM for N frames instead, saves the last frame as PNG
+and prints its hash. There is no PPU yet, so for now this reports an error.
+
+options:
+  --timeout SECONDS   give up on a ROM after SECONDS of emulated time (default 60)
+  --screenshot FILE   PNG file to write
+  --frames N          frames to run before taking the screenshot (default 60)
+  --palette FILE      .pal file to convert the screenshot with
+  --ntsc              apply the NTSC composite video filter to the screenshot";
+
+struct Args {
+    timeout: usize,
+    screenshot: Option<String>,
+    frames: usize,
+    palette: Option<String>,
+    ntsc: bool,
+    paths: Vec<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        timeout: 60,
+        screenshot: None,
+        frames: 60,
+        palette: None,
+        ntsc: false,
+        paths: vec![],
+    };
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let timeout = argv.next().ok_or("--timeout needs a value")?;
+                args.timeout = timeout
+                    .parse()
+                    .map_err(|_| format!("malformed timeout '{}'", timeout))?;
+            }
+            "--screenshot" => {
+                args.screenshot = Some(argv.next().ok_or("--screenshot needs a value")?);
+            }
+            "--frames" => {
+                let frames = argv.next().ok_or("--frames needs a value")?;
+                args.frames = frames
+                    .parse()
+                    .map_err(|_| format!("malformed frame count '{}'", frames))?;
+            }
+            "--palette" => args.palette = Some(argv.next().ok_or("--palette needs a value")?),
+            "--ntsc" => args.ntsc = true,
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => args.paths.push(arg),
+        }
+    }
+
+    if args.paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    if args.screenshot.is_some() && args.paths.len() != 1 {
+        return Err("--screenshot takes exactly one ROM".to_string());
+    }
+    Ok(args)
+}
+
+fn screenshot(args: &Args, path: &str) -> Result<u32, String> {
+    let rom = &args.paths[0];
+    let picture = testrunner::run_frames(loader::load(Path::new(rom))?, args.frames)
+        .map_err(|e| format!("{}: {}", rom, e))?;
+
+    let (png, hash) = if args.ntsc {
+        let filter = NtscFilter::default();
+        let rgb = filter.filter(&picture, 0, args.frames);
+        (
+            png::encode_rgb(filter.width, NtscFilter::HEIGHT, &rgb),
+            hash::crc32(&rgb),
+        )
+    } else {
+        let palette = match &args.palette {
+            Some(file) => Palette::load(file)?,
+            None => Palette::default(),
+        };
+        let frame = palette.to_frame(&picture, 0);
+        (frame.to_png(), frame.hash())
+    };
+    std::fs::write(path, png).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(hash)
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    if let Some(path) = &args.screenshot {
+        match screenshot(&args, path) {
+            Ok(hash) => println!("{:08x}", hash),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut roms: Vec<PathBuf> = vec![];
+    for path in &args.paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            roms.extend(testrunner::find_test_roms(path).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }));
+        } else {
+            roms.push(path.to_path_buf());
+        }
+    }
+
+    let mut failed = 0;
+    for rom in &roms {
+        let path = rom.display().to_string();
+        let report = match testrunner::run_test_rom_file(&path, args.timeout * CPU_FREQUENCY) {
+            Ok(report) => report,
+            Err(e) => {
+                println!("FAIL {} ({})", path, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        if report.passed() {
+            println!("PASS {}", path);
+            continue;
+        }
+        failed += 1;
+        let reason = match &report.outcome {
+            TestOutcome::Failed(code) => format!("result code {}", code),
+            TestOutcome::Timeout => "timed out".to_string(),
+            TestOutcome::Crashed(reason) => format!("crashed: {}", reason),
+            TestOutcome::Passed => unreachable!(),
+        };
+        println!("FAIL {} ({})", path, reason);
+        for line in report.message.lines().filter(|l| !l.trim().is_empty()) {
+            println!("     {}", line);
+        }
+    }
+
+    println!("{} passed, {} failed", roms.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
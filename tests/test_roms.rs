@@ -0,0 +1,26 @@
+// Runs every test ROM under tests/roms (e.g. blargg's instr_test-v5 singles).
+// The ROMs are not distributed with the repository; drop them in and run
+// `cargo test -- --ignored`.
+use rust_nes_emulator::testrunner::{self, CPU_FREQUENCY};
+use std::path::Path;
+
+#[test]
+#[ignore = "needs test ROMs in tests/roms"]
+fn test_roms() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+    assert!(dir.is_dir(), "no test ROMs, {} is missing", dir.display());
+
+    let mut failures = vec![];
+    for rom in testrunner::find_test_roms(&dir).unwrap() {
+        let path = rom.display().to_string();
+        match testrunner::run_test_rom_file(&path, 60 * CPU_FREQUENCY) {
+            Ok(report) if report.passed() => {}
+            Ok(report) => failures.push(format!(
+                "{}: {:?}\n{}",
+                path, report.outcome, report.message
+            )),
+            Err(e) => failures.push(e),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
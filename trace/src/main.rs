@@ -1,20 +1,271 @@
 use rust_nes_emulator::bus::Bus;
+use rust_nes_emulator::cdl::listing;
+use rust_nes_emulator::cdl::CodeDataLogger;
 use rust_nes_emulator::cpu::cpu::CPU;
-use rust_nes_emulator::cpu::trace;
-use rust_nes_emulator::rom::Rom;
+use rust_nes_emulator::cpu::tracer::{parse_hex, TraceOptions, Tracer};
+use rust_nes_emulator::profiler::{Profiler, SortBy};
+use rust_nes_emulator::rom::db::RomDb;
+use rust_nes_emulator::rom::loader;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const USAGE: &str = "usage: trace [options] [ROM]
+
+Without a ROM runs nestest.nes in automation mode (from $C000).
+ROM can be a .nes or .unf file or a .zip or .gz archive with one.
+
+options:
+  --format nestest|mesen|json   line format (default nestest)
+  --range FROM-TO               trace only PCs in the range, e.g. C000-C0FF
+  --bank N                      trace only PCs in 16KiB PRG bank N
+  --start pc:ADDR|frame:N       start tracing when the trigger fires
+  --stop pc:ADDR|frame:N        stop tracing when the trigger fires
+  --entry ADDR                  start execution at ADDR instead of the reset vector
+  --db FILE                     add the games listed in FILE to the ROM database
+  --output FILE                 write to FILE instead of stdout
+  --limit N                     stop after N lines
+  --instructions N              stop after executing N instructions
+  --cdl FILE                    record a code/data log (FCEUX .cdl) into FILE
+  --listing FILE                write a disassembly of PRG-ROM to FILE that tells
+                                the code the run executed from data
+  --profile FILE                write cycles per routine and per address to FILE
+  --sort self|inclusive         order of routines in the profile (default self)
+  --folded FILE                 write folded call stacks for flamegraph tools to FILE
+
+With --profile or --folded the trace is only written when --output is given.";
+
+struct Args {
+    options: TraceOptions,
+    rom: String,
+    entry: Option<u16>,
+    db: Option<String>,
+    output: Option<String>,
+    instructions: Option<usize>,
+    cdl: Option<String>,
+    listing: Option<String>,
+    profile: Option<String>,
+    sort: SortBy,
+    folded: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        options: TraceOptions::default(),
+        rom: "nestest.nes".to_string(),
+        entry: None,
+        db: None,
+        output: None,
+        instructions: None,
+        cdl: None,
+        listing: None,
+        profile: None,
+        sort: SortBy::SelfCycles,
+        folded: None,
+    };
+    let mut rom = None;
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--format" => args.options.format = value()?.parse()?,
+            "--range" => {
+                let range = value()?;
+                let (from, to) = range
+                    .split_once('-')
+                    .ok_or(format!("malformed range '{}'", range))?;
+                args.options.ranges.push(parse_hex(from)?..=parse_hex(to)?);
+            }
+            "--bank" => {
+                let bank = value()?;
+                args.options.banks.push(
+                    bank.parse()
+                        .map_err(|_| format!("malformed bank '{}'", bank))?,
+                );
+            }
+            "--start" => args.options.start = Some(value()?.parse()?),
+            "--stop" => args.options.stop = Some(value()?.parse()?),
+            "--entry" => args.entry = Some(parse_hex(&value()?)?),
+            "--db" => args.db = Some(value()?),
+            "--output" => args.output = Some(value()?),
+            "--instructions" => {
+                let count = value()?;
+                args.instructions = Some(
+                    count
+                        .parse()
+                        .map_err(|_| format!("malformed instruction count '{}'", count))?,
+                );
+            }
+            "--cdl" => args.cdl = Some(value()?),
+            "--listing" => args.listing = Some(value()?),
+            "--profile" => args.profile = Some(value()?),
+            "--sort" => {
+                args.sort = match value()?.as_str() {
+                    "self" => SortBy::SelfCycles,
+                    "inclusive" => SortBy::InclusiveCycles,
+                    sort => return Err(format!("unknown sort order '{}'", sort)),
+                }
+            }
+            "--folded" => args.folded = Some(value()?),
+            "--limit" => {
+                let limit = value()?;
+                args.options.line_limit = Some(
+                    limit
+                        .parse()
+                        .ok()
+                        .filter(|limit| *limit > 0)
+                        .ok_or(format!("malformed limit '{}'", limit))?,
+                );
+            }
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    match rom {
+        Some(rom) => args.rom = rom,
+        // nestest automation mode
+        None => args.entry = args.entry.or(Some(0xc000)),
+    }
+    Ok(args)
+}
+
+fn run<W: Write>(
+    cpu: &mut CPU,
+    mut tracer: Option<Tracer<W>>,
+    profiler: &mut Option<Profiler>,
+    instructions: Option<usize>,
+) -> io::Result<()> {
+    let mut executed = 0;
+    loop {
+        if let Some(tracer) = &mut tracer {
+            tracer.trace(cpu)?;
+            if tracer.is_done() {
+                break;
+            }
+        }
+        if instructions == Some(executed) {
+            break;
+        }
+
+        let running = match profiler {
+            Some(profiler) => profiler.step(cpu),
+            None => cpu.step(),
+        };
+        if !running {
+            break;
+        }
+        executed += 1;
+    }
+    match &mut tracer {
+        Some(tracer) => tracer.flush(),
+        None => Ok(()),
+    }
+}
+
+fn write_profile(profiler: &Profiler, cpu: &CPU, args: &Args) -> io::Result<()> {
+    if let Some(path) = &args.profile {
+        let mut out = BufWriter::new(File::create(path)?);
+        profiler.write_report(&mut out, cpu.cycles, args.sort)?;
+        out.flush()?;
+    }
+    if let Some(path) = &args.folded {
+        let mut out = BufWriter::new(File::create(path)?);
+        profiler.write_folded(&mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn write_listing(path: &str, prg_rom: &[u8], cdl: &CodeDataLogger) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    listing::write_listing(&mut out, prg_rom, cdl)?;
+    out.flush()
+}
 
 fn main() {
+    let mut args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
     //load the game
-    let bytes: Vec<u8> = std::fs::read("nestest.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let db = match &args.db {
+        Some(file) => RomDb::bundled_with(file),
+        None => Ok(RomDb::bundled()),
+    };
+    let rom = db
+        .and_then(|db| loader::load_with_db(Path::new(&args.rom), None, &db))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
 
-    let bus = Bus::new(rom);
+    // the listing needs the PRG-ROM, the bus only lends out mapped bytes
+    let prg_rom = args.listing.as_ref().map(|_| rom.prg_rom.clone());
+    let mut bus = Bus::new(rom).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if args.cdl.is_some() || args.listing.is_some() {
+        bus.enable_cdl();
+    }
     let mut cpu = CPU::new(bus);
     cpu.reset();
 
-    cpu.program_counter = 0xc000;
+    if let Some(entry) = args.entry {
+        cpu.program_counter = entry;
+    }
 
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
-    });
+    let mut profiler = if args.profile.is_some() || args.folded.is_some() {
+        Some(Profiler::new())
+    } else {
+        None
+    };
+
+    let options = std::mem::take(&mut args.options);
+    let result = match &args.output {
+        Some(path) => {
+            let tracer = Tracer::to_file(options, path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            });
+            run(&mut cpu, Some(tracer), &mut profiler, args.instructions)
+        }
+        // profiling long runs should not dump millions of lines on the terminal
+        None if profiler.is_some() => {
+            run::<io::Sink>(&mut cpu, None, &mut profiler, args.instructions)
+        }
+        None => {
+            let tracer = Tracer::new(options, io::stdout().lock());
+            run(&mut cpu, Some(tracer), &mut profiler, args.instructions)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(profiler) = &profiler {
+        write_profile(profiler, &cpu, &args).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
+
+    if let (Some(path), Some(cdl)) = (&args.cdl, cpu.bus().cdl()) {
+        cdl.save(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        });
+    }
+
+    if let (Some(path), Some(prg_rom), Some(cdl)) = (&args.listing, &prg_rom, cpu.bus().cdl()) {
+        write_listing(path, prg_rom, cdl).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        });
+    }
 }